@@ -0,0 +1,44 @@
+use callm::templates::{MessageRole, TemplateImpl, TemplateJinja};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+// Meta-Llama-3-8B-Instruct
+const JINJA_TEMPLATE: &str = r#"{% set loop_messages = messages %}{% for message in loop_messages %}{% set content = '<|start_header_id|>' + message['role'] + '<|end_header_id|>
+
+'+ message['content'] | trim + '<|eot_id|>' %}{% if loop.index0 == 0 %}{% set content = bos_token + content %}{% endif %}{{ content }}{% endfor %}{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>
+
+' }}{% endif %}"#;
+
+// chat history re-rendered on every turn of a chat loop
+fn history(turns: usize) -> Vec<(MessageRole, String)> {
+    let mut messages = vec![(
+        MessageRole::System,
+        "You are a helpful assistant.".to_string(),
+    )];
+    for turn in 0..turns {
+        messages.push((MessageRole::User, format!("Question number {}?", turn)));
+        messages.push((MessageRole::Assistant, format!("Answer number {}.", turn)));
+    }
+    messages.push((MessageRole::User, "Last question?".to_string()));
+    messages
+}
+
+fn bench_apply(c: &mut Criterion) {
+    let messages = history(10);
+
+    let mut group = c.benchmark_group("template_jinja_apply");
+    group.bench_function("compiled_once", |b| {
+        let template = TemplateJinja::new(JINJA_TEMPLATE);
+        b.iter(|| template.apply(black_box(&messages)).unwrap())
+    });
+    group.bench_function("compiled_per_apply", |b| {
+        b.iter(|| {
+            TemplateJinja::new(JINJA_TEMPLATE)
+                .apply(black_box(&messages))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_apply);
+criterion_main!(benches);
@@ -1,6 +1,9 @@
 //! This module provides computation device configuration.
 
+use crate::error::CallmError;
 use candle_core::{DType, Device as CandleDevice};
+use std::fmt;
+use std::str::FromStr;
 
 /// Enum representing different types of devices available for computation.
 #[derive(Clone, Debug, PartialEq)]
@@ -13,6 +16,47 @@ pub enum Device {
     Metal(usize),
 }
 
+impl fmt::Display for Device {
+    /// Formats the device as `cpu`, `cuda:N` or `metal:N`, which `from_str` parses back.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Device::CPU => write!(f, "cpu"),
+            Device::Cuda(n) => write!(f, "cuda:{}", n),
+            Device::Metal(n) => write!(f, "metal:{}", n),
+        }
+    }
+}
+
+impl FromStr for Device {
+    type Err = CallmError;
+
+    /// Parses `cpu`, `cuda`, `cuda:N`, `metal` or `metal:N`, ignoring case.
+    ///
+    /// A missing index selects the first device.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let (name, index) = match s.split_once(':') {
+            Some((name, index)) => {
+                let index = index.parse::<usize>().map_err(|_| {
+                    CallmError::GenericError(format!("Invalid device index in `{}`", s))
+                })?;
+                (name, index)
+            }
+            None => (s.as_str(), 0),
+        };
+
+        match name {
+            "cpu" if index == 0 => Ok(Device::CPU),
+            "cuda" => Ok(Device::Cuda(index)),
+            "metal" => Ok(Device::Metal(index)),
+            _ => Err(CallmError::GenericError(format!(
+                "Unknown device `{}`, expected cpu, cuda[:N] or metal[:N]",
+                s
+            ))),
+        }
+    }
+}
+
 /// Struct describing the configuration of a device.
 #[derive(Clone, Debug)]
 pub struct DeviceConfig {
@@ -28,15 +72,54 @@ impl DeviceConfig {
     /// Automatically detects the available device and initializes the configuration.
     ///
     /// This function checks for the availability of CUDA and Metal devices. If none are available,
-    /// it defaults to using the CPU.
+    /// it defaults to using the CPU. GPU devices are only probed when the corresponding `cuda` or
+    /// `metal` feature is enabled, so CPU-only builds never touch GPU code paths. A GPU that is
+    /// reported available but fails to initialize, e.g. due to a broken driver install, is skipped
+    /// with a warning.
     pub fn autodetect() -> Self {
+        #[cfg(feature = "cuda")]
         if candle_core::utils::cuda_is_available() {
-            Self::new(Device::Cuda(0))
-        } else if candle_core::utils::metal_is_available() {
-            Self::new(Device::Metal(0))
-        } else {
-            Self::new(Device::CPU)
+            match Self::try_new(Device::Cuda(0)) {
+                Ok(config) => return config,
+                Err(e) => log::warn!("CUDA device initialization failed: {}", e),
+            }
         }
+
+        #[cfg(feature = "metal")]
+        if candle_core::utils::metal_is_available() {
+            match Self::try_new(Device::Metal(0)) {
+                Ok(config) => return config,
+                Err(e) => log::warn!("Metal device initialization failed: {}", e),
+            }
+        }
+
+        Self::new(Device::CPU)
+    }
+
+    /// Lists the devices available for computation, starting with the CPU.
+    ///
+    /// GPU devices are only listed when the corresponding `cuda` or `metal` feature is enabled.
+    /// CUDA devices are counted by the driver without creating a context on each of them.
+    pub fn available() -> Vec<Device> {
+        let mut devices = vec![Device::CPU];
+
+        #[cfg(feature = "cuda")]
+        if candle_core::utils::cuda_is_available() {
+            use candle_core::cuda_backend::cudarc::driver::CudaDevice;
+
+            match CudaDevice::count() {
+                Ok(count) => devices.extend((0..count as usize).map(Device::Cuda)),
+                Err(e) => log::warn!("CUDA device count query failed: {}", e),
+            }
+        }
+
+        #[cfg(feature = "metal")]
+        if candle_core::utils::metal_is_available() && CandleDevice::new_metal(0).is_ok() {
+            // NOTE: candle always opens the system default Metal device, whatever the index
+            devices.push(Device::Metal(0));
+        }
+
+        devices
     }
 
     /// Creates a new `DeviceConfig` with the specified device.
@@ -44,24 +127,56 @@ impl DeviceConfig {
     /// # Arguments
     ///
     /// * `device` - The device type to be used.
+    ///
+    /// Panics if the device cannot be created, see `try_new`.
     pub fn new(device: Device) -> Self {
+        Self::try_new(device).expect("Device creation error")
+    }
+
+    /// Creates a new `DeviceConfig` with the specified device, returning an error instead of
+    /// panicking if the device cannot be created.
+    ///
+    /// Fails for GPU devices whose index is out of range or whose support is not compiled in.
+    pub fn try_new(device: Device) -> Result<Self, CallmError> {
         let (candle_device, candle_dtype) = match device {
             Device::CPU => (CandleDevice::Cpu, DType::F32),
-            Device::Cuda(n) => (
-                CandleDevice::new_cuda(n).expect("CUDA device creation error"),
-                DType::BF16,
-            ),
-            Device::Metal(n) => (
-                CandleDevice::new_metal(n).expect("Metal device creation error"),
-                DType::F32,
-            ),
+            Device::Cuda(n) => (CandleDevice::new_cuda(n)?, DType::BF16),
+            Device::Metal(n) => (CandleDevice::new_metal(n)?, DType::F32),
         };
 
-        Self {
+        Ok(Self {
             device,
             candle_device,
             candle_dtype,
-        }
+        })
+    }
+
+    /// Creates a new `DeviceConfig` with the specified device, falling back to the CPU if the
+    /// device cannot be created.
+    pub fn new_or_cpu(device: Device) -> Self {
+        Self::try_new(device).unwrap_or_else(|e| {
+            log::warn!("Falling back to CPU: {}", e);
+            Self::cpu()
+        })
+    }
+
+    /// Creates a new `DeviceConfig` for the CPU.
+    pub fn cpu() -> Self {
+        Self::new(Device::CPU)
+    }
+
+    /// Creates a new `DeviceConfig` for the CUDA device with index `n`.
+    ///
+    /// Panics if the device cannot be created, same as `new`.
+    pub fn cuda(n: usize) -> Self {
+        Self::new(Device::Cuda(n))
+    }
+
+    /// Creates a new `DeviceConfig` for the Metal device with index `n`.
+    ///
+    /// Panics if the device cannot be created, same as `new`.
+    pub fn metal(n: usize) -> Self {
+        Self::new(Device::Metal(n))
     }
 
     /// Returns a reference to the device.
@@ -78,6 +193,37 @@ impl DeviceConfig {
     pub fn candle_dtype(&self) -> DType {
         self.candle_dtype
     }
+
+    /// Overrides the default data type of the device, e.g. F16 on CUDA to save memory.
+    ///
+    /// Returns an error if the device does not support computing in `dtype`.
+    pub fn with_dtype(mut self, dtype: DType) -> Result<Self, CallmError> {
+        let supported = match self.device {
+            Device::CPU | Device::Cuda(_) => {
+                matches!(dtype, DType::F16 | DType::BF16 | DType::F32 | DType::F64)
+            }
+            // NOTE: candle Metal kernels lack F64 and cover BF16 only partially
+            Device::Metal(_) => matches!(dtype, DType::F16 | DType::F32),
+        };
+        if !supported {
+            return Err(CallmError::GenericError(format!(
+                "Data type {:?} is not supported on {:?}",
+                dtype, self.device
+            )));
+        }
+
+        self.candle_dtype = dtype;
+        Ok(self)
+    }
+}
+
+impl FromStr for DeviceConfig {
+    type Err = CallmError;
+
+    /// Parses a device string like `cuda:1` and creates the device, see `Device::from_str`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_new(s.parse()?)
+    }
 }
 
 impl Default for DeviceConfig {
@@ -109,6 +255,21 @@ mod tests {
         assert_eq!(config.candle_dtype(), DType::F32);
     }
 
+    #[test]
+    fn test_cpu() {
+        let config = DeviceConfig::cpu();
+        assert_eq!(config.device(), &Device::CPU);
+        assert!(config.candle_device().is_cpu());
+    }
+
+    #[cfg(not(any(feature = "cuda", feature = "metal")))]
+    #[test]
+    fn test_autodetect_cpu_only() {
+        let config = DeviceConfig::autodetect();
+        assert_eq!(config.device(), &Device::CPU);
+        assert!(config.candle_device().is_cpu());
+    }
+
     #[cfg(feature = "cuda")]
     #[test]
     fn test_new_cuda() {
@@ -116,6 +277,7 @@ mod tests {
         assert_eq!(config.device(), &Device::Cuda(0));
         assert!(config.candle_device().is_cuda());
         assert_eq!(config.candle_dtype(), DType::BF16);
+        assert_eq!(DeviceConfig::cuda(0).device(), &Device::Cuda(0));
     }
 
     #[cfg(feature = "metal")]
@@ -125,6 +287,67 @@ mod tests {
         assert_eq!(config.device(), &Device::Metal(0));
         assert!(config.candle_device().is_metal());
         assert_eq!(config.candle_dtype(), DType::F32);
+        assert_eq!(DeviceConfig::metal(0).device(), &Device::Metal(0));
+    }
+
+    #[test]
+    fn test_with_dtype() {
+        let config = DeviceConfig::cpu().with_dtype(DType::F16).unwrap();
+        assert_eq!(config.candle_dtype(), DType::F16);
+        assert_eq!(config.device(), &Device::CPU);
+        assert!(DeviceConfig::cpu().with_dtype(DType::U32).is_err());
+    }
+
+    #[test]
+    fn test_device_from_str() {
+        assert_eq!("cpu".parse::<Device>().unwrap(), Device::CPU);
+        assert_eq!(" CUDA ".parse::<Device>().unwrap(), Device::Cuda(0));
+        assert_eq!("cuda:1".parse::<Device>().unwrap(), Device::Cuda(1));
+        assert_eq!("metal".parse::<Device>().unwrap(), Device::Metal(0));
+        assert_eq!("metal:2".parse::<Device>().unwrap(), Device::Metal(2));
+
+        assert!("cpu:1".parse::<Device>().is_err());
+        assert!("cuda:".parse::<Device>().is_err());
+        assert!("cuda:-1".parse::<Device>().is_err());
+        assert!("tpu".parse::<Device>().is_err());
+    }
+
+    #[test]
+    fn test_device_display() {
+        for device in [Device::CPU, Device::Cuda(1), Device::Metal(0)] {
+            assert_eq!(device.to_string().parse::<Device>().unwrap(), device);
+        }
+        assert_eq!(Device::Cuda(1).to_string(), "cuda:1");
+    }
+
+    #[test]
+    fn test_available() {
+        let devices = DeviceConfig::available();
+        assert_eq!(devices.first(), Some(&Device::CPU));
+        #[cfg(not(any(feature = "cuda", feature = "metal")))]
+        assert_eq!(devices, vec![Device::CPU]);
+    }
+
+    #[test]
+    fn test_device_config_from_str() {
+        let config: DeviceConfig = "cpu".parse().unwrap();
+        assert_eq!(config.device(), &Device::CPU);
+        assert!(config.candle_device().is_cpu());
+    }
+
+    #[cfg(not(feature = "cuda"))]
+    #[test]
+    fn test_device_config_from_str_unavailable() {
+        assert!("cuda:0".parse::<DeviceConfig>().is_err());
+    }
+
+    #[cfg(not(feature = "cuda"))]
+    #[test]
+    fn test_new_or_cpu() {
+        assert!(DeviceConfig::try_new(Device::Cuda(0)).is_err());
+        let config = DeviceConfig::new_or_cpu(Device::Cuda(0));
+        assert_eq!(config.device(), &Device::CPU);
+        assert!(config.candle_device().is_cpu());
     }
 
     #[test]
@@ -137,4 +360,3 @@ mod tests {
         }
     }
 }
-
@@ -18,12 +18,16 @@ pub enum CallmError {
     #[error("Unsupported model")]
     UnsupportedModel,
 
+    /// An error indicating that the model weights use an unsupported quantization scheme.
+    #[error("Unsupported quantization scheme `{0}`")]
+    UnsupportedQuantization(String),
+
     /// An error wrapping an I/O error from the standard library.
-    #[error("I/O error")]
+    #[error("I/O error: {0}")]
     IOError(#[from] io::Error),
 
     /// An error wrapping a Candle error from the `candle_core` crate.
-    #[error("Candle error")]
+    #[error("Candle error: {0}")]
     CandleError(#[from] candle_core::Error),
 
     /// An error indicating a failure in the template with a custom message.
@@ -31,10 +35,35 @@ pub enum CallmError {
     TemplateError(String),
 
     /// An error indicating a failure in the tokenizer with a custom message.
-    #[error("Tokenizer error")]
+    #[error("Tokenizer error: {msg}")]
     TokenizerError { msg: String },
 
+    /// An error indicating that the prompt exceeds the configured token limit or the model context.
+    #[error("Prompt too long: {tokens} tokens exceed the limit of {limit}")]
+    PromptTooLong { tokens: usize, limit: usize },
+
     /// An error wrapping a serialization/deserialization error from the `serde_json` crate.
-    #[error("Serialization/Deserialization error")]
+    #[error("Serialization/Deserialization error: {0}")]
     SerdeError(#[from] serde_json::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_includes_source() {
+        let e = CallmError::from(io::Error::new(io::ErrorKind::NotFound, "config.json"));
+        assert_eq!(e.to_string(), "I/O error: config.json");
+
+        let e = CallmError::from(serde_json::from_str::<u32>("x").unwrap_err());
+        assert!(e
+            .to_string()
+            .starts_with("Serialization/Deserialization error: expected value"));
+
+        let e = CallmError::TokenizerError {
+            msg: "unknown token".to_string(),
+        };
+        assert_eq!(e.to_string(), "Tokenizer error: unknown token");
+    }
+}
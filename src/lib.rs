@@ -7,3 +7,5 @@ pub mod models;
 pub mod pipelines;
 pub mod templates;
 pub mod utils;
+
+pub use utils::inspect;
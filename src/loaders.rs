@@ -2,6 +2,8 @@
 
 pub mod gguf;
 pub use gguf::LoaderGguf;
+#[cfg(test)]
+pub(crate) mod mock;
 pub mod safetensors;
 pub use safetensors::LoaderSafetensors;
 
@@ -9,14 +11,125 @@ use crate::device::DeviceConfig;
 use crate::error::CallmError;
 use crate::models::ModelImpl;
 use crate::templates::TemplateImpl;
+use candle_core::DType;
+use std::fmt;
 use std::sync::{Arc, Mutex};
 use tokenizers::tokenizer::Tokenizer;
 
+/// Format-specific options for model loaders.
+///
+/// Loaders ignore options they do not support:
+///
+/// | Option | `LoaderSafetensors` | `LoaderGguf` |
+/// | :--- | :---: | :---: |
+/// | `dtype` | ✅ | ❌ |
+/// | `eos_policy` | ✅ | ✅ |
+/// | `disable_kv_cache` | ✅ (Llama only) | ❌ |
+#[derive(Clone, Debug, Default)]
+pub struct LoaderOptions {
+    /// Data type for model weights and computation, overriding the device default.
+    pub dtype: Option<DType>,
+    /// Source of the EOS token when model config and tokenizer disagree.
+    pub eos_policy: EosPolicy,
+    /// Runs the model without a KV cache, for debugging. Makes generation much slower.
+    pub disable_kv_cache: bool,
+}
+
+/// Contents of special tokens that end a turn, treated as stop tokens when a model declares them.
+///
+/// Follows the end-of-generation tokens llama.cpp recognizes by name.
+pub(crate) const END_OF_TURN_TOKENS: &[&str] = &[
+    "<|eot_id|>",
+    "<|eom_id|>",
+    "<|im_end|>",
+    "<|end|>",
+    "<end_of_turn>",
+    "<|endoftext|>",
+    "<EOT>",
+];
+
+/// Policy for resolving the EOS token.
+///
+/// GGUF files embed both model config and tokenizer in their metadata, so `ModelConfig` and
+/// `Tokenizer` resolve to the same `tokenizer.ggml.eos_token_id` there.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EosPolicy {
+    /// Use the model-declared EOS. Currently equivalent to `ModelConfig`.
+    ///
+    /// End-of-turn tokens such as Meta Llama 3 `<|eot_id|>` no longer need to replace the EOS:
+    /// loaders report every declared end token through `LoaderImpl::stop_token_ids`, and
+    /// generation stops at any of them under all policies.
+    #[default]
+    ArchitectureFix,
+    /// Use `eos_token_id` from `config.json` or GGUF metadata as-is.
+    ModelConfig,
+    /// Use `eos_token` from `tokenizer_config.json`, falling back to the model config.
+    Tokenizer,
+}
+
+/// Format-neutral description of a loaded model.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ModelInfo {
+    /// Model architecture as declared by the model, e.g. `llama` in GGUF metadata or
+    /// `LlamaForCausalLM` in `config.json`.
+    pub architecture: String,
+    /// Model name.
+    pub name: Option<String>,
+    /// Model author.
+    pub author: Option<String>,
+    /// Weight type of quantized models, e.g. `Q4_K_M`.
+    pub quantization: Option<String>,
+    /// Context window size.
+    pub context_length: Option<usize>,
+    /// BOS token ID declared by the model.
+    pub bos_token_id: Option<u32>,
+    /// EOS token ID declared by the model.
+    pub eos_token_id: Option<u32>,
+    /// EOS token content used to stop generation, after applying the `EosPolicy`.
+    pub eos_token: Option<String>,
+}
+
+/// Callback receiving model loading progress as `(bytes_loaded, bytes_total)`.
+///
+/// `bytes_total` is the size of the model files. `LoaderSafetensors` reports each tensor as it
+/// is loaded, `LoaderGguf` reports reading the GGUF header and then the tensors. Loaders report
+/// `bytes_loaded == bytes_total` once the model is loaded.
+#[derive(Clone)]
+pub struct LoadProgress(Arc<dyn Fn(u64, u64) + Send + Sync>);
+
+impl LoadProgress {
+    /// Creates a new `LoadProgress` from a callback.
+    pub fn new(callback: impl Fn(u64, u64) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    /// Reports `bytes_loaded` out of `bytes_total`.
+    pub fn report(&self, bytes_loaded: u64, bytes_total: u64) {
+        (self.0)(bytes_loaded, bytes_total)
+    }
+}
+
+impl fmt::Debug for LoadProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LoadProgress")
+    }
+}
+
 /// A trait for defining the interface of model loaders.
 pub trait LoaderImpl: Send {
     /// Sets the device configuration for the loader.
     fn set_device(&mut self, device: Arc<DeviceConfig>);
 
+    /// Sets format-specific options for the loader.
+    ///
+    /// The default implementation ignores all options.
+    fn set_options(&mut self, _options: LoaderOptions) {}
+
+    /// Sets a callback receiving loading progress.
+    ///
+    /// The default implementation ignores the callback.
+    fn set_progress(&mut self, _progress: LoadProgress) {}
+
     /// Loads the model and returns it wrapped in an `Arc<Mutex<dyn ModelImpl>>`.
     fn load(&mut self) -> Result<Arc<Mutex<dyn ModelImpl>>, CallmError>;
 
@@ -25,5 +138,34 @@ pub trait LoaderImpl: Send {
 
     /// Returns the template associated with the model.
     fn template(&mut self) -> Result<Box<dyn TemplateImpl>, CallmError>;
-}
 
+    /// Returns tokens the model declares as ending a turn, besides the EOS token.
+    ///
+    /// Only available once the model has been loaded.
+    fn stop_token_ids(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    /// Returns a description of the model.
+    ///
+    /// Only available once the model has been loaded.
+    fn info(&self) -> Option<ModelInfo> {
+        None
+    }
+
+    /// Reads the model description from metadata only, without loading any weights.
+    ///
+    /// The default implementation returns an error.
+    fn inspect(&mut self) -> Result<ModelInfo, CallmError> {
+        Err(CallmError::GenericError(
+            "Loader does not support inspecting models".to_string(),
+        ))
+    }
+
+    /// Returns the context window size declared by the model, if known.
+    ///
+    /// Only available once the model has been loaded.
+    fn context_length(&self) -> Option<usize> {
+        None
+    }
+}
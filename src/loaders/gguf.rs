@@ -2,22 +2,84 @@
 //!
 //! file format specification: `<https://github.com/ggerganov/ggml/blob/8d6b7038871fada44fbaa61dd5eabe5fccab1cbb/docs/gguf.md>`
 
+pub mod gemma;
 pub mod llama;
+pub mod phi3;
 
-use super::LoaderImpl;
+use super::{LoadProgress, LoaderImpl, LoaderOptions, ModelInfo, END_OF_TURN_TOKENS};
 use crate::device::DeviceConfig;
 use crate::error::CallmError;
-use crate::models::{ModelImpl, ModelLlamaQuantized};
+use crate::models::{ModelGemmaQuantized, ModelImpl, ModelLlamaQuantized, ModelPhi3Quantized};
 use crate::templates::{TemplateDummy, TemplateImpl, TemplateJinja};
+use crate::utils::Timer;
 use candle_core::quantized::gguf_file::{Content, Value};
-use llama::{parse_llama_kv, LoaderGgufInfoModelLlama};
-use std::collections::HashMap;
+use gemma::{parse_gemma_kv, LoaderGgufInfoModelGemma};
+use llama::{apply_llama_kv_defaults, parse_llama_kv, LoaderGgufInfoModelLlama};
+use phi3::{parse_phi3_kv, LoaderGgufInfoModelPhi3};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
 use tokenizers::Tokenizer;
 
+pub use candle_core::quantized::gguf_file::Value as GgufValue;
+
+/// Model handler used for a GGUF file
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GgufHandler {
+    /// Gemma and Gemma 2 models
+    Gemma,
+    Llama,
+    /// Mistral models distributed with the `llama` architecture
+    Mistral,
+    Phi3,
+}
+
+/// Space marker of SentencePiece tokenizers
+const SPM_SPACE: &str = "\u{2581}";
+
+// pre-tokenizer split patterns for `tokenizer.ggml.pre`, applied in order as in llama.cpp
+const PRE_LLAMA_BPE: &[&str] = &[
+    r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+];
+const PRE_DEEPSEEK_LLM: &[&str] = &[
+    r"[\r\n]",
+    r"\s?[A-Za-zµÀ-ÖØ-öø-ƺƼ-ƿǄ-ʓʕ-ʯͰ-ͳͶͷͻ-ͽͿΆΈ-ΊΌΎ-ΡΣ-ϵϷ-ҁҊ-ԯԱ-ՖႠ-ჅᎠ-Ᏽᏸ-ᏽᲐ-ᲺᲽ-Ჿᴀ-ᴫᵫ-ᵷᵹ-ᶚḀ-ἕἘ-Ἕἠ-ὅὈ-Ὅὐ-ὗὙὛὝὟ-ώᾀ-ᾴᾶ-ᾼιῂ-ῄῆ-ῌῐ-ΐῖ-Ίῠ-Ῥῲ-ῴῶ-ῼℂℇℊ-ℓℕℙ-ℝℤΩℨK-ℭℯ-ℴℹℼ-ℿⅅ-ⅉⅎↃↄⰀ-ⱻⱾ-ⳤⳫ-ⳮⳲⳳꙀ-ꙭꚀ-ꚛꜢ-ꝯꝱ-ꞇꞋ-ꞎꭰ-ꮿﬀ-ﬆﬓ-ﬗＡ-Ｚａ-ｚ𐐀-𐑏𐒰-𐓓𐓘-𐓻𐲀-𐲲𐳀-𐳲𑢠-𑣟𞤀-𞥃]+",
+    r"\s?[!-/:-~！-／：-～‘-‟　-。]+",
+    r"\s+$",
+    r"[一-龥ࠀ-一가-퟿]+",
+    r"\p{N}+",
+];
+const PRE_DEEPSEEK_CODER: &[&str] = &[
+    r"[\r\n]",
+    r"\s?\p{L}+",
+    r"\s?\p{P}+",
+    r"[一-龥ࠀ-一가-퟿]+",
+    r"\p{N}",
+];
+const PRE_FALCON: &[&str] = &[
+    r"[\p{P}\$\+<=>\^~\|`]+",
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)",
+    r"[0-9][0-9][0-9]",
+];
+
+// GGUF token types
+const TOKEN_TYPE_NORMAL: i32 = 1;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+
+/// Known `general.architecture` values and the handlers they resolve to
+///
+/// Entries with a name pattern only match when `general.name` contains it (case-insensitive),
+/// so derivatives sharing an architecture string can be special-cased. The first match wins.
+const ARCHITECTURE_ALIASES: &[(&str, Option<&str>, GgufHandler)] = &[
+    ("llama", Some("mistral"), GgufHandler::Mistral),
+    ("llama", None, GgufHandler::Llama),
+    ("gemma", None, GgufHandler::Gemma),
+    ("gemma2", None, GgufHandler::Gemma),
+    ("phi3", None, GgufHandler::Phi3),
+];
+
 /// GGUF general metadata
 #[derive(Clone, Debug, Default)]
 pub struct LoaderGgufInfo {
@@ -52,6 +114,8 @@ pub enum LoaderGgufInfoModel {
     #[default]
     None,
     Llama(LoaderGgufInfoModelLlama),
+    Gemma(LoaderGgufInfoModelGemma),
+    Phi3(LoaderGgufInfoModelPhi3),
 }
 
 /// GGUF tokenizer metadata
@@ -64,9 +128,13 @@ pub struct LoaderGgufInfoTokenizer {
     pre: Option<String>,
     bos_token_id: Option<u32>,
     eos_token_id: Option<u32>,
+    /// All end-of-turn tokens, including every entry of an array-valued EOS
+    stop_token_ids: Vec<u32>,
     unknown_token_id: Option<u32>,
     separator_token_id: Option<u32>,
     padding_token_id: Option<u32>,
+    /// Whether SentencePiece tokenizers prepend a space marker to the input
+    add_space_prefix: Option<bool>,
     chat_template: Option<String>,
     // optional arrays
     scores: Option<Vec<f32>>,
@@ -82,6 +150,9 @@ pub struct LoaderGguf {
     file_size: u64,
     info: LoaderGgufInfo,
     device: Arc<DeviceConfig>,
+    options: LoaderOptions,
+    progress: Option<LoadProgress>,
+    metadata: HashMap<String, Value>,
 }
 
 impl LoaderGguf {
@@ -91,6 +162,40 @@ impl LoaderGguf {
             ..Default::default()
         }
     }
+
+    /// Returns all metadata key-value pairs of the GGUF file, as stored in the file.
+    ///
+    /// Only available once the model has been loaded. Keep a typed handle to the loader to read
+    /// metadata after building a pipeline:
+    ///
+    /// ```no_run
+    /// use callm::loaders::LoaderGguf;
+    /// use callm::pipelines::PipelineText;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let loader = Arc::new(Mutex::new(LoaderGguf::new("/path/to/model.gguf")));
+    /// let _pipeline = PipelineText::builder()
+    ///     .with_loader(loader.clone())
+    ///     .build()?;
+    ///
+    /// let loader = loader.lock().unwrap();
+    /// let mut keys: Vec<_> = loader.metadata().keys().collect();
+    /// keys.sort();
+    /// for key in keys {
+    ///     println!("{} = {:?}", key, loader.metadata()[key]);
+    /// }
+    /// # Ok::<(), callm::error::CallmError>(())
+    /// ```
+    pub fn metadata(&self) -> &HashMap<String, GgufValue> {
+        &self.metadata
+    }
+
+    /// Returns the value of a GGUF metadata key, e.g. `llama.rope.freq_base`.
+    ///
+    /// Only available once the model has been loaded.
+    pub fn metadata_value(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata.get(key)
+    }
 }
 
 impl LoaderImpl for LoaderGguf {
@@ -98,87 +203,144 @@ impl LoaderImpl for LoaderGguf {
         self.device = device;
     }
 
+    fn set_options(&mut self, options: LoaderOptions) {
+        self.options = options;
+    }
+
+    fn set_progress(&mut self, progress: LoadProgress) {
+        self.progress = Some(progress);
+    }
+
     fn load(&mut self) -> Result<Arc<Mutex<dyn ModelImpl>>, CallmError> {
-        let timer = Instant::now();
+        let timer = Timer::start();
         // check if location points to a file
-        let file_metadata = fs::metadata(&self.location)?;
+        let file_metadata =
+            fs::metadata(&self.location).map_err(load_error(&self.location, "access GGUF file"))?;
         if !file_metadata.is_file() {
-            return Err(CallmError::LoaderFail(
-                "Location is not pointing to GGUF file".to_string(),
-            ));
+            return Err(CallmError::LoaderFail(format!(
+                "Location {} is not pointing to GGUF file",
+                self.location.display()
+            )));
         }
         self.file_size = file_metadata.len();
 
         // open file and read GGUF header
-        let mut file = fs::File::open(&self.location).expect("Error opening GGUF file");
-        let gguf_header = Content::read(&mut file).expect("Error reading GGUF header");
+        let mut file = ProgressReader::new(
+            fs::File::open(&self.location).map_err(load_error(&self.location, "open GGUF file"))?,
+            self.file_size,
+            self.progress.clone(),
+        );
+        let mut gguf_header =
+            Content::read(&mut file).map_err(load_error(&self.location, "read GGUF header"))?;
+        // keep metadata as stored in the file, before defaults get applied
+        let metadata = gguf_header.metadata.clone();
 
         // parse general kv
-        let mut gguf_info = parse_general_kv(&gguf_header)?;
+        let mut gguf_info = parse_general_kv(&gguf_header)
+            .map_err(load_error(&self.location, "parse general metadata"))?;
 
         // parse tokenizer kv
-        gguf_info.tokenizer = parse_tokenizer_kv(&gguf_header)?;
+        gguf_info.tokenizer = parse_tokenizer_kv(&gguf_header)
+            .map_err(load_error(&self.location, "parse tokenizer metadata"))?;
 
         // parse model specific kv pairs
-        log::debug!("Model architecture '{}'", gguf_info.architecture.as_str());
+        let handler = resolve_handler(&gguf_info.architecture, gguf_info.name.as_deref());
+        log::debug!(
+            "Model architecture '{}' resolved to handler {:?}",
+            gguf_info.architecture.as_str(),
+            handler
+        );
 
-        let model = match gguf_info.architecture.as_str() {
-            "llama" => {
-                // parse Llama kv (for future use)
-                gguf_info.model = LoaderGgufInfoModel::Llama(
-                    parse_llama_kv(&gguf_header).expect("Error parsing model metadata"),
-                );
+        let model: Arc<Mutex<dyn ModelImpl>> = match handler {
+            Some(GgufHandler::Llama | GgufHandler::Mistral) => {
+                // parse Llama kv, passing defaults on to the quantized model
+                let llama_info = parse_llama_kv(&gguf_header)
+                    .map_err(load_error(&self.location, "parse model metadata"))?;
+                apply_llama_kv_defaults(&mut gguf_header, &llama_info);
+                gguf_info.model = LoaderGgufInfoModel::Llama(llama_info);
 
-                // apply fix for wrong EOS token in Meta-Llama3
-                // NOTE: model defines token 128001 as EOS (<|end_of_text|>)
-                // NOTE: however during inference the model appear to be trained
-                // NOTE: with EOS 128009 (<|eot_id|>)
-                if let Some(defined_eos) = &gguf_info.tokenizer.eos_token_id {
-                    if let Some(defined_eos_str) =
-                        &gguf_info.tokenizer.tokens.get(*defined_eos as usize)
-                    {
-                        if *defined_eos == 128001 && defined_eos_str.as_str() == "<|end_of_text|>" {
-                            log::info!("Workaround for wrong Llama EOS token [128001 -> 128009]");
-                            gguf_info.tokenizer.eos_token_id = Some(128009);
-                        }
-                    }
-                }
                 // load model
                 let mut m = ModelLlamaQuantized::from_gguf(
                     gguf_header,
                     &mut file,
                     Arc::clone(&self.device),
-                )?;
+                )
+                .map_err(load_error(&self.location, "load model tensors"))?;
                 m.load()?;
 
-                m
+                Arc::new(Mutex::new(m))
             }
-            _ => return Err(CallmError::UnsupportedModel),
+            Some(GgufHandler::Gemma) => {
+                gguf_info.model = LoaderGgufInfoModel::Gemma(
+                    parse_gemma_kv(&gguf_header, &gguf_info.architecture)
+                        .map_err(load_error(&self.location, "parse model metadata"))?,
+                );
+
+                let mut m = ModelGemmaQuantized::from_gguf(
+                    gguf_header,
+                    &mut file,
+                    Arc::clone(&self.device),
+                )
+                .map_err(load_error(&self.location, "load model tensors"))?;
+                m.load()?;
+
+                Arc::new(Mutex::new(m))
+            }
+            Some(GgufHandler::Phi3) => {
+                gguf_info.model = LoaderGgufInfoModel::Phi3(
+                    parse_phi3_kv(&gguf_header)
+                        .map_err(load_error(&self.location, "parse model metadata"))?,
+                );
+
+                let mut m =
+                    ModelPhi3Quantized::from_gguf(gguf_header, &mut file, Arc::clone(&self.device))
+                        .map_err(load_error(&self.location, "load model tensors"))?;
+                m.load()?;
+
+                Arc::new(Mutex::new(m))
+            }
+            None => return Err(CallmError::UnsupportedModel),
         };
 
+        log::info!(
+            "Resolved EOS token {:?} ({:?})",
+            gguf_info
+                .tokenizer
+                .eos_token_id
+                .and_then(|id| gguf_info.tokenizer.tokens.get(id as usize)),
+            self.options.eos_policy
+        );
+
+        file.finish();
+
         // store GGUF info
         self.info = gguf_info;
+        self.metadata = metadata;
 
-        log::info!("Loaded in {:.2?}", Instant::now() - timer);
+        log::info!("Loaded in {:.2?}", timer.elapsed());
 
-        Ok(Arc::new(Mutex::new(model)))
+        Ok(model)
     }
 
     fn tokenizer(&mut self) -> Result<Tokenizer, CallmError> {
+        use tokenizers::decoders::byte_fallback::ByteFallback;
+        use tokenizers::decoders::fuse::Fuse;
+        use tokenizers::decoders::sequence::Sequence as DecoderSequence;
+        use tokenizers::decoders::strip::Strip;
         use tokenizers::models::bpe::{Merges, Vocab, BPE};
+        use tokenizers::normalizers::prepend::Prepend;
+        use tokenizers::normalizers::replace::Replace;
+        use tokenizers::normalizers::utils::Sequence as NormalizerSequence;
         use tokenizers::pre_tokenizers::byte_level::ByteLevel;
-        use tokenizers::pre_tokenizers::sequence::Sequence;
-        use tokenizers::pre_tokenizers::split::{Split, SplitPattern};
         use tokenizers::{
             AddedToken, AddedVocabulary, DecoderWrapper, ModelWrapper, NormalizerWrapper,
-            PaddingParams, PostProcessorWrapper, PreTokenizerWrapper, SplitDelimiterBehavior,
-            TokenizerBuilder, TruncationParams,
+            PaddingParams, PostProcessorWrapper, PreTokenizerWrapper, TokenizerBuilder,
+            TruncationParams,
         };
 
         // tokenizer building blocks
-        let normalizer: Option<NormalizerWrapper> = None;
+        let mut normalizer: Option<NormalizerWrapper> = None;
         let mut pre_tokenizer: Option<PreTokenizerWrapper> = None;
-        #[allow(unused_assignments)]
         let mut post_processor: Option<PostProcessorWrapper> = None;
         #[allow(unused_assignments)]
         let mut decoder: Option<DecoderWrapper> = None;
@@ -189,17 +351,18 @@ impl LoaderImpl for LoaderGguf {
         // pre-tokenizer
         if let Some(pre) = &self.info.tokenizer.pre {
             match pre.as_str() {
-                "llama-bpe" => {
-                    let wrappers = vec![
-                        PreTokenizerWrapper::Split(Split::new(SplitPattern::Regex(String::from("(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\\r\\n\\p{L}\\p{N}]?\\p{L}+|\\p{N}{1,3}| ?[^\\s\\p{L}\\p{N}]+[\\r\\n]*|\\s*[\\r\\n]+|\\s+(?!\\S)|\\s+")), SplitDelimiterBehavior::Isolated, false).map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?),
-                        PreTokenizerWrapper::ByteLevel(ByteLevel::new(false, true, false)),
-                    ];
-                    pre_tokenizer = Some(PreTokenizerWrapper::Sequence(Sequence::new(wrappers)));
+                "llama-bpe" => pre_tokenizer = Some(byte_level_pre_tokenizer(PRE_LLAMA_BPE)?),
+                "deepseek-llm" => pre_tokenizer = Some(byte_level_pre_tokenizer(PRE_DEEPSEEK_LLM)?),
+                "deepseek-coder" => {
+                    pre_tokenizer = Some(byte_level_pre_tokenizer(PRE_DEEPSEEK_CODER)?)
+                }
+                "falcon" => pre_tokenizer = Some(byte_level_pre_tokenizer(PRE_FALCON)?),
+                "default" => {}
+                _ => {
+                    return Err(CallmError::TokenizerError {
+                        msg: format!("Unsupported pre-tokenizer `{}`", pre),
+                    })
                 }
-                "deepseek-llm" => todo!(),
-                "deepseek-coder" => todo!(),
-                "falcon" => todo!(),
-                _ => {}
             }
         }
         // tokenizer model
@@ -227,13 +390,18 @@ impl LoaderImpl for LoaderGguf {
                     .tokenizer
                     .merges
                     .as_ref()
-                    .unwrap()
+                    .ok_or(CallmError::TokenizerError {
+                        msg: "Missing GGUF metadata key tokenizer.ggml.merges".to_string(),
+                    })?
                     .iter()
-                    .map(|v| {
-                        let split = v.as_str().split_once(' ').unwrap();
-                        (String::from(split.0), String::from(split.1))
+                    .enumerate()
+                    .map(|(i, v)| match v.split_once(' ') {
+                        Some((left, right)) => Ok((String::from(left), String::from(right))),
+                        None => Err(CallmError::TokenizerError {
+                            msg: format!("Invalid merge {:?} at tokenizer.ggml.merges[{}]", v, i),
+                        }),
                     })
-                    .collect();
+                    .collect::<Result<_, _>>()?;
 
                 // create model
                 let bpe = BPE::builder()
@@ -242,32 +410,101 @@ impl LoaderImpl for LoaderGguf {
                     .build()
                     .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?;
 
-                let model = ModelWrapper::BPE(bpe);
-
-                // create added vocabulary from control tokens
-                if let Some(token_type) = &self.info.tokenizer.token_type {
-                    let mut added_tokens = vec![];
-                    for (i, tkn) in (0_u32..).zip(token_type) {
-                        if *tkn == 3 {
-                            added_tokens.push(AddedToken::from(
-                                &self.info.tokenizer.tokens[i as usize],
-                                true,
-                            ));
-                        }
-                    }
-                    added_vocabulary.add_special_tokens(
-                        added_tokens.as_slice(),
-                        &model,
-                        None::<&tokenizers::normalizers::strip::Strip>,
-                    );
+                ModelWrapper::BPE(bpe)
+            }
+            "llama" => {
+                // SentencePiece marks spaces with U+2581, optionally prepending one to the input
+                let add_space_prefix = self.info.tokenizer.add_space_prefix.unwrap_or(true);
+                let mut normalizers = vec![];
+                if add_space_prefix {
+                    normalizers.push(NormalizerWrapper::Prepend(Prepend::new(
+                        SPM_SPACE.to_string(),
+                    )));
+                }
+                normalizers.push(NormalizerWrapper::Replace(
+                    Replace::new(" ", SPM_SPACE)
+                        .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?,
+                ));
+                normalizer = Some(NormalizerWrapper::Sequence(NormalizerSequence::new(
+                    normalizers,
+                )));
+
+                // decode space markers and byte fallback tokens (<0x0A> style)
+                let mut decoders = vec![
+                    DecoderWrapper::Replace(
+                        Replace::new(SPM_SPACE, " ")
+                            .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?,
+                    ),
+                    DecoderWrapper::ByteFallback(ByteFallback::new()),
+                    DecoderWrapper::Fuse(Fuse::new()),
+                ];
+                if add_space_prefix {
+                    decoders.push(DecoderWrapper::Strip(Strip::new(' ', 1, 0)));
                 }
+                decoder = Some(DecoderWrapper::Sequence(DecoderSequence::new(decoders)));
 
-                model
+                let tokens = &self.info.tokenizer.tokens;
+                let scores =
+                    self.info
+                        .tokenizer
+                        .scores
+                        .as_deref()
+                        .ok_or(CallmError::TokenizerError {
+                            msg: "Missing token scores for SentencePiece tokenizer".to_string(),
+                        })?;
+                let vocab: Vocab = tokens.iter().cloned().zip(0_u32..).collect();
+                let merges =
+                    sentencepiece_merges(tokens, scores, self.info.tokenizer.token_type.as_deref());
+
+                let mut builder = BPE::builder()
+                    .vocab_and_merges(vocab, merges)
+                    .byte_fallback(true)
+                    .fuse_unk(true);
+                if let Some(unk_token) = self
+                    .info
+                    .tokenizer
+                    .unknown_token_id
+                    .and_then(|id| tokens.get(id as usize))
+                {
+                    builder = builder.unk_token(unk_token.clone());
+                }
+
+                ModelWrapper::BPE(
+                    builder
+                        .build()
+                        .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?,
+                )
+            }
+            model => {
+                return Err(CallmError::TokenizerError {
+                    msg: format!("Unsupported tokenizer model `{}`", model),
+                })
             }
-            "llama" => todo!(),
-            _ => unimplemented!(),
         };
 
+        // create added vocabulary from control tokens
+        if let Some(token_type) = &self.info.tokenizer.token_type {
+            let mut added_tokens = vec![];
+            for (i, tkn) in token_type.iter().enumerate() {
+                if *tkn == TOKEN_TYPE_CONTROL {
+                    let token = self.info.tokenizer.tokens.get(i).ok_or(
+                        CallmError::TokenizerError {
+                            msg: format!(
+                                "Control token tokenizer.ggml.token_type[{}] is not in tokenizer.ggml.tokens",
+                                i
+                            ),
+                        },
+                    )?;
+                    added_tokens.push(AddedToken::from(token, true));
+                }
+            }
+            added_vocabulary.add_special_tokens(
+                added_tokens.as_slice(),
+                &model,
+                None::<&tokenizers::normalizers::strip::Strip>,
+            );
+        }
+
         let tokenizer = TokenizerBuilder::new()
             .with_model(model)
             .with_normalizer(normalizer)
@@ -284,25 +521,126 @@ impl LoaderImpl for LoaderGguf {
     }
 
     fn template(&mut self) -> Result<Box<dyn TemplateImpl>, CallmError> {
-        let mut boxed_template: Box<dyn TemplateImpl> =
-            if let Some(template_string) = &self.info.tokenizer.chat_template {
-                // spawn jinja-style chat template from gguf kv tokenizer.chat_template
-                Box::new(TemplateJinja::new(template_string))
-            } else {
-                // fallback to dummy template
-                Box::new(TemplateDummy::new())
-            };
+        let mut boxed_template: Box<dyn TemplateImpl> = match &self.info.tokenizer.chat_template {
+            // spawn jinja-style chat template from gguf kv tokenizer.chat_template
+            Some(template_string) => match TemplateJinja::try_new(template_string) {
+                Ok(template) => Box::new(template),
+                Err(e) => {
+                    log::warn!("Invalid chat template ({}), using dummy template", e);
+                    Box::new(TemplateDummy::new())
+                }
+            },
+            // fallback to dummy template
+            None => Box::new(TemplateDummy::new()),
+        };
 
         // parse GGUF tokenizer kv for BOS and EOS tokens
-        if let Some(tkn_id) = &self.info.tokenizer.bos_token_id {
-            boxed_template.set_bos_token(Some(self.info.tokenizer.tokens[*tkn_id as usize].clone()))
+        let token =
+            |key: &str, id: u32| {
+                self.info.tokenizer.tokens.get(id as usize).cloned().ok_or(
+                    CallmError::TokenizerError {
+                        msg: format!("Token ID {} of {} out of vocabulary range", id, key),
+                    },
+                )
+            };
+        if let Some(tkn_id) = self.info.tokenizer.bos_token_id {
+            boxed_template.set_bos_token(Some(token("tokenizer.ggml.bos_token_id", tkn_id)?));
         }
-        if let Some(tkn_id) = &self.info.tokenizer.eos_token_id {
-            boxed_template.set_eos_token(Some(self.info.tokenizer.tokens[*tkn_id as usize].clone()))
+        if let Some(tkn_id) = self.info.tokenizer.eos_token_id {
+            boxed_template.set_eos_token(Some(token("tokenizer.ggml.eos_token_id", tkn_id)?));
         }
 
         Ok(boxed_template)
     }
+
+    fn stop_token_ids(&self) -> Vec<u32> {
+        self.info.tokenizer.stop_token_ids.clone()
+    }
+
+    fn inspect(&mut self) -> Result<ModelInfo, CallmError> {
+        let mut file =
+            fs::File::open(&self.location).map_err(load_error(&self.location, "open GGUF file"))?;
+        let header =
+            Content::read(&mut file).map_err(load_error(&self.location, "read GGUF header"))?;
+
+        let mut gguf_info = parse_general_kv(&header)
+            .map_err(load_error(&self.location, "parse general metadata"))?;
+        gguf_info.tokenizer = parse_tokenizer_kv(&header)
+            .map_err(load_error(&self.location, "parse tokenizer metadata"))?;
+        gguf_info.model = match resolve_handler(&gguf_info.architecture, gguf_info.name.as_deref())
+        {
+            Some(GgufHandler::Llama | GgufHandler::Mistral) => {
+                LoaderGgufInfoModel::Llama(parse_llama_kv(&header)?)
+            }
+            Some(GgufHandler::Gemma) => {
+                LoaderGgufInfoModel::Gemma(parse_gemma_kv(&header, &gguf_info.architecture)?)
+            }
+            Some(GgufHandler::Phi3) => LoaderGgufInfoModel::Phi3(parse_phi3_kv(&header)?),
+            None => LoaderGgufInfoModel::None,
+        };
+        self.info = gguf_info;
+        self.metadata = header.metadata;
+
+        self.info().ok_or(CallmError::LoaderFail(
+            "Missing GGUF metadata key general.architecture".to_string(),
+        ))
+    }
+
+    fn info(&self) -> Option<ModelInfo> {
+        if self.info.architecture.is_empty() {
+            return None;
+        }
+
+        let tokenizer = &self.info.tokenizer;
+        Some(ModelInfo {
+            architecture: self.info.architecture.clone(),
+            name: self.info.name.clone(),
+            author: self.info.author.clone(),
+            quantization: self
+                .info
+                .file_type
+                .map(|file_type| file_type_name(file_type).to_string()),
+            context_length: self.context_length(),
+            bos_token_id: tokenizer.bos_token_id,
+            eos_token_id: tokenizer.eos_token_id,
+            eos_token: tokenizer
+                .eos_token_id
+                .and_then(|id| tokenizer.tokens.get(id as usize))
+                .cloned(),
+        })
+    }
+
+    fn context_length(&self) -> Option<usize> {
+        match &self.info.model {
+            LoaderGgufInfoModel::Llama(llama_info) => Some(llama_info.context_length as usize),
+            LoaderGgufInfoModel::Gemma(gemma_info) => Some(gemma_info.context_length as usize),
+            LoaderGgufInfoModel::Phi3(phi3_info) => Some(phi3_info.context_length as usize),
+            LoaderGgufInfoModel::None => None,
+        }
+    }
+}
+
+// wrap an error with the GGUF file path and the failing load stage
+fn load_error<'a, E: std::fmt::Display>(
+    path: &'a Path,
+    stage: &'a str,
+) -> impl FnOnce(E) -> CallmError + 'a {
+    move |e| CallmError::LoaderFail(format!("Failed to {} ({}): {}", stage, path.display(), e))
+}
+
+// resolve model handler from general.architecture and general.name
+fn resolve_handler(architecture: &str, name: Option<&str>) -> Option<GgufHandler> {
+    let name = name.map(str::to_lowercase);
+    ARCHITECTURE_ALIASES
+        .iter()
+        .find(|(arch, pattern, _)| {
+            *arch == architecture
+                && match pattern {
+                    Some(p) => name.as_ref().is_some_and(|n| n.contains(p)),
+                    None => true,
+                }
+        })
+        .map(|(_, _, handler)| *handler)
 }
 
 fn parse_required_kv(ctx: &Content) -> Result<LoaderGgufInfo, CallmError> {
@@ -328,59 +666,50 @@ fn parse_general_kv(ctx: &Content) -> Result<LoaderGgufInfo, CallmError> {
     let mut info = parse_required_kv(ctx)?;
 
     // parse general metadata
-    if let Ok(val) = get_metadata(&ctx.metadata, "general.name") {
-        info.name = Some(val.to_string()?.clone());
-    }
-    if let Ok(val) = get_metadata(&ctx.metadata, "general.author") {
-        info.author = Some(val.to_string()?.clone());
-    }
-    if let Ok(val) = get_metadata(&ctx.metadata, "general.url") {
-        info.url = Some(val.to_string()?.clone());
-    }
-    if let Ok(val) = get_metadata(&ctx.metadata, "general.description") {
-        info.description = Some(val.to_string()?.clone());
-    }
+    info.name = get_optional_string(&ctx.metadata, "general.name");
+    info.author = get_optional_string(&ctx.metadata, "general.author");
+    info.url = get_optional_string(&ctx.metadata, "general.url");
+    info.description = get_optional_string(&ctx.metadata, "general.description");
+    info.license = get_optional_string(&ctx.metadata, "general.license");
     if let Ok(val) = get_metadata(&ctx.metadata, "general.file_type") {
         info.file_type = Some(val.to_u32()?);
     }
 
     // parse source metadata
-    if let Ok(val) = get_metadata(&ctx.metadata, "general.source.url") {
-        info.source.url = Some(val.to_string()?.clone());
-    }
-    if let Ok(val) = get_metadata(&ctx.metadata, "general.source.huggingface.repository") {
-        info.source.huggingface_repository = Some(val.to_string()?.clone());
-    }
+    info.source.url = get_optional_string(&ctx.metadata, "general.source.url");
+    info.source.huggingface_repository =
+        get_optional_string(&ctx.metadata, "general.source.huggingface.repository");
 
     Ok(info)
 }
 
-// TODO: proper error handling
 fn parse_tokenizer_kv(ctx: &Content) -> Result<LoaderGgufInfoTokenizer, CallmError> {
     let mut info = LoaderGgufInfoTokenizer {
         model: get_metadata(&ctx.metadata, "tokenizer.ggml.model")?
             .to_string()?
             .clone(),
-        tokens: get_metadata(&ctx.metadata, "tokenizer.ggml.tokens")?
-            .to_vec()?
-            .iter()
-            .map(|v| v.to_string().unwrap().clone())
-            .collect(),
+        tokens: get_array(&ctx.metadata, "tokenizer.ggml.tokens", |v| {
+            v.to_string().cloned()
+        })?,
         ..Default::default()
     };
 
     // optional kv
-    if let Ok(val) = get_metadata(&ctx.metadata, "tokenizer.chat_template") {
-        info.chat_template = Some(val.to_string()?.clone());
-    }
-    if let Ok(val) = get_metadata(&ctx.metadata, "tokenizer.ggml.pre") {
-        info.pre = Some(val.to_string()?.clone());
-    }
+    info.chat_template = get_optional_string(&ctx.metadata, "tokenizer.chat_template");
+    info.pre = get_optional_string(&ctx.metadata, "tokenizer.ggml.pre");
     if let Ok(val) = get_metadata(&ctx.metadata, "tokenizer.ggml.bos_token_id") {
         info.bos_token_id = Some(val.to_u32()?);
     }
-    if let Ok(val) = get_metadata(&ctx.metadata, "tokenizer.ggml.eos_token_id") {
-        info.eos_token_id = Some(val.to_u32()?);
+    // NOTE: some files store several EOS tokens as an array, the first one is the main EOS
+    let eos_token_ids = get_optional_token_ids(&ctx.metadata, "tokenizer.ggml.eos_token_id")?;
+    info.eos_token_id = eos_token_ids.first().copied();
+    info.stop_token_ids = eos_token_ids;
+    for key in ["tokenizer.ggml.eot_token_id", "tokenizer.ggml.eom_token_id"] {
+        for id in get_optional_token_ids(&ctx.metadata, key)? {
+            if !info.stop_token_ids.contains(&id) {
+                info.stop_token_ids.push(id);
+            }
+        }
     }
     if let Ok(val) = get_metadata(&ctx.metadata, "tokenizer.ggml.unknown_token_id") {
         info.unknown_token_id = Some(val.to_u32()?);
@@ -391,34 +720,216 @@ fn parse_tokenizer_kv(ctx: &Content) -> Result<LoaderGgufInfoTokenizer, CallmErr
     if let Ok(val) = get_metadata(&ctx.metadata, "tokenizer.ggml.padding_token_id") {
         info.padding_token_id = Some(val.to_u32()?);
     }
+    if let Ok(val) = get_metadata(&ctx.metadata, "tokenizer.ggml.add_space_prefix") {
+        info.add_space_prefix = Some(val.to_bool()?);
+    }
 
     // optional kv arrays
-    if let Ok(val) = get_metadata(&ctx.metadata, "tokenizer.ggml.scores") {
-        info.scores = Some(val.to_vec()?.iter().map(|v| v.to_f32().unwrap()).collect());
-    }
-    if let Ok(val) = get_metadata(&ctx.metadata, "tokenizer.ggml.token_type") {
-        info.token_type = Some(val.to_vec()?.iter().map(|v| v.to_i32().unwrap()).collect());
-    }
-    if let Ok(val) = get_metadata(&ctx.metadata, "tokenizer.ggml.merges") {
-        info.merges = Some(
-            val.to_vec()?
-                .iter()
-                .map(|v| v.to_string().unwrap().clone())
-                .collect(),
-        );
+    if ctx.metadata.contains_key("tokenizer.ggml.scores") {
+        info.scores = Some(get_array(
+            &ctx.metadata,
+            "tokenizer.ggml.scores",
+            Value::to_f32,
+        )?);
     }
-    if let Ok(val) = get_metadata(&ctx.metadata, "tokenizer.ggml.added_tokens") {
-        info.added_tokens = Some(
-            val.to_vec()?
-                .iter()
-                .map(|v| v.to_string().unwrap().clone())
-                .collect(),
-        );
+    if ctx.metadata.contains_key("tokenizer.ggml.token_type") {
+        info.token_type = Some(get_array(
+            &ctx.metadata,
+            "tokenizer.ggml.token_type",
+            Value::to_i32,
+        )?);
     }
+    // NOTE: chat models often end their turns with a control token other than the declared EOS,
+    // NOTE: e.g. Meta Llama 3 <|eot_id|> (128009) next to EOS <|end_of_text|> (128001)
+    if let Some(token_type) = &info.token_type {
+        for (id, (token, ty)) in info.tokens.iter().zip(token_type).enumerate() {
+            let id = id as u32;
+            if *ty == TOKEN_TYPE_CONTROL
+                && END_OF_TURN_TOKENS.contains(&token.as_str())
+                && !info.stop_token_ids.contains(&id)
+            {
+                info.stop_token_ids.push(id);
+            }
+        }
+    }
+    info.merges = get_optional_string_array(&ctx.metadata, "tokenizer.ggml.merges");
+    info.added_tokens = get_optional_string_array(&ctx.metadata, "tokenizer.ggml.added_tokens");
 
     Ok(info)
 }
 
+// name of a `general.file_type` value, following llama.cpp `llama_ftype`
+fn file_type_name(file_type: u32) -> &'static str {
+    match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        _ => "unknown",
+    }
+}
+
+// derive BPE merges from SentencePiece token scores
+// NOTE: llama.cpp tokenizes by repeatedly merging the adjacent pair forming the highest scoring
+// NOTE: token, which is BPE with merges ranked by the score of the merged token
+fn sentencepiece_merges(
+    tokens: &[String],
+    scores: &[f32],
+    token_type: Option<&[i32]>,
+) -> Vec<(String, String)> {
+    // control and byte tokens never result from merges
+    let is_normal = |id: usize| {
+        token_type
+            .and_then(|types| types.get(id))
+            .map_or(true, |t| *t == TOKEN_TYPE_NORMAL)
+    };
+    let normal_tokens: HashSet<&str> = tokens
+        .iter()
+        .enumerate()
+        .filter(|(id, _)| is_normal(*id))
+        .map(|(_, token)| token.as_str())
+        .collect();
+
+    let mut merges = vec![];
+    for (id, token) in tokens.iter().enumerate().filter(|(id, _)| is_normal(*id)) {
+        let score = scores.get(id).copied().unwrap_or(f32::MIN);
+        for (split, _) in token.char_indices().skip(1) {
+            let (left, right) = token.split_at(split);
+            if normal_tokens.contains(left) && normal_tokens.contains(right) {
+                merges.push((score, id, left, right));
+            }
+        }
+    }
+    // highest score first, ties broken by token ID
+    merges.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    merges
+        .into_iter()
+        .map(|(_, _, left, right)| (left.to_string(), right.to_string()))
+        .collect()
+}
+
+// build a byte-level pre-tokenizer isolating each regex match in turn
+fn byte_level_pre_tokenizer(
+    patterns: &[&str],
+) -> Result<tokenizers::PreTokenizerWrapper, CallmError> {
+    use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+    use tokenizers::pre_tokenizers::sequence::Sequence;
+    use tokenizers::pre_tokenizers::split::{Split, SplitPattern};
+    use tokenizers::{PreTokenizerWrapper, SplitDelimiterBehavior};
+
+    let mut wrappers = patterns
+        .iter()
+        .map(|pattern| {
+            Split::new(
+                SplitPattern::Regex(pattern.to_string()),
+                SplitDelimiterBehavior::Isolated,
+                false,
+            )
+            .map(PreTokenizerWrapper::Split)
+            .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    wrappers.push(PreTokenizerWrapper::ByteLevel(ByteLevel::new(
+        false, true, false,
+    )));
+
+    Ok(PreTokenizerWrapper::Sequence(Sequence::new(wrappers)))
+}
+
+// get optional token ID metadata, given as a single value or an array
+fn get_optional_token_ids(
+    metadata: &HashMap<String, Value>,
+    key: &str,
+) -> Result<Vec<u32>, CallmError> {
+    match metadata.get(key) {
+        None => Ok(Vec::new()),
+        Some(Value::Array(_)) => get_array(metadata, key, Value::to_u32),
+        Some(val) => Ok(vec![val.to_u32().map_err(|e| {
+            CallmError::LoaderFail(format!("Invalid GGUF metadata key {}: {}", key, e))
+        })?]),
+    }
+}
+
+// get optional string metadata, skipping values of the wrong type with a warning
+fn get_optional_string(metadata: &HashMap<String, Value>, key: &str) -> Option<String> {
+    match metadata.get(key)?.to_string() {
+        Ok(val) => {
+            // NOTE: GGUF strings with invalid UTF-8 are converted lossily by candle
+            if val.contains(char::REPLACEMENT_CHARACTER) {
+                log::warn!("GGUF metadata key {} contains invalid UTF-8", key);
+            }
+            Some(val.clone())
+        }
+        Err(e) => {
+            log::warn!("Skipping GGUF metadata key {}: {}", key, e);
+            None
+        }
+    }
+}
+
+// get optional string array metadata, skipping values of the wrong type with a warning
+fn get_optional_string_array(metadata: &HashMap<String, Value>, key: &str) -> Option<Vec<String>> {
+    let strings = metadata.get(key)?.to_vec().and_then(|vals| {
+        vals.iter()
+            .map(|v| v.to_string().cloned())
+            .collect::<Result<Vec<_>, _>>()
+    });
+
+    match strings {
+        Ok(strings) => Some(strings),
+        Err(e) => {
+            log::warn!("Skipping GGUF metadata key {}: {}", key, e);
+            None
+        }
+    }
+}
+
+// get array metadata, converting each element and reporting the index of invalid ones
+fn get_array<T>(
+    metadata: &HashMap<String, Value>,
+    key: &str,
+    convert: impl Fn(&Value) -> candle_core::Result<T>,
+) -> Result<Vec<T>, CallmError> {
+    get_metadata(metadata, key)?
+        .to_vec()
+        .map_err(|e| CallmError::LoaderFail(format!("Invalid GGUF metadata key {}: {}", key, e)))?
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            convert(v).map_err(|e| {
+                CallmError::LoaderFail(format!("Invalid GGUF metadata {}[{}]: {}", key, i, e))
+            })
+        })
+        .collect()
+}
+
 fn get_metadata<'a>(
     metadata: &'a HashMap<String, Value>,
     key: &str,
@@ -434,3 +945,496 @@ fn get_metadata<'a>(
         )))
     }
 }
+
+// reader reporting read bytes to a load progress callback, in steps of at least 1% of the file
+struct ProgressReader<R> {
+    inner: R,
+    read: u64,
+    reported: u64,
+    total: u64,
+    progress: Option<LoadProgress>,
+}
+
+impl<R> ProgressReader<R> {
+    fn new(inner: R, total: u64, progress: Option<LoadProgress>) -> Self {
+        Self {
+            inner,
+            read: 0,
+            reported: 0,
+            total,
+            progress,
+        }
+    }
+
+    // report the whole file as loaded
+    fn finish(&mut self) {
+        if let Some(progress) = &self.progress {
+            progress.report(self.total, self.total);
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if let Some(progress) = &self.progress {
+            let loaded = self.read.min(self.total);
+            if loaded - self.reported >= self.total / 100 {
+                self.reported = loaded;
+                progress.report(loaded, self.total);
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for ProgressReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_handler() {
+        assert_eq!(resolve_handler("llama", None), Some(GgufHandler::Llama));
+        assert_eq!(
+            resolve_handler("llama", Some("Meta-Llama-3-8B-Instruct")),
+            Some(GgufHandler::Llama)
+        );
+        assert_eq!(
+            resolve_handler("llama", Some("Mistral-7B-Instruct-v0.3")),
+            Some(GgufHandler::Mistral)
+        );
+        assert_eq!(resolve_handler("gemma2", None), Some(GgufHandler::Gemma));
+        assert_eq!(resolve_handler("falcon", None), None);
+    }
+
+    #[test]
+    fn test_phi3_header() {
+        use candle_core::quantized::gguf_file;
+
+        let metadata = [
+            ("general.architecture", Value::String("phi3".to_string())),
+            ("general.quantization_version", Value::U32(2)),
+            ("general.name", Value::String("Phi3".to_string())),
+            ("phi3.context_length", Value::U32(4096)),
+            ("phi3.embedding_length", Value::U32(3072)),
+            ("phi3.block_count", Value::U32(32)),
+            ("phi3.feed_forward_length", Value::U32(8192)),
+            ("phi3.rope.dimension_count", Value::U32(96)),
+            ("phi3.attention.head_count", Value::U32(32)),
+            ("phi3.attention.head_count_kv", Value::U32(32)),
+            ("phi3.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+        ];
+        let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let mut file = std::io::Cursor::new(Vec::new());
+        gguf_file::write(&mut file, &metadata, &[]).unwrap();
+        file.set_position(0);
+
+        let header = Content::read(&mut file).unwrap();
+        let info = parse_general_kv(&header).unwrap();
+        assert_eq!(
+            resolve_handler(&info.architecture, info.name.as_deref()),
+            Some(GgufHandler::Phi3)
+        );
+        let phi3_info = parse_phi3_kv(&header).unwrap();
+        assert_eq!(phi3_info.context_length, 4096);
+        assert_eq!(phi3_info.attention.head_count_kv, 32);
+    }
+
+    #[test]
+    fn test_info() {
+        let mut loader = LoaderGguf::default();
+        assert!(loader.info().is_none());
+
+        loader.info.architecture = "llama".to_string();
+        loader.info.name = Some("Meta-Llama-3-8B-Instruct".to_string());
+        loader.info.file_type = Some(15);
+        loader.info.tokenizer.tokens = vec!["<s>".to_string(), "</s>".to_string()];
+        loader.info.tokenizer.bos_token_id = Some(0);
+        loader.info.tokenizer.eos_token_id = Some(1);
+
+        let info = loader.info().unwrap();
+        assert_eq!(info.architecture, "llama");
+        assert_eq!(info.name.as_deref(), Some("Meta-Llama-3-8B-Instruct"));
+        assert_eq!(info.quantization.as_deref(), Some("Q4_K_M"));
+        assert_eq!(info.bos_token_id, Some(0));
+        assert_eq!(info.eos_token.as_deref(), Some("</s>"));
+    }
+
+    #[test]
+    fn test_corrupt_token_ids() {
+        let mut loader = LoaderGguf::default();
+        loader.info.tokenizer.model = "gpt2".to_string();
+        loader.info.tokenizer.tokens = vec!["<s>".to_string()];
+        loader.info.tokenizer.merges = Some(vec![]);
+        loader.info.tokenizer.token_type = Some(vec![TOKEN_TYPE_CONTROL, TOKEN_TYPE_CONTROL]);
+        match loader.tokenizer() {
+            Err(CallmError::TokenizerError { msg }) => {
+                assert!(msg.contains("tokenizer.ggml.token_type[1]"))
+            }
+            _ => panic!("expected TokenizerError"),
+        }
+
+        loader.info.tokenizer.eos_token_id = Some(7);
+        match loader.template() {
+            Err(CallmError::TokenizerError { msg }) => {
+                assert!(msg.contains("tokenizer.ggml.eos_token_id"))
+            }
+            _ => panic!("expected TokenizerError"),
+        }
+    }
+
+    #[test]
+    fn test_unsupported_tokenizer_model() {
+        let mut loader = LoaderGguf::default();
+        loader.info.tokenizer.model = "bert".to_string();
+        loader.info.tokenizer.tokens = vec!["[CLS]".to_string()];
+
+        match loader.tokenizer() {
+            Err(CallmError::TokenizerError { msg }) => assert!(msg.contains("`bert`")),
+            _ => panic!("expected TokenizerError"),
+        }
+    }
+
+    #[test]
+    fn test_end_of_turn_stop_tokens() {
+        use candle_core::quantized::gguf_file;
+
+        let strings =
+            |v: &[&str]| Value::Array(v.iter().map(|s| Value::String(s.to_string())).collect());
+        let metadata = [
+            ("tokenizer.ggml.model", Value::String("gpt2".to_string())),
+            (
+                "tokenizer.ggml.tokens",
+                strings(&[
+                    "<|begin_of_text|>",
+                    "<|end_of_text|>",
+                    "<|eot_id|>",
+                    "<|im_end|>",
+                ]),
+            ),
+            (
+                "tokenizer.ggml.token_type",
+                Value::Array(vec![
+                    Value::I32(3),
+                    Value::I32(3),
+                    Value::I32(3),
+                    Value::I32(1),
+                ]),
+            ),
+            ("tokenizer.ggml.eos_token_id", Value::U32(1)),
+        ];
+        let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let mut file = std::io::Cursor::new(Vec::new());
+        gguf_file::write(&mut file, &metadata, &[]).unwrap();
+        file.set_position(0);
+
+        // <|im_end|> is a normal token here, so it does not end a turn
+        let info = parse_tokenizer_kv(&Content::read(&mut file).unwrap()).unwrap();
+        assert_eq!(info.eos_token_id, Some(1));
+        assert_eq!(info.stop_token_ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_inspect() {
+        use candle_core::quantized::gguf_file;
+
+        let tokens = Value::Array(vec![
+            Value::String("<s>".to_string()),
+            Value::String("</s>".to_string()),
+        ]);
+        let metadata = [
+            ("general.architecture", Value::String("phi3".to_string())),
+            ("general.quantization_version", Value::U32(2)),
+            ("general.file_type", Value::U32(7)),
+            ("phi3.context_length", Value::U32(4096)),
+            ("phi3.embedding_length", Value::U32(3072)),
+            ("phi3.block_count", Value::U32(32)),
+            ("phi3.feed_forward_length", Value::U32(8192)),
+            ("phi3.rope.dimension_count", Value::U32(96)),
+            ("phi3.attention.head_count", Value::U32(32)),
+            ("phi3.attention.head_count_kv", Value::U32(32)),
+            ("phi3.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+            ("tokenizer.ggml.model", Value::String("llama".to_string())),
+            ("tokenizer.ggml.tokens", tokens),
+            ("tokenizer.ggml.eos_token_id", Value::U32(1)),
+        ];
+        let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let path = std::env::temp_dir().join("callm_test_inspect.gguf");
+        let mut file = fs::File::create(&path).unwrap();
+        gguf_file::write(&mut file, &metadata, &[]).unwrap();
+        drop(file);
+
+        let info = LoaderGguf::new(path.to_str().unwrap()).inspect();
+        fs::remove_file(&path).unwrap();
+
+        let info = info.unwrap();
+        assert_eq!(info.architecture, "phi3");
+        assert_eq!(info.quantization.as_deref(), Some("Q8_0"));
+        assert_eq!(info.context_length, Some(4096));
+        assert_eq!(info.eos_token.as_deref(), Some("</s>"));
+    }
+
+    #[test]
+    fn test_load_truncated_file() {
+        let path = std::env::temp_dir().join("callm_test_truncated.gguf");
+        fs::write(&path, b"GGUF\x03\x00").unwrap();
+
+        let result = LoaderGguf::new(path.to_str().unwrap()).load();
+        fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(CallmError::LoaderFail(msg)) => {
+                assert!(msg.contains("read GGUF header"));
+                assert!(msg.contains("callm_test_truncated.gguf"));
+            }
+            _ => panic!("expected a loader failure"),
+        }
+    }
+
+    #[test]
+    fn test_metadata_value() {
+        let mut loader = LoaderGguf::default();
+        assert!(loader.metadata_value("llama.rope.freq_base").is_none());
+
+        loader.metadata =
+            HashMap::from([("llama.rope.freq_base".to_string(), GgufValue::F32(500000.0))]);
+        assert_eq!(
+            loader
+                .metadata_value("llama.rope.freq_base")
+                .unwrap()
+                .to_f32()
+                .unwrap(),
+            500000.0
+        );
+        assert_eq!(loader.metadata().len(), 1);
+    }
+
+    #[test]
+    fn test_sentencepiece_tokenizer() {
+        let tokens = [
+            "<unk>",
+            "<s>",
+            "</s>",
+            "<0x0A>",
+            "\u{2581}",
+            "h",
+            "i",
+            "\u{2581}h",
+            "\u{2581}hi",
+            "hi",
+        ];
+        let mut loader = LoaderGguf::default();
+        loader.info.tokenizer = LoaderGgufInfoTokenizer {
+            model: "llama".to_string(),
+            tokens: tokens.iter().map(|t| t.to_string()).collect(),
+            unknown_token_id: Some(0),
+            scores: Some(vec![0.0, 0.0, 0.0, 0.0, -3.0, -4.0, -5.0, -1.0, -0.5, -2.0]),
+            token_type: Some(vec![2, 3, 3, 6, 1, 1, 1, 1, 1, 1]),
+            ..Default::default()
+        };
+        let tokenizer = loader.tokenizer().unwrap();
+
+        let encoding = tokenizer.encode("hi hi\n", false).unwrap();
+        assert_eq!(encoding.get_ids(), &[8, 8, 3]);
+        assert_eq!(
+            tokenizer.decode(encoding.get_ids(), false).unwrap(),
+            "hi hi\n"
+        );
+
+        let encoding = tokenizer.encode("<s>hi", false).unwrap();
+        assert_eq!(encoding.get_ids(), &[1, 8]);
+    }
+
+    #[test]
+    fn test_sentencepiece_merges() {
+        let tokens: Vec<String> = ["a", "b", "ab", "<0x61>"].map(String::from).to_vec();
+        let merges =
+            sentencepiece_merges(&tokens, &[-2.0, -3.0, -1.0, 0.0], Some(&[1, 1, 1, 6][..]));
+        assert_eq!(merges, vec![("a".to_string(), "b".to_string())]);
+    }
+
+    // byte-level BPE fixture with merges crossing letter/digit and digit/digit boundaries
+    fn bpe_fixture_tokenizer(pre: &str) -> Result<Tokenizer, CallmError> {
+        let tokens = [
+            "a",
+            "b",
+            "1",
+            "2",
+            "\u{120}",
+            "\u{10A}",
+            ".",
+            "ab",
+            "12",
+            "\u{120}a",
+            "\u{120}ab",
+            "b1",
+        ];
+        let merges = ["a b", "1 2", "\u{120} a", "\u{120}a b", "b 1"];
+        let mut loader = LoaderGguf::default();
+        loader.info.tokenizer = LoaderGgufInfoTokenizer {
+            model: "gpt2".to_string(),
+            pre: Some(pre.to_string()),
+            tokens: tokens.iter().map(|t| t.to_string()).collect(),
+            merges: Some(merges.iter().map(|m| m.to_string()).collect()),
+            ..Default::default()
+        };
+        loader.tokenizer()
+    }
+
+    #[test]
+    fn test_deepseek_pre_tokenizers() {
+        // letters, digits and newlines are split apart, so "b1" is never merged
+        let tokenizer = bpe_fixture_tokenizer("deepseek-llm").unwrap();
+        let encoding = tokenizer.encode("ab12 ab\nab1", false).unwrap();
+        assert_eq!(encoding.get_ids(), &[7, 8, 10, 5, 7, 2]);
+        assert_eq!(
+            tokenizer.decode(encoding.get_ids(), false).unwrap(),
+            "ab12 ab\nab1"
+        );
+
+        // deepseek-coder additionally splits digits individually
+        let tokenizer = bpe_fixture_tokenizer("deepseek-coder").unwrap();
+        let encoding = tokenizer.encode("ab12 ab\nab1", false).unwrap();
+        assert_eq!(encoding.get_ids(), &[7, 2, 3, 10, 5, 7, 2]);
+    }
+
+    #[test]
+    fn test_falcon_pre_tokenizer() {
+        // punctuation is isolated first, then digits are grouped by three
+        let tokenizer = bpe_fixture_tokenizer("falcon").unwrap();
+        let encoding = tokenizer.encode("ab.121 ab", false).unwrap();
+        assert_eq!(encoding.get_ids(), &[7, 6, 8, 2, 10]);
+
+        assert!(matches!(
+            bpe_fixture_tokenizer("unknown"),
+            Err(CallmError::TokenizerError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_progress_reader() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = Mutex::new(tx);
+        let progress = LoadProgress::new(move |loaded, total| {
+            tx.lock().unwrap().send((loaded, total)).unwrap();
+        });
+        let mut reader =
+            ProgressReader::new(std::io::Cursor::new(vec![0u8; 200]), 200, Some(progress));
+
+        // reads below 1% of the file are not reported on their own
+        let mut buf = [0u8; 1];
+        reader.read_exact(&mut buf).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        let mut buf = [0u8; 100];
+        reader.read_exact(&mut buf).unwrap();
+        reader.finish();
+
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![(2, 200), (102, 200), (200, 200)]
+        );
+    }
+
+    #[test]
+    fn test_get_optional_token_ids() {
+        let metadata = HashMap::from([
+            ("tokenizer.ggml.eos_token_id".to_string(), Value::U32(2)),
+            (
+                "tokenizer.ggml.eot_token_id".to_string(),
+                Value::Array(vec![Value::U32(7), Value::U32(9)]),
+            ),
+        ]);
+
+        assert_eq!(
+            get_optional_token_ids(&metadata, "tokenizer.ggml.eos_token_id").unwrap(),
+            vec![2]
+        );
+        assert_eq!(
+            get_optional_token_ids(&metadata, "tokenizer.ggml.eot_token_id").unwrap(),
+            vec![7, 9]
+        );
+        assert!(
+            get_optional_token_ids(&metadata, "tokenizer.ggml.eom_token_id")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_get_array_invalid_element() {
+        let metadata = HashMap::from([(
+            "tokenizer.ggml.scores".to_string(),
+            Value::Array(vec![Value::F32(0.0), Value::String("x".to_string())]),
+        )]);
+        match get_array(&metadata, "tokenizer.ggml.scores", Value::to_f32) {
+            Err(CallmError::LoaderFail(msg)) => assert!(msg.contains("tokenizer.ggml.scores[1]")),
+            _ => panic!("expected a loader failure"),
+        }
+        assert!(get_array(&metadata, "tokenizer.ggml.token_type", Value::to_i32).is_err());
+    }
+
+    #[test]
+    fn test_invalid_merge() {
+        let mut loader = LoaderGguf::default();
+        loader.info.tokenizer = LoaderGgufInfoTokenizer {
+            model: "gpt2".to_string(),
+            tokens: vec!["a".to_string(), "b".to_string(), "ab".to_string()],
+            merges: Some(vec!["a b".to_string(), "ab".to_string()]),
+            ..Default::default()
+        };
+        match loader.tokenizer() {
+            Err(CallmError::TokenizerError { msg }) => {
+                assert!(msg.contains("tokenizer.ggml.merges[1]"))
+            }
+            _ => panic!("expected a tokenizer error"),
+        }
+    }
+
+    #[test]
+    fn test_get_optional_string_wrong_type() {
+        let metadata = HashMap::from([
+            (
+                "general.name".to_string(),
+                Value::String("model".to_string()),
+            ),
+            ("general.author".to_string(), Value::U32(1)),
+        ]);
+
+        assert_eq!(
+            get_optional_string(&metadata, "general.name"),
+            Some("model".to_string())
+        );
+        assert_eq!(get_optional_string(&metadata, "general.author"), None);
+        assert_eq!(get_optional_string(&metadata, "general.url"), None);
+    }
+
+    #[test]
+    fn test_get_optional_string_array_wrong_type() {
+        let metadata = HashMap::from([
+            (
+                "tokenizer.ggml.merges".to_string(),
+                Value::Array(vec![Value::String("a b".to_string())]),
+            ),
+            (
+                "tokenizer.ggml.added_tokens".to_string(),
+                Value::Array(vec![Value::U32(1)]),
+            ),
+        ]);
+
+        assert_eq!(
+            get_optional_string_array(&metadata, "tokenizer.ggml.merges"),
+            Some(vec!["a b".to_string()])
+        );
+        assert_eq!(
+            get_optional_string_array(&metadata, "tokenizer.ggml.added_tokens"),
+            None
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use super::get_metadata;
+use crate::error::CallmError;
+use candle_core::quantized::gguf_file::Content;
+
+/// GGUF Gemma and Gemma 2 model config
+#[derive(Clone, Debug, Default)]
+pub struct LoaderGgufInfoModelGemma {
+    pub context_length: u32,
+    pub embedding_length: u32,
+    pub block_count: u32,
+    pub feed_forward_length: u32,
+    pub attention: LoaderGgufInfoModelGemmaAttention,
+    /// Gemma 2 only
+    pub attn_logit_softcapping: Option<f32>,
+    /// Gemma 2 only
+    pub final_logit_softcapping: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LoaderGgufInfoModelGemmaAttention {
+    pub head_count: u32,
+    /// Defaults to `head_count` (multi-head attention) when missing
+    pub head_count_kv: u32,
+    /// Head dimension, which may differ from `embedding_length / head_count`
+    pub key_length: Option<u32>,
+    pub layer_norm_rms_epsilon: f32,
+    /// Gemma 2 only
+    pub sliding_window: Option<u32>,
+}
+
+/// Parses Gemma model metadata, with keys prefixed by `architecture` (`gemma` or `gemma2`)
+pub fn parse_gemma_kv(
+    ctx: &Content,
+    architecture: &str,
+) -> Result<LoaderGgufInfoModelGemma, CallmError> {
+    let key = |name: &str| format!("{}.{}", architecture, name);
+    let optional_u32 = |name: &str| ctx.metadata.get(&key(name)).and_then(|v| v.to_u32().ok());
+    let optional_f32 = |name: &str| ctx.metadata.get(&key(name)).and_then(|v| v.to_f32().ok());
+
+    let head_count = get_metadata(&ctx.metadata, &key("attention.head_count"))?.to_u32()?;
+    let head_count_kv = optional_u32("attention.head_count_kv").unwrap_or_else(|| {
+        log::info!(
+            "Missing {}, defaulting to head count {}",
+            key("attention.head_count_kv"),
+            head_count
+        );
+        head_count
+    });
+
+    let modelinfo = LoaderGgufInfoModelGemma {
+        context_length: get_metadata(&ctx.metadata, &key("context_length"))?.to_u32()?,
+        embedding_length: get_metadata(&ctx.metadata, &key("embedding_length"))?.to_u32()?,
+        block_count: get_metadata(&ctx.metadata, &key("block_count"))?.to_u32()?,
+        feed_forward_length: get_metadata(&ctx.metadata, &key("feed_forward_length"))?.to_u32()?,
+        attention: LoaderGgufInfoModelGemmaAttention {
+            head_count,
+            head_count_kv,
+            key_length: optional_u32("attention.key_length"),
+            layer_norm_rms_epsilon: get_metadata(
+                &ctx.metadata,
+                &key("attention.layer_norm_rms_epsilon"),
+            )?
+            .to_f32()?,
+            sliding_window: optional_u32("attention.sliding_window"),
+        },
+        attn_logit_softcapping: optional_f32("attn_logit_softcapping"),
+        final_logit_softcapping: optional_f32("final_logit_softcapping"),
+    };
+
+    Ok(modelinfo)
+}
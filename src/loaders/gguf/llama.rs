@@ -1,6 +1,6 @@
 use super::get_metadata;
 use crate::error::CallmError;
-use candle_core::quantized::gguf_file::Content;
+use candle_core::quantized::gguf_file::{Content, Value};
 
 /// GGUF Llama model config
 #[derive(Clone, Debug, Default)]
@@ -22,10 +22,23 @@ pub struct LoaderGgufInfoModelLlamaRope {
 pub struct LoaderGgufInfoModelLlamaAttention {
     pub head_count: u32,
     pub layer_norm_rms_epsilon: f32,
-    pub head_count_kv: Option<u32>,
+    /// Defaults to `head_count` (multi-head attention) when missing
+    pub head_count_kv: u32,
 }
 
 pub fn parse_llama_kv(ctx: &Content) -> Result<LoaderGgufInfoModelLlama, CallmError> {
+    let head_count = get_metadata(&ctx.metadata, "llama.attention.head_count")?.to_u32()?;
+    let head_count_kv = match get_metadata(&ctx.metadata, "llama.attention.head_count_kv") {
+        Ok(v) => v.to_u32()?,
+        Err(_) => {
+            log::info!(
+                "Missing llama.attention.head_count_kv, defaulting to head count {}",
+                head_count
+            );
+            head_count
+        }
+    };
+
     let modelinfo = LoaderGgufInfoModelLlama {
         context_length: get_metadata(&ctx.metadata, "llama.context_length")?.to_u32()?,
         embedding_length: get_metadata(&ctx.metadata, "llama.embedding_length")?.to_u32()?,
@@ -35,21 +48,22 @@ pub fn parse_llama_kv(ctx: &Content) -> Result<LoaderGgufInfoModelLlama, CallmEr
             dimension_count: get_metadata(&ctx.metadata, "llama.rope.dimension_count")?.to_u32()?,
         },
         attention: LoaderGgufInfoModelLlamaAttention {
-            head_count: get_metadata(&ctx.metadata, "llama.attention.head_count")?.to_u32()?,
+            head_count,
             layer_norm_rms_epsilon: get_metadata(
                 &ctx.metadata,
                 "llama.attention.layer_norm_rms_epsilon",
             )?
             .to_f32()?,
-            head_count_kv: if let Ok(v) =
-                get_metadata(&ctx.metadata, "llama.attention.head_count_kv")
-            {
-                Some(v.to_u32()?)
-            } else {
-                None
-            },
+            head_count_kv,
         },
     };
 
     Ok(modelinfo)
 }
+
+/// Fills in Llama metadata defaults the quantized model expects to be present
+pub fn apply_llama_kv_defaults(ctx: &mut Content, modelinfo: &LoaderGgufInfoModelLlama) {
+    ctx.metadata
+        .entry("llama.attention.head_count_kv".to_string())
+        .or_insert(Value::U32(modelinfo.attention.head_count_kv));
+}
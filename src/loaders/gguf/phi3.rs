@@ -0,0 +1,49 @@
+use super::get_metadata;
+use crate::error::CallmError;
+use candle_core::quantized::gguf_file::Content;
+
+/// GGUF Phi-3 model config
+#[derive(Clone, Debug, Default)]
+pub struct LoaderGgufInfoModelPhi3 {
+    pub context_length: u32,
+    pub embedding_length: u32,
+    pub block_count: u32,
+    pub feed_forward_length: u32,
+    pub rope: LoaderGgufInfoModelPhi3Rope,
+    pub attention: LoaderGgufInfoModelPhi3Attention,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LoaderGgufInfoModelPhi3Rope {
+    pub dimension_count: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LoaderGgufInfoModelPhi3Attention {
+    pub head_count: u32,
+    pub head_count_kv: u32,
+    pub layer_norm_rms_epsilon: f32,
+}
+
+pub fn parse_phi3_kv(ctx: &Content) -> Result<LoaderGgufInfoModelPhi3, CallmError> {
+    let modelinfo = LoaderGgufInfoModelPhi3 {
+        context_length: get_metadata(&ctx.metadata, "phi3.context_length")?.to_u32()?,
+        embedding_length: get_metadata(&ctx.metadata, "phi3.embedding_length")?.to_u32()?,
+        block_count: get_metadata(&ctx.metadata, "phi3.block_count")?.to_u32()?,
+        feed_forward_length: get_metadata(&ctx.metadata, "phi3.feed_forward_length")?.to_u32()?,
+        rope: LoaderGgufInfoModelPhi3Rope {
+            dimension_count: get_metadata(&ctx.metadata, "phi3.rope.dimension_count")?.to_u32()?,
+        },
+        attention: LoaderGgufInfoModelPhi3Attention {
+            head_count: get_metadata(&ctx.metadata, "phi3.attention.head_count")?.to_u32()?,
+            head_count_kv: get_metadata(&ctx.metadata, "phi3.attention.head_count_kv")?.to_u32()?,
+            layer_norm_rms_epsilon: get_metadata(
+                &ctx.metadata,
+                "phi3.attention.layer_norm_rms_epsilon",
+            )?
+            .to_f32()?,
+        },
+    };
+
+    Ok(modelinfo)
+}
@@ -0,0 +1,124 @@
+//! Test-only loader serving an in-memory model
+
+use super::LoaderImpl;
+use crate::device::DeviceConfig;
+use crate::error::CallmError;
+use crate::models::mock::ModelMock;
+use crate::models::ModelImpl;
+use crate::templates::{TemplateDummy, TemplateImpl, TemplateJinja};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokenizers::models::wordlevel::WordLevel;
+use tokenizers::pre_tokenizers::whitespace::WhitespaceSplit;
+use tokenizers::{AddedToken, Tokenizer};
+
+/// Vocabulary of the mock tokenizer, indexed by token ID.
+const MOCK_VOCAB: [&str; 6] = ["<unk>", "<eos>", "a", "b", "c", "d"];
+
+/// Loader serving a `ModelMock` with a whitespace-separated word-level tokenizer.
+///
+/// The tokenizer uses `MOCK_VOCAB`, with `<eos>` as the special EOS token.
+pub(crate) struct LoaderMock {
+    model: Arc<Mutex<dyn ModelImpl>>,
+    context_length: Option<usize>,
+    stop_token_ids: Vec<u32>,
+    bos_token: Option<String>,
+    eos_token: Option<String>,
+    chat_template: Option<String>,
+    tokenizer_calls: usize,
+}
+
+impl LoaderMock {
+    /// Creates a new `LoaderMock` serving `model`.
+    pub(crate) fn new(model: ModelMock) -> Self {
+        Self {
+            model: Arc::new(Mutex::new(model)),
+            context_length: None,
+            stop_token_ids: Vec::new(),
+            bos_token: None,
+            eos_token: Some("<eos>".to_string()),
+            chat_template: None,
+            tokenizer_calls: 0,
+        }
+    }
+
+    /// Declares a context window size for the served model.
+    pub(crate) fn with_context_length(mut self, context_length: usize) -> Self {
+        self.context_length = Some(context_length);
+        self
+    }
+
+    /// Declares end-of-turn tokens for the served model.
+    pub(crate) fn with_stop_token_ids(mut self, stop_token_ids: Vec<u32>) -> Self {
+        self.stop_token_ids = stop_token_ids;
+        self
+    }
+
+    /// Declares the BOS token of the template, none by default.
+    pub(crate) fn with_bos_token(mut self, bos_token: Option<&str>) -> Self {
+        self.bos_token = bos_token.map(String::from);
+        self
+    }
+
+    /// Declares the EOS token of the template, `<eos>` by default.
+    pub(crate) fn with_eos_token(mut self, eos_token: Option<&str>) -> Self {
+        self.eos_token = eos_token.map(String::from);
+        self
+    }
+
+    /// Serves a Jinja chat template instead of the dummy template.
+    pub(crate) fn with_chat_template(mut self, chat_template: &str) -> Self {
+        self.chat_template = Some(chat_template.to_string());
+        self
+    }
+
+    /// Returns how many tokenizers the loader has built.
+    pub(crate) fn tokenizer_calls(&self) -> usize {
+        self.tokenizer_calls
+    }
+}
+
+impl LoaderImpl for LoaderMock {
+    fn set_device(&mut self, _device: Arc<DeviceConfig>) {}
+
+    fn load(&mut self) -> Result<Arc<Mutex<dyn ModelImpl>>, CallmError> {
+        Ok(Arc::clone(&self.model))
+    }
+
+    fn tokenizer(&mut self) -> Result<Tokenizer, CallmError> {
+        self.tokenizer_calls += 1;
+        let vocab: HashMap<String, u32> = MOCK_VOCAB
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?;
+
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(WhitespaceSplit);
+        tokenizer.add_special_tokens(&[AddedToken::from("<eos>", true)]);
+        Ok(tokenizer)
+    }
+
+    fn template(&mut self) -> Result<Box<dyn TemplateImpl>, CallmError> {
+        let mut template: Box<dyn TemplateImpl> = match &self.chat_template {
+            Some(chat_template) => Box::new(TemplateJinja::try_new(chat_template)?),
+            None => Box::new(TemplateDummy::new()),
+        };
+        template.set_bos_token(self.bos_token.clone());
+        template.set_eos_token(self.eos_token.clone());
+        Ok(template)
+    }
+
+    fn stop_token_ids(&self) -> Vec<u32> {
+        self.stop_token_ids.clone()
+    }
+
+    fn context_length(&self) -> Option<usize> {
+        self.context_length
+    }
+}
@@ -1,24 +1,28 @@
-use super::LoaderImpl;
+use super::{EosPolicy, LoadProgress, LoaderImpl, LoaderOptions, ModelInfo, END_OF_TURN_TOKENS};
 use crate::device::DeviceConfig;
 use crate::error::CallmError;
 use crate::models::{
-    ModelArchitecture, ModelImpl, ModelLlama, ModelMistral, ModelPhi3, ModelQwen2,
+    files_size, tie_word_embeddings, var_builder_with_progress, ModelArchitecture, ModelBert,
+    ModelGemma, ModelGemma2, ModelImpl, ModelLlama, ModelMistral, ModelPhi3, ModelQwen2,
 };
 use crate::templates::{TemplateDummy, TemplateImpl, TemplateJinja};
+use candle_nn::VarBuilder;
 use serde::Deserialize;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use tokenizers::Tokenizer;
+use tokenizers::{AddedToken, Tokenizer};
 
 const USE_FLASH_ATTN: bool = false;
-const DEFAULT_MODEL_SAFETENSORS_FILE: &str = "model.safetensors";
-const DEFAULT_MODEL_INDEX_JSON: &str = "model.safetensors.index.json";
+pub(crate) const DEFAULT_MODEL_SAFETENSORS_FILE: &str = "model.safetensors";
+pub(crate) const DEFAULT_MODEL_INDEX_JSON: &str = "model.safetensors.index.json";
 const DEFAULT_MODEL_CONFIG_JSON: &str = "config.json";
 const DEFAULT_MODEL_TOKENIZER_JSON: &str = "tokenizer.json";
 const DEFAULT_MODEL_TOKENIZER_CONFIG_JSON: &str = "tokenizer_config.json";
+const DEFAULT_MODEL_GENERATION_CONFIG_JSON: &str = "generation_config.json";
 
 #[derive(Debug, Default)]
 pub struct LoaderSafetensors {
@@ -26,13 +30,23 @@ pub struct LoaderSafetensors {
     base_dir: PathBuf,
     model_files: Vec<PathBuf>,
     config_path: PathBuf,
+    config_path_override: Option<PathBuf>,
     tokenizer_path: PathBuf,
     config: Value,
     device: Arc<DeviceConfig>,
+    options: LoaderOptions,
+    progress: Option<LoadProgress>,
     architecture: ModelArchitecture,
     bos_token_id: Option<i64>,
     eos_token_id: Option<i64>,
+    config_bos_token: Option<String>,
+    config_eos_token: Option<String>,
+    tokenizer_bos_token: Option<String>,
+    tokenizer_eos_token: Option<String>,
+    eos_token: Option<String>,
+    stop_token_ids: Vec<u32>,
     chat_template: Option<String>,
+    added_tokens: Vec<(u32, AddedToken)>,
 }
 
 impl LoaderSafetensors {
@@ -43,7 +57,52 @@ impl LoaderSafetensors {
         }
     }
 
+    /// Uses the model config at `config_path` instead of the `config.json` next to the weights.
+    ///
+    /// The config has to declare the same architecture as the discovered one, if there is any.
+    pub fn with_config_path(mut self, config_path: PathBuf) -> Self {
+        self.config_path_override = Some(config_path);
+        self
+    }
+
+    // locate model files and read configs, without loading any weights
+    pub(crate) fn resolve(&mut self) -> Result<(), CallmError> {
+        self.validate_location()?;
+        self.load_config()?;
+        self.resolve_eos_token()
+    }
+
+    pub(crate) fn model_files(&self) -> &[PathBuf] {
+        &self.model_files
+    }
+
+    pub(crate) fn config(&self) -> &Value {
+        &self.config
+    }
+
+    pub(crate) fn architecture(&self) -> &ModelArchitecture {
+        &self.architecture
+    }
+
+    pub(crate) fn tokenizer_path(&self) -> &Path {
+        &self.tokenizer_path
+    }
+
+    pub(crate) fn chat_template(&self) -> Option<&str> {
+        self.chat_template.as_deref()
+    }
+
+    pub(crate) fn bos_token_id(&self) -> Option<i64> {
+        self.bos_token_id
+    }
+
+    pub(crate) fn eos_token(&self) -> Option<&str> {
+        self.eos_token.as_deref()
+    }
+
     fn validate_location(&mut self) -> Result<(), CallmError> {
+        // start over, as a loader may be validated again, e.g. on `load` after `inspect`
+        self.model_files.clear();
         let metadata = fs::metadata(&self.location)?;
         // populate base_dir & model files vec
         match metadata.is_file() {
@@ -74,18 +133,27 @@ impl LoaderSafetensors {
                         .collect();
                 } else {
                     // try default safetensors model filename
-                    self.location.clone_from(&self.base_dir);
-                    self.location.push(DEFAULT_MODEL_SAFETENSORS_FILE);
-                    return self.validate_location();
+                    let mut model_file = self.base_dir.clone();
+                    model_file.push(DEFAULT_MODEL_SAFETENSORS_FILE);
+                    if !fs::metadata(&model_file).is_ok_and(|m| m.is_file()) {
+                        return Err(CallmError::LoaderFail(format!(
+                            "No safetensors model files found in {}",
+                            self.base_dir.display()
+                        )));
+                    }
+                    self.model_files.push(model_file);
                 }
             }
         };
 
-        // check model config
-        self.config_path = {
-            let mut p = PathBuf::from(&self.base_dir);
-            p.push(DEFAULT_MODEL_CONFIG_JSON);
-            p
+        // check model config, preferring an explicitly set one
+        self.config_path = match &self.config_path_override {
+            Some(config_path) => config_path.clone(),
+            None => {
+                let mut p = PathBuf::from(&self.base_dir);
+                p.push(DEFAULT_MODEL_CONFIG_JSON);
+                p
+            }
         };
         let metadata = fs::metadata(&self.config_path)?;
         if !metadata.is_file() {
@@ -119,31 +187,43 @@ impl LoaderSafetensors {
             "Unknown model config format".to_string(),
         ))?;
 
-        // determine BOS token
-        self.bos_token_id = Some(
-            config_map
-                .get("bos_token_id")
-                .ok_or(CallmError::LoaderFail(
-                    "Missing BOS token ID in model config".to_string(),
-                ))?
-                .as_i64()
-                .ok_or(CallmError::LoaderFail(
-                    "Model config BOS token ID is not an integer".to_string(),
-                ))?,
-        );
+        // make sure an overriding config describes the same model as the discovered one
+        if self.config_path_override.is_some() {
+            let mut default_config_path = PathBuf::from(&self.base_dir);
+            default_config_path.push(DEFAULT_MODEL_CONFIG_JSON);
+            if let Ok(f) = fs::File::open(&default_config_path) {
+                let default_config: Value = serde_json::from_reader(io::BufReader::new(f))?;
+                let architectures = config_map.get("architectures");
+                let default_architectures = default_config.get("architectures");
+                if architectures != default_architectures {
+                    return Err(CallmError::LoaderFail(format!(
+                        "Model config {} architectures {:?} differ from {} architectures {:?}",
+                        self.config_path.display(),
+                        architectures,
+                        default_config_path.display(),
+                        default_architectures
+                    )));
+                }
+            }
+            log::debug!("Using model config {}", self.config_path.display());
+        }
 
-        // determine EOS token
-        self.eos_token_id = Some(
-            config_map
-                .get("eos_token_id")
-                .ok_or(CallmError::LoaderFail(
-                    "Missing EOS token ID in model config".to_string(),
-                ))?
-                .as_i64()
-                .ok_or(CallmError::LoaderFail(
-                    "Model config EOS token ID is not an integer".to_string(),
-                ))?,
-        );
+        // determine BOS and EOS tokens, given as IDs or as token content
+        // NOTE: missing IDs are resolved from token content once the tokenizer is available
+        self.bos_token_id = config_token_id(config_map.get("bos_token_id"), "BOS")?;
+        // NOTE: some models declare several EOS IDs, the first one is the main EOS
+        let eos_token_ids = config_token_ids(config_map.get("eos_token_id"), "EOS")?;
+        self.eos_token_id = eos_token_ids.first().copied();
+        self.stop_token_ids.clear();
+        self.add_stop_token_ids(&eos_token_ids);
+        self.config_bos_token = config_map
+            .get("bos_token")
+            .and_then(token_content)
+            .map(String::from);
+        self.config_eos_token = config_map
+            .get("eos_token")
+            .and_then(token_content)
+            .map(String::from);
 
         // determine model architecture
         self.architecture = match config_map
@@ -163,22 +243,20 @@ impl LoaderSafetensors {
             .ok_or(CallmError::LoaderFail(
                 "Model architecture in model config is not a string".to_string(),
             ))? {
-            "LlamaForCausalLM" => {
-                if let Some(eos_token_id) = &self.eos_token_id {
-                    if *eos_token_id == 128001 {
-                        log::debug!("Applying Meta Llama EOS token fix");
-                        self.eos_token_id = Some(128009);
-                    }
-                }
-                ModelArchitecture::Llama
-            }
+            "BertModel" | "BertForMaskedLM" => ModelArchitecture::Bert,
+            "GemmaForCausalLM" => ModelArchitecture::Gemma,
+            "Gemma2ForCausalLM" => ModelArchitecture::Gemma2,
+            "LlamaForCausalLM" => ModelArchitecture::Llama,
             "MistralForCausalLM" => ModelArchitecture::Mistral,
             "Phi3ForCausalLM" => ModelArchitecture::Phi3,
             "Qwen2ForCausalLM" => ModelArchitecture::Qwen2,
             _ => ModelArchitecture::Unsupported,
         };
 
-        // search tokenizer config JSON for chat template
+        // refuse quantized weights instead of loading them as regular tensors
+        check_quantization(config_map)?;
+
+        // search tokenizer config JSON for chat template and added tokens
         let tokenizer_config_path = {
             let mut p = PathBuf::from(&self.base_dir);
             p.push(DEFAULT_MODEL_TOKENIZER_CONFIG_JSON);
@@ -186,39 +264,205 @@ impl LoaderSafetensors {
         };
         if let Ok(f) = fs::File::open(tokenizer_config_path) {
             let mut tokenizer_config_bufreader = io::BufReader::new(f);
-            #[derive(Deserialize)]
-            pub struct ChatTemplate {
-                chat_template: String,
-            }
             if let Ok(v) =
-                serde_json::from_reader::<_, ChatTemplate>(&mut tokenizer_config_bufreader)
+                serde_json::from_reader::<_, TokenizerConfig>(&mut tokenizer_config_bufreader)
             {
-                self.chat_template = Some(v.chat_template);
-                log::debug!("Loaded chat template from tokenizer config");
+                if let Some(chat_template) = v.chat_template.as_ref().and_then(Value::as_str) {
+                    self.chat_template = Some(chat_template.to_string());
+                    log::debug!("Loaded chat template from tokenizer config");
+                }
+                self.tokenizer_bos_token = v
+                    .bos_token
+                    .as_ref()
+                    .and_then(token_content)
+                    .map(String::from);
+                self.tokenizer_eos_token = v
+                    .eos_token
+                    .as_ref()
+                    .and_then(token_content)
+                    .map(String::from);
+                self.added_tokens = v.added_tokens();
             }
         } else {
             log::debug!("Tokenizer config not found, running without chat template");
         }
 
+        // generation config may declare further end tokens, e.g. Meta Llama 3.1 <|eom_id|>
+        let generation_config_path = {
+            let mut p = PathBuf::from(&self.base_dir);
+            p.push(DEFAULT_MODEL_GENERATION_CONFIG_JSON);
+            p
+        };
+        if let Ok(f) = fs::File::open(generation_config_path) {
+            let generation_config = serde_json::from_reader::<_, Value>(io::BufReader::new(f));
+            match generation_config
+                .map_err(CallmError::from)
+                .and_then(|v| config_token_ids(v.get("eos_token_id"), "EOS"))
+            {
+                Ok(ids) => self.add_stop_token_ids(&ids),
+                Err(e) => log::warn!("Ignoring invalid generation config: {}", e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // add token IDs to the stop tokens, skipping duplicates and IDs out of range
+    fn add_stop_token_ids(&mut self, ids: &[i64]) {
+        for id in ids.iter().filter_map(|id| u32::try_from(*id).ok()) {
+            if !self.stop_token_ids.contains(&id) {
+                self.stop_token_ids.push(id);
+            }
+        }
+    }
+
+    // pick the EOS token according to the EOS policy
+    fn resolve_eos_token(&mut self) -> Result<(), CallmError> {
+        let tokenizer = self.tokenizer()?;
+
+        // fall back to token content for IDs missing in the model config
+        let content_id = |content: &Option<String>| {
+            content
+                .as_deref()
+                .and_then(|c| tokenizer.token_to_id(c))
+                .map(i64::from)
+        };
+        if self.bos_token_id.is_none() {
+            self.bos_token_id = content_id(&self.config_bos_token)
+                .or_else(|| content_id(&self.tokenizer_bos_token));
+        }
+        if self.eos_token_id.is_none() {
+            self.eos_token_id = content_id(&self.config_eos_token)
+                .or_else(|| content_id(&self.tokenizer_eos_token));
+            if let Some(id) = self.eos_token_id.and_then(|id| u32::try_from(id).ok()) {
+                self.stop_token_ids.retain(|stop_id| *stop_id != id);
+                self.stop_token_ids.insert(0, id);
+            }
+        }
+
+        // NOTE: chat models often end their turns with a special token other than the EOS,
+        // NOTE: e.g. Meta Llama 3 <|eot_id|> (128009) next to EOS <|end_of_text|> (128001)
+        let mut end_of_turn_ids: Vec<i64> = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| {
+                token.special && END_OF_TURN_TOKENS.contains(&token.content.as_str())
+            })
+            .map(|(id, _)| i64::from(id))
+            .collect();
+        end_of_turn_ids.sort_unstable();
+        self.add_stop_token_ids(&end_of_turn_ids);
+        log::debug!("Stop token IDs {:?}", self.stop_token_ids);
+
+        let config_eos_token = lookup_token(
+            &tokenizer,
+            self.eos_token_id,
+            self.config_eos_token
+                .as_deref()
+                .or(self.tokenizer_eos_token.as_deref()),
+            "EOS",
+        );
+        if self.tokenizer_eos_token.is_some() && self.tokenizer_eos_token != config_eos_token {
+            log::debug!(
+                "Model config EOS {:?} differs from tokenizer EOS {:?}",
+                config_eos_token,
+                self.tokenizer_eos_token
+            );
+        }
+
+        let policy = self.options.eos_policy;
+        self.eos_token = match policy {
+            EosPolicy::ArchitectureFix | EosPolicy::ModelConfig => config_eos_token,
+            EosPolicy::Tokenizer => match &self.tokenizer_eos_token {
+                Some(eos_token) => Some(eos_token.clone()),
+                None => {
+                    log::warn!("No EOS token in tokenizer config, using model config EOS");
+                    config_eos_token
+                }
+            },
+        };
+        log::info!("Resolved EOS token {:?} ({:?})", self.eos_token, policy);
+
+        // NOTE: encoder models do not generate text, so they do not need an EOS token
+        if self.eos_token.is_none() && self.architecture != ModelArchitecture::Bert {
+            return Err(CallmError::LoaderFail(
+                "Unable to resolve EOS token".to_string(),
+            ));
+        }
+
         Ok(())
     }
 
+    // create a var builder over the model files, serving tied word embeddings if declared
+    fn var_builder(&self) -> Result<VarBuilder<'static>, CallmError> {
+        let vb = var_builder_with_progress(&self.model_files, &self.device, self.progress.clone())?;
+        match self
+            .config
+            .get("tie_word_embeddings")
+            .and_then(Value::as_bool)
+        {
+            Some(true) => Ok(tie_word_embeddings(vb)),
+            _ => Ok(vb),
+        }
+    }
+
     fn load_model(&mut self) -> Result<Arc<Mutex<dyn ModelImpl>>, CallmError> {
+        if self.options.disable_kv_cache {
+            if self.architecture == ModelArchitecture::Llama {
+                log::warn!("KV cache disabled, generation will be much slower");
+            } else {
+                log::warn!(
+                    "KV cache cannot be disabled for {:?}, keeping it",
+                    self.architecture
+                );
+            }
+        }
+
         let model: Arc<Mutex<dyn ModelImpl>> = match self.architecture {
+            ModelArchitecture::Bert => {
+                use candle_transformers::models::bert::Config;
+                let config: Config = serde_json::from_value(self.config.clone())?;
+                Arc::new(Mutex::new(ModelBert::from_var_builder(
+                    self.var_builder()?,
+                    &config,
+                    Arc::clone(&self.device),
+                )?))
+            }
+            ModelArchitecture::Gemma => {
+                use candle_transformers::models::gemma::Config;
+                let config: Config = serde_json::from_value(self.config.clone())?;
+                Arc::new(Mutex::new(ModelGemma::from_var_builder(
+                    self.var_builder()?,
+                    &config,
+                    Arc::clone(&self.device),
+                )?))
+            }
+            ModelArchitecture::Gemma2 => {
+                use candle_transformers::models::gemma2::Config;
+                let config: Config = serde_json::from_value(self.config.clone())?;
+                Arc::new(Mutex::new(ModelGemma2::from_var_builder(
+                    self.var_builder()?,
+                    &config,
+                    Arc::clone(&self.device),
+                )?))
+            }
             ModelArchitecture::Llama => {
                 use candle_transformers::models::llama::LlamaConfig;
                 let config: LlamaConfig = serde_json::from_value(self.config.clone())?;
-                Arc::new(Mutex::new(ModelLlama::from_paths(
-                    &self.model_files,
-                    &config.into_config(USE_FLASH_ATTN),
-                    Arc::clone(&self.device),
-                )?))
+                Arc::new(Mutex::new(
+                    ModelLlama::from_var_builder(
+                        self.var_builder()?,
+                        &config.into_config(USE_FLASH_ATTN),
+                        Arc::clone(&self.device),
+                    )?
+                    .with_kv_cache(!self.options.disable_kv_cache)?,
+                ))
             }
             ModelArchitecture::Mistral => {
                 use candle_transformers::models::mistral::Config;
                 let config: Config = serde_json::from_value(self.config.clone())?;
-                Arc::new(Mutex::new(ModelMistral::from_paths(
-                    &self.model_files,
+                Arc::new(Mutex::new(ModelMistral::from_var_builder(
+                    self.var_builder()?,
                     &config,
                     Arc::clone(&self.device),
                 )?))
@@ -226,8 +470,8 @@ impl LoaderSafetensors {
             ModelArchitecture::Phi3 => {
                 use candle_transformers::models::phi3::Config;
                 let config: Config = serde_json::from_value(self.config.clone())?;
-                Arc::new(Mutex::new(ModelPhi3::from_paths(
-                    &self.model_files,
+                Arc::new(Mutex::new(ModelPhi3::from_var_builder(
+                    self.var_builder()?,
                     &config,
                     Arc::clone(&self.device),
                 )?))
@@ -235,8 +479,8 @@ impl LoaderSafetensors {
             ModelArchitecture::Qwen2 => {
                 use candle_transformers::models::qwen2::Config;
                 let config: Config = serde_json::from_value(self.config.clone())?;
-                Arc::new(Mutex::new(ModelQwen2::from_paths(
-                    &self.model_files,
+                Arc::new(Mutex::new(ModelQwen2::from_var_builder(
+                    self.var_builder()?,
                     &config,
                     Arc::clone(&self.device),
                 )?))
@@ -255,43 +499,247 @@ impl LoaderImpl for LoaderSafetensors {
         self.device = device;
     }
 
+    fn set_options(&mut self, options: LoaderOptions) {
+        self.options = options;
+    }
+
+    fn set_progress(&mut self, progress: LoadProgress) {
+        self.progress = Some(progress);
+    }
+
     fn load(&mut self) -> Result<Arc<Mutex<dyn ModelImpl>>, CallmError> {
-        self.validate_location()?;
-        self.load_config()?;
-        self.load_model()
+        if let Some(dtype) = self.options.dtype {
+            log::debug!("Overriding model dtype with {:?}", dtype);
+            self.device = Arc::new(self.device.as_ref().clone().with_dtype(dtype)?);
+        }
+        self.resolve()?;
+        let model = self.load_model()?;
+        if let Some(progress) = &self.progress {
+            let total = files_size(&self.model_files)?;
+            progress.report(total, total);
+        }
+
+        Ok(model)
     }
 
     fn tokenizer(&mut self) -> Result<Tokenizer, CallmError> {
         let file_str = fs::read_to_string(&self.tokenizer_path)?;
-        Tokenizer::from_bytes(file_str.as_bytes())
-            .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })
+        let mut tokenizer = Tokenizer::from_bytes(file_str.as_bytes())
+            .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?;
 
         // Tokenizer::from_file(&self.tokenizer_path)
         //     .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })
+
+        // register added tokens declared only in tokenizer config
+        let known_added_tokens = tokenizer.get_added_tokens_decoder();
+        for (id, token) in &self.added_tokens {
+            if known_added_tokens.contains_key(id) {
+                continue;
+            }
+            if token.special {
+                tokenizer.add_special_tokens(std::slice::from_ref(token));
+            } else {
+                tokenizer.add_tokens(std::slice::from_ref(token));
+            }
+            if tokenizer.token_to_id(&token.content) != Some(*id) {
+                log::warn!(
+                    "Added token '{}' registered with a different ID than {}",
+                    token.content,
+                    id
+                );
+            }
+        }
+
+        Ok(tokenizer)
     }
 
     fn template(&mut self) -> Result<Box<dyn TemplateImpl>, CallmError> {
-        let mut boxed_template: Box<dyn TemplateImpl> =
-            if let Some(template_string) = &self.chat_template {
-                Box::new(TemplateJinja::new(template_string))
-            } else {
-                Box::new(TemplateDummy::new())
-            };
+        let mut boxed_template: Box<dyn TemplateImpl> = match &self.chat_template {
+            Some(template_string) => match TemplateJinja::try_new(template_string) {
+                Ok(template) => Box::new(template),
+                Err(e) => {
+                    log::warn!("Invalid chat template ({}), using dummy template", e);
+                    Box::new(TemplateDummy::new())
+                }
+            },
+            None => Box::new(TemplateDummy::new()),
+        };
 
         let tokenizer = self.tokenizer()?;
-        if let Some(tkn_id) = &self.bos_token_id {
-            boxed_template.set_bos_token(tokenizer.id_to_token(*tkn_id as u32));
-        }
-        if let Some(tkn_id) = &self.eos_token_id {
-            boxed_template.set_eos_token(tokenizer.id_to_token(*tkn_id as u32));
+        boxed_template.set_bos_token(lookup_token(
+            &tokenizer,
+            self.bos_token_id,
+            self.config_bos_token
+                .as_deref()
+                .or(self.tokenizer_bos_token.as_deref()),
+            "BOS",
+        ));
+        if self.eos_token.is_some() {
+            boxed_template.set_eos_token(self.eos_token.clone());
         }
 
         Ok(boxed_template)
     }
+
+    fn inspect(&mut self) -> Result<ModelInfo, CallmError> {
+        self.resolve()?;
+        self.info().ok_or(CallmError::LoaderFail(
+            "Missing architecture in model config".to_string(),
+        ))
+    }
+
+    fn info(&self) -> Option<ModelInfo> {
+        let architecture = self
+            .config
+            .get("architectures")
+            .and_then(|architectures| architectures.get(0))
+            .and_then(Value::as_str)?;
+
+        Some(ModelInfo {
+            architecture: architecture.to_string(),
+            name: self
+                .config
+                .get("_name_or_path")
+                .and_then(Value::as_str)
+                .filter(|name| !name.is_empty())
+                .map(String::from),
+            context_length: self.context_length(),
+            bos_token_id: self.bos_token_id.and_then(|id| u32::try_from(id).ok()),
+            eos_token_id: self.eos_token_id.and_then(|id| u32::try_from(id).ok()),
+            eos_token: self.eos_token.clone(),
+            ..Default::default()
+        })
+    }
+
+    fn stop_token_ids(&self) -> Vec<u32> {
+        self.stop_token_ids.clone()
+    }
+
+    fn context_length(&self) -> Option<usize> {
+        self.config
+            .get("max_position_embeddings")
+            .and_then(Value::as_u64)
+            .map(|n| n as usize)
+    }
+}
+
+// subset of tokenizer config JSON
+#[derive(Deserialize)]
+struct TokenizerConfig {
+    chat_template: Option<Value>,
+    bos_token: Option<Value>,
+    eos_token: Option<Value>,
+    #[serde(default)]
+    added_tokens_decoder: HashMap<String, TokenizerConfigAddedToken>,
+}
+
+#[derive(Deserialize)]
+struct TokenizerConfigAddedToken {
+    content: String,
+    #[serde(default)]
+    special: bool,
+    #[serde(default)]
+    single_word: bool,
+    #[serde(default)]
+    lstrip: bool,
+    #[serde(default)]
+    rstrip: bool,
+    #[serde(default)]
+    normalized: bool,
+}
+
+impl TokenizerConfig {
+    // convert added tokens decoder into (ID, token) pairs, skipping non-numeric IDs
+    fn added_tokens(&self) -> Vec<(u32, AddedToken)> {
+        let mut added_tokens: Vec<_> = self
+            .added_tokens_decoder
+            .iter()
+            .filter_map(|(id, tkn)| {
+                let token = AddedToken::from(tkn.content.clone(), tkn.special)
+                    .single_word(tkn.single_word)
+                    .lstrip(tkn.lstrip)
+                    .rstrip(tkn.rstrip)
+                    .normalized(tkn.normalized);
+                id.parse().ok().map(|id| (id, token))
+            })
+            .collect();
+        added_tokens.sort_by_key(|(id, _)| *id);
+        added_tokens
+    }
+}
+
+// fail on a quantization config, as no quantized safetensors scheme is supported yet
+fn check_quantization(config_map: &serde_json::Map<String, Value>) -> Result<(), CallmError> {
+    let Some(quantization_config) = config_map.get("quantization_config") else {
+        return Ok(());
+    };
+    let scheme = quantization_config
+        .get("quant_method")
+        .and_then(Value::as_str)
+        .unwrap_or("unknown");
+
+    Err(CallmError::UnsupportedQuantization(scheme.to_string()))
+}
+
+// look up token content by ID, falling back to the token string from the configs
+fn lookup_token(
+    tokenizer: &Tokenizer,
+    id: Option<i64>,
+    fallback: Option<&str>,
+    name: &str,
+) -> Option<String> {
+    if let Some(token) = id.and_then(|id| tokenizer.id_to_token(id as u32)) {
+        return Some(token);
+    }
+    if let Some(id) = id {
+        log::warn!(
+            "{} token ID {} not found in tokenizer, falling back to {:?}",
+            name,
+            id,
+            fallback
+        );
+    }
+    fallback.map(String::from)
+}
+
+// parse an optional model config token ID or array of token IDs
+fn config_token_ids(value: Option<&Value>, name: &str) -> Result<Vec<i64>, CallmError> {
+    match value {
+        Some(Value::Array(ids)) => ids
+            .iter()
+            .map(|id| {
+                id.as_i64().ok_or(CallmError::LoaderFail(format!(
+                    "Model config {} token IDs contain a non-integer",
+                    name
+                )))
+            })
+            .collect(),
+        _ => Ok(config_token_id(value, name)?.into_iter().collect()),
+    }
+}
+
+// parse an optional model config token ID
+fn config_token_id(value: Option<&Value>, name: &str) -> Result<Option<i64>, CallmError> {
+    match value {
+        None | Some(Value::Null) => Ok(None),
+        Some(v) => v.as_i64().map(Some).ok_or(CallmError::LoaderFail(format!(
+            "Model config {} token ID is not an integer",
+            name
+        ))),
+    }
+}
+
+// get token content from either a plain string or a serialized AddedToken object
+fn token_content(value: &Value) -> Option<&str> {
+    match value {
+        Value::String(content) => Some(content.as_str()),
+        Value::Object(token) => token.get("content").and_then(Value::as_str),
+        _ => None,
+    }
 }
 
 // read Safetensors model index pointed by 'path' and return vector of model filenames
-fn read_model_index_json<P: AsRef<Path>>(path: P) -> Result<Vec<String>, CallmError> {
+pub(crate) fn read_model_index_json<P: AsRef<Path>>(path: P) -> Result<Vec<String>, CallmError> {
     use serde_json::Value;
 
     let file = fs::File::open(path)?;
@@ -319,3 +767,184 @@ fn read_model_index_json<P: AsRef<Path>>(path: P) -> Result<Vec<String>, CallmEr
         "Model index deserialization failure".to_string(),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_location_twice() {
+        let dir = std::env::temp_dir().join("callm_test_validate_twice");
+        fs::create_dir_all(&dir).unwrap();
+        for file in [
+            DEFAULT_MODEL_SAFETENSORS_FILE,
+            DEFAULT_MODEL_CONFIG_JSON,
+            DEFAULT_MODEL_TOKENIZER_JSON,
+        ] {
+            fs::write(dir.join(file), b"").unwrap();
+        }
+
+        let mut loader = LoaderSafetensors::new(dir.to_str().unwrap());
+        loader.validate_location().unwrap();
+        loader.validate_location().unwrap();
+        let location = loader.location.clone();
+        let model_files = loader.model_files().to_vec();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(location, dir);
+        assert_eq!(model_files, vec![dir.join(DEFAULT_MODEL_SAFETENSORS_FILE)]);
+    }
+
+    #[test]
+    fn test_validate_location_without_model_files() {
+        let dir = std::env::temp_dir().join("callm_test_no_model_files");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut loader = LoaderSafetensors::new(dir.to_str().unwrap());
+        let result = loader.validate_location();
+        fs::remove_dir(&dir).unwrap();
+
+        match result {
+            Err(CallmError::LoaderFail(msg)) => {
+                assert!(msg.starts_with("No safetensors model files found in"))
+            }
+            _ => panic!("Missing model files not reported"),
+        }
+    }
+
+    #[test]
+    fn test_info() {
+        let mut loader = LoaderSafetensors::default();
+        assert!(loader.info().is_none());
+
+        loader.config = serde_json::json!({
+            "architectures": ["LlamaForCausalLM"],
+            "max_position_embeddings": 8192,
+        });
+        loader.bos_token_id = Some(128000);
+        loader.eos_token_id = Some(128001);
+        loader.eos_token = Some("<|end_of_text|>".to_string());
+
+        let info = loader.info().unwrap();
+        assert_eq!(info.architecture, "LlamaForCausalLM");
+        assert_eq!(info.name, None);
+        assert_eq!(info.context_length, Some(8192));
+        assert_eq!(info.bos_token_id, Some(128000));
+        assert_eq!(info.eos_token_id, Some(128001));
+        assert_eq!(info.eos_token.as_deref(), Some("<|end_of_text|>"));
+    }
+
+    #[test]
+    fn test_check_quantization() {
+        let config: Value = serde_json::from_str(
+            r#"{
+                "quantization_config": {"bits": 4, "group_size": 128, "quant_method": "awq"}
+            }"#,
+        )
+        .unwrap();
+        match check_quantization(config.as_object().unwrap()) {
+            Err(CallmError::UnsupportedQuantization(scheme)) => assert_eq!(scheme, "awq"),
+            _ => panic!("AWQ config not rejected"),
+        }
+
+        let config: Value = serde_json::from_str(r#"{"hidden_size": 4096}"#).unwrap();
+        assert!(check_quantization(config.as_object().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_lookup_token_fallback() {
+        use tokenizers::models::bpe::BPE;
+
+        let vocab = HashMap::from([("<s>".to_string(), 0)]);
+        let bpe = BPE::builder()
+            .vocab_and_merges(vocab, vec![])
+            .build()
+            .unwrap();
+        let tokenizer = Tokenizer::new(bpe);
+
+        assert_eq!(
+            lookup_token(&tokenizer, Some(0), Some("<bos>"), "BOS"),
+            Some("<s>".to_string())
+        );
+        assert_eq!(
+            lookup_token(&tokenizer, Some(7), Some("<bos>"), "BOS"),
+            Some("<bos>".to_string())
+        );
+        assert_eq!(lookup_token(&tokenizer, Some(7), None, "BOS"), None);
+    }
+
+    #[test]
+    fn test_token_content() {
+        let config: TokenizerConfig = serde_json::from_str(
+            r#"{
+                "bos_token": {"__type": "AddedToken", "content": "<s>", "special": true},
+                "eos_token": "</s>"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.bos_token.as_ref().and_then(token_content),
+            Some("<s>")
+        );
+        assert_eq!(
+            config.eos_token.as_ref().and_then(token_content),
+            Some("</s>")
+        );
+        assert_eq!(token_content(&Value::Null), None);
+    }
+
+    #[test]
+    fn test_config_token_id() {
+        assert_eq!(
+            config_token_id(Some(&Value::from(2)), "EOS").unwrap(),
+            Some(2)
+        );
+        assert_eq!(config_token_id(Some(&Value::Null), "EOS").unwrap(), None);
+        assert_eq!(config_token_id(None, "EOS").unwrap(), None);
+        assert!(config_token_id(Some(&Value::from("</s>")), "EOS").is_err());
+    }
+
+    #[test]
+    fn test_config_token_ids() {
+        // Meta Llama 3.1 instruct config.json
+        let eos = serde_json::json!([128001, 128008, 128009]);
+        assert_eq!(
+            config_token_ids(Some(&eos), "EOS").unwrap(),
+            vec![128001, 128008, 128009]
+        );
+        assert_eq!(
+            config_token_ids(Some(&Value::from(2)), "EOS").unwrap(),
+            vec![2]
+        );
+        assert!(config_token_ids(None, "EOS").unwrap().is_empty());
+        assert!(config_token_ids(Some(&serde_json::json!([2, null])), "EOS").is_err());
+
+        let mut loader = LoaderSafetensors::default();
+        loader.add_stop_token_ids(&[128001, 128009, 128001, -1]);
+        assert_eq!(loader.stop_token_ids(), vec![128001, 128009]);
+    }
+
+    #[test]
+    fn test_tokenizer_config_added_tokens() {
+        let config: TokenizerConfig = serde_json::from_str(
+            r#"{
+                "added_tokens_decoder": {
+                    "32001": {"content": "<|end|>", "special": true, "rstrip": true},
+                    "32000": {"content": "<|endoftext|>", "special": true},
+                    "oops": {"content": "<|bad|>"}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let added_tokens = config.added_tokens();
+        assert!(config.chat_template.is_none());
+        assert_eq!(added_tokens.len(), 2);
+        assert_eq!(added_tokens[0].0, 32000);
+        assert_eq!(added_tokens[0].1.content, "<|endoftext|>");
+        assert_eq!(added_tokens[1].0, 32001);
+        assert!(added_tokens[1].1.special);
+        assert!(added_tokens[1].1.rstrip);
+    }
+}
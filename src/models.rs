@@ -1,29 +1,56 @@
 //! This module provides various model implementations for different architectures.
 
+pub mod bert;
+pub use bert::ModelBert;
+pub mod builder;
+pub use builder::ModelBuilder;
+pub mod gemma;
+pub use gemma::ModelGemma;
+pub mod gemma2;
+pub use gemma2::ModelGemma2;
+pub mod gemma_quantized;
+pub use gemma_quantized::ModelGemmaQuantized;
 pub mod llama;
 pub use llama::ModelLlama;
 pub mod llama_quantized;
 pub use llama_quantized::ModelLlamaQuantized;
 pub mod mistral;
 pub use mistral::ModelMistral;
+#[cfg(test)]
+pub(crate) mod mock;
 pub mod phi3;
 pub use phi3::ModelPhi3;
+pub mod phi3_quantized;
+pub use phi3_quantized::ModelPhi3Quantized;
 pub mod qwen2;
 pub use qwen2::ModelQwen2;
 
+use crate::device::DeviceConfig;
 use crate::error::CallmError;
-use candle_core::Tensor;
+use crate::loaders::LoadProgress;
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{DType, Device, Shape, Tensor};
+use candle_nn::var_builder::SimpleBackend;
+use candle_nn::{Init, VarBuilder};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /// Enum representing different model architectures supported by the system.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum ModelArchitecture {
     /// Default value for unsupported architectures.
     #[default]
     Unsupported,
+    Bert,
+    Gemma,
+    Gemma2,
+    GemmaQuantized,
     Llama,
     LlamaQuantized,
     Mistral,
     Phi3,
+    Phi3Quantized,
     Qwen2,
 }
 
@@ -58,6 +85,73 @@ pub trait ModelImpl: Send {
     /// - `Err(CallmError)` if an error occurs during the forward pass.
     fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, CallmError>;
 
+    /// Performs a forward pass through the model with an optional attention mask.
+    ///
+    /// The mask is a `(batch, seq_len)` tensor of `u8` or `u32` values, holding `1` for input
+    /// tokens to attend to and `0` for padding. It replaces the causal mask the model builds
+    /// otherwise, so how the visible tokens attend to each other depends on the model. Without
+    /// a mask this is the same as `forward`.
+    ///
+    /// The default implementation rejects masks. Models whose candle implementation takes a
+    /// mask override it, see `supports_attention_mask`.
+    ///
+    /// # Arguments
+    /// - `input`: The input tensor to the model.
+    /// - `index_pos`: The position index for the input tensor.
+    /// - `mask`: The attention mask, replacing the causal mask of the model.
+    ///
+    /// # Returns
+    /// - `Ok(Tensor)` with the output tensor if the forward pass is successful.
+    /// - `Err(CallmError)` if the model does not support attention masks or an error occurs
+    ///   during the forward pass.
+    fn forward_with_mask(
+        &mut self,
+        input: &Tensor,
+        index_pos: usize,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor, CallmError> {
+        match mask {
+            None => self.forward(input, index_pos),
+            Some(_) => Err(CallmError::GenericError(
+                "Attention masks are not supported by this model".to_string(),
+            )),
+        }
+    }
+
+    /// Computes the last hidden state of the model for the whole input.
+    ///
+    /// Returns a tensor of shape `(batch, seq_len, hidden_size)`. The KV cache, if any, may be
+    /// filled by the pass and has to be cleared afterwards.
+    ///
+    /// # Arguments
+    /// - `input`: The input tensor to the model.
+    ///
+    /// # Returns
+    /// - `Ok(Tensor)` with the hidden states if the model exposes them.
+    /// - `Err(CallmError)` if the model does not expose hidden states or an error occurs during
+    ///   the forward pass.
+    fn hidden_states(&mut self, _input: &Tensor) -> Result<Tensor, CallmError> {
+        Err(CallmError::GenericError(
+            "Hidden states are not supported by this model".to_string(),
+        ))
+    }
+
+    /// Returns whether `forward_with_mask` accepts attention masks.
+    ///
+    /// Qwen2 does, attending bidirectionally among the unmasked tokens like the embedding models
+    /// built on it (e.g. gte-Qwen2) expect.
+    fn supports_attention_mask(&self) -> bool {
+        false
+    }
+
+    /// Returns whether forward passes accept a batch of equally long sequences.
+    ///
+    /// Batched forward passes return the logits of the last position of every sequence, with
+    /// the batch as the first dimension.
+    fn supports_batch(&self) -> bool {
+        false
+    }
+
     /// Clears the key-value cache of the model.
     ///
     /// # Returns
@@ -66,5 +160,246 @@ pub trait ModelImpl: Send {
     fn clear_kv_cache(&mut self) -> Result<(), CallmError> {
         Ok(())
     }
+
+    /// Creates an independent copy of the model with an empty KV cache, sharing the weights.
+    ///
+    /// The copy keeps its own cache, so a second sequence can be generated alongside the first
+    /// without re-running either, e.g. the negative prompt of classifier-free guidance.
+    ///
+    /// # Returns
+    /// - `Ok(Box<dyn ModelImpl>)` with the copy.
+    /// - `Err(CallmError)` if the model cannot be copied.
+    fn fork(&self) -> Result<Box<dyn ModelImpl>, CallmError> {
+        Err(CallmError::GenericError(
+            "Forking is not supported by this model".to_string(),
+        ))
+    }
+
+    /// Returns whether a multi-token input may follow positions already in the KV cache.
+    ///
+    /// Models building their causal mask from the position offset let the input attend to the
+    /// cached positions. Others mask a multi-token input as if the cache was empty, so tokens
+    /// appended to a cached sequence have to be fed one at a time.
+    fn supports_cached_prefill(&self) -> bool {
+        false
+    }
+
+    /// Returns whether forward passes keep previous positions in a KV cache.
+    ///
+    /// Models without a cache have to be passed the whole sequence on every forward pass.
+    fn uses_kv_cache(&self) -> bool {
+        true
+    }
+
+    /// Returns the maximum sequence position supported by the model.
+    ///
+    /// Forward passes must not write positions at or beyond this value into the KV cache.
+    fn max_position(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Returns the KV cache size in bytes per token of context, if known.
+    fn kv_cache_bytes_per_token(&self) -> Option<usize> {
+        None
+    }
 }
 
+/// Computes the KV cache size in bytes per token of context.
+pub(crate) fn kv_cache_bytes_per_token(
+    num_layers: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    dtype: DType,
+) -> usize {
+    // one key and one value vector per layer and KV head
+    2 * num_layers * num_kv_heads * head_dim * dtype.size_in_bytes()
+}
+
+pub(crate) const LM_HEAD_WEIGHT: &str = "lm_head.weight";
+pub(crate) const EMBED_TOKENS_WEIGHT: &str = "model.embed_tokens.weight";
+
+/// Serves `lm_head.weight` from the token embeddings if the weights leave it out.
+///
+/// Models with tied word embeddings share the LM head with the embedding matrix, so checkpoints
+/// often store only the latter.
+pub(crate) fn tie_word_embeddings(vb: VarBuilder<'static>) -> VarBuilder<'static> {
+    if vb.contains_tensor(LM_HEAD_WEIGHT) || !vb.contains_tensor(EMBED_TOKENS_WEIGHT) {
+        return vb;
+    }
+
+    log::info!("Using token embeddings as LM head (tied word embeddings)");
+    vb.rename_f(|name| match name {
+        LM_HEAD_WEIGHT => EMBED_TOKENS_WEIGHT.to_string(),
+        _ => name.to_string(),
+    })
+}
+
+/// Creates a `VarBuilder` backed by memory-mapped safetensors files.
+///
+/// Weights are converted to the compute dtype of the device as they are loaded.
+/// BF16 checkpoints loaded on a device computing in F32 (e.g. CPU) get upcast.
+pub(crate) fn var_builder_from_paths<P: AsRef<Path>>(
+    paths: &[P],
+    device: &DeviceConfig,
+) -> Result<VarBuilder<'static>, CallmError> {
+    var_builder_with_progress(paths, device, None)
+}
+
+/// Creates a `VarBuilder` like `var_builder_from_paths`, reporting loaded tensors to `progress`.
+pub(crate) fn var_builder_with_progress<P: AsRef<Path>>(
+    paths: &[P],
+    device: &DeviceConfig,
+    progress: Option<LoadProgress>,
+) -> Result<VarBuilder<'static>, CallmError> {
+    // NOTE: unsafe inherited from memmap2::MmapOptions
+    let tensors = unsafe { MmapedSafetensors::multi(paths)? };
+
+    if device.candle_dtype() == DType::F32
+        && tensors
+            .tensors()
+            .iter()
+            .any(|(_, view)| matches!(DType::try_from(view.dtype()), Ok(DType::BF16)))
+    {
+        log::info!(
+            "Upcasting BF16 weights to F32 for {:?} device",
+            device.device()
+        );
+    }
+
+    let backend: Box<dyn SimpleBackend> = match progress {
+        Some(progress) => Box::new(ProgressSafetensors {
+            tensors,
+            loaded: AtomicU64::new(0),
+            total: files_size(paths)?,
+            progress,
+        }),
+        None => Box::new(tensors),
+    };
+
+    Ok(VarBuilder::from_backend(
+        backend,
+        device.candle_dtype(),
+        device.candle_device().clone(),
+    ))
+}
+
+/// Returns the total size of the given files in bytes.
+pub(crate) fn files_size<P: AsRef<Path>>(paths: &[P]) -> Result<u64, CallmError> {
+    let mut size = 0;
+    for path in paths {
+        size += fs::metadata(path)?.len();
+    }
+    Ok(size)
+}
+
+// safetensors backend reporting the bytes of each tensor as it is loaded
+struct ProgressSafetensors {
+    tensors: MmapedSafetensors,
+    loaded: AtomicU64,
+    total: u64,
+    progress: LoadProgress,
+}
+
+impl SimpleBackend for ProgressSafetensors {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> candle_core::Result<Tensor> {
+        let tensor = SimpleBackend::get(&self.tensors, s, name, h, dtype, dev)?;
+        let bytes = self
+            .tensors
+            .get(name)
+            .map_or(0, |view| view.data().len() as u64);
+        let loaded = self.loaded.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        // NOTE: tied word embeddings load the same tensor twice
+        self.progress.report(loaded.min(self.total), self.total);
+        Ok(tensor)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        SimpleBackend::contains_tensor(&self.tensors, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_kv_cache_bytes_per_token() {
+        // Llama 3 8B: 32 layers, 8 KV heads of dimension 128
+        assert_eq!(kv_cache_bytes_per_token(32, 8, 128, DType::BF16), 131072);
+        assert_eq!(kv_cache_bytes_per_token(32, 8, 128, DType::F32), 262144);
+    }
+
+    #[test]
+    fn test_forward_with_mask_default() {
+        let mut model = mock::ModelMock::scripted(3, vec![2]);
+        let device = candle_core::Device::Cpu;
+        let input = Tensor::new(&[[0u32, 1]], &device).unwrap();
+        let mask = Tensor::new(&[[1u8, 1]], &device).unwrap();
+
+        assert!(!model.supports_attention_mask());
+        assert!(model.forward_with_mask(&input, 0, None).is_ok());
+        assert!(model.forward_with_mask(&input, 0, Some(&mask)).is_err());
+    }
+
+    #[test]
+    fn test_tie_word_embeddings() {
+        let device = candle_core::Device::Cpu;
+        let embeddings = Tensor::new(&[[1.0f32, 2.0], [3.0, 4.0]], &device).unwrap();
+        let tensors = HashMap::from([(EMBED_TOKENS_WEIGHT.to_string(), embeddings)]);
+        let vb = tie_word_embeddings(VarBuilder::from_tensors(tensors, DType::F32, &device));
+
+        let lm_head = vb.get((2, 2), LM_HEAD_WEIGHT).unwrap();
+        assert_eq!(
+            lm_head.to_vec2::<f32>().unwrap(),
+            vec![vec![1.0, 2.0], vec![3.0, 4.0]]
+        );
+        assert!(vb.get((2, 2), EMBED_TOKENS_WEIGHT).is_ok());
+    }
+
+    #[test]
+    fn test_var_builder_upcasts_bf16_on_cpu() {
+        let device = DeviceConfig::new(Device::CPU);
+        let path = std::env::temp_dir().join("callm_test_upcast_bf16.safetensors");
+        let weight = Tensor::new(&[1.0f32, 2.0, 3.0], device.candle_device())
+            .unwrap()
+            .to_dtype(DType::BF16)
+            .unwrap();
+        candle_core::safetensors::save(&HashMap::from([("weight", weight)]), &path).unwrap();
+
+        let vb = var_builder_from_paths(&[&path], &device).unwrap();
+        let loaded = vb.get(3, "weight").unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.dtype(), DType::F32);
+        assert_eq!(loaded.to_vec1::<f32>().unwrap(), vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_var_builder_reports_progress() {
+        let device = DeviceConfig::new(Device::CPU);
+        let path = std::env::temp_dir().join("callm_test_load_progress.safetensors");
+        let weight = Tensor::new(&[1.0f32, 2.0, 3.0, 4.0], device.candle_device()).unwrap();
+        candle_core::safetensors::save(&HashMap::from([("weight", weight)]), &path).unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let progress = LoadProgress::new(move |loaded, total| {
+            tx.lock().unwrap().send((loaded, total)).unwrap();
+        });
+        let vb = var_builder_with_progress(&[&path], &device, Some(progress)).unwrap();
+        vb.get(4, "weight").unwrap();
+        let total = files_size(&[&path]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![(16, total)]);
+    }
+}
@@ -0,0 +1,53 @@
+use super::{var_builder_from_paths, ModelImpl};
+use crate::{device::DeviceConfig, error::CallmError};
+use candle_core::Tensor;
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config};
+use std::path::Path;
+use std::sync::Arc;
+
+/// BERT encoder, serving hidden states for embeddings.
+///
+/// Encoder models cannot generate text, so `forward` always fails.
+pub struct ModelBert {
+    model: BertModel,
+}
+
+impl ModelBert {
+    pub fn from_paths<P: AsRef<Path>>(
+        paths: &[P],
+        config: &Config,
+        device: Arc<DeviceConfig>,
+    ) -> Result<Self, CallmError> {
+        let vb = var_builder_from_paths(paths, &device)?;
+        Self::from_var_builder(vb, config, device)
+    }
+
+    pub fn from_var_builder(
+        vb: VarBuilder,
+        config: &Config,
+        _device: Arc<DeviceConfig>,
+    ) -> Result<Self, CallmError> {
+        Ok(Self {
+            model: BertModel::load(vb, config)?,
+        })
+    }
+}
+
+impl ModelImpl for ModelBert {
+    fn forward(&mut self, _input: &Tensor, _index_pos: usize) -> Result<Tensor, CallmError> {
+        Err(CallmError::GenericError(
+            "BERT models cannot generate text".to_string(),
+        ))
+    }
+
+    fn uses_kv_cache(&self) -> bool {
+        false
+    }
+
+    fn hidden_states(&mut self, input: &Tensor) -> Result<Tensor, CallmError> {
+        // single segment input
+        let token_type_ids = input.zeros_like()?;
+        Ok(self.model.forward(input, &token_type_ids, None)?)
+    }
+}
@@ -0,0 +1,106 @@
+//! Builder for constructing models from an explicit config
+
+use super::{
+    var_builder_from_paths, ModelBert, ModelGemma, ModelGemma2, ModelLlama, ModelMistral,
+    ModelPhi3, ModelQwen2,
+};
+use crate::device::DeviceConfig;
+use crate::error::CallmError;
+use candle_nn::VarBuilder;
+use candle_transformers::models::{bert, gemma, gemma2, llama, mistral, phi3, qwen2};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Builder for constructing models programmatically.
+///
+/// Unlike the format loaders, the builder does not discover `config.json` on its own.
+/// The caller provides an already parsed model config together with the weights.
+#[derive(Default)]
+pub struct ModelBuilder {
+    paths: Vec<PathBuf>,
+    var_builder: Option<VarBuilder<'static>>,
+    device: Option<DeviceConfig>,
+}
+
+impl ModelBuilder {
+    /// Creates a new `ModelBuilder`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the safetensors files to load weights from.
+    pub fn with_paths<P: AsRef<Path>>(mut self, paths: &[P]) -> Self {
+        self.paths = paths.iter().map(|p| p.as_ref().to_path_buf()).collect();
+        self
+    }
+
+    /// Sets the `VarBuilder` to load weights from, taking precedence over paths.
+    pub fn with_var_builder(mut self, var_builder: VarBuilder<'static>) -> Self {
+        self.var_builder = Some(var_builder);
+        self
+    }
+
+    /// Sets the device configuration.
+    pub fn with_device(mut self, device: DeviceConfig) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// Builds a `ModelBert` with the given config.
+    pub fn build_bert(self, config: &bert::Config) -> Result<ModelBert, CallmError> {
+        let (vb, device) = self.into_parts()?;
+        ModelBert::from_var_builder(vb, config, device)
+    }
+
+    /// Builds a `ModelGemma` with the given config.
+    pub fn build_gemma(self, config: &gemma::Config) -> Result<ModelGemma, CallmError> {
+        let (vb, device) = self.into_parts()?;
+        ModelGemma::from_var_builder(vb, config, device)
+    }
+
+    /// Builds a `ModelGemma2` with the given config.
+    pub fn build_gemma2(self, config: &gemma2::Config) -> Result<ModelGemma2, CallmError> {
+        let (vb, device) = self.into_parts()?;
+        ModelGemma2::from_var_builder(vb, config, device)
+    }
+
+    /// Builds a `ModelLlama` with the given config.
+    pub fn build_llama(self, config: &llama::Config) -> Result<ModelLlama, CallmError> {
+        let (vb, device) = self.into_parts()?;
+        ModelLlama::from_var_builder(vb, config, device)
+    }
+
+    /// Builds a `ModelMistral` with the given config.
+    pub fn build_mistral(self, config: &mistral::Config) -> Result<ModelMistral, CallmError> {
+        let (vb, device) = self.into_parts()?;
+        ModelMistral::from_var_builder(vb, config, device)
+    }
+
+    /// Builds a `ModelPhi3` with the given config.
+    pub fn build_phi3(self, config: &phi3::Config) -> Result<ModelPhi3, CallmError> {
+        let (vb, device) = self.into_parts()?;
+        ModelPhi3::from_var_builder(vb, config, device)
+    }
+
+    /// Builds a `ModelQwen2` with the given config.
+    pub fn build_qwen2(self, config: &qwen2::Config) -> Result<ModelQwen2, CallmError> {
+        let (vb, device) = self.into_parts()?;
+        ModelQwen2::from_var_builder(vb, config, device)
+    }
+
+    fn into_parts(self) -> Result<(VarBuilder<'static>, Arc<DeviceConfig>), CallmError> {
+        let device = Arc::new(self.device.unwrap_or_default());
+        let vb = match self.var_builder {
+            Some(vb) => vb,
+            None if !self.paths.is_empty() => var_builder_from_paths(&self.paths, &device)?,
+            None => {
+                return Err(CallmError::GenericError(
+                    "No model weights specified. Use `with_paths` or `with_var_builder`"
+                        .to_string(),
+                ));
+            }
+        };
+
+        Ok((vb, device))
+    }
+}
@@ -0,0 +1,78 @@
+use super::{kv_cache_bytes_per_token, var_builder_from_paths, ModelImpl};
+use crate::{device::DeviceConfig, error::CallmError};
+use candle_core::Tensor;
+use candle_nn::VarBuilder;
+use candle_transformers::models::gemma::{Config, Model};
+use std::path::Path;
+use std::sync::Arc;
+
+const USE_FLASH_ATTN: bool = false;
+
+#[derive(Clone)]
+pub struct ModelGemma {
+    model: Model,
+    max_position: usize,
+    kv_cache_bytes_per_token: usize,
+}
+
+impl ModelGemma {
+    pub fn from_paths<P: AsRef<Path>>(
+        paths: &[P],
+        config: &Config,
+        device: Arc<DeviceConfig>,
+    ) -> Result<Self, CallmError> {
+        let vb = var_builder_from_paths(paths, &device)?;
+        Self::from_var_builder(vb, config, device)
+    }
+
+    pub fn from_var_builder(
+        vb: VarBuilder,
+        config: &Config,
+        _device: Arc<DeviceConfig>,
+    ) -> Result<Self, CallmError> {
+        let dtype = vb.dtype();
+        Ok(Self {
+            model: Model::new(USE_FLASH_ATTN, config, vb)?,
+            max_position: config.max_position_embeddings,
+            kv_cache_bytes_per_token: kv_cache_bytes_per_token(
+                config.num_hidden_layers,
+                config.num_key_value_heads,
+                config.head_dim,
+                dtype,
+            ),
+        })
+    }
+}
+
+impl ModelImpl for ModelGemma {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, CallmError> {
+        Ok(self.model.forward(input, index_pos)?)
+    }
+
+    fn clear_kv_cache(&mut self) -> Result<(), CallmError> {
+        self.model.clear_kv_cache();
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn ModelImpl>, CallmError> {
+        let mut model = self.clone();
+        model.clear_kv_cache()?;
+        Ok(Box::new(model))
+    }
+
+    fn supports_cached_prefill(&self) -> bool {
+        true
+    }
+
+    fn supports_batch(&self) -> bool {
+        true
+    }
+
+    fn max_position(&self) -> usize {
+        self.max_position
+    }
+
+    fn kv_cache_bytes_per_token(&self) -> Option<usize> {
+        Some(self.kv_cache_bytes_per_token)
+    }
+}
@@ -0,0 +1,176 @@
+use super::{kv_cache_bytes_per_token, var_builder_from_paths, ModelImpl};
+use crate::{device::DeviceConfig, error::CallmError};
+use candle_core::Tensor;
+use candle_nn::VarBuilder;
+use candle_transformers::models::gemma2::{Config, Model};
+use std::path::Path;
+use std::sync::Arc;
+
+const USE_FLASH_ATTN: bool = false;
+
+#[derive(Clone)]
+pub struct ModelGemma2 {
+    model: Model,
+    max_position: usize,
+    kv_cache_bytes_per_token: usize,
+}
+
+impl ModelGemma2 {
+    pub fn from_paths<P: AsRef<Path>>(
+        paths: &[P],
+        config: &Config,
+        device: Arc<DeviceConfig>,
+    ) -> Result<Self, CallmError> {
+        let vb = var_builder_from_paths(paths, &device)?;
+        Self::from_var_builder(vb, config, device)
+    }
+
+    pub fn from_var_builder(
+        vb: VarBuilder,
+        config: &Config,
+        _device: Arc<DeviceConfig>,
+    ) -> Result<Self, CallmError> {
+        // NOTE: attention and final logit softcapping are applied by candle from the config
+        // NOTE: candle restricts every layer to the sliding window, while Gemma 2 alternates
+        // NOTE: sliding window and global attention layers, so outputs diverge beyond the window
+        let max_position = match config.sliding_window {
+            Some(window) if window < config.max_position_embeddings => {
+                log::warn!(
+                    "Capping context of {} tokens to the sliding window of {} tokens",
+                    config.max_position_embeddings,
+                    window
+                );
+                window
+            }
+            _ => config.max_position_embeddings,
+        };
+        let dtype = vb.dtype();
+        Ok(Self {
+            model: Model::new(USE_FLASH_ATTN, config, vb)?,
+            max_position,
+            kv_cache_bytes_per_token: kv_cache_bytes_per_token(
+                config.num_hidden_layers,
+                config.num_key_value_heads,
+                config.head_dim,
+                dtype,
+            ),
+        })
+    }
+}
+
+impl ModelImpl for ModelGemma2 {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, CallmError> {
+        Ok(self.model.forward(input, index_pos)?)
+    }
+
+    fn clear_kv_cache(&mut self) -> Result<(), CallmError> {
+        self.model.clear_kv_cache();
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn ModelImpl>, CallmError> {
+        let mut model = self.clone();
+        model.clear_kv_cache()?;
+        Ok(Box::new(model))
+    }
+
+    fn supports_cached_prefill(&self) -> bool {
+        true
+    }
+
+    fn supports_batch(&self) -> bool {
+        true
+    }
+
+    fn max_position(&self) -> usize {
+        self.max_position
+    }
+
+    fn kv_cache_bytes_per_token(&self) -> Option<usize> {
+        Some(self.kv_cache_bytes_per_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ModelBuilder;
+    use candle_core::DType;
+    use candle_nn::VarMap;
+
+    // tiny Gemma 2 with softcapping, whose context exceeds the sliding window
+    fn tiny_config(sliding_window: usize) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "attention_bias": false,
+            "attn_logit_softcapping": 50.0,
+            "final_logit_softcapping": 30.0,
+            "head_dim": 4,
+            "hidden_act": "gelu_pytorch_tanh",
+            "hidden_activation": "gelu_pytorch_tanh",
+            "hidden_size": 8,
+            "intermediate_size": 16,
+            "max_position_embeddings": 16,
+            "num_attention_heads": 2,
+            "num_hidden_layers": 2,
+            "num_key_value_heads": 1,
+            "query_pre_attn_scalar": 4,
+            "rms_norm_eps": 1e-6,
+            "rope_theta": 10000.0,
+            "sliding_window": sliding_window,
+            "vocab_size": 10
+        }))
+        .unwrap()
+    }
+
+    fn tiny_model(config: &Config) -> ModelGemma2 {
+        let device = DeviceConfig::cpu();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, device.candle_device());
+        ModelBuilder::new()
+            .with_var_builder(vb)
+            .with_device(device)
+            .build_gemma2(config)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_max_position_capped_to_sliding_window() {
+        assert_eq!(tiny_model(&tiny_config(4)).max_position(), 4);
+        assert_eq!(tiny_model(&tiny_config(32)).max_position(), 16);
+    }
+
+    #[test]
+    fn test_forward_consistent_up_to_cap() {
+        let mut model = tiny_model(&tiny_config(4));
+        let device = candle_core::Device::Cpu;
+        let tokens = [1u32, 5, 2, 7];
+
+        // the whole window in one pass
+        let input = Tensor::new(&tokens, &device).unwrap().unsqueeze(0).unwrap();
+        let prefill = model.forward(&input, 0).unwrap().flatten_all().unwrap();
+
+        // the same tokens one at a time through the KV cache
+        model.clear_kv_cache().unwrap();
+        let mut incremental = None;
+        for (pos, token) in tokens.iter().enumerate() {
+            let input = Tensor::new(&[[*token]], &device).unwrap();
+            incremental = Some(model.forward(&input, pos).unwrap().flatten_all().unwrap());
+        }
+        let incremental = incremental.unwrap();
+
+        let diff = (&prefill - incremental)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-4, "logits differ by {}", diff);
+
+        // final logit softcapping bounds the logits
+        let logits = prefill.to_vec1::<f32>().unwrap();
+        assert_eq!(logits.len(), 10);
+        assert!(logits.iter().all(|logit| logit.abs() <= 30.0));
+    }
+}
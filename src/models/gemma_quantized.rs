@@ -0,0 +1,424 @@
+use super::{kv_cache_bytes_per_token, ModelImpl};
+use crate::device::DeviceConfig;
+use crate::error::CallmError;
+use candle_core::quantized::gguf_file::Content;
+use candle_core::quantized::QMatMul;
+use candle_core::{DType, Device, IndexOp, Module, Tensor};
+use candle_nn::Embedding;
+use candle_transformers::quantized_nn::RmsNorm;
+use std::io::{Read, Seek};
+use std::sync::Arc;
+
+const DEFAULT_ROPE_FREQ_BASE: f32 = 10000.0;
+/// Block count of Gemma 2 27B, which scales queries by the model dimension per head
+const GEMMA2_27B_BLOCK_COUNT: usize = 46;
+
+// NOTE: candle-transformers has no quantized Gemma implementation, so the model is assembled
+// NOTE: from quantized building blocks, following llama.cpp's Gemma and Gemma 2 graphs
+
+#[derive(Clone)]
+struct LayerWeights {
+    attention_wq: QMatMul,
+    attention_wk: QMatMul,
+    attention_wv: QMatMul,
+    attention_wo: QMatMul,
+    attention_norm: RmsNorm,
+    post_attention_norm: Option<RmsNorm>,
+    ffn_gate: QMatMul,
+    ffn_up: QMatMul,
+    ffn_down: QMatMul,
+    ffn_norm: RmsNorm,
+    post_ffw_norm: Option<RmsNorm>,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    attention_scale: f64,
+    attn_logit_softcapping: Option<f64>,
+    sliding_window: Option<usize>,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl LayerWeights {
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: &Tensor,
+        index_pos: usize,
+        cos: &Tensor,
+        sin: &Tensor,
+    ) -> candle_core::Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+        let q = self
+            .attention_wq
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = self
+            .attention_wk
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = self
+            .attention_wv
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        // Gemma GGUF files keep the Hugging Face (non-interleaved) rotary layout
+        let cos = cos.narrow(0, index_pos, seq_len)?;
+        let sin = sin.narrow(0, index_pos, seq_len)?;
+        let q = candle_nn::rotary_emb::rope(&q, &cos, &sin)?;
+        let k = candle_nn::rotary_emb::rope(&k, &cos, &sin)?;
+
+        let (k, v) = match &self.kv_cache {
+            Some((k_cache, v_cache)) if index_pos > 0 => (
+                Tensor::cat(&[k_cache, &k], 2)?,
+                Tensor::cat(&[v_cache, &v], 2)?,
+            ),
+            _ => (k, v),
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let n_rep = self.n_head / self.n_kv_head;
+        let k = candle_transformers::utils::repeat_kv(k, n_rep)?;
+        let v = candle_transformers::utils::repeat_kv(v, n_rep)?;
+
+        let mut att = (q.matmul(&k.t()?)? * self.attention_scale)?;
+        if let Some(cap) = self.attn_logit_softcapping {
+            att = ((att / cap)?.tanh()? * cap)?;
+        }
+        let att = candle_nn::ops::softmax_last_dim(&att.broadcast_add(mask)?)?;
+        let y = att.matmul(&v.contiguous()?)?;
+        let y = y
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, self.n_head * self.head_dim))?;
+        self.attention_wo.forward(&y)
+    }
+
+    fn forward_mlp(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let gate = self.ffn_gate.forward(x)?.gelu()?;
+        let up = self.ffn_up.forward(x)?;
+        self.ffn_down.forward(&(gate * up)?)
+    }
+}
+
+#[derive(Clone)]
+struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    output: QMatMul,
+    embedding_length: usize,
+    final_logit_softcapping: Option<f64>,
+    cos: Tensor,
+    sin: Tensor,
+}
+
+impl ModelWeights {
+    fn from_gguf<R: Seek + Read>(
+        content: &Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self, CallmError> {
+        let arch = content
+            .metadata
+            .get("general.architecture")
+            .ok_or(CallmError::LoaderFail(
+                "Missing general.architecture".to_string(),
+            ))?
+            .to_string()?
+            .clone();
+        let md = |key: &str| {
+            content
+                .metadata
+                .get(&format!("{}.{}", arch, key))
+                .ok_or(CallmError::LoaderFail(format!(
+                    "Missing {}.{} in GGUF metadata",
+                    arch, key
+                )))
+        };
+        let md_u32 = |key: &str| -> Result<usize, CallmError> { Ok(md(key)?.to_u32()? as usize) };
+        let md_f32_opt = |key: &str| md(key).ok().and_then(|v| v.to_f32().ok());
+
+        let is_gemma2 = arch == "gemma2";
+        let context_length = md_u32("context_length")?;
+        let embedding_length = md_u32("embedding_length")?;
+        let block_count = md_u32("block_count")?;
+        let n_head = md_u32("attention.head_count")?;
+        let n_kv_head = md_u32("attention.head_count_kv").unwrap_or(n_head);
+        let head_dim = md_u32("attention.key_length").unwrap_or(embedding_length / n_head);
+        let rms_norm_eps = md("attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+        let rope_freq_base = md_f32_opt("rope.freq_base").unwrap_or(DEFAULT_ROPE_FREQ_BASE);
+        let sliding_window = md_u32("attention.sliding_window").ok();
+        let attn_logit_softcapping = md_f32_opt("attn_logit_softcapping").map(f64::from);
+        let final_logit_softcapping = md_f32_opt("final_logit_softcapping").map(f64::from);
+
+        let attention_scale = if is_gemma2 && block_count == GEMMA2_27B_BLOCK_COUNT {
+            1.0 / ((embedding_length / n_head) as f64).sqrt()
+        } else {
+            1.0 / (head_dim as f64).sqrt()
+        };
+
+        let (cos, sin) = rotary_tables(head_dim, rope_freq_base, context_length, device)?;
+
+        let tensor = |reader: &mut R, name: &str| content.tensor(reader, name, device);
+        let tok_embeddings_q = tensor(reader, "token_embd.weight")?;
+        let tok_embeddings = Embedding::new(tok_embeddings_q.dequantize(device)?, embedding_length);
+        // models without an output tensor share it with the token embeddings
+        let output = match content.tensor_infos.contains_key("output.weight") {
+            true => QMatMul::from_qtensor(tensor(reader, "output.weight")?)?,
+            false => QMatMul::from_qtensor(tok_embeddings_q)?,
+        };
+        let norm = RmsNorm::from_qtensor(tensor(reader, "output_norm.weight")?, rms_norm_eps)?;
+
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{}", layer_idx);
+            let mut qmatmul = |name: &str| -> Result<QMatMul, CallmError> {
+                Ok(QMatMul::from_qtensor(tensor(
+                    reader,
+                    &format!("{}.{}.weight", prefix, name),
+                )?)?)
+            };
+            let attention_wq = qmatmul("attn_q")?;
+            let attention_wk = qmatmul("attn_k")?;
+            let attention_wv = qmatmul("attn_v")?;
+            let attention_wo = qmatmul("attn_output")?;
+            let ffn_gate = qmatmul("ffn_gate")?;
+            let ffn_up = qmatmul("ffn_up")?;
+            let ffn_down = qmatmul("ffn_down")?;
+
+            let mut rms_norm = |name: &str| -> Result<RmsNorm, CallmError> {
+                Ok(RmsNorm::from_qtensor(
+                    tensor(reader, &format!("{}.{}.weight", prefix, name))?,
+                    rms_norm_eps,
+                )?)
+            };
+            let attention_norm = rms_norm("attn_norm")?;
+            let ffn_norm = rms_norm("ffn_norm")?;
+            let (post_attention_norm, post_ffw_norm) = match is_gemma2 {
+                true => (
+                    Some(rms_norm("post_attention_norm")?),
+                    Some(rms_norm("post_ffw_norm")?),
+                ),
+                false => (None, None),
+            };
+
+            layers.push(LayerWeights {
+                attention_wq,
+                attention_wk,
+                attention_wv,
+                attention_wo,
+                attention_norm,
+                post_attention_norm,
+                ffn_gate,
+                ffn_up,
+                ffn_down,
+                ffn_norm,
+                post_ffw_norm,
+                n_head,
+                n_kv_head,
+                head_dim,
+                attention_scale,
+                attn_logit_softcapping,
+                // Gemma 2 alternates local sliding window and global attention layers
+                sliding_window: sliding_window.filter(|_| is_gemma2 && layer_idx % 2 == 0),
+                kv_cache: None,
+            });
+        }
+
+        Ok(Self {
+            tok_embeddings,
+            layers,
+            norm,
+            output,
+            embedding_length,
+            final_logit_softcapping,
+            cos,
+            sin,
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let mut layer_in =
+            (self.tok_embeddings.forward(x)? * (self.embedding_length as f64).sqrt())?;
+
+        for layer in self.layers.iter_mut() {
+            let mask = attention_mask(seq_len, index_pos, layer.sliding_window, x.device())?;
+
+            let residual = &layer_in;
+            let h = layer.attention_norm.forward(&layer_in)?;
+            let h = layer.forward_attn(&h, &mask, index_pos, &self.cos, &self.sin)?;
+            let h = match &layer.post_attention_norm {
+                Some(norm) => norm.forward(&h)?,
+                None => h,
+            };
+            let x = (h + residual)?;
+
+            let residual = &x;
+            let h = layer.ffn_norm.forward(&x)?;
+            let h = layer.forward_mlp(&h)?;
+            let h = match &layer.post_ffw_norm {
+                Some(norm) => norm.forward(&h)?,
+                None => h,
+            };
+            layer_in = (h + residual)?;
+        }
+
+        let x = self.norm.forward(&layer_in)?;
+        let x = x.i((.., seq_len - 1, ..))?;
+        let logits = self.output.forward(&x)?;
+        match self.final_logit_softcapping {
+            Some(cap) => (logits / cap)?.tanh()? * cap,
+            None => Ok(logits),
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.kv_cache = None;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ModelGemmaQuantized {
+    model: ModelWeights,
+    max_position: usize,
+    kv_cache_bytes_per_token: usize,
+}
+
+impl ModelGemmaQuantized {
+    pub fn from_gguf<R>(
+        content: Content,
+        reader: &mut R,
+        device: Arc<DeviceConfig>,
+    ) -> Result<Self, CallmError>
+    where
+        R: Seek + Read,
+    {
+        let model = ModelWeights::from_gguf(&content, reader, device.candle_device())?;
+        let max_position = model.cos.dim(0)?;
+        let layer = model.layers.first();
+        // NOTE: quantized models keep the KV cache in F32
+        let kv_cache_bytes_per_token = kv_cache_bytes_per_token(
+            model.layers.len(),
+            layer.map_or(0, |l| l.n_kv_head),
+            layer.map_or(0, |l| l.head_dim),
+            DType::F32,
+        );
+
+        Ok(Self {
+            model,
+            max_position,
+            kv_cache_bytes_per_token,
+        })
+    }
+}
+
+impl ModelImpl for ModelGemmaQuantized {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, CallmError> {
+        self.model
+            .forward(input, index_pos)
+            .map_err(CallmError::CandleError)
+    }
+
+    fn clear_kv_cache(&mut self) -> Result<(), CallmError> {
+        self.model.clear_kv_cache();
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn ModelImpl>, CallmError> {
+        let mut model = self.clone();
+        model.clear_kv_cache()?;
+        Ok(Box::new(model))
+    }
+
+    fn supports_cached_prefill(&self) -> bool {
+        true
+    }
+
+    fn supports_batch(&self) -> bool {
+        true
+    }
+
+    fn max_position(&self) -> usize {
+        self.max_position
+    }
+
+    fn kv_cache_bytes_per_token(&self) -> Option<usize> {
+        Some(self.kv_cache_bytes_per_token)
+    }
+}
+
+// precompute rotary embedding cos/sin tables for every position
+fn rotary_tables(
+    head_dim: usize,
+    freq_base: f32,
+    max_position: usize,
+    device: &Device,
+) -> candle_core::Result<(Tensor, Tensor)> {
+    let theta: Vec<f32> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1.0 / freq_base.powf(i as f32 / head_dim as f32))
+        .collect();
+    let theta_len = theta.len();
+    let theta = Tensor::from_vec(theta, (1, theta_len), device)?;
+    let positions = Tensor::arange(0u32, max_position as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((max_position, 1))?;
+    let idx_theta = positions.matmul(&theta)?;
+
+    Ok((idx_theta.cos()?, idx_theta.sin()?))
+}
+
+// build a causal attention mask for `seq_len` new positions following `index_pos` cached ones,
+// hiding positions outside the sliding window if there is one
+fn attention_mask(
+    seq_len: usize,
+    index_pos: usize,
+    sliding_window: Option<usize>,
+    device: &Device,
+) -> candle_core::Result<Tensor> {
+    let kv_len = index_pos + seq_len;
+    let mask: Vec<f32> = (0..seq_len)
+        .flat_map(|i| {
+            let pos = index_pos + i;
+            (0..kv_len).map(move |j| {
+                if j > pos || sliding_window.is_some_and(|window| pos - j >= window) {
+                    f32::NEG_INFINITY
+                } else {
+                    0.0
+                }
+            })
+        })
+        .collect();
+    Tensor::from_vec(mask, (seq_len, kv_len), device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attention_mask() {
+        let device = Device::Cpu;
+        let inf = f32::NEG_INFINITY;
+
+        let mask = attention_mask(2, 1, None, &device).unwrap();
+        assert_eq!(
+            mask.to_vec2::<f32>().unwrap(),
+            vec![vec![0.0, 0.0, inf], vec![0.0, 0.0, 0.0]]
+        );
+
+        let mask = attention_mask(1, 3, Some(2), &device).unwrap();
+        assert_eq!(
+            mask.to_vec2::<f32>().unwrap(),
+            vec![vec![inf, inf, 0.0, 0.0]]
+        );
+    }
+}
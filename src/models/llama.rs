@@ -1,4 +1,4 @@
-use super::ModelImpl;
+use super::{kv_cache_bytes_per_token, var_builder_from_paths, ModelImpl};
 use crate::{device::DeviceConfig, error::CallmError};
 use candle_core::Tensor;
 use candle_nn::VarBuilder;
@@ -6,11 +6,14 @@ use candle_transformers::models::llama::{Cache, Config, Llama as Model};
 use std::path::Path;
 use std::sync::Arc;
 
-const USE_KV_CACHE: bool = true;
+// NOTE: candle sizes the Llama rotary embedding tables to a fixed sequence length
+const MAX_SEQ_LEN: usize = 4096;
 
+#[derive(Clone)]
 pub struct ModelLlama {
     model: Model,
     cache: Cache,
+    use_kv_cache: bool,
     config: Config,
     device: Arc<DeviceConfig>,
 }
@@ -21,26 +24,42 @@ impl ModelLlama {
         config: &Config,
         device: Arc<DeviceConfig>,
     ) -> Result<Self, CallmError> {
-        // NOTE: unsafe inherited from memmap2::MmapOptions
-        let vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(
-                paths,
-                device.candle_dtype(),
-                device.candle_device(),
-            )?
-        };
+        let vb = var_builder_from_paths(paths, &device)?;
+        Self::from_var_builder(vb, config, device)
+    }
 
+    pub fn from_var_builder(
+        vb: VarBuilder,
+        config: &Config,
+        device: Arc<DeviceConfig>,
+    ) -> Result<Self, CallmError> {
         Ok(Self {
             model: Model::load(vb, config)?,
-            cache: Self::spawn_kv_cache(config, &device)?,
+            cache: Self::spawn_kv_cache(true, config, &device)?,
+            use_kv_cache: true,
             config: config.clone(),
             device,
         })
     }
 
-    fn spawn_kv_cache(config: &Config, device: &DeviceConfig) -> Result<Cache, CallmError> {
+    /// Enables or disables the KV cache, which is enabled by default.
+    ///
+    /// Without the cache every forward pass recomputes attention over the whole sequence, which
+    /// makes generation dramatically slower (quadratic in the output length). Only meant for
+    /// debugging divergence between cached and uncached forward passes.
+    pub fn with_kv_cache(mut self, use_kv_cache: bool) -> Result<Self, CallmError> {
+        self.cache = Self::spawn_kv_cache(use_kv_cache, &self.config, &self.device)?;
+        self.use_kv_cache = use_kv_cache;
+        Ok(self)
+    }
+
+    fn spawn_kv_cache(
+        use_kv_cache: bool,
+        config: &Config,
+        device: &DeviceConfig,
+    ) -> Result<Cache, CallmError> {
         let cache = Cache::new(
-            USE_KV_CACHE,
+            use_kv_cache,
             device.candle_dtype(),
             config,
             device.candle_device(),
@@ -56,7 +75,34 @@ impl ModelImpl for ModelLlama {
     }
 
     fn clear_kv_cache(&mut self) -> Result<(), CallmError> {
-        self.cache = Self::spawn_kv_cache(&self.config, &self.device)?;
+        self.cache = Self::spawn_kv_cache(self.use_kv_cache, &self.config, &self.device)?;
         Ok(())
     }
+
+    fn fork(&self) -> Result<Box<dyn ModelImpl>, CallmError> {
+        let mut model = self.clone();
+        model.clear_kv_cache()?;
+        Ok(Box::new(model))
+    }
+
+    fn uses_kv_cache(&self) -> bool {
+        self.use_kv_cache
+    }
+
+    fn supports_batch(&self) -> bool {
+        true
+    }
+
+    fn max_position(&self) -> usize {
+        MAX_SEQ_LEN
+    }
+
+    fn kv_cache_bytes_per_token(&self) -> Option<usize> {
+        Some(kv_cache_bytes_per_token(
+            self.config.num_hidden_layers,
+            self.config.num_key_value_heads,
+            self.config.hidden_size / self.config.num_attention_heads,
+            self.device.candle_dtype(),
+        ))
+    }
 }
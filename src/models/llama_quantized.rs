@@ -1,19 +1,24 @@
-use super::ModelImpl;
+use super::{kv_cache_bytes_per_token, ModelImpl};
 use crate::device::DeviceConfig;
 use crate::error::CallmError;
 use candle_core::quantized::gguf_file::Content;
-use candle_core::Tensor;
-use candle_transformers::models::quantized_llama::ModelWeights as Model;
+use candle_core::{DType, Tensor};
+use candle_transformers::models::quantized_llama::{ModelWeights as Model, MAX_SEQ_LEN};
 use std::io::{Read, Seek};
 use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct ModelLlamaQuantized {
     model: Model,
+    kv_cache_bytes_per_token: Option<usize>,
 }
 
 impl ModelLlamaQuantized {
     pub fn from_weights(model: Model) -> Self {
-        Self { model }
+        Self {
+            model,
+            kv_cache_bytes_per_token: None,
+        }
     }
 
     pub fn from_gguf<R>(
@@ -24,8 +29,10 @@ impl ModelLlamaQuantized {
     where
         R: Seek + Read,
     {
+        let kv_cache_bytes_per_token = kv_cache_bytes_from_gguf(&content);
         Ok(Self {
             model: Model::from_gguf(content, reader, device.candle_device())?,
+            kv_cache_bytes_per_token,
         })
     }
 }
@@ -36,4 +43,38 @@ impl ModelImpl for ModelLlamaQuantized {
             .forward(input, index_pos)
             .map_err(CallmError::CandleError)
     }
+
+    fn fork(&self) -> Result<Box<dyn ModelImpl>, CallmError> {
+        let mut model = self.clone();
+        model.clear_kv_cache()?;
+        Ok(Box::new(model))
+    }
+
+    fn supports_batch(&self) -> bool {
+        true
+    }
+
+    fn max_position(&self) -> usize {
+        MAX_SEQ_LEN
+    }
+
+    fn kv_cache_bytes_per_token(&self) -> Option<usize> {
+        self.kv_cache_bytes_per_token
+    }
+}
+
+// compute KV cache size per token from GGUF Llama metadata
+// NOTE: quantized models keep the KV cache in F32
+fn kv_cache_bytes_from_gguf(content: &Content) -> Option<usize> {
+    let md = |key: &str| -> Option<usize> {
+        content.metadata.get(key)?.to_u32().ok().map(|v| v as usize)
+    };
+    let head_count = md("llama.attention.head_count")?;
+
+    Some(kv_cache_bytes_per_token(
+        md("llama.block_count")?,
+        md("llama.attention.head_count_kv").unwrap_or(head_count),
+        md("llama.embedding_length")? / head_count,
+        DType::F32,
+    ))
 }
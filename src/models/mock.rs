@@ -0,0 +1,139 @@
+use super::ModelImpl;
+use crate::error::CallmError;
+use candle_core::Tensor;
+use std::sync::{Arc, Mutex};
+
+type LogitsFn = dyn Fn(&[u32], usize) -> Vec<f32> + Send + Sync;
+
+/// In-memory model returning canned logits, for testing pipelines without model weights.
+///
+/// Logits are computed from the tokens seen so far and the number of tokens generated since
+/// the prompt was processed, separately for every sequence of a batch. Hidden states hold the
+/// token ID and position of every token.
+#[derive(Clone)]
+pub(crate) struct ModelMock {
+    vocab_size: usize,
+    logits: Arc<LogitsFn>,
+    tokens: Vec<Vec<u32>>,
+    prompt_len: Option<usize>,
+    use_kv_cache: bool,
+    cached_prefill: bool,
+    batch_sizes: Arc<Mutex<Vec<usize>>>,
+}
+
+impl ModelMock {
+    /// Creates a model computing logits with `logits(tokens, step)`.
+    pub(crate) fn new<F>(vocab_size: usize, logits: F) -> Self
+    where
+        F: Fn(&[u32], usize) -> Vec<f32> + Send + Sync + 'static,
+    {
+        Self {
+            vocab_size,
+            logits: Arc::new(logits),
+            tokens: Vec::new(),
+            prompt_len: None,
+            use_kv_cache: true,
+            cached_prefill: true,
+            batch_sizes: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Makes the model expect the whole sequence on every forward pass.
+    pub(crate) fn without_kv_cache(mut self) -> Self {
+        self.use_kv_cache = false;
+        self
+    }
+
+    /// Makes the model expect tokens following cached ones one at a time.
+    pub(crate) fn without_cached_prefill(mut self) -> Self {
+        self.cached_prefill = false;
+        self
+    }
+
+    /// Returns the batch size of every forward pass, recorded as they run.
+    pub(crate) fn batch_sizes(&self) -> Arc<Mutex<Vec<usize>>> {
+        Arc::clone(&self.batch_sizes)
+    }
+
+    /// Creates a model emitting `script` token by token, repeating the last one afterwards.
+    pub(crate) fn scripted(vocab_size: usize, script: Vec<u32>) -> Self {
+        Self::new(vocab_size, move |_, step| {
+            let token = script[step.min(script.len() - 1)] as usize;
+            (0..vocab_size)
+                .map(|id| if id == token { 10.0 } else { 0.0 })
+                .collect()
+        })
+    }
+}
+
+impl ModelImpl for ModelMock {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, CallmError> {
+        if !self.use_kv_cache {
+            assert_eq!(
+                index_pos, 0,
+                "Mock without KV cache expects whole sequences"
+            );
+        }
+        let rows = input.to_vec2::<u32>()?;
+        if !self.cached_prefill && index_pos > 0 {
+            assert_eq!(
+                rows[0].len(),
+                1,
+                "Mock expects cached tokens to be followed by one"
+            );
+        }
+        let batch_size = rows.len();
+        self.batch_sizes.lock().unwrap().push(batch_size);
+        self.tokens.resize(batch_size, Vec::new());
+
+        let mut logits = Vec::with_capacity(batch_size * self.vocab_size);
+        for (tokens, row) in self.tokens.iter_mut().zip(rows) {
+            tokens.truncate(index_pos);
+            tokens.extend(row);
+            let prompt_len = *self.prompt_len.get_or_insert(tokens.len());
+
+            let row_logits = (self.logits)(tokens, tokens.len() - prompt_len);
+            assert_eq!(
+                row_logits.len(),
+                self.vocab_size,
+                "Mock logits size mismatch"
+            );
+            logits.extend(row_logits);
+        }
+        Ok(Tensor::new(logits, input.device())?.reshape((batch_size, 1, self.vocab_size))?)
+    }
+
+    fn clear_kv_cache(&mut self) -> Result<(), CallmError> {
+        self.tokens.clear();
+        self.prompt_len = None;
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn ModelImpl>, CallmError> {
+        let mut model = self.clone();
+        model.clear_kv_cache()?;
+        Ok(Box::new(model))
+    }
+
+    fn uses_kv_cache(&self) -> bool {
+        self.use_kv_cache
+    }
+
+    fn supports_cached_prefill(&self) -> bool {
+        self.cached_prefill
+    }
+
+    fn supports_batch(&self) -> bool {
+        true
+    }
+
+    fn hidden_states(&mut self, input: &Tensor) -> Result<Tensor, CallmError> {
+        let tokens = input.flatten_all()?.to_vec1::<u32>()?;
+        let hidden: Vec<f32> = tokens
+            .iter()
+            .enumerate()
+            .flat_map(|(position, id)| [*id as f32, position as f32])
+            .collect();
+        Ok(Tensor::new(hidden, input.device())?.reshape((1, tokens.len(), 2))?)
+    }
+}
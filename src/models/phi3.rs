@@ -1,4 +1,4 @@
-use super::ModelImpl;
+use super::{kv_cache_bytes_per_token, var_builder_from_paths, ModelImpl};
 use crate::{device::DeviceConfig, error::CallmError};
 use candle_core::Tensor;
 use candle_nn::VarBuilder;
@@ -6,8 +6,11 @@ use candle_transformers::models::phi3::{Config, Model};
 use std::path::Path;
 use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct ModelPhi3 {
     model: Model,
+    max_position: usize,
+    kv_cache_bytes_per_token: usize,
 }
 
 impl ModelPhi3 {
@@ -16,17 +19,25 @@ impl ModelPhi3 {
         config: &Config,
         device: Arc<DeviceConfig>,
     ) -> Result<Self, CallmError> {
-        // NOTE: unsafe inherited from memmap2::MmapOptions
-        let vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(
-                paths,
-                device.candle_dtype(),
-                device.candle_device(),
-            )?
-        };
+        let vb = var_builder_from_paths(paths, &device)?;
+        Self::from_var_builder(vb, config, device)
+    }
 
+    pub fn from_var_builder(
+        vb: VarBuilder,
+        config: &Config,
+        _device: Arc<DeviceConfig>,
+    ) -> Result<Self, CallmError> {
+        let dtype = vb.dtype();
         Ok(Self {
             model: Model::new(config, vb)?,
+            max_position: config.max_position_embeddings,
+            kv_cache_bytes_per_token: kv_cache_bytes_per_token(
+                config.num_hidden_layers,
+                config.num_key_value_heads,
+                config.hidden_size / config.num_attention_heads,
+                dtype,
+            ),
         })
     }
 }
@@ -40,4 +51,26 @@ impl ModelImpl for ModelPhi3 {
         self.model.clear_kv_cache();
         Ok(())
     }
+
+    fn fork(&self) -> Result<Box<dyn ModelImpl>, CallmError> {
+        let mut model = self.clone();
+        model.clear_kv_cache()?;
+        Ok(Box::new(model))
+    }
+
+    fn supports_cached_prefill(&self) -> bool {
+        true
+    }
+
+    fn supports_batch(&self) -> bool {
+        true
+    }
+
+    fn max_position(&self) -> usize {
+        self.max_position
+    }
+
+    fn kv_cache_bytes_per_token(&self) -> Option<usize> {
+        Some(self.kv_cache_bytes_per_token)
+    }
 }
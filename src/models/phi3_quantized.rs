@@ -0,0 +1,180 @@
+use super::{kv_cache_bytes_per_token, ModelImpl};
+use crate::device::DeviceConfig;
+use crate::error::CallmError;
+use candle_core::quantized::gguf_file::Content;
+use candle_core::{DType, Tensor};
+use candle_transformers::models::quantized_phi3::ModelWeights as Model;
+use std::io::{Read, Seek};
+use std::sync::Arc;
+
+const USE_FLASH_ATTN: bool = false;
+
+#[derive(Clone)]
+pub struct ModelPhi3Quantized {
+    model: Model,
+    // copy taken before the first forward pass, holding empty KV caches
+    // NOTE: candle's Phi-3 KV cache is append-only, so clearing restores this copy, which shares
+    // the weights with `model`
+    initial: Model,
+    max_position: usize,
+    kv_cache_bytes_per_token: Option<usize>,
+}
+
+impl ModelPhi3Quantized {
+    pub fn from_gguf<R>(
+        content: Content,
+        reader: &mut R,
+        device: Arc<DeviceConfig>,
+    ) -> Result<Self, CallmError>
+    where
+        R: Seek + Read,
+    {
+        let max_position = content
+            .metadata
+            .get("phi3.context_length")
+            .and_then(|v| v.to_u32().ok())
+            .map_or(usize::MAX, |v| v as usize);
+        let kv_cache_bytes_per_token = kv_cache_bytes_from_gguf(&content);
+        let model = Model::from_gguf(USE_FLASH_ATTN, content, reader, device.candle_device())?;
+        Ok(Self {
+            initial: model.clone(),
+            model,
+            max_position,
+            kv_cache_bytes_per_token,
+        })
+    }
+}
+
+impl ModelImpl for ModelPhi3Quantized {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, CallmError> {
+        self.model
+            .forward(input, index_pos)
+            .map_err(CallmError::CandleError)
+    }
+
+    fn clear_kv_cache(&mut self) -> Result<(), CallmError> {
+        self.model = self.initial.clone();
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn ModelImpl>, CallmError> {
+        let mut model = self.clone();
+        model.clear_kv_cache()?;
+        Ok(Box::new(model))
+    }
+
+    fn max_position(&self) -> usize {
+        self.max_position
+    }
+
+    fn kv_cache_bytes_per_token(&self) -> Option<usize> {
+        self.kv_cache_bytes_per_token
+    }
+}
+
+// compute KV cache size per token from GGUF Phi-3 metadata
+// NOTE: quantized models keep the KV cache in F32
+fn kv_cache_bytes_from_gguf(content: &Content) -> Option<usize> {
+    let md = |key: &str| -> Option<usize> {
+        content.metadata.get(key)?.to_u32().ok().map(|v| v as usize)
+    };
+    let head_count = md("phi3.attention.head_count")?;
+
+    Some(kv_cache_bytes_per_token(
+        md("phi3.block_count")?,
+        md("phi3.attention.head_count_kv").unwrap_or(head_count),
+        md("phi3.embedding_length")? / head_count,
+        DType::F32,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use candle_core::quantized::gguf_file::{self, Value};
+    use candle_core::quantized::{GgmlDType, QTensor};
+
+    const VOCAB_SIZE: usize = 10;
+    const HIDDEN_SIZE: usize = 8;
+    const FEED_FORWARD: usize = 16;
+
+    // writes a single layer Phi-3 GGUF with random weights
+    fn tiny_gguf() -> std::io::Cursor<Vec<u8>> {
+        let device = candle_core::Device::Cpu;
+        let metadata = [
+            ("general.architecture", Value::String("phi3".to_string())),
+            ("phi3.context_length", Value::U32(32)),
+            ("phi3.embedding_length", Value::U32(HIDDEN_SIZE as u32)),
+            ("phi3.block_count", Value::U32(1)),
+            ("phi3.feed_forward_length", Value::U32(FEED_FORWARD as u32)),
+            ("phi3.rope.dimension_count", Value::U32(4)),
+            ("phi3.attention.head_count", Value::U32(2)),
+            ("phi3.attention.head_count_kv", Value::U32(2)),
+            ("phi3.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+        ];
+        let shapes: [(&str, &[usize]); 9] = [
+            ("token_embd.weight", &[VOCAB_SIZE, HIDDEN_SIZE]),
+            ("output_norm.weight", &[HIDDEN_SIZE]),
+            ("output.weight", &[VOCAB_SIZE, HIDDEN_SIZE]),
+            ("blk.0.attn_norm.weight", &[HIDDEN_SIZE]),
+            ("blk.0.attn_qkv.weight", &[3 * HIDDEN_SIZE, HIDDEN_SIZE]),
+            ("blk.0.attn_output.weight", &[HIDDEN_SIZE, HIDDEN_SIZE]),
+            ("blk.0.ffn_norm.weight", &[HIDDEN_SIZE]),
+            ("blk.0.ffn_up.weight", &[2 * FEED_FORWARD, HIDDEN_SIZE]),
+            ("blk.0.ffn_down.weight", &[HIDDEN_SIZE, FEED_FORWARD]),
+        ];
+        let tensors: Vec<(&str, QTensor)> = shapes
+            .iter()
+            .map(|(name, shape)| {
+                let weight = Tensor::randn(0f32, 1.0, *shape, &device).unwrap();
+                (*name, QTensor::quantize(&weight, GgmlDType::F32).unwrap())
+            })
+            .collect();
+
+        let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(k, v)| (*k, v)).collect();
+        let mut file = std::io::Cursor::new(Vec::new());
+        gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+        file.set_position(0);
+        file
+    }
+
+    // greedily generates `count` tokens after `prompt`
+    fn greedy(model: &mut ModelPhi3Quantized, prompt: &[u32], count: usize) -> Vec<u32> {
+        let device = candle_core::Device::Cpu;
+        let mut tokens = prompt.to_vec();
+        let mut input = Tensor::new(prompt, &device).unwrap().unsqueeze(0).unwrap();
+        let mut index_pos = 0;
+        for _ in 0..count {
+            let logits = model.forward(&input, index_pos).unwrap();
+            let token = logits
+                .flatten_all()
+                .unwrap()
+                .argmax(0)
+                .unwrap()
+                .to_scalar::<u32>()
+                .unwrap();
+            index_pos += input.dim(1).unwrap();
+            tokens.push(token);
+            input = Tensor::new(&[token], &device)
+                .unwrap()
+                .unsqueeze(0)
+                .unwrap();
+        }
+        tokens
+    }
+
+    #[test]
+    fn test_clear_kv_cache() {
+        let mut file = tiny_gguf();
+        let content = Content::read(&mut file).unwrap();
+        let device = Arc::new(DeviceConfig::new(Device::CPU));
+        let mut model = ModelPhi3Quantized::from_gguf(content, &mut file, device).unwrap();
+        assert_eq!(model.max_position(), 32);
+
+        let first = greedy(&mut model, &[1, 2, 3], 4);
+        model.clear_kv_cache().unwrap();
+        assert_eq!(greedy(&mut model, &[1, 2, 3], 4), first);
+    }
+}
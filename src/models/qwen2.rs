@@ -1,13 +1,22 @@
-use super::ModelImpl;
+use super::{
+    kv_cache_bytes_per_token, var_builder_from_paths, ModelImpl, EMBED_TOKENS_WEIGHT,
+    LM_HEAD_WEIGHT,
+};
 use crate::{device::DeviceConfig, error::CallmError};
 use candle_core::Tensor;
-use candle_nn::VarBuilder;
-use candle_transformers::models::qwen2::{Config, ModelForCausalLM as Model};
+use candle_nn::{linear_no_bias, Linear, VarBuilder};
+use candle_transformers::models::qwen2::{Config, Model};
 use std::path::Path;
 use std::sync::Arc;
 
+// NOTE: built from the base model rather than candle's `ModelForCausalLM`, which does not pass
+// NOTE: attention masks on
+#[derive(Clone)]
 pub struct ModelQwen2 {
     model: Model,
+    lm_head: Linear,
+    max_position: usize,
+    kv_cache_bytes_per_token: usize,
 }
 
 impl ModelQwen2 {
@@ -16,28 +25,92 @@ impl ModelQwen2 {
         config: &Config,
         device: Arc<DeviceConfig>,
     ) -> Result<Self, CallmError> {
-        // NOTE: unsafe inherited from memmap2::MmapOptions
-        let vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(
-                paths,
-                device.candle_dtype(),
-                device.candle_device(),
-            )?
-        };
+        let vb = var_builder_from_paths(paths, &device)?;
+        Self::from_var_builder(vb, config, device)
+    }
 
+    pub fn from_var_builder(
+        vb: VarBuilder,
+        config: &Config,
+        _device: Arc<DeviceConfig>,
+    ) -> Result<Self, CallmError> {
+        let dtype = vb.dtype();
+        let lm_head = match vb.contains_tensor(LM_HEAD_WEIGHT) {
+            true => linear_no_bias(config.hidden_size, config.vocab_size, vb.pp("lm_head"))?,
+            // tied word embeddings
+            false => Linear::new(
+                vb.get((config.vocab_size, config.hidden_size), EMBED_TOKENS_WEIGHT)?,
+                None,
+            ),
+        };
         Ok(Self {
             model: Model::new(config, vb)?,
+            lm_head,
+            max_position: config.max_position_embeddings,
+            kv_cache_bytes_per_token: kv_cache_bytes_per_token(
+                config.num_hidden_layers,
+                config.num_key_value_heads,
+                config.hidden_size / config.num_attention_heads,
+                dtype,
+            ),
         })
     }
 }
 
 impl ModelImpl for ModelQwen2 {
     fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, CallmError> {
-        Ok(self.model.forward(input, index_pos)?)
+        self.forward_with_mask(input, index_pos, None)
+    }
+
+    fn forward_with_mask(
+        &mut self,
+        input: &Tensor,
+        index_pos: usize,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor, CallmError> {
+        // NOTE: candle expands the mask over the input only, ignoring cached positions
+        if mask.is_some() && index_pos > 0 {
+            return Err(CallmError::GenericError(
+                "Qwen2 attention masks need whole sequences starting at position 0".to_string(),
+            ));
+        }
+
+        let seq_len = input.dim(1)?;
+        Ok(self
+            .model
+            .forward(input, index_pos, mask)?
+            .narrow(1, seq_len - 1, 1)?
+            .apply(&self.lm_head)?)
+    }
+
+    fn supports_attention_mask(&self) -> bool {
+        true
     }
 
     fn clear_kv_cache(&mut self) -> Result<(), CallmError> {
         self.model.clear_kv_cache();
         Ok(())
     }
+
+    fn fork(&self) -> Result<Box<dyn ModelImpl>, CallmError> {
+        let mut model = self.clone();
+        model.clear_kv_cache()?;
+        Ok(Box::new(model))
+    }
+
+    fn supports_cached_prefill(&self) -> bool {
+        true
+    }
+
+    fn supports_batch(&self) -> bool {
+        true
+    }
+
+    fn max_position(&self) -> usize {
+        self.max_position
+    }
+
+    fn kv_cache_bytes_per_token(&self) -> Option<usize> {
+        Some(self.kv_cache_bytes_per_token)
+    }
 }
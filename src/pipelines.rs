@@ -1,4 +1,10 @@
 //! This module provides pipelines.
 
+pub mod chat;
+pub use chat::ChatSession;
+pub mod embedding;
+pub use embedding::{PipelineEmbedding, Pooling};
+pub mod stopping;
+pub use stopping::StoppingCriteria;
 pub mod text;
-pub use text::PipelineText;
+pub use text::{FinishReason, GenerationResult, PipelineText, SamplingState, TemperatureSchedule};
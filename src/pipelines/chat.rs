@@ -0,0 +1,234 @@
+//! Chat sessions keeping the conversation history
+
+use super::text::PipelineText;
+use crate::error::CallmError;
+use crate::templates::{ChatMessage, MessageRole};
+
+/// Tokens kept free for the reply by default.
+const DEFAULT_RESERVED_TOKENS: usize = 512;
+
+/// A conversation with a text generation pipeline.
+///
+/// The session stores the message history and renders all of it on every turn. Once the prompt
+/// no longer leaves room for the reply in the context window, the oldest turns are dropped. A
+/// leading system message is always kept.
+///
+/// ```no_run
+/// use callm::pipelines::{ChatSession, PipelineText};
+///
+/// let pipeline = PipelineText::builder()
+///     .with_location("/path/to/model")
+///     .build()?;
+/// let mut session = ChatSession::new(pipeline).with_system_message("You are a pirate.");
+/// println!("{}", session.send("Hello!")?);
+/// println!("{}", session.send("Where is the treasure?")?);
+/// # Ok::<(), callm::error::CallmError>(())
+/// ```
+pub struct ChatSession {
+    pipeline: PipelineText,
+    history: Vec<(MessageRole, String)>,
+    reserved_tokens: usize,
+}
+
+impl ChatSession {
+    /// Creates a new `ChatSession` with an empty history, using a loaded `pipeline`.
+    pub fn new(pipeline: PipelineText) -> Self {
+        Self {
+            pipeline,
+            history: Vec::new(),
+            reserved_tokens: DEFAULT_RESERVED_TOKENS,
+        }
+    }
+
+    /// Restarts the conversation with a system message, kept when trimming the history.
+    pub fn with_system_message(mut self, system_message: &str) -> Self {
+        self.history = vec![(MessageRole::System, system_message.to_string())];
+        self
+    }
+
+    /// Sets the number of tokens kept free for the reply, 512 by default.
+    pub fn with_reserved_tokens(mut self, reserved_tokens: usize) -> Self {
+        self.reserved_tokens = reserved_tokens;
+        self
+    }
+
+    /// Sets the number of tokens kept free for the reply.
+    ///
+    /// Older turns are dropped once the prompt would leave fewer tokens in the context window.
+    pub fn set_reserved_tokens(&mut self, reserved_tokens: usize) {
+        self.reserved_tokens = reserved_tokens;
+    }
+
+    /// Sends a user message and returns the reply, adding both to the history.
+    ///
+    /// If generation fails, the user message is removed from the history again. Turns trimmed to
+    /// make room for it stay removed.
+    pub fn send(&mut self, user_msg: &str) -> Result<String, CallmError> {
+        self.history.push((MessageRole::User, user_msg.to_string()));
+        let reply = self
+            .trim_history()
+            .and_then(|_| self.pipeline.run_chat(&self.history));
+
+        match reply {
+            Ok(reply) => {
+                self.history.push((MessageRole::Assistant, reply.clone()));
+                Ok(reply)
+            }
+            Err(e) => {
+                self.history.pop();
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns the conversation history, oldest message first.
+    pub fn history(&self) -> &[(MessageRole, String)] {
+        &self.history
+    }
+
+    /// Removes all messages from the history except the system message.
+    pub fn clear(&mut self) {
+        self.history.truncate(self.pinned_messages());
+    }
+
+    /// Gets the underlying pipeline, e.g. to change inference parameters between turns.
+    pub fn pipeline_mut(&mut self) -> &mut PipelineText {
+        &mut self.pipeline
+    }
+
+    /// Ends the session, returning the underlying pipeline.
+    pub fn into_pipeline(self) -> PipelineText {
+        self.pipeline
+    }
+
+    // number of leading messages never trimmed
+    fn pinned_messages(&self) -> usize {
+        usize::from(matches!(
+            self.history.first(),
+            Some((MessageRole::System, _))
+        ))
+    }
+
+    // drop the oldest turns until the prompt leaves room for the reply
+    fn trim_history(&mut self) -> Result<(), CallmError> {
+        let budget = self
+            .pipeline
+            .context_length()?
+            .saturating_sub(self.reserved_tokens);
+        let pinned = self.pinned_messages();
+
+        loop {
+            let messages: Vec<ChatMessage> = self.history.iter().map(ChatMessage::from).collect();
+            let prompt = self.pipeline.chat_prompt(&messages)?;
+            let tokens = self.pipeline.count_templated_tokens(&prompt)?;
+            if tokens <= budget {
+                return Ok(());
+            }
+            // NOTE: the newest user message is never dropped, generation reports the overflow
+            if self.history.len() - pinned <= 1 {
+                log::warn!(
+                    "Chat prompt of {} tokens exceeds budget of {} tokens",
+                    tokens,
+                    budget
+                );
+                return Ok(());
+            }
+
+            // drop a whole turn, so the history still starts with a user message
+            self.history.remove(pinned);
+            while self.history.len() - pinned > 1 && self.history[pinned].0 != MessageRole::User {
+                self.history.remove(pinned);
+            }
+            log::debug!(
+                "Trimmed chat history to {} messages to fit {} tokens",
+                self.history.len(),
+                budget
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loaders::mock::LoaderMock;
+    use crate::models::mock::ModelMock;
+    use std::sync::{Arc, Mutex};
+
+    // token IDs of the mock tokenizer
+    const VOCAB_SIZE: usize = 6;
+    const EOS: u32 = 1;
+    const A: u32 = 2;
+
+    // joins message contents, one mock token per word
+    const CHAT_TEMPLATE: &str =
+        "{% for message in messages %}{{ message['content'] }} {% endfor %}";
+
+    fn mock_session(context_length: usize) -> ChatSession {
+        let loader = LoaderMock::new(ModelMock::scripted(VOCAB_SIZE, vec![A, EOS]))
+            .with_context_length(context_length)
+            .with_chat_template(CHAT_TEMPLATE);
+        let mut pipeline = PipelineText::new(Arc::new(Mutex::new(loader)));
+        pipeline.set_temperature(0.0);
+        pipeline.load().unwrap();
+        ChatSession::new(pipeline)
+    }
+
+    fn history(messages: &[(MessageRole, &str)]) -> Vec<(MessageRole, String)> {
+        messages
+            .iter()
+            .map(|(role, content)| (role.clone(), content.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_send_appends_turns() {
+        let mut session = mock_session(64).with_system_message("b");
+        assert_eq!(session.send("c").unwrap(), "a");
+        assert_eq!(
+            session.history(),
+            history(&[
+                (MessageRole::System, "b"),
+                (MessageRole::User, "c"),
+                (MessageRole::Assistant, "a"),
+            ])
+        );
+
+        session.clear();
+        assert_eq!(session.history(), history(&[(MessageRole::System, "b")]));
+    }
+
+    #[test]
+    fn test_trims_oldest_turns() {
+        // 4 of 6 tokens are left for the prompt
+        let mut session = mock_session(6)
+            .with_system_message("b")
+            .with_reserved_tokens(2);
+        session.send("c").unwrap();
+        session.send("d").unwrap();
+        session.send("c").unwrap();
+
+        // "b c a d a c" exceeds the budget, the first turn is dropped
+        assert_eq!(
+            session.history(),
+            history(&[
+                (MessageRole::System, "b"),
+                (MessageRole::User, "d"),
+                (MessageRole::Assistant, "a"),
+                (MessageRole::User, "c"),
+                (MessageRole::Assistant, "a"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_failed_send_drops_user_message() {
+        let mut session = mock_session(4).with_reserved_tokens(0);
+        session.send("c").unwrap();
+        assert!(matches!(
+            session.send("a b c d a"),
+            Err(CallmError::PromptTooLong { .. })
+        ));
+        assert_eq!(session.history(), history(&[]));
+    }
+}
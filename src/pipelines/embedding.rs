@@ -0,0 +1,339 @@
+//! Pipeline computing text embeddings
+
+use crate::device::DeviceConfig;
+use crate::error::CallmError;
+use crate::loaders::{LoaderImpl, LoaderOptions};
+use crate::models::ModelImpl;
+use crate::utils::autodetect_loader;
+use candle_core::{DType, Tensor};
+use std::sync::{Arc, Mutex};
+use tokenizers::Tokenizer;
+
+/// Strategy for pooling per-token hidden states into a single embedding.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Pooling {
+    /// Averages the hidden states of all tokens.
+    #[default]
+    Mean,
+    /// Takes the hidden state of the first token, e.g. `[CLS]` of BERT models.
+    Cls,
+    /// Takes the hidden state of the last token, as used by causal embedding models.
+    LastToken,
+}
+
+/// A pipeline turning text into embedding vectors.
+///
+/// Embeddings are pooled from the last hidden state of the model, which has to implement
+/// `ModelImpl::hidden_states`. BERT models loaded from safetensors do.
+pub struct PipelineEmbedding {
+    model: Option<Arc<Mutex<dyn ModelImpl>>>,
+    loader: Arc<Mutex<dyn LoaderImpl>>,
+    device: Arc<DeviceConfig>,
+    // tokenizer and context length, read once on load
+    tokenizer: Option<Tokenizer>,
+    context_length: Option<usize>,
+    pooling: Pooling,
+    normalize: bool,
+}
+
+impl PipelineEmbedding {
+    /// Returns a new builder for constructing a `PipelineEmbedding`.
+    pub fn builder() -> PipelineEmbeddingBuilder {
+        PipelineEmbeddingBuilder::new()
+    }
+
+    /// Creates a new `PipelineEmbedding` with the given loader.
+    pub fn new(loader: Arc<Mutex<dyn LoaderImpl>>) -> Self {
+        Self {
+            model: None,
+            loader,
+            device: Arc::new(DeviceConfig::autodetect()),
+            tokenizer: None,
+            context_length: None,
+            pooling: Pooling::default(),
+            normalize: false,
+        }
+    }
+
+    /// Creates a new `PipelineEmbedding` from a given path.
+    pub fn from_path(path: &str) -> Result<Self, CallmError> {
+        Ok(Self::new(autodetect_loader(path)?))
+    }
+
+    /// Loads the model and prepares it for inference.
+    pub fn load(&mut self) -> Result<(), CallmError> {
+        let mut loader = self.loader.lock().unwrap();
+        loader.set_device(Arc::clone(&self.device));
+        let model = loader.load()?;
+        model.lock().unwrap().load()?;
+        // Build the tokenizer once, rebuilding it is slow for GGUF vocabularies
+        self.tokenizer = Some(loader.tokenizer()?);
+        self.context_length = loader.context_length();
+        self.model = Some(model);
+
+        Ok(())
+    }
+
+    /// Computes the embedding of the given text.
+    pub fn embed(&self, text: &str) -> Result<Vec<f32>, CallmError> {
+        let (model, tokenizer) = match (&self.model, &self.tokenizer) {
+            (Some(model), Some(tokenizer)) => (model, tokenizer),
+            _ => {
+                return Err(CallmError::GenericError(
+                    "Cannot run inference, model not loaded".to_string(),
+                ))
+            }
+        };
+
+        let tokens = tokenizer
+            .encode(text, true)
+            .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?
+            .get_ids()
+            .to_vec();
+        if tokens.is_empty() {
+            return Err(CallmError::GenericError(
+                "Cannot embed empty input".to_string(),
+            ));
+        }
+        if let Some(limit) = self.context_length.filter(|limit| tokens.len() > *limit) {
+            return Err(CallmError::PromptTooLong {
+                tokens: tokens.len(),
+                limit,
+            });
+        }
+
+        let input = Tensor::new(tokens.as_slice(), self.device.candle_device())?.unsqueeze(0)?;
+        let hidden_states = {
+            let mut model = model.lock().map_err(|_| {
+                CallmError::GenericError("Model unusable after a failed request".to_string())
+            })?;
+            let hidden_states = model.hidden_states(&input);
+            model.clear_kv_cache()?;
+            hidden_states?
+        };
+
+        // (batch, seq_len, hidden_size) -> (hidden_size)
+        let hidden_states = hidden_states.squeeze(0)?.to_dtype(DType::F32)?;
+        let pooled = match self.pooling {
+            Pooling::Mean => hidden_states.mean(0)?,
+            Pooling::Cls => hidden_states.get(0)?,
+            Pooling::LastToken => hidden_states.get(tokens.len() - 1)?,
+        };
+
+        let mut embedding = pooled.to_vec1::<f32>()?;
+        if self.normalize {
+            normalize_l2(&mut embedding);
+        }
+
+        Ok(embedding)
+    }
+
+    /// Computes embeddings of the given texts, in order.
+    ///
+    /// Texts are currently embedded one after another.
+    pub fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, CallmError> {
+        texts.iter().map(|text| self.embed(text)).collect()
+    }
+
+    /// Sets the device configuration for the pipeline.
+    pub fn set_device(&mut self, device: DeviceConfig) {
+        self.device = Arc::new(device);
+    }
+
+    /// Gets the device configuration for the pipeline.
+    pub fn device(&self) -> Arc<DeviceConfig> {
+        Arc::clone(&self.device)
+    }
+
+    /// Sets the pooling strategy.
+    pub fn set_pooling(&mut self, pooling: Pooling) {
+        self.pooling = pooling;
+    }
+
+    /// Gets the pooling strategy.
+    pub fn pooling(&self) -> Pooling {
+        self.pooling
+    }
+
+    /// Sets whether embeddings are scaled to unit length.
+    pub fn set_normalize(&mut self, normalize: bool) {
+        self.normalize = normalize;
+    }
+
+    /// Gets whether embeddings are scaled to unit length.
+    pub fn normalize(&self) -> bool {
+        self.normalize
+    }
+}
+
+/// Scales `v` to unit Euclidean length, leaving zero vectors untouched.
+fn normalize_l2(v: &mut [f32]) {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Builder for constructing a `PipelineEmbedding`.
+#[derive(Default)]
+pub struct PipelineEmbeddingBuilder {
+    location: Option<String>,
+    loader: Option<Arc<Mutex<dyn LoaderImpl>>>,
+    loader_options: Option<LoaderOptions>,
+    device: Option<DeviceConfig>,
+    autoload: bool,
+    pooling: Pooling,
+    normalize: bool,
+}
+
+impl PipelineEmbeddingBuilder {
+    /// Creates a new `PipelineEmbeddingBuilder`.
+    pub fn new() -> Self {
+        Self {
+            autoload: true,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the model location.
+    pub fn with_location(mut self, location: &str) -> Self {
+        self.location = Some(location.to_string());
+        self
+    }
+
+    /// Sets the loader to use.
+    pub fn with_loader(mut self, loader: Arc<Mutex<dyn LoaderImpl>>) -> Self {
+        self.loader = Some(loader);
+        self
+    }
+
+    /// Sets format-specific options passed to the loader.
+    pub fn with_loader_options(mut self, loader_options: LoaderOptions) -> Self {
+        self.loader_options = Some(loader_options);
+        self
+    }
+
+    /// Sets the device configuration.
+    pub fn with_device(mut self, device: DeviceConfig) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// Sets the pooling strategy.
+    pub fn with_pooling(mut self, pooling: Pooling) -> Self {
+        self.pooling = pooling;
+        self
+    }
+
+    /// Sets whether embeddings are scaled to unit length.
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Sets whether to autoload the model.
+    pub fn autoload(mut self, autoload: bool) -> Self {
+        self.autoload = autoload;
+        self
+    }
+
+    pub fn build(self) -> Result<PipelineEmbedding, CallmError> {
+        let mut pipeline = match self.loader {
+            Some(loader) => PipelineEmbedding::new(loader),
+            None => match self.location {
+                Some(location) => PipelineEmbedding::from_path(&location)?,
+                None => {
+                    return Err(CallmError::GenericError(
+                        "No location or loader specified. Use `with_location` or `with_loader`"
+                            .to_string(),
+                    ));
+                }
+            },
+        };
+
+        if let Some(loader_options) = self.loader_options {
+            pipeline.loader.lock().unwrap().set_options(loader_options);
+        }
+
+        pipeline.pooling = self.pooling;
+        pipeline.normalize = self.normalize;
+
+        if let Some(device) = self.device {
+            pipeline.device = Arc::new(device);
+        }
+
+        if self.autoload {
+            pipeline.load()?;
+        }
+
+        Ok(pipeline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loaders::mock::LoaderMock;
+    use crate::models::mock::ModelMock;
+
+    fn mock_pipeline(pooling: Pooling, normalize: bool) -> PipelineEmbedding {
+        let loader = LoaderMock::new(ModelMock::scripted(6, vec![1]));
+        PipelineEmbedding::builder()
+            .with_loader(Arc::new(Mutex::new(loader)))
+            .with_device(DeviceConfig::cpu())
+            .with_pooling(pooling)
+            .with_normalize(normalize)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_mock_pooling() {
+        // mock hidden states are [token ID, position] for tokens 2, 3 and 4
+        assert_eq!(
+            mock_pipeline(Pooling::Mean, false).embed("a b c").unwrap(),
+            vec![3.0, 1.0]
+        );
+        assert_eq!(
+            mock_pipeline(Pooling::Cls, false).embed("a b c").unwrap(),
+            vec![2.0, 0.0]
+        );
+        assert_eq!(
+            mock_pipeline(Pooling::LastToken, false)
+                .embed("a b c")
+                .unwrap(),
+            vec![4.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn test_mock_embed_batch() {
+        let pipeline = mock_pipeline(Pooling::Cls, true);
+        let embeddings = pipeline.embed_batch(&["c", "a b"]).unwrap();
+        assert_eq!(embeddings, vec![vec![1.0, 0.0], vec![1.0, 0.0]]);
+        assert!(pipeline.embed("").is_err());
+    }
+
+    #[test]
+    fn test_mock_tokenizer_built_once() {
+        let loader = Arc::new(Mutex::new(LoaderMock::new(ModelMock::scripted(6, vec![1]))));
+        let pipeline = PipelineEmbedding::builder()
+            .with_loader(loader.clone())
+            .with_device(DeviceConfig::cpu())
+            .build()
+            .unwrap();
+        pipeline.embed_batch(&["a", "b c", "d"]).unwrap();
+        assert_eq!(loader.lock().unwrap().tokenizer_calls(), 1);
+    }
+
+    #[test]
+    fn test_normalize_l2() {
+        let mut v = vec![3.0, 4.0];
+        normalize_l2(&mut v);
+        assert_eq!(v, vec![0.6, 0.8]);
+
+        let mut zero = vec![0.0, 0.0];
+        normalize_l2(&mut zero);
+        assert_eq!(zero, vec![0.0, 0.0]);
+    }
+}
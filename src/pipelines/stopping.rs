@@ -0,0 +1,396 @@
+//! Stopping criteria for text generation
+
+use super::FinishReason;
+use crate::error::CallmError;
+use crate::utils::Timer;
+use std::time::Duration;
+
+/// A trait for deciding when text generation should stop.
+pub trait StoppingCriteria: Send {
+    /// Checks the generation state after a new token has been sampled.
+    ///
+    /// `tokens` holds the generated token IDs (excluding the prompt) and `text` their decoded
+    /// text, up to the last complete character. Returning `Some` stops generation with the
+    /// given reason.
+    fn should_stop(&mut self, tokens: &[u32], text: &str) -> Option<FinishReason>;
+
+    /// Returns whether the criteria reads the decoded text, `true` by default.
+    ///
+    /// The output is only decoded if one of the active criteria reads it, otherwise `text` is
+    /// empty.
+    fn uses_text(&self) -> bool {
+        true
+    }
+
+    /// Resets internal state before a new generation starts.
+    fn reset(&mut self) {}
+}
+
+/// Stops generation when the model emits the EOS token.
+#[derive(Clone, Debug)]
+pub struct EosCriteria {
+    eos_token: u32,
+    alternate_eos_tokens: Vec<u32>,
+}
+
+impl EosCriteria {
+    /// Creates a new `EosCriteria` for the given EOS token ID.
+    pub fn new(eos_token: u32) -> Self {
+        Self {
+            eos_token,
+            alternate_eos_tokens: Vec::new(),
+        }
+    }
+
+    /// Treats the given token IDs as EOS too, e.g. end-of-turn tokens declared by the model.
+    pub fn with_alternate_eos_tokens(mut self, alternate_eos_tokens: Vec<u32>) -> Self {
+        self.alternate_eos_tokens = alternate_eos_tokens;
+        self
+    }
+}
+
+impl StoppingCriteria for EosCriteria {
+    fn should_stop(&mut self, tokens: &[u32], _text: &str) -> Option<FinishReason> {
+        tokens
+            .last()
+            .is_some_and(|token| {
+                *token == self.eos_token || self.alternate_eos_tokens.contains(token)
+            })
+            .then_some(FinishReason::Eos)
+    }
+
+    fn uses_text(&self) -> bool {
+        false
+    }
+}
+
+/// Stops generation when the model emits any of the given tokens.
+#[derive(Clone, Debug)]
+pub struct StopTokenCriteria {
+    stop_token_ids: Vec<u32>,
+}
+
+impl StopTokenCriteria {
+    /// Creates a new `StopTokenCriteria` for the given token IDs.
+    pub fn new(stop_token_ids: Vec<u32>) -> Self {
+        Self { stop_token_ids }
+    }
+}
+
+impl StoppingCriteria for StopTokenCriteria {
+    fn should_stop(&mut self, tokens: &[u32], _text: &str) -> Option<FinishReason> {
+        tokens
+            .last()
+            .filter(|token| self.stop_token_ids.contains(token))
+            .map(|token| FinishReason::StopToken(*token))
+    }
+
+    fn uses_text(&self) -> bool {
+        false
+    }
+}
+
+/// Stops generation once `max_tokens` tokens have been generated.
+///
+/// Generation runs are bounded by their token budget anyway, as the temperature schedule needs
+/// it upfront and the KV cache must not overflow. This criteria lets custom criteria sets stop
+/// earlier, it reports the same `FinishReason::Length`.
+#[derive(Clone, Debug)]
+pub struct MaxTokensCriteria {
+    max_tokens: usize,
+}
+
+impl MaxTokensCriteria {
+    /// Creates a new `MaxTokensCriteria` for the given token limit.
+    pub fn new(max_tokens: usize) -> Self {
+        Self { max_tokens }
+    }
+}
+
+impl StoppingCriteria for MaxTokensCriteria {
+    fn should_stop(&mut self, tokens: &[u32], _text: &str) -> Option<FinishReason> {
+        (tokens.len() >= self.max_tokens).then_some(FinishReason::Length)
+    }
+
+    fn uses_text(&self) -> bool {
+        false
+    }
+}
+
+/// Stops generation once it has run for longer than `timeout`.
+///
+/// The clock starts on `reset`, before the first token, so prompt processing counts towards
+/// the timeout. No time is measured on `wasm32`, where only a zero timeout stops generation.
+#[derive(Clone, Debug)]
+pub struct TimeoutCriteria {
+    timeout: Duration,
+    timer: Option<Timer>,
+}
+
+impl TimeoutCriteria {
+    /// Creates a new `TimeoutCriteria` for the given time limit.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            timer: None,
+        }
+    }
+}
+
+impl StoppingCriteria for TimeoutCriteria {
+    fn should_stop(&mut self, _tokens: &[u32], _text: &str) -> Option<FinishReason> {
+        let timer = self.timer.get_or_insert_with(Timer::start);
+        (timer.elapsed() >= self.timeout).then_some(FinishReason::Timeout)
+    }
+
+    fn reset(&mut self) {
+        self.timer = Some(Timer::start());
+    }
+
+    fn uses_text(&self) -> bool {
+        false
+    }
+}
+
+/// Stops generation once the output text contains any of the given strings.
+///
+/// Only the newly decoded tail of the text is searched on every step, extended backwards so
+/// stop sequences spanning several tokens are found.
+#[derive(Clone, Debug)]
+pub struct StopSequenceCriteria {
+    stop_sequences: Vec<String>,
+    searched_len: usize,
+}
+
+impl StopSequenceCriteria {
+    /// Creates a new `StopSequenceCriteria` for the given strings.
+    pub fn new(stop_sequences: Vec<String>) -> Self {
+        Self {
+            stop_sequences,
+            searched_len: 0,
+        }
+    }
+}
+
+impl StoppingCriteria for StopSequenceCriteria {
+    fn should_stop(&mut self, _tokens: &[u32], text: &str) -> Option<FinishReason> {
+        let max_len = self.stop_sequences.iter().map(String::len).max()?;
+        let start = self
+            .searched_len
+            .saturating_sub(max_len.saturating_sub(1))
+            .min(text.len());
+        self.searched_len = text.len();
+        find_stop_sequence(&text.as_bytes()[start..], &self.stop_sequences)
+            .map(|_| FinishReason::StopSequence)
+    }
+
+    fn reset(&mut self) {
+        self.searched_len = 0;
+    }
+}
+
+/// Returns the position of the earliest occurrence of any non-empty stop sequence in `bytes`.
+pub(crate) fn find_stop_sequence(bytes: &[u8], stop_sequences: &[String]) -> Option<usize> {
+    stop_sequences
+        .iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| {
+            bytes
+                .windows(stop.len())
+                .position(|window| window == stop.as_bytes())
+        })
+        .min()
+}
+
+/// Stops generation once the output repeats itself verbatim.
+///
+/// Triggers when the output ends with the same `ngram_size` tokens repeated `max_repeats` times
+/// in a row. Only the tokens appended since the previous step are checked.
+#[derive(Clone, Debug)]
+pub struct RepetitionCriteria {
+    ngram_size: usize,
+    max_repeats: usize,
+    checked_len: usize,
+    // trailing tokens equal to the token `ngram_size` positions earlier
+    periodic_len: usize,
+}
+
+impl RepetitionCriteria {
+    /// Creates a new `RepetitionCriteria`.
+    ///
+    /// Returns `CallmError::GenericError` if `ngram_size` is zero or `max_repeats` is below 2.
+    pub fn try_new(ngram_size: usize, max_repeats: usize) -> Result<Self, CallmError> {
+        validate_repetition_stop(ngram_size, max_repeats)?;
+        Ok(Self {
+            ngram_size,
+            max_repeats,
+            checked_len: 0,
+            periodic_len: 0,
+        })
+    }
+}
+
+impl StoppingCriteria for RepetitionCriteria {
+    fn should_stop(&mut self, tokens: &[u32], _text: &str) -> Option<FinishReason> {
+        // a shorter output belongs to a new generation
+        if tokens.len() < self.checked_len {
+            self.reset();
+        }
+        for i in self.checked_len..tokens.len() {
+            if i >= self.ngram_size && tokens[i] == tokens[i - self.ngram_size] {
+                self.periodic_len += 1;
+            } else {
+                self.periodic_len = 0;
+            }
+        }
+        self.checked_len = tokens.len();
+
+        // the first occurrence is followed by `max_repeats - 1` copies of it
+        (self.periodic_len >= self.ngram_size * (self.max_repeats - 1))
+            .then_some(FinishReason::Repetition)
+    }
+
+    fn uses_text(&self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {
+        self.checked_len = 0;
+        self.periodic_len = 0;
+    }
+}
+
+/// Checks the parameters of a `RepetitionCriteria`.
+pub(crate) fn validate_repetition_stop(
+    ngram_size: usize,
+    max_repeats: usize,
+) -> Result<(), CallmError> {
+    if ngram_size == 0 {
+        return Err(CallmError::GenericError(
+            "Repetition stop n-gram size must be at least 1".to_string(),
+        ));
+    }
+    if max_repeats < 2 {
+        return Err(CallmError::GenericError(format!(
+            "Repetition stop needs at least 2 repeats, got {}",
+            max_repeats
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eos_criteria() {
+        let mut criteria = EosCriteria::new(2);
+        assert_eq!(criteria.should_stop(&[5, 2], ""), Some(FinishReason::Eos));
+        assert_eq!(criteria.should_stop(&[2, 5], ""), None);
+        assert_eq!(criteria.should_stop(&[], ""), None);
+
+        let mut criteria = EosCriteria::new(2).with_alternate_eos_tokens(vec![8]);
+        assert_eq!(criteria.should_stop(&[5, 8], ""), Some(FinishReason::Eos));
+        assert_eq!(criteria.should_stop(&[5, 2], ""), Some(FinishReason::Eos));
+    }
+
+    #[test]
+    fn test_stop_token_criteria() {
+        let mut criteria = StopTokenCriteria::new(vec![10, 11]);
+        assert_eq!(
+            criteria.should_stop(&[5, 11], ""),
+            Some(FinishReason::StopToken(11))
+        );
+        assert_eq!(criteria.should_stop(&[10, 5], ""), None);
+    }
+
+    #[test]
+    fn test_max_tokens_criteria() {
+        let mut criteria = MaxTokensCriteria::new(2);
+        assert_eq!(criteria.should_stop(&[5], ""), None);
+        assert_eq!(
+            criteria.should_stop(&[5, 6], ""),
+            Some(FinishReason::Length)
+        );
+    }
+
+    #[test]
+    fn test_timeout_criteria() {
+        let mut criteria = TimeoutCriteria::new(Duration::from_secs(60));
+        criteria.reset();
+        assert_eq!(criteria.should_stop(&[5], ""), None);
+
+        let mut criteria = TimeoutCriteria::new(Duration::ZERO);
+        criteria.reset();
+        assert_eq!(criteria.should_stop(&[5], ""), Some(FinishReason::Timeout));
+
+        // the deadline restarts with every generation
+        let mut criteria = TimeoutCriteria::new(Duration::from_millis(20));
+        criteria.reset();
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(criteria.should_stop(&[5], ""), Some(FinishReason::Timeout));
+        criteria.reset();
+        assert_eq!(criteria.should_stop(&[5], ""), None);
+    }
+
+    #[test]
+    fn test_find_stop_sequence() {
+        let stops = vec!["\n\n".to_string(), "END".to_string(), String::new()];
+        assert_eq!(find_stop_sequence(b"abc END\n\n", &stops), Some(4));
+        assert_eq!(find_stop_sequence(b"abc\n\nEND", &stops), Some(3));
+        assert_eq!(find_stop_sequence(b"abc EN", &stops), None);
+    }
+
+    #[test]
+    fn test_stop_sequence_criteria() {
+        let mut criteria = StopSequenceCriteria::new(vec!["</s>".to_string()]);
+        assert_eq!(criteria.should_stop(&[], "Hello </"), None);
+        // the stop sequence spans the previously searched text
+        assert_eq!(
+            criteria.should_stop(&[], "Hello </s> world"),
+            Some(FinishReason::StopSequence)
+        );
+
+        criteria.reset();
+        assert_eq!(criteria.should_stop(&[], "Hello"), None);
+        assert_eq!(
+            StopSequenceCriteria::new(vec![]).should_stop(&[], "x"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_repetition_criteria() {
+        let mut criteria = RepetitionCriteria::try_new(2, 3).unwrap();
+        assert_eq!(criteria.should_stop(&[1, 2, 1, 2], ""), None);
+        assert_eq!(
+            criteria.should_stop(&[1, 2, 1, 2, 1, 2], ""),
+            Some(FinishReason::Repetition)
+        );
+
+        // only back-to-back repeats at the end of the output count
+        criteria.reset();
+        assert_eq!(criteria.should_stop(&[1, 2, 5, 1, 2, 6, 1, 2], ""), None);
+        assert_eq!(criteria.should_stop(&[7, 7, 7], ""), None);
+        assert_eq!(
+            criteria.should_stop(&[7, 7, 7, 7, 7, 7], ""),
+            Some(FinishReason::Repetition)
+        );
+
+        // tokens are checked as they are appended
+        let tokens = [7, 3, 4, 3, 4, 3, 4];
+        let mut criteria = RepetitionCriteria::try_new(2, 3).unwrap();
+        let stops: Vec<bool> = (1..=tokens.len())
+            .map(|n| criteria.should_stop(&tokens[..n], "").is_some())
+            .collect();
+        assert_eq!(stops, [false, false, false, false, false, false, true]);
+    }
+
+    #[test]
+    fn test_repetition_criteria_invalid() {
+        assert!(RepetitionCriteria::try_new(0, 2).is_err());
+        assert!(RepetitionCriteria::try_new(2, 1).is_err());
+        assert!(RepetitionCriteria::try_new(1, 2).is_ok());
+    }
+}
@@ -1,23 +1,295 @@
 //! Pipeline for text generation
 
+use super::stopping::{
+    find_stop_sequence, validate_repetition_stop, EosCriteria, RepetitionCriteria,
+    StopSequenceCriteria, StopTokenCriteria, StoppingCriteria, TimeoutCriteria,
+};
 use crate::device::DeviceConfig;
 use crate::error::CallmError;
-use crate::loaders::LoaderImpl;
+use crate::loaders::{LoadProgress, LoaderImpl, LoaderOptions, ModelInfo};
 use crate::models::ModelImpl;
-use crate::templates::MessageRole;
-use crate::utils::autodetect_loader;
-use std::sync::{Arc, Mutex};
+use crate::templates::{ChatMessage, MessageRole, TemplateImpl};
+use crate::utils::{adds_prefix_space, autodetect_loader, decode_bytes, Timer};
+#[cfg(feature = "hub")]
+use crate::utils::{download_model, DownloadProgress, HubRepo};
+use candle_core::{DType, Tensor, D};
+use candle_transformers::generation::Sampling;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::Duration;
+use tokenizers::Tokenizer;
+#[cfg(feature = "async")]
+use tokio::sync::mpsc;
+
+/// Value substituted for non-finite logits.
+const NON_FINITE_LOGIT: f32 = -1e9;
+
+/// Number of text chunks buffered by `run_stream` ahead of the receiver.
+#[cfg(feature = "async")]
+const STREAM_CHANNEL_CAPACITY: usize = 32;
+
+/// Reason for which text generation finished.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model emitted the EOS token.
+    Eos,
+    /// Generation hit a length limit.
+    Length,
+    /// The output started repeating itself verbatim.
+    Repetition,
+    /// The caller requested to stop generation.
+    Cancelled,
+    /// The model emitted the given stop token.
+    StopToken(u32),
+    /// The output contained a stop sequence.
+    StopSequence,
+    /// Generation ran longer than its time limit.
+    Timeout,
+}
+
+/// Outcome of a generation run together with usage statistics.
+///
+/// Serializable, e.g. for building HTTP server responses.
+#[derive(Clone, Debug, Serialize)]
+pub struct GenerationResult {
+    /// Generated text, preceded by the prompt if echo is enabled.
+    pub text: String,
+    /// Generated token IDs, excluding the prompt.
+    pub tokens: Vec<u32>,
+    /// Reason the generation finished.
+    pub finish_reason: FinishReason,
+    /// Number of prompt tokens.
+    pub prompt_tokens: usize,
+    /// Number of generated tokens.
+    pub completion_tokens: usize,
+    /// Time until the first token was generated, including prompt processing, in seconds.
+    pub prompt_secs: f64,
+    /// Time spent generating the remaining tokens, in seconds.
+    pub completion_secs: f64,
+    /// Total generation time, serialized as `elapsed_secs` in seconds like the other timings.
+    #[serde(rename = "elapsed_secs", serialize_with = "serialize_secs")]
+    pub elapsed: Duration,
+    /// Generated tokens per second over the total generation time.
+    pub tokens_per_second: f64,
+    /// Log-probability of each generated token, if enabled with `set_logprobs`.
+    pub logprobs: Option<Vec<f32>>,
+    /// Most likely `(token ID, logprob)` alternatives at each generated position, if enabled with
+    /// `set_top_logprobs`.
+    pub top_logprobs: Option<Vec<Vec<(u32, f32)>>>,
+}
+
+/// Snapshot of the sampling random number generator.
+///
+/// Restoring a snapshot with `PipelineText::restore_sampling_state` makes the next generation
+/// continue the random stream where the snapshotted one stopped.
+#[derive(Clone, Debug)]
+pub struct SamplingState(StdRng);
+
+/// Schedule varying the sampling temperature over a generation run.
+///
+/// While a schedule is set, the fixed pipeline temperature is not used.
+#[derive(Clone)]
+pub enum TemperatureSchedule {
+    /// Interpolates linearly from `start` at the first token to `end` at the generation limit.
+    Linear { start: f64, end: f64 },
+    /// Computes the temperature from the generation step and the generation limit.
+    Custom(Arc<dyn Fn(usize, usize) -> f64 + Send + Sync>),
+}
+
+impl TemperatureSchedule {
+    /// Returns the temperature for generation step `step` out of `max_tokens`.
+    pub fn temperature(&self, step: usize, max_tokens: usize) -> f64 {
+        match self {
+            Self::Linear { start, end } => {
+                let progress = step as f64 / max_tokens.saturating_sub(1).max(1) as f64;
+                start + (end - start) * progress.min(1.0)
+            }
+            Self::Custom(schedule) => schedule(step, max_tokens),
+        }
+    }
+}
+
+/// Callback receiving decoded bytes as tokens are generated.
+///
+/// Returns whether generation should continue.
+type BytesCallback<'a> = dyn FnMut(&[u8]) -> Result<bool, CallmError> + 'a;
+
+/// Input to a generation run.
+#[derive(Clone, Copy)]
+enum Prompt<'a> {
+    /// Raw text to be tokenized, with special tokens if enabled.
+    Text(&'a str),
+    /// Text rendered by the chat template, tokenized without special tokens.
+    Templated(&'a str),
+    /// Completion prompt tokenized without special tokens, then framed with BOS/EOS on request.
+    Completion {
+        text: &'a str,
+        add_bos: bool,
+        add_eos: bool,
+    },
+    /// Already tokenized input.
+    Tokens(&'a [u32]),
+}
+
+/// Outcome of a single generation run.
+struct Generation {
+    /// Generated token IDs, excluding the prompt.
+    tokens: Vec<u32>,
+    /// Decoded generated tokens, preceded by the prompt if echo is enabled.
+    bytes: Vec<u8>,
+    /// Reason the generation finished.
+    finish_reason: FinishReason,
+    /// Number of prompt tokens.
+    prompt_tokens: usize,
+    /// Seconds until the first token was generated.
+    prompt_secs: f64,
+    /// Seconds spent generating the remaining tokens.
+    completion_secs: f64,
+    /// Total generation time.
+    elapsed: Duration,
+    /// Log-probability of each generated token, if enabled.
+    logprobs: Option<Vec<f32>>,
+    /// Most likely alternatives at each generated position, if enabled.
+    top_logprobs: Option<Vec<Vec<(u32, f32)>>>,
+}
+
+impl From<Generation> for GenerationResult {
+    fn from(generation: Generation) -> Self {
+        Self {
+            text: String::from_utf8_lossy(&generation.bytes).into_owned(),
+            completion_tokens: generation.tokens.len(),
+            tokens_per_second: tokens_per_second(generation.tokens.len(), generation.elapsed),
+            tokens: generation.tokens,
+            finish_reason: generation.finish_reason,
+            prompt_tokens: generation.prompt_tokens,
+            prompt_secs: generation.prompt_secs,
+            completion_secs: generation.completion_secs,
+            elapsed: generation.elapsed,
+            logprobs: generation.logprobs,
+            top_logprobs: generation.top_logprobs,
+        }
+    }
+}
+
+/// Incremental decoder yielding the bytes added by each generated token.
+///
+/// Decoding tokens one by one loses context some decoders rely on (e.g. leading space
+/// handling), so each step decodes a window starting at the previous token and emits the
+/// difference.
+#[derive(Default)]
+struct TokenStream {
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl TokenStream {
+    /// Returns the bytes added by the last token in `tokens`.
+    fn next(
+        &mut self,
+        tokenizer: &Tokenizer,
+        tokens: &[u32],
+        skip_special_tokens: bool,
+    ) -> Result<Vec<u8>, CallmError> {
+        let prev = decode_bytes(
+            tokenizer,
+            &tokens[self.prev_index..self.current_index],
+            skip_special_tokens,
+        )?;
+        let mut current = decode_bytes(tokenizer, &tokens[self.prev_index..], skip_special_tokens)?;
+        self.prev_index = self.current_index;
+        self.current_index = tokens.len();
+
+        if current.starts_with(&prev) {
+            Ok(current.split_off(prev.len()))
+        } else {
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Output text checked by stopping criteria, extended by one token per step.
+///
+/// Incomplete characters are held back until they complete, so the text only grows.
+#[derive(Default)]
+struct StopText {
+    stream: TokenStream,
+    utf8: Utf8Buffer,
+    text: String,
+}
+
+impl StopText {
+    /// Appends the text of the last token in `tokens`, returning the whole text.
+    fn push(
+        &mut self,
+        tokenizer: &Tokenizer,
+        tokens: &[u32],
+        skip_special_tokens: bool,
+    ) -> Result<&str, CallmError> {
+        let bytes = self.stream.next(tokenizer, tokens, skip_special_tokens)?;
+        self.text.push_str(&self.utf8.push(&bytes));
+        Ok(&self.text)
+    }
+}
 
 /// Pipeline for text generation
+///
+/// A pipeline serves one request at a time: generation borrows it mutably, so a pipeline shared
+/// between threads has to be wrapped in a `Mutex`. A loader may be shared across pipelines, in
+/// which case concurrent requests wait for each other instead of interleaving.
 pub struct PipelineText {
     model: Option<Arc<Mutex<dyn ModelImpl>>>,
     loader: Arc<Mutex<dyn LoaderImpl>>,
     device: Arc<DeviceConfig>,
+    // tokenizer and template, built once on load
+    tokenizer: Option<Arc<Tokenizer>>,
+    template: Option<Arc<Mutex<Box<dyn TemplateImpl>>>>,
+    // tokens held in the KV cache of the model, shared by pipelines sharing the model
+    cached_tokens: Arc<Mutex<Vec<u32>>>,
     // inference parameters
     seed: Option<u64>,
     temperature: f64,
+    temperature_schedule: Option<TemperatureSchedule>,
     top_k: Option<usize>,
     top_p: Option<f64>,
+    min_p: Option<f64>,
+    negative_prompt: Option<String>,
+    guidance_scale: f64,
+    repeat_penalty: f32,
+    repeat_last_n: Option<usize>,
+    frequency_penalty: f32,
+    presence_penalty: f32,
+    repetition_stop: Option<(usize, usize)>,
+    timeout: Option<Duration>,
+    strip_leading_space: bool,
+    stream_special_tokens: bool,
+    output_special_tokens: bool,
+    max_prompt_tokens: Option<usize>,
+    max_tokens: Option<usize>,
+    ignore_eos: bool,
+    echo: bool,
+    add_generation_prompt: bool,
+    add_special_tokens: bool,
+    logprobs: bool,
+    top_logprobs: usize,
+    keep_cache: bool,
+    nan_guard: bool,
+    stop_token_ids: Vec<u32>,
+    stop_sequences: Vec<String>,
+    stopping_criteria: Vec<Box<dyn StoppingCriteria>>,
+    finish_reason: Option<FinishReason>,
+    incomplete_utf8: bool,
+    // sampling RNG state
+    sampling_state: Option<SamplingState>,
+    restored_sampling_state: Option<SamplingState>,
 }
 
 impl PipelineText {
@@ -32,10 +304,94 @@ impl PipelineText {
             loader,
             model: None,
             device: Arc::new(DeviceConfig::autodetect()),
+            tokenizer: None,
+            template: None,
+            cached_tokens: Arc::new(Mutex::new(Vec::new())),
             seed: None,
             temperature: 0.7,
+            temperature_schedule: None,
             top_k: None,
             top_p: None,
+            min_p: None,
+            negative_prompt: None,
+            guidance_scale: 1.0,
+            repeat_penalty: 1.0,
+            repeat_last_n: None,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            repetition_stop: None,
+            timeout: None,
+            strip_leading_space: false,
+            stream_special_tokens: false,
+            output_special_tokens: false,
+            max_prompt_tokens: None,
+            max_tokens: None,
+            ignore_eos: false,
+            echo: false,
+            add_generation_prompt: true,
+            add_special_tokens: false,
+            logprobs: false,
+            top_logprobs: 0,
+            keep_cache: false,
+            nan_guard: true,
+            stop_token_ids: Vec::new(),
+            stop_sequences: Vec::new(),
+            stopping_criteria: Vec::new(),
+            finish_reason: None,
+            incomplete_utf8: false,
+            sampling_state: None,
+            restored_sampling_state: None,
+        }
+    }
+
+    /// Creates a sibling pipeline sharing the model and loader, with a copy of the inference
+    /// parameters.
+    ///
+    /// Parameters of the sibling can be changed independently, without reloading the model.
+    /// Custom stopping criteria are not copied. Requests on sibling pipelines share the model, so
+    /// they wait for each other.
+    pub fn clone_config(&self) -> Self {
+        Self {
+            model: self.model.clone(),
+            loader: Arc::clone(&self.loader),
+            device: Arc::clone(&self.device),
+            tokenizer: self.tokenizer.clone(),
+            template: self.template.clone(),
+            cached_tokens: Arc::clone(&self.cached_tokens),
+            seed: self.seed,
+            temperature: self.temperature,
+            temperature_schedule: self.temperature_schedule.clone(),
+            top_k: self.top_k,
+            top_p: self.top_p,
+            min_p: self.min_p,
+            negative_prompt: self.negative_prompt.clone(),
+            guidance_scale: self.guidance_scale,
+            repeat_penalty: self.repeat_penalty,
+            repeat_last_n: self.repeat_last_n,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            repetition_stop: self.repetition_stop,
+            timeout: self.timeout,
+            strip_leading_space: self.strip_leading_space,
+            stream_special_tokens: self.stream_special_tokens,
+            output_special_tokens: self.output_special_tokens,
+            max_prompt_tokens: self.max_prompt_tokens,
+            max_tokens: self.max_tokens,
+            ignore_eos: self.ignore_eos,
+            echo: self.echo,
+            add_generation_prompt: self.add_generation_prompt,
+            add_special_tokens: self.add_special_tokens,
+            logprobs: self.logprobs,
+            top_logprobs: self.top_logprobs,
+            keep_cache: self.keep_cache,
+            nan_guard: self.nan_guard,
+            stop_token_ids: self.stop_token_ids.clone(),
+            stop_sequences: self.stop_sequences.clone(),
+            stopping_criteria: Vec::new(),
+            finish_reason: None,
+            incomplete_utf8: false,
+            sampling_state: None,
+            restored_sampling_state: None,
         }
     }
 
@@ -53,258 +409,2952 @@ impl PipelineText {
         let model = loader.load()?;
         // Load the model
         model.lock().unwrap().load()?;
-        // Store the model trait object
+        // Build tokenizer and template once, rebuilding them is slow for GGUF vocabularies
+        self.tokenizer = Some(Arc::new(loader.tokenizer()?));
+        self.template = Some(Arc::new(Mutex::new(loader.template()?)));
+        // Store the model trait object, starting with an empty KV cache
         self.model = Some(model);
+        self.cached_tokens = Arc::new(Mutex::new(Vec::new()));
 
         Ok(())
     }
 
+    /// Completes `prompt` as-is, without applying the chat template.
+    ///
+    /// This is the entry point for base models and fill-in-the-middle prompts. The prompt is
+    /// tokenized verbatim, regardless of `set_add_special_tokens`. `add_bos` prepends the BOS
+    /// token unless the prompt starts with it, and `add_eos` appends the EOS token of the model.
+    pub fn complete(
+        &mut self,
+        prompt: &str,
+        add_bos: bool,
+        add_eos: bool,
+    ) -> Result<String, CallmError> {
+        let prompt = Prompt::Completion {
+            text: prompt,
+            add_bos,
+            add_eos,
+        };
+        let bytes = self.generate(prompt, None, None)?.bytes;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
     /// Runs the text generation pipeline on the given input text.
     pub fn run(&mut self, text: &str) -> Result<String, CallmError> {
-        use candle_core::Tensor;
-        use candle_transformers::generation::{LogitsProcessor, Sampling};
+        let bytes = self.run_bytes(text)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
 
-        let model = self.model.as_mut().ok_or(CallmError::GenericError(
-            "Cannot run inference, model not loaded".to_string(),
-        ))?;
-        let mut model = model.lock().unwrap();
+    /// Runs the text generation pipeline on the given input text, returning raw bytes.
+    ///
+    /// Unlike `run`, the output is not converted to UTF-8, so byte sequences left incomplete
+    /// by the tokenizer are preserved as-is.
+    pub fn run_bytes(&mut self, text: &str) -> Result<Vec<u8>, CallmError> {
+        Ok(self.generate(Prompt::Text(text), None, None)?.bytes)
+    }
 
-        let mut loader = self.loader.lock().unwrap();
+    /// Runs the text generation pipeline on the given input token IDs, skipping tokenization.
+    pub fn run_tokens(&mut self, tokens: &[u32]) -> Result<String, CallmError> {
+        let bytes = self.generate(Prompt::Tokens(tokens), None, None)?.bytes;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
 
-        // Prepare seed
-        let seed = self.seed.unwrap_or_else(|| {
-            let s = rand::random::<u64>();
-            log::info!("Using random seed {}", s);
-            s
-        });
+    /// Runs the text generation pipeline, writing decoded tokens to `w` as they are generated.
+    ///
+    /// The writer is flushed after every write. Returns the number of generated tokens.
+    pub fn run_to_writer(&mut self, text: &str, w: &mut dyn Write) -> Result<usize, CallmError> {
+        let mut on_bytes = |bytes: &[u8]| -> Result<bool, CallmError> {
+            w.write_all(bytes)?;
+            w.flush()?;
+            Ok(true)
+        };
+        let generation = self.generate(Prompt::Text(text), Some(&mut on_bytes), None)?;
+        Ok(generation.tokens.len())
+    }
 
-        // Spawn logits processor
-        let sampling = {
-            if self.temperature <= 0.0 {
-                Sampling::ArgMax
-            } else {
-                match (self.top_k, self.top_p) {
-                    (None, None) => Sampling::All {
-                        temperature: self.temperature,
-                    },
-                    (Some(k), None) => Sampling::TopK {
-                        k,
-                        temperature: self.temperature,
-                    },
-                    (None, Some(p)) => Sampling::TopP {
-                        p,
-                        temperature: self.temperature,
-                    },
-                    (Some(k), Some(p)) => Sampling::TopKThenTopP {
-                        k,
-                        p,
-                        temperature: self.temperature,
-                    },
-                }
-            }
+    /// Runs the text generation pipeline, passing text to `on_token` as it is generated.
+    ///
+    /// Bytes of incomplete UTF-8 characters are held back until the character is complete.
+    /// Returning `false` from `on_token` stops generation early. Returns the full generated text.
+    pub fn run_with_callback(
+        &mut self,
+        text: &str,
+        mut on_token: impl FnMut(&str) -> bool,
+    ) -> Result<String, CallmError> {
+        let mut utf8 = Utf8Buffer::default();
+        let mut on_bytes = |bytes: &[u8]| -> Result<bool, CallmError> {
+            let delta = utf8.push(bytes);
+            Ok(delta.is_empty() || on_token(&delta))
         };
-        let mut logits_processor = LogitsProcessor::from_sampling(seed, sampling);
+        let generation = self.generate(Prompt::Text(text), Some(&mut on_bytes), None)?;
 
-        // Spawn tokenizer
-        let tokenizer = loader.tokenizer()?;
+        // Flush trailing bytes of an incomplete character
+        let rest = utf8.finish();
+        if !rest.is_empty() {
+            on_token(&rest);
+        }
 
-        // Spawn template and get EOS token
-        let template = loader.template()?;
-        let eos_token_str = template.get_eos_token().expect("Missing EOS token");
-        let eos_token = tokenizer
-            .token_to_id(eos_token_str)
-            .expect("EOS token missing in the tokenizer");
+        Ok(String::from_utf8_lossy(&generation.bytes).into_owned())
+    }
 
-        // Tokenize user input
-        let mut tokens = tokenizer
-            .encode(text, false)
-            .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?
-            .get_ids()
-            .to_vec();
+    /// Runs the text generation pipeline, stopping early once `cancel` is set.
+    ///
+    /// The flag is checked before every generated token, so it can be set from another thread
+    /// to abort a running generation. A cancelled run returns the text generated so far, and
+    /// `finish_reason` reports `FinishReason::Cancelled`.
+    pub fn run_cancellable(
+        &mut self,
+        text: &str,
+        cancel: Arc<AtomicBool>,
+    ) -> Result<String, CallmError> {
+        let bytes = self
+            .generate(Prompt::Text(text), None, Some(&cancel))?
+            .bytes;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
 
-        let num_tokens_at_start = tokens.len();
-        log::trace!("EOS token: {} '{}'", eos_token, eos_token_str);
-        log::trace!("Tokens: {:?}", tokens);
-        log::trace!("Tokens count: {}", num_tokens_at_start);
+    /// Generates completions for equally long tokenized prompts in a single batch.
+    ///
+    /// `prompts` pairs each prompt text, used for echo, with its tokens. Every sequence is
+    /// sampled and checked against the built-in stopping criteria on its own, finished
+    /// sequences are carried along until the whole batch is done.
+    fn generate_batch(
+        &mut self,
+        prompts: &[(&str, &[u32])],
+    ) -> Result<Vec<Generation>, CallmError> {
+        let model = self.model.as_ref().ok_or(CallmError::GenericError(
+            "Cannot run inference, model not loaded".to_string(),
+        ))?;
+        let mut model = lock_serialized(model.as_ref(), "Model")?;
+        let mut model = KvCacheGuard::new(&mut *model);
+        // The batch replaces whatever is left in the KV cache
+        if !std::mem::take(&mut *self.cached_tokens.lock().unwrap()).is_empty() {
+            model.clear_kv_cache()?;
+        }
+        let loader = lock_serialized(self.loader.as_ref(), "Loader")?;
 
-        // TODO: calculate real max number of tokens by subtracting num_tokens_at_start from
-        // context size
-        for index in 0..1000 {
-            let ctxt_size = if index > 0 { 1 } else { tokens.len() };
-            let start_pos = tokens.len().saturating_sub(ctxt_size);
-            let ctxt = &tokens[start_pos..];
-            let input = Tensor::new(ctxt, self.device.candle_device())?.unsqueeze(0)?;
+        let mut rng = sampling_rng(self.restored_sampling_state.take(), self.seed);
+        let tokenizer = self.loaded_tokenizer()?;
+        let (_, eos_token) = self.special_token_ids(&tokenizer)?;
+
+        let batch_size = prompts.len();
+        let num_tokens_at_start = prompts[0].1.len();
+        if let Some(limit) = self.max_prompt_tokens {
+            if num_tokens_at_start > limit {
+                return Err(CallmError::PromptTooLong {
+                    tokens: num_tokens_at_start,
+                    limit,
+                });
+            }
+        }
+        let max_position = model.max_position();
+        let context_length = loader
+            .context_length()
+            .map_or(max_position, |n| n.min(max_position));
+        if num_tokens_at_start > context_length {
+            return Err(CallmError::PromptTooLong {
+                tokens: num_tokens_at_start,
+                limit: context_length,
+            });
+        }
 
-            let logits = model.forward(&input, start_pos)?.squeeze(0)?.squeeze(0)?;
+        let vocab_size = tokenizer.get_vocab_size(true);
+        let repeat_last_n = self
+            .repeat_last_n
+            .unwrap_or_else(|| default_repeat_last_n(max_position));
+        let max_tokens = self
+            .max_tokens
+            .unwrap_or_else(|| default_max_tokens(context_length, num_tokens_at_start));
+        log::debug!(
+            "Generating a batch of {} prompts of {} tokens",
+            batch_size,
+            num_tokens_at_start
+        );
 
-            let new_token = logits_processor.sample(&logits)?;
-            tokens.push(new_token);
+        let mut rows: Vec<Vec<u32>> = prompts.iter().map(|(_, tokens)| tokens.to_vec()).collect();
+        let mut finish_reasons: Vec<Option<FinishReason>> = vec![None; batch_size];
+        let mut criteria: Vec<_> = (0..batch_size)
+            .map(|_| self.builtin_criteria(eos_token, loader.stop_token_ids()))
+            .collect::<Result<_, _>>()?;
+        // Decode the output only if a stopping criteria reads it
+        let mut stop_texts: Vec<Option<StopText>> = criteria
+            .iter()
+            .map(|criteria| {
+                criteria
+                    .iter()
+                    .any(|criteria| criteria.uses_text())
+                    .then(StopText::default)
+            })
+            .collect();
 
-            log::trace!("New token generated: {}", new_token);
-            if new_token == eos_token {
+        let timer = Timer::start();
+        let mut prompt_secs = 0.0;
+        for index in 0..max_tokens {
+            // Stop before writing positions past the model capacity into the KV cache
+            let position = num_tokens_at_start + index;
+            if position > max_position {
+                log::warn!("Reached maximum model position {}", max_position);
                 break;
             }
-        }
 
-        // Clear KV cache
-        model.clear_kv_cache()?;
+            // Feed whole prompts first, then the last token of every sequence
+            // NOTE: finished sequences repeat their last token, their logits are ignored
+            let (input, start_pos): (Vec<u32>, usize) = match index {
+                0 => (rows.concat(), 0),
+                _ => (
+                    rows.iter().filter_map(|row| row.last().copied()).collect(),
+                    position - 1,
+                ),
+            };
+            let seq_len = input.len() / batch_size;
+            let input =
+                Tensor::from_vec(input, (batch_size, seq_len), self.device.candle_device())?;
+            let logits = model.forward(&input, start_pos)?.flatten_from(1)?;
 
-        // Decode newly added tokens
-        let new_text = tokenizer
-            .decode(&tokens[num_tokens_at_start..], true)
-            .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?;
+            for (row, tokens) in rows.iter_mut().enumerate() {
+                if finish_reasons[row].is_some() {
+                    continue;
+                }
 
-        Ok(new_text)
-    }
+                let logits =
+                    self.clamp_logits(logits.get(row)?, vocab_size, index == 0 && row == 0)?;
+                let new_token = self.sample_token(
+                    logits,
+                    tokens,
+                    num_tokens_at_start,
+                    repeat_last_n,
+                    self.temperature(index, max_tokens),
+                    &mut rng,
+                )?;
+                tokens.push(new_token);
 
-    /// Sets the device configuration for the pipeline.
-    pub fn set_device(&mut self, device: DeviceConfig) {
-        self.device = Arc::new(device);
-    }
+                let text = match stop_texts[row].as_mut() {
+                    Some(stop_text) => stop_text.push(
+                        &tokenizer,
+                        &tokens[num_tokens_at_start..],
+                        !self.output_special_tokens,
+                    )?,
+                    None => "",
+                };
+                finish_reasons[row] = criteria[row].iter_mut().find_map(|criteria| {
+                    criteria.should_stop(&tokens[num_tokens_at_start..], text)
+                });
+            }
+            if index == 0 {
+                prompt_secs = timer.elapsed().as_secs_f64();
+            }
+            if finish_reasons.iter().all(Option::is_some) {
+                break;
+            }
+        }
+        let elapsed = timer.elapsed();
+        self.sampling_state = Some(SamplingState(rng));
+        model.clear()?;
 
-    /// Gets the device configuration for the pipeline.
-    pub fn device(&self) -> Arc<DeviceConfig> {
-        Arc::clone(&self.device)
+        let mut generations = Vec::with_capacity(batch_size);
+        for ((text, _), (mut tokens, finish_reason)) in
+            prompts.iter().zip(rows.into_iter().zip(finish_reasons))
+        {
+            let finish_reason = finish_reason.unwrap_or(FinishReason::Length);
+            let tokens = tokens.split_off(num_tokens_at_start);
+            let bytes = self.decode_output(&tokenizer, &tokens, finish_reason)?;
+            let echo: &[u8] = match self.echo {
+                true => text.as_bytes(),
+                false => &[],
+            };
+            generations.push(Generation {
+                tokens,
+                bytes: [echo, bytes.as_slice()].concat(),
+                finish_reason,
+                prompt_tokens: num_tokens_at_start,
+                prompt_secs,
+                completion_secs: elapsed.as_secs_f64() - prompt_secs,
+                elapsed,
+                logprobs: None,
+                top_logprobs: None,
+            });
+        }
+
+        Ok(generations)
     }
 
-    /// Runs the text generation pipeline on a chat message sequence.
-    pub fn run_chat(&mut self, messages: &[(MessageRole, String)]) -> Result<String, CallmError> {
-        if self.model.is_none() {
-            return Err(CallmError::GenericError(
+    /// Gets the tokenizer built on load.
+    fn loaded_tokenizer(&self) -> Result<Arc<Tokenizer>, CallmError> {
+        self.tokenizer
+            .as_ref()
+            .map(Arc::clone)
+            .ok_or(CallmError::GenericError(
                 "Cannot run inference, model not loaded".to_string(),
-            ));
-        }
+            ))
+    }
 
-        let prompt = {
-            let mut loader = self.loader.lock().unwrap();
+    /// Gets the BOS and EOS token IDs declared by the template.
+    fn special_token_ids(
+        &self,
+        tokenizer: &Tokenizer,
+    ) -> Result<(Option<u32>, Option<u32>), CallmError> {
+        let template = self.template.as_ref().ok_or(CallmError::GenericError(
+            "Cannot run inference, model not loaded".to_string(),
+        ))?;
+        let template = template.lock().unwrap();
+        let bos_token = bos_token_id(tokenizer, &**template);
 
-            let template = loader.template()?;
-            template.apply(messages)?
+        // NOTE: base models may declare no EOS, generation then only stops at the token limit
+        let eos_token = match template.get_eos_token() {
+            Some(eos_token_str) => {
+                let eos_token = tokenizer.token_to_id(eos_token_str).ok_or_else(|| {
+                    CallmError::GenericError(format!(
+                        "EOS token '{}' missing in the tokenizer",
+                        eos_token_str
+                    ))
+                })?;
+                log::trace!("EOS token: {} '{}'", eos_token, eos_token_str);
+                Some(eos_token)
+            }
+            None => {
+                log::warn!("Missing EOS token, generation stops at the token limit only");
+                None
+            }
         };
 
-        self.run(&prompt)
+        Ok((bos_token, eos_token))
     }
 
-    /// Sets the seed for the pipeline.
-    pub fn set_seed(&mut self, seed: u64) {
-        self.seed = Some(seed);
+    /// Creates the built-in stopping criteria for a generation run.
+    fn builtin_criteria(
+        &self,
+        eos_token: Option<u32>,
+        alternate_eos_tokens: Vec<u32>,
+    ) -> Result<Vec<Box<dyn StoppingCriteria>>, CallmError> {
+        let mut criteria: Vec<Box<dyn StoppingCriteria>> = Vec::new();
+        if let Some(eos_token) = eos_token.filter(|_| !self.ignore_eos) {
+            criteria.push(Box::new(
+                EosCriteria::new(eos_token).with_alternate_eos_tokens(alternate_eos_tokens),
+            ));
+        }
+        if !self.stop_token_ids.is_empty() {
+            criteria.push(Box::new(StopTokenCriteria::new(
+                self.stop_token_ids.clone(),
+            )));
+        }
+        if !self.stop_sequences.is_empty() {
+            criteria.push(Box::new(StopSequenceCriteria::new(
+                self.stop_sequences.clone(),
+            )));
+        }
+        if let Some((ngram_size, max_repeats)) = self.repetition_stop {
+            criteria.push(Box::new(RepetitionCriteria::try_new(
+                ngram_size,
+                max_repeats,
+            )?));
+        }
+        if let Some(timeout) = self.timeout {
+            criteria.push(Box::new(TimeoutCriteria::new(timeout)));
+        }
+        for criteria in criteria.iter_mut() {
+            criteria.reset();
+        }
+        Ok(criteria)
     }
 
-    /// Sets the temperature for the pipeline.
-    pub fn set_temperature(&mut self, temperature: f64) {
-        self.temperature = temperature;
+    /// Returns the sampling temperature for generation step `index`.
+    fn temperature(&self, index: usize, max_tokens: usize) -> f64 {
+        match &self.temperature_schedule {
+            Some(schedule) => schedule.temperature(index, max_tokens),
+            None => self.temperature,
+        }
     }
 
-    /// Sets the top-k value for the pipeline.
-    pub fn set_top_k(&mut self, top_k: usize) {
-        self.top_k = Some(top_k);
-    }
+    /// Sanitizes the logits of a single position and clamps them to the tokenizer vocab.
+    fn clamp_logits(
+        &self,
+        mut logits: Tensor,
+        vocab_size: usize,
+        warn: bool,
+    ) -> Result<Tensor, CallmError> {
+        // Keep NaN/Inf logits from derailing sampling
+        if self.nan_guard {
+            if let Some(sanitized) = replace_non_finite_logits(&logits)? {
+                logits = sanitized;
+            }
+        }
 
-    /// Sets the top-p value for the pipeline.
-    pub fn set_top_p(&mut self, top_p: f64) {
-        self.top_p = Some(top_p);
+        // Clamp sampling to token IDs known to the tokenizer (e.g. padded model vocabs)
+        let logits_size = logits.dim(0)?;
+        if logits_size != vocab_size && warn {
+            log::warn!(
+                "Model vocab size {} differs from tokenizer vocab size {}",
+                logits_size,
+                vocab_size
+            );
+        }
+        if logits_size > vocab_size {
+            logits = logits.narrow(0, 0, vocab_size)?;
+        }
+
+        Ok(logits)
     }
-}
 
-/// Builder for constructing a `PipelineText`.
-#[derive(Default)]
-pub struct PipelineTextBuilder {
-    location: Option<String>,
-    loader: Option<Arc<Mutex<dyn LoaderImpl>>>,
-    device: Option<DeviceConfig>,
-    autoload: bool,
-    temperature: f64,
-    seed: Option<u64>,
-    top_k: Option<usize>,
-    top_p: Option<f64>,
-}
+    /// Applies penalties and sampling filters to `logits`, then samples the next token.
+    ///
+    /// `tokens` holds the sequence so far, the completion starting at `prompt_tokens`.
+    fn sample_token(
+        &self,
+        mut logits: Tensor,
+        tokens: &[u32],
+        prompt_tokens: usize,
+        repeat_last_n: usize,
+        temperature: f64,
+        rng: &mut StdRng,
+    ) -> Result<u32, CallmError> {
+        use candle_transformers::generation::LogitsProcessor;
 
-impl PipelineTextBuilder {
-    /// Creates a new `PipelineTextBuilder`.
-    pub fn new() -> Self {
-        Self {
-            temperature: 0.7,
-            autoload: true,
-            ..Default::default()
+        // Penalize recently seen tokens
+        if self.repeat_penalty != 1.0 {
+            let start_at = tokens.len().saturating_sub(repeat_last_n);
+            logits = candle_transformers::utils::apply_repeat_penalty(
+                &logits,
+                self.repeat_penalty,
+                &tokens[start_at..],
+            )?;
+        }
+        if self.frequency_penalty != 0.0 || self.presence_penalty != 0.0 {
+            logits = apply_frequency_presence_penalty(
+                &logits,
+                self.frequency_penalty,
+                self.presence_penalty,
+                &tokens[prompt_tokens..],
+            )?;
         }
-    }
 
-    /// Sets the model location.
-    pub fn with_location(mut self, location: &str) -> Self {
-        self.location = Some(location.to_string());
-        self
-    }
+        // Prepare sampling strategy
+        let mut sampling = sampling(temperature, self.top_k, self.top_p);
 
-    /// Sets the loader to use.
-    pub fn with_loader(mut self, loader: Arc<Mutex<dyn LoaderImpl>>) -> Self {
-        self.loader = Some(loader);
-        self
-    }
+        // Drop unlikely tokens ahead of top-k/top-p, going greedy if a single one is left
+        if let (Some(min_p), false) = (self.min_p, matches!(sampling, Sampling::ArgMax)) {
+            let (filtered, kept) = apply_min_p(&logits, min_p)?;
+            logits = filtered;
+            if kept <= 1 {
+                sampling = Sampling::ArgMax;
+            }
+        }
 
-    /// Sets the device configuration.
-    pub fn with_device(mut self, device: DeviceConfig) -> Self {
-        self.device = Some(device);
-        self
+        // The processor is reseeded from the pipeline RNG for every token, keeping the
+        // random stream in a state that can be snapshotted
+        let mut logits_processor = LogitsProcessor::from_sampling(rng.gen(), sampling);
+        Ok(logits_processor.sample(&logits)?)
     }
 
-    /// Sets the temperature.
-    pub fn with_temperature(mut self, temperature: f64) -> Self {
-        self.temperature = temperature;
-        self
+    /// Decodes generated tokens into the output, trimming stop sequences and leading space.
+    fn decode_output(
+        &self,
+        tokenizer: &Tokenizer,
+        tokens: &[u32],
+        finish_reason: FinishReason,
+    ) -> Result<Vec<u8>, CallmError> {
+        let mut bytes = decode_bytes(tokenizer, tokens, !self.output_special_tokens)?;
+        if finish_reason == FinishReason::StopSequence {
+            // Trim the stop sequence along with anything decoded after it
+            if let Some(position) = find_stop_sequence(&bytes, &self.stop_sequences) {
+                bytes.truncate(position);
+            }
+        }
+        if self.strip_leading_space {
+            strip_leading_space(&mut bytes);
+        }
+        Ok(bytes)
     }
 
-    /// Sets the seed.
-    pub fn with_seed(mut self, seed: u64) -> Self {
-        self.seed = Some(seed);
-        self
-    }
+    /// Generates a completion for `prompt`, passing newly decoded bytes to `on_bytes` if given.
+    ///
+    /// Generation stops early with `FinishReason::Cancelled` once `cancel` is set.
+    fn generate(
+        &mut self,
+        prompt: Prompt,
+        mut on_bytes: Option<&mut BytesCallback>,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<Generation, CallmError> {
+        let model = self.model.as_ref().ok_or(CallmError::GenericError(
+            "Cannot run inference, model not loaded".to_string(),
+        ))?;
+        let mut model = lock_serialized(model.as_ref(), "Model")?;
+        // Ensure the KV cache gets cleared on every exit path
+        let mut model = KvCacheGuard::new(&mut *model);
+        // Take over the tokens left in the KV cache, the cache is cleared on failure
+        let cached_tokens = std::mem::take(&mut *self.cached_tokens.lock().unwrap());
 
-    /// Sets the top-k value.
-    pub fn with_top_k(mut self, top_k: usize) -> Self {
-        self.top_k = Some(top_k);
-        self
-    }
+        let mut loader = lock_serialized(self.loader.as_ref(), "Loader")?;
 
-    /// Sets the top-p value.
-    pub fn with_top_p(mut self, top_p: f64) -> Self {
-        self.top_p = Some(top_p);
-        self
-    }
+        let mut rng = sampling_rng(self.restored_sampling_state.take(), self.seed);
 
-    /// Sets whether to autoload the model.
-    pub fn autoload(mut self, autoload: bool) -> Self {
-        self.autoload = autoload;
-        self
-    }
+        // Get tokenizer built on load, the BOS and the EOS token
+        let tokenizer = self.loaded_tokenizer()?;
+        let (bos_token, eos_token) = self.special_token_ids(&tokenizer)?;
 
-    /// Builds the `PipelineText` instance.
-    pub fn build(self) -> Result<PipelineText, CallmError> {
-        let mut pipeline = match self.loader {
-            Some(loader) => PipelineText::new(loader),
-            None => match self.location {
-                Some(location) => PipelineText::from_path(&location)?,
-                None => {
-                    return Err(CallmError::GenericError(
-                        "No location or loader specified. Use `with_location` or `with_loader`"
-                            .to_string(),
-                    ));
+        // Only raw prompts get special tokens, templated prompts render their own
+        let (add_special_tokens, bos_token) = match prompt {
+            Prompt::Text(_) => (
+                self.add_special_tokens,
+                bos_token.filter(|_| self.add_special_tokens),
+            ),
+            Prompt::Completion { add_bos, .. } => (false, bos_token.filter(|_| add_bos)),
+            Prompt::Templated(_) | Prompt::Tokens(_) => (false, None),
+        };
+
+        // Tokenize user input
+        let mut tokens = match prompt {
+            Prompt::Text(text) | Prompt::Templated(text) => {
+                encode_prompt(&tokenizer, text, add_special_tokens, bos_token)?
+            }
+            Prompt::Completion { text, add_eos, .. } => {
+                let mut tokens = encode_prompt(&tokenizer, text, false, bos_token)?;
+                if add_eos {
+                    tokens.push(eos_token.ok_or(CallmError::GenericError(
+                        "Cannot append EOS, the model declares no EOS token".to_string(),
+                    ))?);
                 }
-            },
+                tokens
+            }
+            Prompt::Tokens(tokens) => tokens.to_vec(),
         };
 
-        pipeline.temperature = self.temperature;
-        pipeline.seed = self.seed;
-        pipeline.top_k = self.top_k;
-        pipeline.top_p = self.top_p;
+        // Reject oversized prompts before spending any compute on them
+        if let Some(limit) = self.max_prompt_tokens {
+            if tokens.len() > limit {
+                return Err(CallmError::PromptTooLong {
+                    tokens: tokens.len(),
+                    limit,
+                });
+            }
+        }
 
-        if let Some(device) = self.device {
-            pipeline.device = Arc::new(device);
+        // Tokenize negative prompt for classifier-free guidance
+        let mut negative_tokens = match &self.negative_prompt {
+            Some(negative_prompt) => Some(encode_prompt(
+                &tokenizer,
+                negative_prompt,
+                add_special_tokens,
+                bos_token,
+            )?),
+            None => None,
+        };
+        if negative_tokens.as_ref().is_some_and(Vec::is_empty) {
+            return Err(CallmError::GenericError(
+                "Negative prompt is empty".to_string(),
+            ));
         }
 
-        if self.autoload {
-            pipeline.load()?;
+        let num_tokens_at_start = tokens.len();
+        log::trace!("Tokens: {:?}", tokens);
+        log::trace!("Tokens count: {}", num_tokens_at_start);
+
+        // Generation budget is bounded by both the declared context and the model capacity
+        let max_position = model.max_position();
+        let context_length = loader
+            .context_length()
+            .map_or(max_position, |n| n.min(max_position));
+        if num_tokens_at_start > context_length {
+            return Err(CallmError::PromptTooLong {
+                tokens: num_tokens_at_start,
+                limit: context_length,
+            });
         }
 
-        Ok(pipeline)
+        let vocab_size = tokenizer.get_vocab_size(true);
+        let repeat_last_n = self
+            .repeat_last_n
+            .unwrap_or_else(|| default_repeat_last_n(max_position));
+        let mut finish_reason = FinishReason::Length;
+        let mut stream = TokenStream::default();
+        let mut strip_pending = self.strip_leading_space;
+        let mut stop_buffer = StopSequenceBuffer::default();
+
+        // Collect built-in stopping criteria ahead of user-provided ones
+        let mut builtin_criteria = self.builtin_criteria(eos_token, loader.stop_token_ids())?;
+        for criteria in self.stopping_criteria.iter_mut() {
+            criteria.reset();
+        }
+        // Decode the output only if a stopping criteria reads it
+        let mut stop_text = builtin_criteria
+            .iter()
+            .chain(self.stopping_criteria.iter())
+            .any(|criteria| criteria.uses_text())
+            .then(StopText::default);
+
+        // Collect logprobs of generated tokens if requested
+        let mut logprobs = self.logprobs.then(Vec::new);
+        let mut top_logprobs = (self.logprobs && self.top_logprobs > 0).then(Vec::new);
+
+        // Prepare prompt echo, passing the input text through verbatim
+        let echo = match (self.echo, prompt) {
+            (false, _) => Vec::new(),
+            (
+                true,
+                Prompt::Text(text) | Prompt::Templated(text) | Prompt::Completion { text, .. },
+            ) => text.as_bytes().to_vec(),
+            (true, Prompt::Tokens(_)) => {
+                decode_bytes(&tokenizer, &tokens, !self.output_special_tokens)?
+            }
+        };
+        let cancelled = match on_bytes.as_mut() {
+            Some(on_bytes) if !echo.is_empty() => !on_bytes(&echo)?,
+            _ => false,
+        };
+
+        // NOTE: the token budget bounds the loop instead of a `MaxTokensCriteria`, as the
+        // NOTE: temperature schedule needs it upfront
+        let max_tokens = self
+            .max_tokens
+            .unwrap_or_else(|| default_max_tokens(context_length, num_tokens_at_start));
+        log::trace!("Max tokens: {}", max_tokens);
+
+        // Reuse the KV cache if the prompt continues the cached tokens
+        let use_kv_cache = model.uses_kv_cache();
+        let keep_cache = self.keep_cache && use_kv_cache && negative_tokens.is_none();
+        let reused_tokens = if keep_cache
+            && cached_tokens.len() < tokens.len()
+            && tokens.starts_with(&cached_tokens)
+        {
+            cached_tokens.len()
+        } else {
+            0
+        };
+        if reused_tokens > 0 {
+            log::debug!("Reusing {} tokens in the KV cache", reused_tokens);
+        } else if !cached_tokens.is_empty() {
+            model.clear_kv_cache()?;
+        }
+        let mut fed_tokens = 0;
+
+        // The negative prompt of classifier-free guidance runs on a copy of the model with its
+        // own KV cache
+        let mut uncond_model = match negative_tokens {
+            Some(_) => Some(model.fork()?),
+            None => None,
+        };
+
+        let timer = Timer::start();
+        let mut prompt_secs = 0.0;
+        for index in 0..max_tokens {
+            if cancelled || cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+                finish_reason = FinishReason::Cancelled;
+                break;
+            }
+
+            // Stop before writing positions past the model capacity into the KV cache
+            if tokens.len() > max_position
+                || negative_tokens
+                    .as_ref()
+                    .is_some_and(|negative_tokens| negative_tokens.len() > max_position)
+            {
+                log::warn!("Reached maximum model position {}", max_position);
+                break;
+            }
+
+            let start_pos = if index > 0 && use_kv_cache {
+                tokens.len() - 1
+            } else {
+                reused_tokens
+            };
+            let mut logits =
+                feed_tokens(&mut *model, &tokens, start_pos, self.device.candle_device())?;
+            fed_tokens = tokens.len();
+
+            if let (Some(uncond_model), Some(negative_tokens)) =
+                (uncond_model.as_mut(), negative_tokens.as_ref())
+            {
+                let start_pos = if index > 0 && use_kv_cache {
+                    negative_tokens.len() - 1
+                } else {
+                    0
+                };
+                let uncond = feed_tokens(
+                    uncond_model.as_mut(),
+                    negative_tokens,
+                    start_pos,
+                    self.device.candle_device(),
+                )?;
+
+                // guided = uncond + scale * (cond - uncond)
+                logits = (&uncond + ((logits - &uncond)? * self.guidance_scale)?)?;
+            }
+
+            logits = self.clamp_logits(logits, vocab_size, index == 0)?;
+
+            // Capture the model distribution ahead of penalties and sampling
+            let step_logprobs = match logprobs {
+                Some(_) => Some(
+                    candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?
+                        .to_vec1::<f32>()?,
+                ),
+                None => None,
+            };
+
+            let new_token = self.sample_token(
+                logits,
+                &tokens,
+                num_tokens_at_start,
+                repeat_last_n,
+                self.temperature(index, max_tokens),
+                &mut rng,
+            )?;
+            tokens.push(new_token);
+            if let (Some(logprobs), Some(step_logprobs)) = (logprobs.as_mut(), &step_logprobs) {
+                logprobs.push(step_logprobs[new_token as usize]);
+                if let Some(top_logprobs) = top_logprobs.as_mut() {
+                    top_logprobs.push(top_n_logprobs(step_logprobs, self.top_logprobs));
+                }
+            }
+            if index == 0 {
+                prompt_secs = timer.elapsed().as_secs_f64();
+            }
+            if let Some(negative_tokens) = negative_tokens.as_mut() {
+                negative_tokens.push(new_token);
+            }
+
+            log::trace!("New token generated: {}", new_token);
+            if let Some(on_bytes) = on_bytes.as_mut() {
+                let mut bytes = stream.next(
+                    &tokenizer,
+                    &tokens[num_tokens_at_start..],
+                    !self.stream_special_tokens,
+                )?;
+                if strip_pending && !bytes.is_empty() {
+                    strip_pending = false;
+                    strip_leading_space(&mut bytes);
+                }
+                let bytes = stop_buffer.push(&bytes, &self.stop_sequences);
+                if !bytes.is_empty() && !on_bytes(&bytes)? {
+                    finish_reason = FinishReason::Cancelled;
+                    break;
+                }
+            }
+
+            // Check stopping criteria against the output generated so far
+            let text = match stop_text.as_mut() {
+                Some(stop_text) => stop_text.push(
+                    &tokenizer,
+                    &tokens[num_tokens_at_start..],
+                    !self.output_special_tokens,
+                )?,
+                None => "",
+            };
+            if let Some(reason) = builtin_criteria
+                .iter_mut()
+                .chain(self.stopping_criteria.iter_mut())
+                .find_map(|criteria| criteria.should_stop(&tokens[num_tokens_at_start..], text))
+            {
+                finish_reason = reason;
+                break;
+            }
+        }
+        let elapsed = timer.elapsed();
+        let completion_secs = elapsed.as_secs_f64();
+        log::debug!("Generation finished: {:?}", finish_reason);
+        // Pass on streamed bytes held back as a possible stop sequence start
+        if let Some(on_bytes) = on_bytes.as_mut() {
+            let bytes = stop_buffer.finish();
+            if !bytes.is_empty() && !matches!(finish_reason, FinishReason::Cancelled) {
+                on_bytes(&bytes)?;
+            }
+        }
+        self.finish_reason = Some(finish_reason);
+        self.sampling_state = Some(SamplingState(rng));
+
+        // Keep the KV cache for a follow-up prompt, or clear it
+        if keep_cache {
+            model.keep();
+            *self.cached_tokens.lock().unwrap() = tokens[..fed_tokens].to_vec();
+        } else {
+            model.clear()?;
+        }
+
+        // Decode newly added tokens
+        let tokens = tokens.split_off(num_tokens_at_start);
+        let bytes = self.decode_output(&tokenizer, &tokens, finish_reason)?;
+        self.incomplete_utf8 = ends_with_incomplete_utf8(&bytes);
+        if self.incomplete_utf8 {
+            log::debug!("Generated output ends with an incomplete UTF-8 character");
+        }
+        let bytes = [echo, bytes].concat();
+        Ok(Generation {
+            tokens,
+            bytes,
+            finish_reason,
+            prompt_tokens: num_tokens_at_start,
+            prompt_secs,
+            completion_secs: completion_secs - prompt_secs,
+            elapsed,
+            logprobs,
+            top_logprobs,
+        })
+    }
+
+    /// Sets the device configuration for the pipeline.
+    pub fn set_device(&mut self, device: DeviceConfig) {
+        self.device = Arc::new(device);
+    }
+
+    /// Gets the device configuration for the pipeline.
+    pub fn device(&self) -> Arc<DeviceConfig> {
+        Arc::clone(&self.device)
+    }
+
+    /// Returns a description of the loaded model, e.g. its name and quantization type.
+    ///
+    /// Returns `None` before the model has been loaded.
+    pub fn model_info(&self) -> Result<Option<ModelInfo>, CallmError> {
+        Ok(lock_serialized(self.loader.as_ref(), "Loader")?.info())
+    }
+
+    /// Gets the loader backing the pipeline.
+    pub fn loader(&self) -> Arc<Mutex<dyn LoaderImpl>> {
+        Arc::clone(&self.loader)
+    }
+
+    /// Computes the log-probability the model assigns to each token of `text`.
+    ///
+    /// Returns `(token ID, logprob)` pairs for every token but the first, which has no
+    /// preceding context. Models only return logits for the last position of a forward pass, so
+    /// tokens are fed one at a time through the KV cache.
+    pub fn prompt_logprobs(&mut self, text: &str) -> Result<Vec<(u32, f32)>, CallmError> {
+        let model = self.model.as_mut().ok_or(CallmError::GenericError(
+            "Cannot run inference, model not loaded".to_string(),
+        ))?;
+        let mut model = lock_serialized(model.as_ref(), "Model")?;
+        // Ensure the KV cache gets cleared on every exit path
+        let mut model = KvCacheGuard::new(&mut *model);
+        // Start from an empty KV cache
+        if !std::mem::take(&mut *self.cached_tokens.lock().unwrap()).is_empty() {
+            model.clear_kv_cache()?;
+        }
+
+        let tokenizer = self.tokenizer.clone().ok_or(CallmError::GenericError(
+            "Cannot run inference, model not loaded".to_string(),
+        ))?;
+        let tokens = tokenizer
+            .encode(text, false)
+            .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?
+            .get_ids()
+            .to_vec();
+        if tokens.len() > model.max_position() {
+            return Err(CallmError::GenericError(format!(
+                "Text of {} tokens exceeds maximum model position {}",
+                tokens.len(),
+                model.max_position()
+            )));
+        }
+
+        let mut logprobs = Vec::with_capacity(tokens.len().saturating_sub(1));
+        let use_kv_cache = model.uses_kv_cache();
+        for (index_pos, pair) in tokens.windows(2).enumerate() {
+            // Without a KV cache, the whole prefix is passed on every step
+            let (ctxt, start_pos) = match use_kv_cache {
+                true => (&pair[..1], index_pos),
+                false => (&tokens[..=index_pos], 0),
+            };
+            let input = Tensor::new(ctxt, self.device.candle_device())?.unsqueeze(0)?;
+            let logits = model
+                .forward(&input, start_pos)?
+                .squeeze(0)?
+                .squeeze(0)?
+                .to_dtype(DType::F32)?;
+            let token_logprobs = candle_nn::ops::log_softmax(&logits, D::Minus1)?;
+            logprobs.push((
+                pair[1],
+                token_logprobs.get(pair[1] as usize)?.to_scalar::<f32>()?,
+            ));
+        }
+
+        model.clear()?;
+
+        Ok(logprobs)
+    }
+
+    /// Estimates the KV cache size in bytes for a context of `context_len` tokens.
+    ///
+    /// Fails if the model is not loaded or does not report its KV cache dimensions.
+    pub fn estimate_kv_cache_bytes(&self, context_len: usize) -> Result<usize, CallmError> {
+        let model = self.model.as_ref().ok_or(CallmError::GenericError(
+            "Cannot estimate KV cache size, model not loaded".to_string(),
+        ))?;
+        let bytes_per_token = lock_serialized(model.as_ref(), "Model")?
+            .kv_cache_bytes_per_token()
+            .ok_or(CallmError::GenericError(
+                "KV cache size unknown for this model".to_string(),
+            ))?;
+
+        Ok(bytes_per_token * context_len)
+    }
+
+    /// Returns the number of tokens `text` encodes to as a raw prompt, as counted for the prompt
+    /// limits.
+    pub fn count_tokens(&self, text: &str) -> Result<usize, CallmError> {
+        let tokenizer = self.tokenizer.as_ref().ok_or(CallmError::GenericError(
+            "Cannot count tokens, model not loaded".to_string(),
+        ))?;
+        let bos_token = match (&self.template, self.add_special_tokens) {
+            (Some(template), true) => bos_token_id(tokenizer, &**template.lock().unwrap()),
+            _ => None,
+        };
+        Ok(encode_prompt(tokenizer, text, self.add_special_tokens, bos_token)?.len())
+    }
+
+    /// Returns the number of tokens a prompt rendered by the chat template encodes to.
+    pub(crate) fn count_templated_tokens(&self, prompt: &str) -> Result<usize, CallmError> {
+        let tokenizer = self.tokenizer.as_ref().ok_or(CallmError::GenericError(
+            "Cannot count tokens, model not loaded".to_string(),
+        ))?;
+        Ok(encode_prompt(tokenizer, prompt, false, None)?.len())
+    }
+
+    /// Returns the context window shared by prompt and generated tokens.
+    ///
+    /// This is the context length declared by the model, bounded by the model capacity.
+    pub fn context_length(&self) -> Result<usize, CallmError> {
+        let model = self.model.as_ref().ok_or(CallmError::GenericError(
+            "Cannot get context length, model not loaded".to_string(),
+        ))?;
+        let max_position = lock_serialized(model.as_ref(), "Model")?.max_position();
+        Ok(lock_serialized(self.loader.as_ref(), "Loader")?
+            .context_length()
+            .map_or(max_position, |n| n.min(max_position)))
+    }
+
+    /// Returns whether the loaded tokenizer prepends a space to the input.
+    ///
+    /// Completions of such tokenizers usually start with a space, see
+    /// `set_strip_leading_space`.
+    pub fn adds_prefix_space(&self) -> Result<bool, CallmError> {
+        match &self.tokenizer {
+            Some(tokenizer) => Ok(adds_prefix_space(tokenizer)),
+            None => Ok(adds_prefix_space(
+                &lock_serialized(self.loader.as_ref(), "Loader")?.tokenizer()?,
+            )),
+        }
+    }
+
+    /// Runs the text generation pipeline on a chat message sequence.
+    ///
+    /// A single `MessageRole::Raw` message bypasses the chat template and is used as the prompt
+    /// verbatim. Special tokens are not added to it, so it has to include the BOS token (and any
+    /// EOS/turn tokens) the model expects.
+    pub fn run_chat(&mut self, messages: &[(MessageRole, String)]) -> Result<String, CallmError> {
+        let messages: Vec<ChatMessage> = messages.iter().map(ChatMessage::from).collect();
+        self.run_chat_messages(&messages)
+    }
+
+    /// Runs the text generation pipeline on a sequence of structured chat messages.
+    pub fn run_chat_messages(&mut self, messages: &[ChatMessage]) -> Result<String, CallmError> {
+        let prompt = self.chat_prompt(messages)?;
+        let bytes = self.generate(Prompt::Templated(&prompt), None, None)?.bytes;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Runs the text generation pipeline on several independent prompts.
+    ///
+    /// Returns one output per prompt, in order. Prompts tokenizing to the same length are
+    /// generated together in one batch, each sequence sampling and stopping on its own. Prompts
+    /// of other lengths would need padding masked out of attention, so each length group is run
+    /// as a separate batch and a prompt without equally long siblings runs on its own.
+    ///
+    /// | Architecture | Batched forward |
+    /// |--------------|-----------------|
+    /// | Gemma, Gemma 2, Gemma (GGUF) | Yes |
+    /// | Llama, Llama (GGUF) | Yes |
+    /// | Mistral | Yes |
+    /// | Phi-3 | Yes |
+    /// | Phi-3 (GGUF) | No, sequential |
+    /// | Qwen2 | Yes |
+    ///
+    /// Prompts are also generated one after another with the KV cache disabled, with a negative
+    /// prompt or with custom stopping criteria, which track a single sequence. Batched prompts
+    /// do not reuse a KV cache kept with `set_keep_cache`. After the call `finish_reason`
+    /// reports how the last prompt finished.
+    pub fn run_batch(&mut self, prompts: &[&str]) -> Result<Vec<String>, CallmError> {
+        let batched = match &self.model {
+            Some(model) => {
+                let model = lock_serialized(model.as_ref(), "Model")?;
+                model.supports_batch() && model.uses_kv_cache()
+            }
+            None => false,
+        };
+        if !batched || self.negative_prompt.is_some() || !self.stopping_criteria.is_empty() {
+            log::debug!("Generating {} prompts sequentially", prompts.len());
+            return prompts.iter().map(|prompt| self.run(prompt)).collect();
+        }
+
+        // Group prompts by token count, equally long prompts need no padding
+        let tokenizer = self.loaded_tokenizer()?;
+        let (bos_token, _) = self.special_token_ids(&tokenizer)?;
+        let bos_token = bos_token.filter(|_| self.add_special_tokens);
+        let mut groups: BTreeMap<usize, Vec<(usize, Vec<u32>)>> = BTreeMap::new();
+        for (index, prompt) in prompts.iter().enumerate() {
+            let tokens = encode_prompt(&tokenizer, prompt, self.add_special_tokens, bos_token)?;
+            groups
+                .entry(tokens.len())
+                .or_default()
+                .push((index, tokens));
+        }
+
+        let mut outputs: Vec<Option<(Vec<u8>, FinishReason)>> = vec![None; prompts.len()];
+        for group in groups.into_values() {
+            if let [(index, _)] = group.as_slice() {
+                let bytes = self.run_bytes(prompts[*index])?;
+                outputs[*index] = Some((bytes, self.finish_reason.unwrap_or(FinishReason::Length)));
+                continue;
+            }
+
+            let batch: Vec<(&str, &[u32])> = group
+                .iter()
+                .map(|(index, tokens)| (prompts[*index], tokens.as_slice()))
+                .collect();
+            for ((index, _), generation) in group.iter().zip(self.generate_batch(&batch)?) {
+                outputs[*index] = Some((generation.bytes, generation.finish_reason));
+            }
+        }
+
+        // Report the last prompt, as if the prompts were generated in order
+        if let Some(Some((bytes, finish_reason))) = outputs.last() {
+            self.finish_reason = Some(*finish_reason);
+            self.incomplete_utf8 = ends_with_incomplete_utf8(bytes);
+        }
+        Ok(outputs
+            .into_iter()
+            .flatten()
+            .map(|(bytes, _)| String::from_utf8_lossy(&bytes).into_owned())
+            .collect())
+    }
+
+    /// Runs the text generation pipeline on the given input text, returning the output along
+    /// with usage statistics.
+    pub fn run_detailed(&mut self, text: &str) -> Result<GenerationResult, CallmError> {
+        Ok(self.generate(Prompt::Text(text), None, None)?.into())
+    }
+
+    /// Runs the text generation pipeline on structured chat messages, returning the output along
+    /// with usage statistics.
+    pub fn run_chat_detailed(
+        &mut self,
+        messages: &[ChatMessage],
+    ) -> Result<GenerationResult, CallmError> {
+        let prompt = self.chat_prompt(messages)?;
+        Ok(self
+            .generate(Prompt::Templated(&prompt), None, None)?
+            .into())
+    }
+
+    /// Turns chat messages into a prompt, applying the model template unless given a raw prompt.
+    pub(crate) fn chat_prompt(&mut self, messages: &[ChatMessage]) -> Result<String, CallmError> {
+        if self.model.is_none() {
+            return Err(CallmError::GenericError(
+                "Cannot run inference, model not loaded".to_string(),
+            ));
+        }
+
+        let prompt = match messages {
+            [message] if message.role == MessageRole::Raw => message.content.clone(),
+            _ if messages.iter().any(|m| m.role == MessageRole::Raw) => {
+                return Err(CallmError::TemplateError(
+                    "Raw prompt must be the only message".to_string(),
+                ));
+            }
+            _ => {
+                let template = self.template.as_ref().ok_or(CallmError::GenericError(
+                    "Cannot run inference, model not loaded".to_string(),
+                ))?;
+                let mut template = template.lock().unwrap();
+                template.set_add_generation_prompt(self.add_generation_prompt);
+                template.apply_messages(messages)?
+            }
+        };
+
+        Ok(prompt)
+    }
+
+    /// Returns the reason the last generation finished, if any completed.
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason
+    }
+
+    /// Returns whether the last generated output ends in the middle of a UTF-8 character.
+    ///
+    /// `run` replaces the partial character with U+FFFD in that case, while `run_bytes` keeps the
+    /// raw bytes. Generating more tokens is needed to get a clean boundary.
+    pub fn ends_with_incomplete_utf8(&self) -> bool {
+        self.incomplete_utf8
+    }
+
+    /// Returns the sampling RNG state at the end of the last generation.
+    pub fn sampling_state(&self) -> Option<SamplingState> {
+        self.sampling_state.clone()
+    }
+
+    /// Restores the sampling RNG state, taking precedence over the seed for the next generation.
+    pub fn restore_sampling_state(&mut self, sampling_state: SamplingState) {
+        self.restored_sampling_state = Some(sampling_state);
+    }
+
+    /// Sets the seed for the pipeline.
+    ///
+    /// Seeded runs are reproducible. Without a seed, every run draws a fresh random seed (logged
+    /// at info level), so repeated runs differ.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+
+    /// Sets the temperature for the pipeline.
+    pub fn set_temperature(&mut self, temperature: f64) {
+        self.temperature = temperature;
+    }
+
+    /// Sets a schedule varying the temperature over generation, taking precedence over the
+    /// fixed temperature.
+    pub fn set_temperature_schedule(&mut self, temperature_schedule: TemperatureSchedule) {
+        self.temperature_schedule = Some(temperature_schedule);
+    }
+
+    /// Sets the top-k value for the pipeline.
+    pub fn set_top_k(&mut self, top_k: usize) {
+        self.top_k = Some(top_k);
+    }
+
+    /// Sets the top-p value for the pipeline.
+    pub fn set_top_p(&mut self, top_p: f64) {
+        self.top_p = Some(top_p);
+    }
+
+    /// Sets the min-p value for the pipeline.
+    ///
+    /// Tokens less likely than `min_p` times the most likely token are dropped before
+    /// temperature, top-k and top-p sampling.
+    pub fn set_min_p(&mut self, min_p: f64) {
+        self.min_p = Some(min_p);
+    }
+
+    /// Sets the repeat penalty for the pipeline (1.0 disables it).
+    pub fn set_repeat_penalty(&mut self, repeat_penalty: f32) {
+        self.repeat_penalty = repeat_penalty;
+    }
+
+    /// Sets the number of most recent tokens the repeat penalty considers.
+    ///
+    /// When not set, a quarter of the model context is used, capped at 256 tokens.
+    pub fn set_repeat_context_window(&mut self, repeat_last_n: usize) {
+        self.repeat_last_n = Some(repeat_last_n);
+    }
+
+    /// Sets the frequency penalty (0.0 disables it).
+    ///
+    /// Lowers the logit of each token by the penalty times the number of times the token has
+    /// been generated so far, like the OpenAI API. Penalties apply to raw logits, so they are
+    /// scaled down by the temperature like any other logit difference, and top-k/top-p filter
+    /// the penalized distribution.
+    pub fn set_frequency_penalty(&mut self, frequency_penalty: f32) {
+        self.frequency_penalty = frequency_penalty;
+    }
+
+    /// Sets the presence penalty (0.0 disables it).
+    ///
+    /// Lowers the logit of every token generated so far by the penalty, once regardless of how
+    /// often it occurred. Interacts with sampling settings the same way as the frequency penalty.
+    pub fn set_presence_penalty(&mut self, presence_penalty: f32) {
+        self.presence_penalty = presence_penalty;
+    }
+
+    /// Stops generation once the output ends with the same `ngram_size` tokens repeated
+    /// `max_repeats` times in a row.
+    ///
+    /// Returns an error if `ngram_size` is zero or `max_repeats` is below 2.
+    pub fn set_repetition_stop(
+        &mut self,
+        ngram_size: usize,
+        max_repeats: usize,
+    ) -> Result<(), CallmError> {
+        validate_repetition_stop(ngram_size, max_repeats)?;
+        self.repetition_stop = Some((ngram_size, max_repeats));
+        Ok(())
+    }
+
+    /// Stops generation once it has run for longer than `timeout`, including prompt processing.
+    ///
+    /// The output generated so far is returned with `FinishReason::Timeout`.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// Sets whether a single leading space is stripped from the generated output.
+    pub fn set_strip_leading_space(&mut self, strip_leading_space: bool) {
+        self.strip_leading_space = strip_leading_space;
+    }
+
+    /// Sets whether special tokens are included in output streamed by `run_to_writer`.
+    pub fn set_stream_special_tokens(&mut self, stream_special_tokens: bool) {
+        self.stream_special_tokens = stream_special_tokens;
+    }
+
+    /// Sets whether special tokens are included in the final generated output.
+    pub fn set_output_special_tokens(&mut self, output_special_tokens: bool) {
+        self.output_special_tokens = output_special_tokens;
+    }
+
+    /// Sets whether special tokens like `<|eot_id|>` are stripped from both streamed and final
+    /// output, `true` by default.
+    ///
+    /// Shorthand for setting `set_stream_special_tokens` and `set_output_special_tokens` to the
+    /// opposite value, keeping streamed chunks consistent with the final output.
+    pub fn set_skip_special_tokens(&mut self, skip_special_tokens: bool) {
+        self.stream_special_tokens = !skip_special_tokens;
+        self.output_special_tokens = !skip_special_tokens;
+    }
+
+    /// Sets the maximum number of prompt tokens accepted, independent of the model context.
+    ///
+    /// Longer prompts are rejected with `CallmError::PromptTooLong` before inference starts.
+    pub fn set_max_prompt_tokens(&mut self, max_prompt_tokens: usize) {
+        self.max_prompt_tokens = Some(max_prompt_tokens);
+    }
+
+    /// Sets the maximum number of tokens to generate.
+    ///
+    /// Defaults to the context space left after the prompt. Generation never writes past the
+    /// model context, whatever the limit.
+    pub fn set_max_tokens(&mut self, max_tokens: usize) {
+        self.max_tokens = Some(max_tokens);
+    }
+
+    /// Sets whether generation continues past the EOS token.
+    ///
+    /// When set, generation only stops at the length limit (or on repetition, if enabled), which
+    /// is useful for fixed-length benchmarks.
+    pub fn set_ignore_eos(&mut self, ignore_eos: bool) {
+        self.ignore_eos = ignore_eos;
+    }
+
+    /// Sets whether the prompt is prepended to the generated output.
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// Sets whether chat templates append the assistant header after the messages, `true` by
+    /// default.
+    ///
+    /// Disabling it renders a conversation as-is, e.g. for scoring or fill-in-the-middle prompts.
+    pub fn set_add_generation_prompt(&mut self, add_generation_prompt: bool) {
+        self.add_generation_prompt = add_generation_prompt;
+    }
+
+    /// Sets whether tokenizing a text prompt adds special tokens, `false` by default.
+    ///
+    /// Enabling it lets the tokenizer insert the special tokens of its post-processor into plain
+    /// prompts, and prepends the BOS token of the model if the tokenizer did not. Models like
+    /// Llama expect BOS at the start of the prompt and produce worse output without it. It only
+    /// applies to raw prompts: prompts rendered by the chat template (`run_chat`,
+    /// `run_chat_detailed`, `ChatSession`) already contain these tokens and are always tokenized
+    /// without them, and `complete` picks BOS/EOS per call.
+    pub fn set_add_special_tokens(&mut self, add_special_tokens: bool) {
+        self.add_special_tokens = add_special_tokens;
+    }
+
+    /// Sets whether `run_detailed` and `run_chat_detailed` report the log-probability of each
+    /// generated token, `false` by default.
+    ///
+    /// Logprobs are taken from the model distribution at each step, before repeat penalties,
+    /// temperature and other sampling filters are applied.
+    pub fn set_logprobs(&mut self, logprobs: bool) {
+        self.logprobs = logprobs;
+    }
+
+    /// Sets the number of most likely alternatives reported along with each generated token's
+    /// logprob, `0` by default. Only used with `set_logprobs` enabled.
+    pub fn set_top_logprobs(&mut self, top_logprobs: usize) {
+        self.top_logprobs = top_logprobs;
+    }
+
+    /// Sets whether the KV cache is kept after generation, `false` by default.
+    ///
+    /// A following prompt that starts with the tokens of the previous prompt and completion,
+    /// like the next turn of a chat, then only processes the tokens appended to it. Other
+    /// prompts clear the cache first. Disabling it clears the cache.
+    ///
+    /// Appended tokens take a single forward pass on models masking them against the cache
+    /// (see `ModelImpl::supports_cached_prefill`), and one pass each otherwise, e.g. on Llama.
+    ///
+    /// Classifier-free guidance and models without a KV cache always start from scratch.
+    pub fn set_keep_cache(&mut self, keep_cache: bool) {
+        self.keep_cache = keep_cache;
+        if !keep_cache {
+            if let Err(e) = self.clear_kv_cache() {
+                log::error!("Failed to clear KV cache: {}", e);
+            }
+        }
+    }
+
+    /// Clears the KV cache kept by `set_keep_cache`.
+    pub fn clear_kv_cache(&mut self) -> Result<(), CallmError> {
+        let Some(model) = self.model.as_ref() else {
+            return Ok(());
+        };
+        let mut model = lock_serialized(model.as_ref(), "Model")?;
+        if !std::mem::take(&mut *self.cached_tokens.lock().unwrap()).is_empty() {
+            model.clear_kv_cache()?;
+        }
+        Ok(())
+    }
+
+    /// Sets token IDs which stop generation as soon as one is produced, besides EOS.
+    pub fn set_stop_token_ids(&mut self, stop_token_ids: Vec<u32>) {
+        self.stop_token_ids = stop_token_ids;
+    }
+
+    /// Sets strings which stop generation once they appear in the output.
+    ///
+    /// The stop sequence and anything after it are trimmed from the returned text. Streamed
+    /// output holds back text that may be the start of a stop sequence until it is resolved.
+    pub fn set_stop_sequences(&mut self, stop_sequences: Vec<String>) {
+        self.stop_sequences = stop_sequences;
+    }
+
+    /// Adds a custom stopping criteria, checked after the built-in ones.
+    pub fn add_stopping_criteria(&mut self, criteria: Box<dyn StoppingCriteria>) {
+        self.stopping_criteria.push(criteria);
+    }
+
+    /// Sets whether non-finite (NaN/Inf) logits are replaced before sampling.
+    pub fn set_nan_guard(&mut self, nan_guard: bool) {
+        self.nan_guard = nan_guard;
+    }
+
+    /// Sets the negative prompt used for classifier-free guidance, or disables guidance with
+    /// `None`.
+    ///
+    /// Guidance needs a second forward pass per generated token, run on a copy of the model
+    /// keeping its own KV cache (see `ModelImpl::fork`). An empty negative prompt is rejected
+    /// when generating.
+    pub fn set_negative_prompt(&mut self, negative_prompt: Option<&str>) {
+        self.negative_prompt = negative_prompt.map(String::from);
+    }
+
+    /// Sets the classifier-free guidance scale for the pipeline.
+    pub fn set_guidance_scale(&mut self, guidance_scale: f64) {
+        self.guidance_scale = guidance_scale;
+    }
+}
+
+#[cfg(feature = "async")]
+impl PipelineText {
+    /// Runs the text generation pipeline on a blocking thread, for use from async code.
+    ///
+    /// Generation runs on a copy of the pipeline made with `clone_config`, so custom stopping
+    /// criteria are not applied and `finish_reason` of this pipeline is not updated. Concurrent
+    /// runs sharing the model are served one after another.
+    pub fn run_async(
+        &self,
+        text: &str,
+    ) -> impl Future<Output = Result<String, CallmError>> + Send + 'static {
+        let mut pipeline = self.clone_config();
+        let text = text.to_string();
+        async move {
+            tokio::task::spawn_blocking(move || pipeline.run(&text))
+                .await
+                .map_err(|e| CallmError::GenericError(format!("Generation task failed: {}", e)))?
+        }
+    }
+
+    /// Runs the text generation pipeline on a blocking thread, streaming text as it is generated.
+    ///
+    /// Text is received in chunks of complete UTF-8 characters, and a failed generation sends
+    /// its error as the last item. Dropping the receiver stops generation. Like `run_async`, the
+    /// generation runs on a copy of the pipeline made with `clone_config`.
+    ///
+    /// Has to be called from within a Tokio runtime.
+    pub fn run_stream(&self, text: &str) -> mpsc::Receiver<Result<String, CallmError>> {
+        let mut pipeline = self.clone_config();
+        let text = text.to_string();
+        let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+        tokio::task::spawn_blocking(move || {
+            let result = pipeline.run_with_callback(&text, |delta| {
+                tx.blocking_send(Ok(delta.to_string())).is_ok()
+            });
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(e));
+            }
+        });
+        rx
+    }
+}
+
+/// Locks `mutex`, waiting for a concurrent request holding it to finish.
+///
+/// Returns an error instead of panicking if a previous request panicked while holding the lock.
+fn lock_serialized<'a, T: ?Sized>(
+    mutex: &'a Mutex<T>,
+    name: &str,
+) -> Result<MutexGuard<'a, T>, CallmError> {
+    let poisoned = || CallmError::GenericError(format!("{} unusable after a failed request", name));
+    match mutex.try_lock() {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::WouldBlock) => {
+            log::debug!("{} busy with another request, waiting", name);
+            mutex.lock().map_err(|_| poisoned())
+        }
+        Err(TryLockError::Poisoned(_)) => Err(poisoned()),
+    }
+}
+
+/// Prepares the sampling RNG, continuing a restored stream if there is one.
+fn sampling_rng(restored: Option<SamplingState>, seed: Option<u64>) -> StdRng {
+    match restored {
+        Some(SamplingState(rng)) => {
+            log::info!("Using restored sampling state");
+            rng
+        }
+        None => {
+            let seed = seed.unwrap_or_else(|| {
+                let s = rand::random::<u64>();
+                log::info!("Using random seed {}", s);
+                s
+            });
+            StdRng::seed_from_u64(seed)
+        }
+    }
+}
+
+/// Feeds `tokens[start_pos..]` to the model, returning the logits of the last token.
+///
+/// Positions before `start_pos` have to be in the KV cache already.
+fn feed_tokens(
+    model: &mut dyn ModelImpl,
+    tokens: &[u32],
+    start_pos: usize,
+    device: &candle_core::Device,
+) -> Result<Tensor, CallmError> {
+    // Tokens following cached ones are fed one at a time unless the model masks them right
+    let step = if start_pos > 0 && !model.supports_cached_prefill() {
+        1
+    } else {
+        tokens.len().max(1)
+    };
+    let mut logits = None;
+    for pos in (start_pos..tokens.len()).step_by(step) {
+        let ctxt = &tokens[pos..tokens.len().min(pos + step)];
+        let input = Tensor::new(ctxt, device)?.unsqueeze(0)?;
+        logits = Some(model.forward(&input, pos)?);
+    }
+
+    Ok(logits
+        .ok_or(CallmError::GenericError("Empty model input".to_string()))?
+        .squeeze(0)?
+        .squeeze(0)?)
+}
+
+/// Returns the `n` most likely `(token ID, logprob)` pairs, most likely first.
+fn top_n_logprobs(logprobs: &[f32], n: usize) -> Vec<(u32, f32)> {
+    let mut top: Vec<(u32, f32)> = logprobs
+        .iter()
+        .enumerate()
+        .map(|(id, logprob)| (id as u32, *logprob))
+        .collect();
+    let by_logprob = |a: &(u32, f32), b: &(u32, f32)| b.1.total_cmp(&a.1);
+    if n < top.len() {
+        top.select_nth_unstable_by(n, by_logprob);
+        top.truncate(n);
+    }
+    top.sort_by(by_logprob);
+    top
+}
+
+/// Tokenizes a text prompt, prepending `bos_token` unless the tokenizer already added it.
+///
+/// Tokenizers built from GGUF metadata have no post-processor, so adding special tokens does
+/// not insert their BOS on its own.
+fn encode_prompt(
+    tokenizer: &Tokenizer,
+    text: &str,
+    add_special_tokens: bool,
+    bos_token: Option<u32>,
+) -> Result<Vec<u32>, CallmError> {
+    let mut tokens = tokenizer
+        .encode(text, add_special_tokens)
+        .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?
+        .get_ids()
+        .to_vec();
+    if let Some(bos_token) = bos_token {
+        if tokens.first() != Some(&bos_token) {
+            tokens.insert(0, bos_token);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Returns the ID of the template BOS token, if the template declares one.
+fn bos_token_id(tokenizer: &Tokenizer, template: &dyn TemplateImpl) -> Option<u32> {
+    template
+        .get_bos_token()
+        .and_then(|bos_token| tokenizer.token_to_id(bos_token))
+}
+
+/// Returns the default repeat penalty window for a given model context length.
+fn default_repeat_last_n(context_length: usize) -> usize {
+    (context_length / 4).min(256)
+}
+
+/// Returns the sampling strategy for the given temperature and top-k/top-p settings.
+fn sampling(temperature: f64, top_k: Option<usize>, top_p: Option<f64>) -> Sampling {
+    if temperature <= 0.0 {
+        return Sampling::ArgMax;
+    }
+
+    match (top_k, top_p) {
+        (None, None) => Sampling::All { temperature },
+        (Some(k), None) => Sampling::TopK { k, temperature },
+        (None, Some(p)) => Sampling::TopP { p, temperature },
+        (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+    }
+}
+
+/// Masks out logits of tokens less likely than `min_p` times the most likely one.
+///
+/// Returns the masked logits and the number of tokens kept.
+fn apply_min_p(logits: &Tensor, min_p: f64) -> Result<(Tensor, usize), CallmError> {
+    let mut values = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    // p / p_max < min_p is equivalent to logit - max_logit < ln(min_p)
+    let max_logit = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let threshold = max_logit + min_p.ln() as f32;
+    let mut kept = 0;
+    for v in values.iter_mut() {
+        if *v < threshold {
+            *v = f32::NEG_INFINITY;
+        } else {
+            kept += 1;
+        }
+    }
+
+    Ok((Tensor::new(values, logits.device())?, kept))
+}
+
+/// Serializes a duration as fractional seconds.
+fn serialize_secs<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Returns the number of tokens generated per second, or 0 if no time elapsed.
+fn tokens_per_second(tokens: usize, elapsed: Duration) -> f64 {
+    match elapsed.as_secs_f64() {
+        secs if secs > 0.0 => tokens as f64 / secs,
+        _ => 0.0,
+    }
+}
+
+/// Returns the default generation limit, filling the context left after the prompt.
+fn default_max_tokens(context_length: usize, prompt_length: usize) -> usize {
+    context_length.saturating_sub(prompt_length)
+}
+
+/// Removes a single leading space from `bytes`, if present.
+fn strip_leading_space(bytes: &mut Vec<u8>) {
+    if bytes.first() == Some(&b' ') {
+        bytes.remove(0);
+    }
+}
+
+/// Lowers logits of the given tokens by `frequency_penalty` per occurrence and by
+/// `presence_penalty` once.
+fn apply_frequency_presence_penalty(
+    logits: &Tensor,
+    frequency_penalty: f32,
+    presence_penalty: f32,
+    tokens: &[u32],
+) -> Result<Tensor, CallmError> {
+    let mut values = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for token in tokens {
+        *counts.entry(*token).or_default() += 1;
+    }
+    for (token, count) in counts {
+        if let Some(v) = values.get_mut(token as usize) {
+            *v -= frequency_penalty * count as f32 + presence_penalty;
+        }
+    }
+
+    Ok(Tensor::new(values, logits.device())?)
+}
+
+/// Replaces non-finite logits with a large negative value, returning `None` if all are finite.
+fn replace_non_finite_logits(logits: &Tensor) -> Result<Option<Tensor>, CallmError> {
+    let mut values = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    let mut replaced = 0;
+    for v in values.iter_mut().filter(|v| !v.is_finite()) {
+        *v = NON_FINITE_LOGIT;
+        replaced += 1;
+    }
+    if replaced == 0 {
+        return Ok(None);
+    }
+
+    log::warn!("Replaced {} non-finite logits", replaced);
+    Ok(Some(Tensor::new(values, logits.device())?))
+}
+
+/// Returns whether `bytes` end with a truncated multi-byte UTF-8 character.
+fn ends_with_incomplete_utf8(bytes: &[u8]) -> bool {
+    // a UTF-8 character spans at most 4 bytes, so only the tail needs checking
+    let tail = &bytes[bytes.len().saturating_sub(3)..];
+    (0..tail.len()).any(|start| {
+        std::str::from_utf8(&tail[start..])
+            .is_err_and(|e| e.error_len().is_none() && e.valid_up_to() == 0)
+    })
+}
+
+/// Buffer holding back streamed bytes which may be the start of a stop sequence.
+#[derive(Default)]
+struct StopSequenceBuffer {
+    pending: Vec<u8>,
+    stopped: bool,
+}
+
+impl StopSequenceBuffer {
+    /// Appends `bytes`, returning the bytes which cannot be part of a stop sequence.
+    ///
+    /// Once a stop sequence is found, only the bytes preceding it are returned and everything
+    /// pushed later is dropped.
+    fn push(&mut self, bytes: &[u8], stop_sequences: &[String]) -> Vec<u8> {
+        if self.stopped {
+            return Vec::new();
+        }
+        self.pending.extend_from_slice(bytes);
+
+        if let Some(position) = find_stop_sequence(&self.pending, stop_sequences) {
+            self.stopped = true;
+            self.pending.truncate(position);
+            return std::mem::take(&mut self.pending);
+        }
+
+        // keep the longest tail which a stop sequence starts with
+        let held = stop_sequences
+            .iter()
+            .flat_map(|stop| {
+                (1..stop.len().min(self.pending.len() + 1))
+                    .filter(|len| self.pending.ends_with(&stop.as_bytes()[..*len]))
+            })
+            .max()
+            .unwrap_or(0);
+        let rest = self.pending.split_off(self.pending.len() - held);
+        std::mem::replace(&mut self.pending, rest)
+    }
+
+    /// Returns any held back bytes, unless a stop sequence was found.
+    fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Buffer turning a byte stream into UTF-8 text, holding back incomplete characters.
+#[derive(Default)]
+struct Utf8Buffer {
+    pending: Vec<u8>,
+}
+
+impl Utf8Buffer {
+    /// Appends `bytes`, returning the text decodable so far.
+    ///
+    /// Invalid sequences are replaced with `U+FFFD`, while a trailing incomplete character is
+    /// kept for the next call.
+    fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+
+        let mut text = String::new();
+        let mut rest = self.pending.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match e.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        None => {
+                            rest = invalid;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.pending = rest.to_vec();
+
+        text
+    }
+
+    /// Returns any held back bytes, lossily converted to text.
+    fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+/// Guard clearing the model KV cache when dropped.
+///
+/// Keeps an early return (or panic) during generation from leaving a dirty cache behind for
+/// the next request.
+struct KvCacheGuard<'a> {
+    model: &'a mut dyn ModelImpl,
+    armed: bool,
+}
+
+impl<'a> KvCacheGuard<'a> {
+    fn new(model: &'a mut dyn ModelImpl) -> Self {
+        Self { model, armed: true }
+    }
+
+    /// Clears the KV cache, reporting failure to the caller.
+    fn clear(mut self) -> Result<(), CallmError> {
+        self.armed = false;
+        self.model.clear_kv_cache()
+    }
+
+    /// Leaves the KV cache filled, for a follow-up generation to continue.
+    fn keep(mut self) {
+        self.armed = false;
+    }
+}
+
+impl<'a> Deref for KvCacheGuard<'a> {
+    type Target = dyn ModelImpl + 'a;
+
+    fn deref(&self) -> &Self::Target {
+        self.model
+    }
+}
+
+impl DerefMut for KvCacheGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.model
+    }
+}
+
+impl Drop for KvCacheGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            if let Err(e) = self.model.clear_kv_cache() {
+                log::error!("Failed to clear KV cache: {}", e);
+            }
+        }
+    }
+}
+
+/// Builder for constructing a `PipelineText`.
+#[derive(Default)]
+pub struct PipelineTextBuilder {
+    location: Option<String>,
+    #[cfg(feature = "hub")]
+    hf_repo: Option<HubRepo>,
+    #[cfg(feature = "hub")]
+    download_progress: Option<Box<DownloadProgress>>,
+    loader: Option<Arc<Mutex<dyn LoaderImpl>>>,
+    loader_options: Option<LoaderOptions>,
+    load_progress: Option<LoadProgress>,
+    device: Option<DeviceConfig>,
+    device_str: Option<String>,
+    autoload: bool,
+    temperature: f64,
+    temperature_schedule: Option<TemperatureSchedule>,
+    seed: Option<u64>,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+    min_p: Option<f64>,
+    negative_prompt: Option<String>,
+    guidance_scale: f64,
+    repeat_penalty: f32,
+    repeat_last_n: Option<usize>,
+    frequency_penalty: f32,
+    presence_penalty: f32,
+    repetition_stop: Option<(usize, usize)>,
+    timeout: Option<Duration>,
+    strip_leading_space: bool,
+    stream_special_tokens: bool,
+    output_special_tokens: bool,
+    max_prompt_tokens: Option<usize>,
+    max_tokens: Option<usize>,
+    ignore_eos: bool,
+    echo: bool,
+    add_generation_prompt: bool,
+    add_special_tokens: bool,
+    logprobs: bool,
+    top_logprobs: usize,
+    keep_cache: bool,
+    nan_guard: bool,
+    stop_token_ids: Vec<u32>,
+    stop_sequences: Vec<String>,
+    stopping_criteria: Vec<Box<dyn StoppingCriteria>>,
+}
+
+impl PipelineTextBuilder {
+    /// Creates a new `PipelineTextBuilder`.
+    pub fn new() -> Self {
+        Self {
+            temperature: 0.7,
+            guidance_scale: 1.0,
+            repeat_penalty: 1.0,
+            nan_guard: true,
+            add_generation_prompt: true,
+            autoload: true,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the model location.
+    pub fn with_location(mut self, location: &str) -> Self {
+        self.location = Some(location.to_string());
+        self
+    }
+
+    /// Sets a Hugging Face Hub repo to download the model from, used when no location is set.
+    ///
+    /// Accepts a repo ID like `meta-llama/Meta-Llama-3-8B-Instruct`, or a `HubRepo` selecting a
+    /// revision or a single GGUF file.
+    #[cfg(feature = "hub")]
+    pub fn with_hf_repo(mut self, repo: impl Into<HubRepo>) -> Self {
+        self.hf_repo = Some(repo.into());
+        self
+    }
+
+    /// Sets a callback receiving `(filename, bytes_downloaded, bytes_total)` during downloads.
+    #[cfg(feature = "hub")]
+    pub fn with_download_progress(
+        mut self,
+        callback: impl FnMut(&str, usize, usize) + Send + 'static,
+    ) -> Self {
+        self.download_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Sets the loader to use.
+    pub fn with_loader(mut self, loader: Arc<Mutex<dyn LoaderImpl>>) -> Self {
+        self.loader = Some(loader);
+        self
+    }
+
+    /// Sets format-specific options passed to the loader.
+    pub fn with_loader_options(mut self, loader_options: LoaderOptions) -> Self {
+        self.loader_options = Some(loader_options);
+        self
+    }
+
+    /// Sets a callback receiving `(bytes_loaded, bytes_total)` while the model loads.
+    pub fn with_load_progress(
+        mut self,
+        callback: impl Fn(u64, u64) + Send + Sync + 'static,
+    ) -> Self {
+        self.load_progress = Some(LoadProgress::new(callback));
+        self
+    }
+
+    /// Sets whether the model uses a KV cache, overriding `LoaderOptions::disable_kv_cache`.
+    ///
+    /// Disabling the cache makes every step recompute the whole sequence, slowing generation down
+    /// dramatically. Only meant for comparing cached and uncached outputs when debugging.
+    pub fn with_kv_cache(mut self, kv_cache: bool) -> Self {
+        self.loader_options
+            .get_or_insert_with(LoaderOptions::default)
+            .disable_kv_cache = !kv_cache;
+        self
+    }
+
+    /// Sets the device configuration.
+    pub fn with_device(mut self, device: DeviceConfig) -> Self {
+        self.device = Some(device);
+        self.device_str = None;
+        self
+    }
+
+    /// Sets the device from a string like `cpu`, `cuda:1` or `metal`.
+    ///
+    /// The string is parsed when building the pipeline, which fails for unknown or unavailable
+    /// devices.
+    pub fn with_device_str(mut self, device: &str) -> Self {
+        self.device_str = Some(device.to_string());
+        self.device = None;
+        self
+    }
+
+    /// Sets the temperature.
+    pub fn with_temperature(mut self, temperature: f64) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    /// Sets a schedule varying the temperature over generation.
+    pub fn with_temperature_schedule(mut self, temperature_schedule: TemperatureSchedule) -> Self {
+        self.temperature_schedule = Some(temperature_schedule);
+        self
+    }
+
+    /// Sets the seed, making runs reproducible instead of randomly seeded.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Sets the top-k value.
+    pub fn with_top_k(mut self, top_k: usize) -> Self {
+        self.top_k = Some(top_k);
+        self
+    }
+
+    /// Sets the top-p value.
+    pub fn with_top_p(mut self, top_p: f64) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    /// Sets the min-p value.
+    pub fn with_min_p(mut self, min_p: f64) -> Self {
+        self.min_p = Some(min_p);
+        self
+    }
+
+    /// Sets the negative prompt used for classifier-free guidance.
+    pub fn with_negative_prompt(mut self, negative_prompt: &str) -> Self {
+        self.negative_prompt = Some(negative_prompt.to_string());
+        self
+    }
+
+    /// Sets the classifier-free guidance scale.
+    pub fn with_guidance_scale(mut self, guidance_scale: f64) -> Self {
+        self.guidance_scale = guidance_scale;
+        self
+    }
+
+    /// Sets the repeat penalty (1.0 disables it).
+    pub fn with_repeat_penalty(mut self, repeat_penalty: f32) -> Self {
+        self.repeat_penalty = repeat_penalty;
+        self
+    }
+
+    /// Sets the number of most recent tokens the repeat penalty considers.
+    pub fn with_repeat_last_n(mut self, repeat_last_n: usize) -> Self {
+        self.repeat_last_n = Some(repeat_last_n);
+        self
+    }
+
+    /// Sets the frequency penalty (0.0 disables it).
+    pub fn with_frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.frequency_penalty = frequency_penalty;
+        self
+    }
+
+    /// Sets the presence penalty (0.0 disables it).
+    pub fn with_presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.presence_penalty = presence_penalty;
+        self
+    }
+
+    /// Stops generation once the output ends with the same `ngram_size` tokens repeated
+    /// `max_repeats` times in a row.
+    ///
+    /// `build` fails if `ngram_size` is zero or `max_repeats` is below 2.
+    pub fn with_repetition_stop(mut self, ngram_size: usize, max_repeats: usize) -> Self {
+        self.repetition_stop = Some((ngram_size, max_repeats));
+        self
+    }
+
+    /// Stops generation once it has run for longer than `timeout`, including prompt processing.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets whether a single leading space is stripped from the generated output.
+    pub fn with_strip_leading_space(mut self, strip_leading_space: bool) -> Self {
+        self.strip_leading_space = strip_leading_space;
+        self
+    }
+
+    /// Sets whether special tokens are included in streamed output.
+    pub fn with_stream_special_tokens(mut self, stream_special_tokens: bool) -> Self {
+        self.stream_special_tokens = stream_special_tokens;
+        self
+    }
+
+    /// Sets whether special tokens are included in the final generated output.
+    pub fn with_output_special_tokens(mut self, output_special_tokens: bool) -> Self {
+        self.output_special_tokens = output_special_tokens;
+        self
+    }
+
+    /// Sets whether special tokens are stripped from both streamed and final output, `true` by
+    /// default.
+    pub fn with_skip_special_tokens(mut self, skip_special_tokens: bool) -> Self {
+        self.stream_special_tokens = !skip_special_tokens;
+        self.output_special_tokens = !skip_special_tokens;
+        self
+    }
+
+    /// Sets the maximum number of prompt tokens accepted.
+    pub fn with_max_prompt_tokens(mut self, max_prompt_tokens: usize) -> Self {
+        self.max_prompt_tokens = Some(max_prompt_tokens);
+        self
+    }
+
+    /// Sets the maximum number of tokens to generate.
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Sets whether generation continues past the EOS token.
+    pub fn with_ignore_eos(mut self, ignore_eos: bool) -> Self {
+        self.ignore_eos = ignore_eos;
+        self
+    }
+
+    /// Sets whether the prompt is prepended to the generated output.
+    pub fn with_echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    /// Sets whether chat templates append the assistant header after the messages, `true` by
+    /// default.
+    pub fn with_add_generation_prompt(mut self, add_generation_prompt: bool) -> Self {
+        self.add_generation_prompt = add_generation_prompt;
+        self
+    }
+
+    /// Sets whether tokenizing a raw text prompt adds special tokens, `false` by default.
+    pub fn with_add_special_tokens(mut self, add_special_tokens: bool) -> Self {
+        self.add_special_tokens = add_special_tokens;
+        self
+    }
+
+    /// Sets whether generated tokens' logprobs are reported, see `PipelineText::set_logprobs`.
+    pub fn with_logprobs(mut self, logprobs: bool) -> Self {
+        self.logprobs = logprobs;
+        self
+    }
+
+    /// Sets the number of most likely alternatives reported with each logprob.
+    pub fn with_top_logprobs(mut self, top_logprobs: usize) -> Self {
+        self.top_logprobs = top_logprobs;
+        self
+    }
+
+    /// Sets whether the KV cache is kept after generation, see `PipelineText::set_keep_cache`.
+    pub fn with_keep_cache(mut self, keep_cache: bool) -> Self {
+        self.keep_cache = keep_cache;
+        self
+    }
+
+    /// Sets token IDs which stop generation as soon as one is produced, besides EOS.
+    pub fn with_stop_token_ids(mut self, stop_token_ids: Vec<u32>) -> Self {
+        self.stop_token_ids = stop_token_ids;
+        self
+    }
+
+    /// Adds a string which stops generation once it appears in the output.
+    pub fn with_stop_sequence(mut self, stop_sequence: impl Into<String>) -> Self {
+        self.stop_sequences.push(stop_sequence.into());
+        self
+    }
+
+    /// Adds a custom stopping criteria.
+    pub fn with_stopping_criteria(mut self, criteria: Box<dyn StoppingCriteria>) -> Self {
+        self.stopping_criteria.push(criteria);
+        self
+    }
+
+    /// Sets whether non-finite (NaN/Inf) logits are replaced before sampling.
+    pub fn with_nan_guard(mut self, nan_guard: bool) -> Self {
+        self.nan_guard = nan_guard;
+        self
+    }
+
+    /// Sets whether to autoload the model.
+    pub fn autoload(mut self, autoload: bool) -> Self {
+        self.autoload = autoload;
+        self
+    }
+
+    /// Builds the `PipelineText` instance.
+    pub fn build(self) -> Result<PipelineText, CallmError> {
+        if let Some((ngram_size, max_repeats)) = self.repetition_stop {
+            validate_repetition_stop(ngram_size, max_repeats)?;
+        }
+
+        #[cfg(feature = "hub")]
+        let location = match (self.location, self.hf_repo) {
+            (None, Some(repo)) if self.loader.is_none() => {
+                let mut progress = self.download_progress;
+                let path = download_model(&repo, progress.as_deref_mut())?;
+                Some(path.to_string_lossy().to_string())
+            }
+            (location, _) => location,
+        };
+        #[cfg(not(feature = "hub"))]
+        let location = self.location;
+
+        let mut pipeline = match self.loader {
+            Some(loader) => PipelineText::new(loader),
+            None => match location {
+                Some(location) => PipelineText::from_path(&location)?,
+                None => {
+                    return Err(CallmError::GenericError(
+                        "No location or loader specified. Use `with_location` or `with_loader`"
+                            .to_string(),
+                    ));
+                }
+            },
+        };
+
+        if let Some(loader_options) = self.loader_options {
+            pipeline.loader.lock().unwrap().set_options(loader_options);
+        }
+        if let Some(load_progress) = self.load_progress {
+            pipeline.loader.lock().unwrap().set_progress(load_progress);
+        }
+
+        pipeline.temperature = self.temperature;
+        pipeline.temperature_schedule = self.temperature_schedule;
+        pipeline.seed = self.seed;
+        pipeline.top_k = self.top_k;
+        pipeline.top_p = self.top_p;
+        pipeline.min_p = self.min_p;
+        pipeline.negative_prompt = self.negative_prompt;
+        pipeline.guidance_scale = self.guidance_scale;
+        pipeline.repeat_penalty = self.repeat_penalty;
+        pipeline.repeat_last_n = self.repeat_last_n;
+        pipeline.frequency_penalty = self.frequency_penalty;
+        pipeline.presence_penalty = self.presence_penalty;
+        pipeline.repetition_stop = self.repetition_stop;
+        pipeline.timeout = self.timeout;
+        pipeline.strip_leading_space = self.strip_leading_space;
+        pipeline.stream_special_tokens = self.stream_special_tokens;
+        pipeline.output_special_tokens = self.output_special_tokens;
+        pipeline.max_prompt_tokens = self.max_prompt_tokens;
+        pipeline.max_tokens = self.max_tokens;
+        pipeline.ignore_eos = self.ignore_eos;
+        pipeline.echo = self.echo;
+        pipeline.add_generation_prompt = self.add_generation_prompt;
+        pipeline.add_special_tokens = self.add_special_tokens;
+        pipeline.logprobs = self.logprobs;
+        pipeline.top_logprobs = self.top_logprobs;
+        pipeline.keep_cache = self.keep_cache;
+        pipeline.stop_token_ids = self.stop_token_ids;
+        pipeline.stop_sequences = self.stop_sequences;
+        pipeline.stopping_criteria = self.stopping_criteria;
+        pipeline.nan_guard = self.nan_guard;
+
+        if let Some(device_str) = self.device_str {
+            pipeline.device = Arc::new(device_str.parse()?);
+        }
+        if let Some(device) = self.device {
+            pipeline.device = Arc::new(device);
+        }
+
+        if self.autoload {
+            pipeline.load()?;
+        }
+
+        Ok(pipeline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use crate::loaders::mock::LoaderMock;
+    use crate::models::mock::ModelMock;
+
+    // token IDs of the mock tokenizer
+    const VOCAB_SIZE: usize = 6;
+    const EOS: u32 = 1;
+    const A: u32 = 2;
+    const B: u32 = 3;
+    const C: u32 = 4;
+    const D: u32 = 5;
+
+    fn mock_pipeline(model: ModelMock) -> PipelineText {
+        let mut pipeline = PipelineText::new(Arc::new(Mutex::new(LoaderMock::new(model))));
+        pipeline.set_temperature(0.0);
+        pipeline.load().unwrap();
+        pipeline
+    }
+
+    #[test]
+    fn test_mock_stops_at_eos() {
+        let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![A, B, EOS, C]));
+        assert_eq!(pipeline.run("a").unwrap(), "a b");
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::Eos));
+    }
+
+    #[test]
+    fn test_mock_ignore_eos() {
+        let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![A, EOS, C]));
+        pipeline.set_ignore_eos(true);
+        pipeline.set_max_tokens(4);
+        assert_eq!(pipeline.run("a").unwrap(), "a c c");
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::Length));
+    }
+
+    #[test]
+    fn test_mock_missing_eos() {
+        // without a declared EOS, generation runs up to the token limit
+        let loader =
+            LoaderMock::new(ModelMock::scripted(VOCAB_SIZE, vec![A, EOS, C])).with_eos_token(None);
+        let mut pipeline = PipelineText::new(Arc::new(Mutex::new(loader)));
+        pipeline.set_temperature(0.0);
+        pipeline.set_max_tokens(3);
+        pipeline.load().unwrap();
+        assert_eq!(pipeline.run("a").unwrap(), "a c");
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::Length));
+
+        // an EOS unknown to the tokenizer is an error
+        let loader =
+            LoaderMock::new(ModelMock::scripted(VOCAB_SIZE, vec![A])).with_eos_token(Some("</s>"));
+        let mut pipeline = PipelineText::new(Arc::new(Mutex::new(loader)));
+        pipeline.load().unwrap();
+        assert!(matches!(
+            pipeline.run("a"),
+            Err(CallmError::GenericError(_))
+        ));
+    }
+
+    #[test]
+    fn test_mock_tokenizer_built_once() {
+        let loader = Arc::new(Mutex::new(LoaderMock::new(ModelMock::scripted(
+            VOCAB_SIZE,
+            vec![A, EOS],
+        ))));
+        let mut pipeline = PipelineText::new(loader.clone());
+        pipeline.set_temperature(0.0);
+        pipeline.load().unwrap();
+        assert_eq!(pipeline.run("a").unwrap(), "a");
+        assert_eq!(pipeline.run("b").unwrap(), "a");
+        pipeline.clone_config().run("c").unwrap();
+        assert_eq!(loader.lock().unwrap().tokenizer_calls(), 1);
+    }
+
+    #[test]
+    fn test_mock_keep_cache() {
+        // the mock counts steps from the first prompt after the KV cache was cleared, so a
+        // reused cache continues the script where the previous generation stopped
+        let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![B, EOS, C, EOS]));
+        pipeline.set_keep_cache(true);
+        assert_eq!(pipeline.run("a").unwrap(), "b");
+        assert_eq!(pipeline.run("a b c").unwrap(), "c");
+
+        // a prompt not continuing the cached tokens starts from scratch
+        assert_eq!(pipeline.run("d").unwrap(), "b");
+
+        // disabling clears the cache
+        pipeline.set_keep_cache(false);
+        assert_eq!(pipeline.run("d b c").unwrap(), "b");
+    }
+
+    #[test]
+    fn test_mock_keep_cache_prefill() {
+        // tokens appended to the cache take one pass, or one per token without model support
+        for (model, suffix_passes) in [
+            (ModelMock::scripted(VOCAB_SIZE, vec![EOS]), 1),
+            (
+                ModelMock::scripted(VOCAB_SIZE, vec![EOS]).without_cached_prefill(),
+                3,
+            ),
+        ] {
+            let batch_sizes = model.batch_sizes();
+            let mut pipeline = mock_pipeline(model);
+            pipeline.set_keep_cache(true);
+            pipeline.run("a").unwrap();
+            let passes = batch_sizes.lock().unwrap().len();
+            pipeline.run("a b c d").unwrap();
+            assert_eq!(batch_sizes.lock().unwrap().len() - passes, suffix_passes);
+        }
+    }
+
+    #[test]
+    fn test_mock_max_tokens() {
+        let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![A, B, C, D]));
+        pipeline.set_max_tokens(2);
+        assert_eq!(pipeline.run("a").unwrap(), "a b");
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::Length));
+    }
+
+    #[test]
+    fn test_mock_context_length() {
+        let loader =
+            LoaderMock::new(ModelMock::scripted(VOCAB_SIZE, vec![B])).with_context_length(4);
+        let mut pipeline = PipelineText::new(Arc::new(Mutex::new(loader)));
+        pipeline.set_temperature(0.0);
+        pipeline.load().unwrap();
+
+        // the generation budget is what the prompt leaves of the context
+        assert_eq!(pipeline.run("a a").unwrap(), "b b");
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::Length));
+
+        assert!(matches!(
+            pipeline.run("a a a a a"),
+            Err(CallmError::PromptTooLong {
+                tokens: 5,
+                limit: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn test_mock_clone_config() {
+        let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![A, B, C, D]));
+        pipeline.set_max_tokens(2);
+        let mut sibling = pipeline.clone_config();
+        sibling.set_max_tokens(3);
+
+        assert!(Arc::ptr_eq(
+            pipeline.model.as_ref().unwrap(),
+            sibling.model.as_ref().unwrap()
+        ));
+        assert_eq!(pipeline.run("a").unwrap(), "a b");
+        assert_eq!(sibling.run("a").unwrap(), "a b c");
+    }
+
+    #[test]
+    fn test_mock_model_stop_token_ids() {
+        let model = ModelMock::scripted(VOCAB_SIZE, vec![A, D, B]);
+        let loader = LoaderMock::new(model).with_stop_token_ids(vec![D]);
+        let mut pipeline = PipelineText::new(Arc::new(Mutex::new(loader)));
+        pipeline.set_temperature(0.0);
+        pipeline.set_max_tokens(3);
+        pipeline.load().unwrap();
+        assert_eq!(pipeline.run("a").unwrap(), "a d");
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::Eos));
+
+        // model-declared stop tokens are ignored along with EOS
+        pipeline.set_ignore_eos(true);
+        assert_eq!(pipeline.run("a").unwrap(), "a d b");
+    }
+
+    #[test]
+    fn test_mock_run_cancellable() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let model_cancel = Arc::clone(&cancel);
+        let model = ModelMock::new(VOCAB_SIZE, move |_, step| {
+            // cancel while the third token is being generated
+            if step == 2 {
+                model_cancel.store(true, Ordering::Relaxed);
+            }
+            vec![0.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+        });
+        let mut pipeline = mock_pipeline(model);
+        pipeline.set_max_tokens(5);
+
+        assert_eq!(
+            pipeline.run_cancellable("a", Arc::clone(&cancel)).unwrap(),
+            "c c c"
+        );
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::Cancelled));
+
+        // the KV cache got cleared, so the next run starts over
+        cancel.store(false, Ordering::Relaxed);
+        pipeline.set_max_tokens(2);
+        assert_eq!(pipeline.run_cancellable("a", cancel).unwrap(), "c c");
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::Length));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_mock_run_async() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![A, B, EOS]));
+        pipeline.set_max_tokens(5);
+
+        let text = runtime.block_on(pipeline.run_async("a")).unwrap();
+        assert_eq!(text, "a b");
+
+        let chunks: Vec<String> = runtime.block_on(async {
+            let mut rx = pipeline.run_stream("a");
+            let mut chunks = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                chunks.push(chunk.unwrap());
+            }
+            chunks
+        });
+        assert_eq!(chunks.concat(), "a b");
+    }
+
+    #[test]
+    fn test_mock_run_batch() {
+        let model = ModelMock::new(VOCAB_SIZE, |tokens, step| {
+            // echo the first prompt token once, then stop
+            let token = if step == 0 { tokens[0] } else { EOS };
+            (0..VOCAB_SIZE)
+                .map(|id| if id == token as usize { 1.0 } else { 0.0 })
+                .collect()
+        });
+        let batch_sizes = model.batch_sizes();
+        let mut pipeline = mock_pipeline(model);
+        assert_eq!(
+            pipeline.run_batch(&["b", "c d", "a"]).unwrap(),
+            vec!["b", "c", "a"]
+        );
+        // "b" and "a" share a batch, "c d" runs on its own
+        assert_eq!(*batch_sizes.lock().unwrap(), vec![2, 2, 1, 1]);
+        assert!(pipeline.run_batch(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_mock_run_batch_rows_finish_apart() {
+        let model = ModelMock::new(VOCAB_SIZE, |tokens, step| {
+            // "a" is answered with one token, "b" with three
+            let script = match tokens[0] {
+                A => vec![A, EOS],
+                _ => vec![B, C, D, EOS],
+            };
+            let token = script[step.min(script.len() - 1)] as usize;
+            (0..VOCAB_SIZE)
+                .map(|id| if id == token { 1.0 } else { 0.0 })
+                .collect()
+        });
+        let batch_sizes = model.batch_sizes();
+        let mut pipeline = mock_pipeline(model);
+        pipeline.set_max_tokens(3);
+        assert_eq!(pipeline.run_batch(&["a", "b"]).unwrap(), vec!["a", "b c d"]);
+        // the batch runs until its longest sequence hits the token limit
+        assert_eq!(*batch_sizes.lock().unwrap(), vec![2, 2, 2]);
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::Length));
+    }
+
+    #[test]
+    fn test_mock_without_kv_cache() {
+        let model = ModelMock::scripted(VOCAB_SIZE, vec![A, B, EOS]).without_kv_cache();
+        let mut pipeline = mock_pipeline(model);
+        assert_eq!(pipeline.run("a").unwrap(), "a b");
+    }
+
+    // records the text passed on every step
+    struct TextRecorder {
+        uses_text: bool,
+        texts: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl StoppingCriteria for TextRecorder {
+        fn should_stop(&mut self, _tokens: &[u32], text: &str) -> Option<FinishReason> {
+            self.texts.lock().unwrap().push(text.to_string());
+            None
+        }
+
+        fn uses_text(&self) -> bool {
+            self.uses_text
+        }
+    }
+
+    #[test]
+    fn test_mock_stopping_criteria_text() {
+        for uses_text in [true, false] {
+            let texts = Arc::new(Mutex::new(Vec::new()));
+            let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![A, B, EOS]));
+            pipeline.add_stopping_criteria(Box::new(TextRecorder {
+                uses_text,
+                texts: Arc::clone(&texts),
+            }));
+            assert_eq!(pipeline.run("a").unwrap(), "a b");
+
+            // the output is not decoded when no criteria reads it, EOS stops before the last step
+            let expected = if uses_text {
+                vec!["a", "a b"]
+            } else {
+                vec!["", ""]
+            };
+            assert_eq!(*texts.lock().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_mock_stop_sequence() {
+        let model = ModelMock::scripted(VOCAB_SIZE, vec![A, B, C, D, EOS]);
+        let mut pipeline = mock_pipeline(model);
+        // the stop sequence spans two tokens and is trimmed from the output
+        pipeline.set_stop_sequences(vec!["b c".to_string()]);
+
+        let mut streamed = Vec::new();
+        let output = pipeline
+            .run_with_callback("a", |text| {
+                streamed.push(text.to_string());
+                true
+            })
+            .unwrap();
+        assert_eq!(output, "a ");
+        assert_eq!(streamed.concat(), "a ");
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::StopSequence));
+    }
+
+    #[test]
+    fn test_mock_seed() {
+        let model = ModelMock::new(VOCAB_SIZE, |_, _| vec![-100.0, -100.0, 1.0, 1.0, 1.0, 1.0]);
+        let mut pipeline = mock_pipeline(model);
+        pipeline.set_temperature(1.0);
+        pipeline.set_max_tokens(32);
+
+        // unseeded runs draw fresh seeds
+        assert_ne!(pipeline.run("a").unwrap(), pipeline.run("a").unwrap());
+
+        pipeline.set_seed(42);
+        let output = pipeline.run("a").unwrap();
+        assert_eq!(pipeline.run("a").unwrap(), output);
+    }
+
+    #[test]
+    fn test_mock_getters_with_poisoned_loader() {
+        let loader = Arc::new(Mutex::new(LoaderMock::new(ModelMock::scripted(
+            VOCAB_SIZE,
+            vec![EOS],
+        ))));
+        let pipeline = PipelineText::new(loader.clone());
+        assert!(pipeline.model_info().unwrap().is_none());
+
+        let _ = std::thread::spawn(move || {
+            let _guard = loader.lock().unwrap();
+            panic!("request failed");
+        })
+        .join();
+        assert!(pipeline.model_info().is_err());
+        assert!(pipeline.adds_prefix_space().is_err());
+    }
+
+    #[test]
+    fn test_mock_run_detailed() {
+        let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![A, B, EOS]));
+        let result = pipeline.run_detailed("a b").unwrap();
+        assert_eq!(result.text, "a b");
+        assert_eq!(result.tokens, vec![A, B, EOS]);
+        assert_eq!(result.finish_reason, FinishReason::Eos);
+        assert_eq!(result.prompt_tokens, 2);
+        assert_eq!(result.completion_tokens, 3);
+        assert!(result.elapsed.as_secs_f64() >= result.prompt_secs);
+
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["finish_reason"], "eos");
+        assert_eq!(json["completion_tokens"], 3);
+        assert_eq!(json["elapsed_secs"], result.elapsed.as_secs_f64());
+        assert!(json.get("elapsed").is_none());
+    }
+
+    #[test]
+    fn test_mock_stop_token_ids() {
+        let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![A, C, B, EOS]));
+        pipeline.set_stop_token_ids(vec![C]);
+        assert_eq!(pipeline.run("a").unwrap(), "a c");
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::StopToken(C)));
+    }
+
+    #[test]
+    fn test_mock_repetition_stop() {
+        let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![A, B, A, B, A, B]));
+        pipeline.set_repetition_stop(2, 2).unwrap();
+        assert_eq!(pipeline.run("a").unwrap(), "a b a b");
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::Repetition));
+
+        assert!(pipeline.set_repetition_stop(0, 2).is_err());
+        assert!(pipeline.set_repetition_stop(2, 1).is_err());
+        let result = PipelineText::builder()
+            .with_loader(Arc::new(Mutex::new(LoaderMock::new(ModelMock::scripted(
+                VOCAB_SIZE,
+                vec![EOS],
+            )))))
+            .with_repetition_stop(2, 1)
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_mock_timeout() {
+        let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![A, B, EOS]));
+        pipeline.set_timeout(Duration::ZERO);
+        assert_eq!(pipeline.run("a").unwrap(), "a");
+        assert_eq!(pipeline.finish_reason(), Some(FinishReason::Timeout));
+    }
+
+    #[test]
+    fn test_mock_repeat_penalty() {
+        // `a` always scores highest, followed closely by `b`
+        let model = || ModelMock::new(VOCAB_SIZE, |_, _| vec![0.0, 0.0, 2.0, 1.5, 0.0, 0.0]);
+
+        let mut pipeline = mock_pipeline(model());
+        pipeline.set_max_tokens(3);
+        assert_eq!(pipeline.run("a").unwrap(), "a a a");
+
+        // penalized `a` drops to 1.0, then `b` drops to 0.75 once generated
+        let mut pipeline = mock_pipeline(model());
+        pipeline.set_max_tokens(3);
+        pipeline.set_repeat_penalty(2.0);
+        assert_eq!(pipeline.run("a").unwrap(), "b a a");
+    }
+
+    #[test]
+    fn test_mock_presence_penalty() {
+        let model = ModelMock::new(VOCAB_SIZE, |_, _| vec![0.0, 0.0, 2.0, 1.5, 1.2, 0.0]);
+        let mut pipeline = mock_pipeline(model);
+        pipeline.set_max_tokens(3);
+        pipeline.set_presence_penalty(1.0);
+        // unlike the repeat penalty, the prompt is not penalized
+        assert_eq!(pipeline.run("a").unwrap(), "a b c");
+    }
+
+    #[test]
+    fn test_mock_min_p() {
+        let model = ModelMock::new(VOCAB_SIZE, |_, _| vec![0.0, 0.0, 2.0, 1.5, 1.2, 0.0]);
+        let mut pipeline = mock_pipeline(model);
+        // a near-uniform distribution collapses to greedy once min-p leaves a single token
+        pipeline.set_temperature(100.0);
+        pipeline.set_min_p(0.9);
+        pipeline.set_max_tokens(3);
+        assert_eq!(pipeline.run("a").unwrap(), "a a a");
+    }
+
+    #[test]
+    fn test_mock_repeat_last_n() {
+        let model = ModelMock::new(VOCAB_SIZE, |_, _| vec![0.0, 0.0, 2.0, 1.5, 0.0, 0.0]);
+        let mut pipeline = PipelineText::builder()
+            .with_loader(Arc::new(Mutex::new(LoaderMock::new(model))))
+            .with_temperature(0.0)
+            .with_max_tokens(3)
+            .with_repeat_penalty(2.0)
+            .with_repeat_last_n(1)
+            .build()
+            .unwrap();
+
+        // only the last token gets penalized, so `a` and `b` alternate
+        assert_eq!(pipeline.run("a").unwrap(), "b a b");
+    }
+
+    #[test]
+    fn test_mock_with_device_str() {
+        let build = |device: &str| {
+            PipelineText::builder()
+                .with_loader(Arc::new(Mutex::new(LoaderMock::new(ModelMock::scripted(
+                    VOCAB_SIZE,
+                    vec![EOS],
+                )))))
+                .with_device_str(device)
+                .build()
+        };
+        assert_eq!(build("cpu").unwrap().device().device(), &Device::CPU);
+        assert!(build("tpu").is_err());
+    }
+
+    #[test]
+    fn test_mock_complete() {
+        let mut pipeline = PipelineText::builder()
+            .with_loader(Arc::new(Mutex::new(
+                LoaderMock::new(ModelMock::scripted(VOCAB_SIZE, vec![A, B, EOS]))
+                    .with_chat_template("<{{ messages[0]['content'] }}>"),
+            )))
+            .with_temperature(0.0)
+            .with_add_special_tokens(true)
+            .build()
+            .unwrap();
+        assert!(pipeline.add_special_tokens);
+        // the prompt is not passed through the chat template
+        assert_eq!(pipeline.complete("c d", false, false).unwrap(), "a b");
+        assert_eq!(pipeline.count_tokens("c d").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_mock_add_special_tokens_bos() {
+        // the mock tokenizer has no post-processor, so the template BOS is prepended
+        let loader =
+            LoaderMock::new(ModelMock::scripted(VOCAB_SIZE, vec![EOS])).with_bos_token(Some("d"));
+        let mut pipeline = PipelineText::new(Arc::new(Mutex::new(loader)));
+        pipeline.load().unwrap();
+        assert_eq!(pipeline.count_tokens("a b").unwrap(), 2);
+
+        pipeline.set_add_special_tokens(true);
+        assert_eq!(pipeline.count_tokens("a b").unwrap(), 3);
+        // a prompt starting with BOS does not get a second one
+        assert_eq!(pipeline.count_tokens("d a b").unwrap(), 3);
+        assert_eq!(pipeline.run_detailed("a b").unwrap().prompt_tokens, 3);
+    }
+
+    #[test]
+    fn test_mock_special_tokens_raw_prompts_only() {
+        let loader = LoaderMock::new(ModelMock::scripted(VOCAB_SIZE, vec![EOS]))
+            .with_bos_token(Some("d"))
+            .with_chat_template("{{ messages[0]['content'] }}");
+        let mut pipeline = PipelineText::new(Arc::new(Mutex::new(loader)));
+        pipeline.load().unwrap();
+        pipeline.set_add_special_tokens(true);
+        assert_eq!(pipeline.run_detailed("a b").unwrap().prompt_tokens, 3);
+
+        // templated prompts never get the BOS token of the tokenizer
+        let messages = [ChatMessage::from(&(MessageRole::User, "a b".to_string()))];
+        let result = pipeline.run_chat_detailed(&messages).unwrap();
+        assert_eq!(result.prompt_tokens, 2);
+    }
+
+    #[test]
+    fn test_mock_complete_special_tokens() {
+        // emits the token whose ID is the prompt length
+        let model = ModelMock::new(VOCAB_SIZE, |tokens, step| {
+            let token = if step == 0 {
+                tokens.len()
+            } else {
+                EOS as usize
+            };
+            (0..VOCAB_SIZE)
+                .map(|id| if id == token { 1.0 } else { 0.0 })
+                .collect()
+        });
+        let loader = LoaderMock::new(model).with_bos_token(Some("d"));
+        let mut pipeline = PipelineText::new(Arc::new(Mutex::new(loader)));
+        pipeline.set_temperature(0.0);
+        pipeline.load().unwrap();
+
+        // "a b" is two tokens, the global setting does not apply
+        pipeline.set_add_special_tokens(true);
+        assert_eq!(pipeline.complete("a b", false, false).unwrap(), "a");
+        pipeline.set_add_special_tokens(false);
+        assert_eq!(pipeline.complete("a b", true, false).unwrap(), "b");
+        assert_eq!(pipeline.complete("a b", true, true).unwrap(), "c");
+        assert_eq!(pipeline.complete("d a b", true, false).unwrap(), "b");
+    }
+
+    #[test]
+    fn test_mock_skip_special_tokens() {
+        let mut pipeline = mock_pipeline(ModelMock::scripted(VOCAB_SIZE, vec![A, EOS]));
+        assert_eq!(pipeline.run("a").unwrap(), "a");
+
+        pipeline.set_skip_special_tokens(false);
+        let mut streamed = Vec::new();
+        pipeline.run_to_writer("a", &mut streamed).unwrap();
+        assert_eq!(
+            String::from_utf8(streamed).unwrap(),
+            pipeline.run("a").unwrap()
+        );
+        assert_eq!(pipeline.run("a").unwrap(), "a <eos>");
+    }
+
+    #[test]
+    fn test_mock_logprobs() {
+        let logits = [0.0, 0.0, 2.0, 1.0, 0.0, 0.0];
+        let mut pipeline = mock_pipeline(ModelMock::new(VOCAB_SIZE, move |_, _| logits.to_vec()));
+        pipeline.set_max_tokens(2);
+        assert!(pipeline.run_detailed("a").unwrap().logprobs.is_none());
+
+        pipeline.set_logprobs(true);
+        pipeline.set_top_logprobs(2);
+        let result = pipeline.run_detailed("a").unwrap();
+        let log_sum_exp = logits.iter().map(|l: &f32| l.exp()).sum::<f32>().ln();
+        let logprobs = result.logprobs.unwrap();
+        assert_eq!(logprobs.len(), result.tokens.len());
+        assert!((logprobs[0] - (2.0 - log_sum_exp)).abs() < 1e-5);
+
+        let top_logprobs = result.top_logprobs.unwrap();
+        assert_eq!(top_logprobs.len(), 2);
+        assert_eq!(
+            top_logprobs[0]
+                .iter()
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>(),
+            vec![A, B]
+        );
+        assert!((top_logprobs[0][1].1 - (1.0 - log_sum_exp)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_mock_negative_prompt() {
+        // logits depend on the first token, telling the prompt and negative prompt apart
+        let cond = [0.0, 0.0, 2.0, 1.0, 0.0, 0.0];
+        let uncond = [0.0, 0.0, 1.0, 0.0, 3.0, 0.0];
+        let model = ModelMock::new(VOCAB_SIZE, move |tokens, _| match tokens[0] {
+            A => cond.to_vec(),
+            _ => uncond.to_vec(),
+        });
+        let mut pipeline = mock_pipeline(model);
+        pipeline.set_max_tokens(2);
+        pipeline.set_logprobs(true);
+        pipeline.set_negative_prompt(Some("c"));
+        pipeline.set_guidance_scale(2.0);
+
+        // guided = uncond + 2 * (cond - uncond) = [0, 0, 3, 2, -3, 0]
+        let guided: Vec<f32> = cond
+            .iter()
+            .zip(uncond)
+            .map(|(c, u)| u + 2.0 * (c - u))
+            .collect();
+        let log_sum_exp = guided.iter().map(|l| l.exp()).sum::<f32>().ln();
+        let result = pipeline.run_detailed("a").unwrap();
+        assert_eq!(result.tokens, vec![A, A]);
+        for logprob in result.logprobs.unwrap() {
+            assert!((logprob - (3.0 - log_sum_exp)).abs() < 1e-5);
+        }
+
+        pipeline.set_negative_prompt(Some(""));
+        assert!(pipeline.run("a").is_err());
+        pipeline.set_negative_prompt(None);
+        assert_eq!(pipeline.run_detailed("a").unwrap().tokens, vec![A, A]);
+    }
+
+    #[test]
+    fn test_top_n_logprobs() {
+        assert_eq!(
+            top_n_logprobs(&[-3.0, -0.5, -2.0, -1.0], 3),
+            vec![(1, -0.5), (3, -1.0), (2, -2.0)]
+        );
+        assert_eq!(top_n_logprobs(&[-1.0, -0.5], 5), vec![(1, -0.5), (0, -1.0)]);
+    }
+
+    #[test]
+    fn test_mock_temperature_schedule() {
+        let model = ModelMock::new(VOCAB_SIZE, |_, _| vec![0.0, 0.0, 2.0, 1.5, 0.0, 0.0]);
+        let mut pipeline = mock_pipeline(model);
+        // a schedule cooling down to greedy sampling overrides the high fixed temperature
+        pipeline.set_temperature(100.0);
+        pipeline.set_temperature_schedule(TemperatureSchedule::Custom(Arc::new(|_, _| 0.0)));
+        pipeline.set_max_tokens(3);
+        assert_eq!(pipeline.run("a").unwrap(), "a a a");
+    }
+
+    #[test]
+    fn test_temperature_schedule() {
+        let schedule = TemperatureSchedule::Linear {
+            start: 1.0,
+            end: 0.2,
+        };
+        assert_eq!(schedule.temperature(0, 5), 1.0);
+        assert!((schedule.temperature(2, 5) - 0.6).abs() < 1e-9);
+        assert!((schedule.temperature(4, 5) - 0.2).abs() < 1e-9);
+        assert!((schedule.temperature(9, 5) - 0.2).abs() < 1e-9);
+        assert_eq!(schedule.temperature(0, 1), 1.0);
+
+        let schedule = TemperatureSchedule::Custom(Arc::new(|step, _| 1.0 / (step + 1) as f64));
+        assert_eq!(schedule.temperature(3, 10), 0.25);
+    }
+
+    #[test]
+    fn test_default_repeat_last_n() {
+        assert_eq!(default_repeat_last_n(512), 128);
+        assert_eq!(default_repeat_last_n(4096), 256);
+        assert_eq!(default_repeat_last_n(usize::MAX), 256);
+    }
+
+    #[test]
+    fn test_tokens_per_second() {
+        assert_eq!(tokens_per_second(50, Duration::from_secs(2)), 25.0);
+        assert_eq!(tokens_per_second(50, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn test_default_max_tokens() {
+        assert_eq!(default_max_tokens(4096, 96), 4000);
+        assert_eq!(default_max_tokens(512, 600), 0);
+    }
+
+    #[test]
+    fn test_lock_serialized_poisoned() {
+        let mutex = Arc::new(Mutex::new(0));
+        assert!(lock_serialized(mutex.as_ref(), "Test").is_ok());
+
+        let poisoner = Arc::clone(&mutex);
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poison");
+        })
+        .join();
+        assert!(lock_serialized(mutex.as_ref(), "Test").is_err());
+    }
+
+    #[test]
+    fn test_ends_with_incomplete_utf8() {
+        assert!(!ends_with_incomplete_utf8(b""));
+        assert!(!ends_with_incomplete_utf8("abé".as_bytes()));
+        assert!(ends_with_incomplete_utf8(b"ab\xC3"));
+        assert!(ends_with_incomplete_utf8(b"ab\xF0\x9F\x98"));
+        assert!(!ends_with_incomplete_utf8(b"ab\xFF"));
+    }
+
+    #[test]
+    fn test_stop_sequence_buffer() {
+        let stops = vec!["</s>".to_string()];
+        let mut buffer = StopSequenceBuffer::default();
+        assert_eq!(buffer.push(b"Hello", &stops), b"Hello");
+        // a partial match is held back until resolved
+        assert_eq!(buffer.push(b" <", &stops), b" ");
+        assert_eq!(buffer.push(b"b>", &stops), b"<b>");
+        assert_eq!(buffer.push(b" </", &stops), b" ");
+        assert_eq!(buffer.push(b"s> bye", &stops), b"");
+        assert_eq!(buffer.push(b"more", &stops), b"");
+        assert!(buffer.finish().is_empty());
+
+        let mut buffer = StopSequenceBuffer::default();
+        assert_eq!(buffer.push(b"a</", &stops), b"a");
+        assert_eq!(buffer.finish(), b"</");
+
+        let mut buffer = StopSequenceBuffer::default();
+        assert_eq!(buffer.push(b"abc", &[]), b"abc");
+    }
+
+    #[test]
+    fn test_utf8_buffer() {
+        let mut buffer = Utf8Buffer::default();
+        // 'é' is encoded as bytes 0xC3 0xA9
+        assert_eq!(buffer.push(b"a\xC3"), "a");
+        assert_eq!(buffer.push(b"\xA9b"), "éb");
+        assert_eq!(buffer.push(b"\xFFc"), "\u{FFFD}c");
+        assert_eq!(buffer.push(b"\xC3"), "");
+        assert_eq!(buffer.finish(), "\u{FFFD}");
+    }
+
+    #[test]
+    fn test_apply_frequency_presence_penalty() {
+        let device = candle_core::Device::Cpu;
+        let logits = Tensor::new(&[1.0f32, 1.0, 1.0, 1.0], &device).unwrap();
+        let penalized = apply_frequency_presence_penalty(&logits, 0.5, 0.25, &[1, 2, 2, 7])
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(penalized, vec![1.0, 0.25, -0.25, 1.0]);
+    }
+
+    #[test]
+    fn test_apply_min_p() {
+        let device = candle_core::Device::Cpu;
+        let logits = Tensor::new(&[2.0f32, 1.5, 0.0, 2.0f32.ln()], &device).unwrap();
+
+        // 0.4 keeps tokens at least 40% as likely as the top one, i.e. e^-0.5 ~ 0.61
+        let (filtered, kept) = apply_min_p(&logits, 0.4).unwrap();
+        assert_eq!(kept, 2);
+        assert_eq!(
+            filtered.to_vec1::<f32>().unwrap(),
+            vec![2.0, 1.5, f32::NEG_INFINITY, f32::NEG_INFINITY]
+        );
+
+        let (_, kept) = apply_min_p(&logits, 0.9).unwrap();
+        assert_eq!(kept, 1);
+    }
+
+    #[test]
+    fn test_replace_non_finite_logits() {
+        let device = candle_core::Device::Cpu;
+        let logits = Tensor::new(&[1.0f32, f32::NAN, f32::INFINITY], &device).unwrap();
+        let sanitized = replace_non_finite_logits(&logits).unwrap().unwrap();
+        assert_eq!(
+            sanitized.to_vec1::<f32>().unwrap(),
+            vec![1.0, NON_FINITE_LOGIT, NON_FINITE_LOGIT]
+        );
+
+        let logits = Tensor::new(&[1.0f32, 2.0], &device).unwrap();
+        assert!(replace_non_finite_logits(&logits).unwrap().is_none());
     }
 }
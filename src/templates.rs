@@ -3,13 +3,32 @@
 pub mod dummy;
 pub use dummy::TemplateDummy;
 pub mod jinja;
-pub use jinja::TemplateJinja;
+pub use jinja::{SystemMessageMode, TemplateJinja};
 
 use crate::error::CallmError;
 use std::fmt;
 
+/// Renders a Jinja chat template for the given messages, without loading a model.
+///
+/// Returns `CallmError::TemplateError` if the template fails to compile.
+pub fn apply(
+    template: &str,
+    messages: &[(MessageRole, String)],
+    bos_token: Option<&str>,
+    eos_token: Option<&str>,
+) -> Result<String, CallmError> {
+    let mut template = TemplateJinja::try_new(template)?;
+    template.set_bos_token(bos_token.map(String::from));
+    template.set_eos_token(eos_token.map(String::from));
+    template.apply(messages)
+}
+
 /// A trait defining the interface for template implementations.
-pub trait TemplateImpl {
+///
+/// Implementations have to provide `apply`. Templates reading the optional message fields (like
+/// `ChatMessage::name`) override `apply_messages` as well, which otherwise drops them. Templates have to be `Send`, as the text pipeline holds its template across threads, e.g.
+/// in `PipelineText::run_async`.
+pub trait TemplateImpl: Send {
     /// Returns the beginning-of-sequence (BOS) token.
     fn get_bos_token(&self) -> Option<&str>;
 
@@ -24,6 +43,45 @@ pub trait TemplateImpl {
 
     /// Applies the template to the given messages and returns the formatted string.
     fn apply(&self, messages: &[(MessageRole, String)]) -> Result<String, CallmError>;
+
+    /// Applies the template to the given structured messages and returns the formatted string.
+    ///
+    /// Defaults to `apply`, dropping the optional message fields.
+    fn apply_messages(&self, messages: &[ChatMessage]) -> Result<String, CallmError> {
+        let messages: Vec<(MessageRole, String)> = messages
+            .iter()
+            .map(|message| (message.role.clone(), message.content.clone()))
+            .collect();
+        self.apply(&messages)
+    }
+
+    /// Applies the template with extra variables, e.g. a `tools` array for function calling.
+    ///
+    /// `extra` has to be a JSON object (or `null`), its keys are merged into the template context
+    /// next to `messages`. Built-in variables take precedence over extra keys of the same name.
+    /// Templates without variables ignore the extras.
+    fn apply_with_context(
+        &self,
+        messages: &[ChatMessage],
+        extra: serde_json::Value,
+    ) -> Result<String, CallmError> {
+        let _ = extra;
+        self.apply_messages(messages)
+    }
+
+    /// Sets whether applying the template appends a generation prompt.
+    ///
+    /// Only Jinja templates honor this, enabled by default, other templates ignore it.
+    fn set_add_generation_prompt(&mut self, add_generation_prompt: bool) {
+        let _ = add_generation_prompt;
+    }
+
+    /// Returns whether applying the template appends a generation prompt.
+    ///
+    /// Non-Jinja templates have no generation prompt and always report `false`.
+    fn adds_generation_prompt(&self) -> bool {
+        false
+    }
 }
 
 /// An enum representing the roles in a message exchange.
@@ -35,6 +93,12 @@ pub enum MessageRole {
     User,
     /// The assistant role.
     Assistant,
+    /// A tool result, usually paired with `ChatMessage::tool_call_id`.
+    Tool,
+    /// A function result, used by templates predating the `tool` role.
+    Function,
+    /// A pre-formatted prompt, passed through `PipelineText::run_chat` without templating.
+    Raw,
 }
 
 impl fmt::Display for MessageRole {
@@ -44,7 +108,63 @@ impl fmt::Display for MessageRole {
             MessageRole::System => write!(f, "system"),
             MessageRole::User => write!(f, "user"),
             MessageRole::Assistant => write!(f, "assistant"),
+            MessageRole::Tool => write!(f, "tool"),
+            MessageRole::Function => write!(f, "function"),
+            MessageRole::Raw => write!(f, "raw"),
         }
     }
 }
 
+/// A chat message with optional participant metadata.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatMessage {
+    /// The role of the message author.
+    pub role: MessageRole,
+    /// The message content.
+    pub content: String,
+    /// Optional participant name, exposed to templates as `message['name']`.
+    pub name: Option<String>,
+    /// Optional tool call ID, exposed to templates as `message['tool_call_id']`.
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    /// Creates a new `ChatMessage` with the given role and content.
+    pub fn new(role: MessageRole, content: &str) -> Self {
+        Self {
+            role,
+            content: content.to_string(),
+            name: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// Sets the participant name.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Sets the tool call ID.
+    pub fn with_tool_call_id(mut self, tool_call_id: &str) -> Self {
+        self.tool_call_id = Some(tool_call_id.to_string());
+        self
+    }
+}
+
+impl From<(MessageRole, String)> for ChatMessage {
+    fn from((role, content): (MessageRole, String)) -> Self {
+        Self {
+            role,
+            content,
+            name: None,
+            tool_call_id: None,
+        }
+    }
+}
+
+impl From<&(MessageRole, String)> for ChatMessage {
+    fn from((role, content): &(MessageRole, String)) -> Self {
+        Self::new(role.clone(), content)
+    }
+}
@@ -1,5 +1,6 @@
 /// Dummy template that does not process text in any way
 /// Only first message content is taken as input
+use super::ChatMessage;
 use super::MessageRole;
 use super::TemplateImpl;
 use crate::error::CallmError;
@@ -20,13 +21,18 @@ impl TemplateDummy {
 
 impl TemplateImpl for TemplateDummy {
     fn apply(&self, messages: &[(MessageRole, String)]) -> Result<String, CallmError> {
+        let messages: Vec<ChatMessage> = messages.iter().map(ChatMessage::from).collect();
+        self.apply_messages(&messages)
+    }
+
+    fn apply_messages(&self, messages: &[ChatMessage]) -> Result<String, CallmError> {
         if messages.is_empty() {
             return Err(CallmError::TemplateError(
                 "Error applying template (no messages passed in)".to_string(),
             ));
         }
 
-        Ok(messages[0].1.clone())
+        Ok(messages[0].content.clone())
     }
 
     fn get_bos_token(&self) -> Option<&str> {
@@ -1,36 +1,167 @@
+use super::ChatMessage;
 use super::MessageRole;
 use super::TemplateImpl;
 use crate::error::CallmError;
-use minijinja::{context, render};
+use chrono::format::{Item, StrftimeItems};
+use minijinja::{Environment, Error, ErrorKind, Value};
+use std::collections::BTreeMap;
+
+/// How a leading system message is passed to the template.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SystemMessageMode {
+    /// Use `Variable` if the template references `system_message`, `Messages` otherwise.
+    #[default]
+    Auto,
+    /// Keep the system message in the `messages` list.
+    Messages,
+    /// Pass the system message content as the `system_message` variable, removing it from the
+    /// `messages` list.
+    Variable,
+}
+
+// name of the chat template in the environment
+const TEMPLATE_NAME: &str = "chat";
 
 #[derive(Clone, Debug, Default)]
 pub struct TemplateJinja {
     template: String,
+    env: Environment<'static>,
     bos_token: Option<String>,
     eos_token: Option<String>,
     add_generation_prompt: bool,
+    system_message_mode: SystemMessageMode,
 }
 
 impl TemplateJinja {
     pub fn new(template: &str) -> Self {
-        Self {
+        // a template failing to compile reports its error on apply
+        Self::try_new(template).unwrap_or_else(|e| {
+            log::debug!("{}", e);
+            Self {
+                template: template.to_string(),
+                env: environment(),
+                add_generation_prompt: true,
+                ..Default::default()
+            }
+        })
+    }
+
+    /// Creates a new `TemplateJinja`, validating the template syntax up front.
+    ///
+    /// Returns `CallmError::TemplateError` if the template fails to compile.
+    pub fn try_new(template: &str) -> Result<Self, CallmError> {
+        // compile once, reused by every apply
+        let mut env = environment();
+        env.add_template_owned(TEMPLATE_NAME, template.to_string())
+            .map_err(|e| CallmError::TemplateError(e.to_string()))?;
+
+        Ok(Self {
             template: template.to_string(),
+            env,
             add_generation_prompt: true,
             ..Default::default()
+        })
+    }
+
+    /// Sets how a leading system message is passed to the template.
+    pub fn set_system_message_mode(&mut self, system_message_mode: SystemMessageMode) {
+        self.system_message_mode = system_message_mode;
+    }
+
+    // check whether the template expects the system prompt as a separate variable
+    fn uses_system_message_variable(&self) -> bool {
+        match self.system_message_mode {
+            SystemMessageMode::Auto => self
+                .env
+                .get_template(TEMPLATE_NAME)
+                .is_ok_and(|tmpl| tmpl.undeclared_variables(false).contains("system_message")),
+            SystemMessageMode::Messages => false,
+            SystemMessageMode::Variable => true,
         }
     }
+}
 
-    pub fn set_add_generation_prompt(&mut self, add_generation_prompt: bool) {
-        self.add_generation_prompt = add_generation_prompt;
+// create an environment with the globals chat templates expect from Hugging Face transformers
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.add_function("raise_exception", raise_exception);
+    env.add_function("strftime_now", strftime_now);
+    env
+}
+
+// abort rendering with the message given by the template
+fn raise_exception(message: String) -> Result<String, Error> {
+    Err(Error::new(ErrorKind::InvalidOperation, message))
+}
+
+// format the current local time, e.g. `strftime_now("%d %b %Y")` for the date in Llama 3.1 prompts
+fn strftime_now(format: String) -> Result<String, Error> {
+    let items: Vec<Item> = StrftimeItems::new(&format).collect();
+    if items.contains(&Item::Error) {
+        return Err(Error::new(
+            ErrorKind::InvalidOperation,
+            format!("invalid strftime format {:?}", format),
+        ));
     }
+    Ok(chrono::Local::now()
+        .format_with_items(items.iter())
+        .to_string())
 }
 
 impl TemplateImpl for TemplateJinja {
     fn apply(&self, messages: &[(MessageRole, String)]) -> Result<String, CallmError> {
-        // parse messages into String tuples
+        let messages: Vec<ChatMessage> = messages.iter().map(ChatMessage::from).collect();
+        self.apply_messages(&messages)
+    }
+
+    fn apply_messages(&self, messages: &[ChatMessage]) -> Result<String, CallmError> {
+        self.apply_with_context(messages, serde_json::Value::Null)
+    }
+
+    fn apply_with_context(
+        &self,
+        messages: &[ChatMessage],
+        extra: serde_json::Value,
+    ) -> Result<String, CallmError> {
+        // start from the extra variables, built-in variables take precedence over them
+        let mut ctx: BTreeMap<String, Value> = match extra {
+            serde_json::Value::Null => BTreeMap::new(),
+            serde_json::Value::Object(extra) => extra
+                .iter()
+                .map(|(key, value)| (key.clone(), Value::from_serialize(value)))
+                .collect(),
+            _ => {
+                return Err(CallmError::TemplateError(
+                    "Extra template context must be a JSON object".to_string(),
+                ))
+            }
+        };
+
+        // split off leading system message if the template takes it separately
+        let (system_message, messages) = match messages.split_first() {
+            Some((first, rest))
+                if first.role == MessageRole::System && self.uses_system_message_variable() =>
+            {
+                (Value::from(first.content.as_str()), rest)
+            }
+            _ => (Value::UNDEFINED, messages),
+        };
+
+        // parse messages into maps, leaving unset optional fields undefined
         let msgs: Vec<_> = messages
             .iter()
-            .map(|(role, content)| context!(role => role.to_string(), content => content.to_string()))
+            .map(|message| {
+                let mut msg = BTreeMap::new();
+                msg.insert("role", message.role.to_string());
+                msg.insert("content", message.content.clone());
+                if let Some(name) = &message.name {
+                    msg.insert("name", name.clone());
+                }
+                if let Some(tool_call_id) = &message.tool_call_id {
+                    msg.insert("tool_call_id", tool_call_id.clone());
+                }
+                Value::from(msg)
+            })
             .collect();
 
         let bos_token = if let Some(tkn) = &self.bos_token {
@@ -45,9 +176,33 @@ impl TemplateImpl for TemplateJinja {
             ""
         };
 
-        let output = render!(&self.template, messages => msgs, bos_token, eos_token, add_generation_prompt => self.add_generation_prompt);
+        ctx.insert("messages".to_string(), Value::from(msgs));
+        ctx.insert("system_message".to_string(), system_message);
+        ctx.insert("bos_token".to_string(), Value::from(bos_token));
+        ctx.insert("eos_token".to_string(), Value::from(eos_token));
+        ctx.insert(
+            "add_generation_prompt".to_string(),
+            Value::from(self.add_generation_prompt),
+        );
+        let ctx = Value::from(ctx);
+        match self.env.get_template(TEMPLATE_NAME) {
+            Ok(tmpl) => tmpl.render(ctx),
+            // compile again to surface the syntax error
+            Err(_) => self.env.render_str(&self.template, ctx),
+        }
+        .map_err(|e| CallmError::TemplateError(e.to_string()))
+    }
+
+    fn set_add_generation_prompt(&mut self, add_generation_prompt: bool) {
+        self.add_generation_prompt = add_generation_prompt;
+    }
 
-        Ok(output)
+    fn adds_generation_prompt(&self) -> bool {
+        self.add_generation_prompt
+            && self.env.get_template(TEMPLATE_NAME).is_ok_and(|tmpl| {
+                tmpl.undeclared_variables(false)
+                    .contains("add_generation_prompt")
+            })
     }
 
     fn get_bos_token(&self) -> Option<&str> {
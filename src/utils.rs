@@ -1,10 +1,21 @@
 //! This module provides utility functions.
 
 use crate::error::CallmError;
-use crate::loaders::{LoaderGguf, LoaderImpl, LoaderSafetensors};
+use crate::loaders::{LoaderGguf, LoaderImpl, LoaderSafetensors, ModelInfo};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use tokenizers::{DecoderWrapper, PreTokenizerWrapper, Tokenizer};
+
+mod convert;
+pub use convert::convert_to_gguf;
+mod timer;
+pub(crate) use timer::Timer;
+#[cfg(feature = "hub")]
+mod hub;
+#[cfg(feature = "hub")]
+pub use hub::{download_model, DownloadProgress, HubRepo};
 
 /// Attempts to determine the appropriate model loader for a given file or directory path.
 ///
@@ -25,24 +36,34 @@ use std::sync::{Arc, Mutex};
 ///
 /// This function will return an error if:
 /// * The path does not exist.
-/// * The file extension is not recognized.
-/// * The function fails to extract or convert the file extension.
+/// * The file extension is not recognized, or the file is in the unsupported GGML format.
+/// * The file has no extension, or the extension is not valid UTF-8.
 pub fn autodetect_loader(path: &str) -> Result<Arc<Mutex<dyn LoaderImpl>>, CallmError> {
     // Get path metadata
     let metadata = fs::metadata(path)?;
     if metadata.is_file() {
         let pthbuf = PathBuf::from(path);
-        match pthbuf
+        let extension = pthbuf
             .as_path()
             .extension()
-            .expect("Unable to extract file extension name")
+            .ok_or(CallmError::LoaderFail(format!(
+                "Unable to detect model format of `{}`, file has no extension",
+                path
+            )))?
             .to_str()
-            .expect("Unable to convert file extension name")
-        {
+            .ok_or(CallmError::LoaderFail(format!(
+                "Unable to detect model format of `{}`, file extension is not valid UTF-8",
+                path
+            )))?;
+        match extension {
             "gguf" => {
                 return Ok(Arc::new(Mutex::new(LoaderGguf::new(path))));
             }
-            "ggml" => todo!("GGML format is not supported, yet."),
+            "ggml" => {
+                return Err(CallmError::LoaderFail(
+                    "GGML format is not supported, convert the model to GGUF".to_string(),
+                ))
+            }
             "safetensors" => return Ok(Arc::new(Mutex::new(LoaderSafetensors::new(path)))),
             _ => {
                 // As a last resort, try pointing the loader to the parent directory
@@ -60,3 +81,158 @@ pub fn autodetect_loader(path: &str) -> Result<Arc<Mutex<dyn LoaderImpl>>, Callm
     ))
 }
 
+/// Reads the description of the model at `path` without loading any weights.
+///
+/// Only the GGUF header, or the safetensors model config and tokenizer files are read, so this
+/// is quick enough to scan a directory of models and never touches the GPU. The loader is picked
+/// like in `autodetect_loader`.
+///
+/// # Errors
+///
+/// Returns an error if no suitable loader is found or the model metadata cannot be read.
+pub fn inspect(path: &str) -> Result<ModelInfo, CallmError> {
+    let loader = autodetect_loader(path)?;
+    let mut loader = loader.lock().unwrap();
+    loader.inspect()
+}
+
+/// Decodes token IDs into raw bytes, without lossy UTF-8 conversion.
+///
+/// Byte-level BPE tokens are mapped back to the exact bytes they represent, so multi-byte
+/// characters split across tokens survive decoding. Tokenizers using other decoders fall back
+/// to regular string decoding.
+///
+/// # Arguments
+///
+/// * `tokenizer` - The tokenizer that produced the token IDs.
+/// * `ids` - The token IDs to decode.
+/// * `skip_special_tokens` - Whether special tokens are left out of the output.
+///
+/// # Errors
+///
+/// This function will return an error if the tokenizer fails to decode the token IDs.
+pub fn decode_bytes(
+    tokenizer: &Tokenizer,
+    ids: &[u32],
+    skip_special_tokens: bool,
+) -> Result<Vec<u8>, CallmError> {
+    match tokenizer.get_decoder() {
+        Some(DecoderWrapper::ByteLevel(_)) => {
+            let char_bytes = byte_level_char_bytes();
+            let added_tokens = tokenizer.get_added_tokens_decoder();
+            let mut bytes = vec![];
+            for id in ids {
+                if skip_special_tokens && added_tokens.get(id).is_some_and(|tkn| tkn.special) {
+                    continue;
+                }
+                let Some(token) = tokenizer.id_to_token(*id) else {
+                    continue;
+                };
+                // tokens with characters outside the byte-level alphabet are kept verbatim
+                match token
+                    .chars()
+                    .map(|c| char_bytes.get(&c).copied())
+                    .collect::<Option<Vec<u8>>>()
+                {
+                    Some(token_bytes) => bytes.extend(token_bytes),
+                    None => bytes.extend(token.as_bytes()),
+                }
+            }
+            Ok(bytes)
+        }
+        _ => Ok(tokenizer
+            .decode(ids, skip_special_tokens)
+            .map_err(|e| CallmError::TokenizerError { msg: e.to_string() })?
+            .into_bytes()),
+    }
+}
+
+/// Returns whether the tokenizer prepends a space to the input before tokenizing.
+///
+/// Only the `add_prefix_space` flag of a byte-level pre-tokenizer is considered; other
+/// pre-tokenizers report `false`.
+pub fn adds_prefix_space(tokenizer: &Tokenizer) -> bool {
+    matches!(
+        tokenizer.get_pre_tokenizer(),
+        Some(PreTokenizerWrapper::ByteLevel(byte_level)) if byte_level.add_prefix_space
+    )
+}
+
+// map GPT-2 byte-level characters back to the bytes they encode
+// see: `<https://github.com/openai/gpt-2/blob/master/src/encoder.py#L9>`
+fn byte_level_char_bytes() -> HashMap<char, u8> {
+    let mut bs: Vec<u8> = (b'!'..=b'~')
+        .chain(b'\xA1'..=b'\xAC')
+        .chain(b'\xAE'..=b'\xFF')
+        .collect();
+    let mut cs: Vec<u32> = bs.iter().map(|b| *b as u32).collect();
+    let mut n = 0;
+    for b in 0..=255u8 {
+        if !bs.contains(&b) {
+            bs.push(b);
+            cs.push(256 + n);
+            n += 1;
+        }
+    }
+
+    bs.into_iter()
+        .zip(cs)
+        .filter_map(|(b, c)| char::from_u32(c).map(|c| (c, b)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::decoders::byte_level::ByteLevel;
+    use tokenizers::models::bpe::BPE;
+    use tokenizers::pre_tokenizers::byte_level::ByteLevel as ByteLevelPreTokenizer;
+
+    #[test]
+    fn test_decode_bytes_partial_utf8() {
+        // 'é' is encoded as bytes 0xC3 0xA9, represented by 'Ã' and '©' at byte level
+        let vocab = HashMap::from([("Ã".to_string(), 0), ("©".to_string(), 1)]);
+        let bpe = BPE::builder()
+            .vocab_and_merges(vocab, vec![])
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(bpe);
+        tokenizer.with_decoder(ByteLevel::default());
+
+        assert_eq!(decode_bytes(&tokenizer, &[0], true).unwrap(), vec![0xC3]);
+        assert_eq!(
+            decode_bytes(&tokenizer, &[0, 1], true).unwrap(),
+            "é".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_inspect_unknown_format() {
+        let dir = std::env::temp_dir().join("callm_test_inspect_unknown_format");
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("model");
+        fs::write(&path, b"").unwrap();
+        let result = inspect(path.to_str().unwrap());
+        assert!(matches!(result, Err(CallmError::LoaderFail(_))));
+
+        let path = dir.join("model.ggml");
+        fs::write(&path, b"").unwrap();
+        let result = inspect(path.to_str().unwrap());
+        assert!(matches!(result, Err(CallmError::LoaderFail(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_adds_prefix_space() {
+        let mut tokenizer = Tokenizer::new(BPE::default());
+        assert!(!adds_prefix_space(&tokenizer));
+
+        tokenizer.with_pre_tokenizer(ByteLevelPreTokenizer::new(true, true, true));
+        assert!(adds_prefix_space(&tokenizer));
+
+        tokenizer.with_pre_tokenizer(ByteLevelPreTokenizer::new(false, true, true));
+        assert!(!adds_prefix_space(&tokenizer));
+    }
+}
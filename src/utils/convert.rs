@@ -0,0 +1,419 @@
+//! Conversion of safetensors models to GGUF
+
+use crate::error::CallmError;
+use crate::loaders::LoaderSafetensors;
+use crate::models::{ModelArchitecture, EMBED_TOKENS_WEIGHT, LM_HEAD_WEIGHT};
+use candle_core::quantized::gguf_file::{self, Value};
+use candle_core::quantized::{GgmlDType, QTensor};
+use candle_core::{DType, Device, Tensor};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// GGML quantization format version written by current llama.cpp
+const GGML_QUANTIZATION_VERSION: u32 = 2;
+
+// GGUF token types
+const TOKEN_TYPE_NORMAL: i32 = 1;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+
+// subset of a Llama model config JSON
+#[derive(Deserialize)]
+struct LlamaHyperparams {
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    rms_norm_eps: f64,
+    #[serde(default = "default_rope_theta")]
+    rope_theta: f64,
+    max_position_embeddings: usize,
+}
+
+fn default_rope_theta() -> f64 {
+    10000.0
+}
+
+impl LlamaHyperparams {
+    fn num_key_value_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+}
+
+/// Converts a safetensors model into a GGUF file with quantized weights.
+///
+/// The model weights, config and tokenizer are read from `safetensors_dir` the same way
+/// `LoaderSafetensors` finds them. Matrices are quantized to `quant`, while norms and
+/// matrices whose rows do not fit the quantization block size are kept in `F32`. The written
+/// file carries the architecture, tokenizer and chat template metadata needed by `LoaderGguf`.
+///
+/// Only Llama models with a byte-level BPE tokenizer are supported for now.
+///
+/// # Arguments
+///
+/// * `safetensors_dir` - Path to the safetensors model directory or file.
+/// * `out_path` - Path of the GGUF file to write.
+/// * `quant` - Quantization type of the converted weights.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// * The model files cannot be found or read.
+/// * The model architecture or tokenizer type is not supported.
+/// * Quantizing the weights or writing the output file fails.
+pub fn convert_to_gguf<P: AsRef<Path>, Q: AsRef<Path>>(
+    safetensors_dir: P,
+    out_path: Q,
+    quant: GgmlDType,
+) -> Result<(), CallmError> {
+    let location = safetensors_dir
+        .as_ref()
+        .to_str()
+        .ok_or(CallmError::LoaderFail(
+            "Model path is not valid UTF-8".to_string(),
+        ))?;
+    let mut loader = LoaderSafetensors::new(location);
+    loader.resolve()?;
+    if *loader.architecture() != ModelArchitecture::Llama {
+        return Err(CallmError::UnsupportedModel);
+    }
+    let hparams: LlamaHyperparams = serde_json::from_value(loader.config().clone())?;
+
+    let mut metadata = llama_metadata(&hparams);
+    let tokenizer_json: serde_json::Value =
+        serde_json::from_reader(io::BufReader::new(fs::File::open(loader.tokenizer_path())?))?;
+    let tokens = tokenizer_metadata(&tokenizer_json, &mut metadata)?;
+    if let Some(bos_token_id) = loader.bos_token_id() {
+        metadata.insert(
+            "tokenizer.ggml.bos_token_id".to_string(),
+            Value::U32(bos_token_id as u32),
+        );
+    }
+    if let Some(eos_token_id) = loader
+        .eos_token()
+        .and_then(|eos| tokens.iter().position(|token| token == eos))
+    {
+        metadata.insert(
+            "tokenizer.ggml.eos_token_id".to_string(),
+            Value::U32(eos_token_id as u32),
+        );
+    }
+    if let Some(chat_template) = loader.chat_template() {
+        metadata.insert(
+            "tokenizer.chat_template".to_string(),
+            Value::String(chat_template.to_string()),
+        );
+    }
+
+    // quantize tensors file by file, keeping only the quantized copies in memory
+    let mut tensors = BTreeMap::new();
+    let mut embed_tokens = None;
+    for model_file in loader.model_files() {
+        for (name, tensor) in candle_core::safetensors::load(model_file, &Device::Cpu)? {
+            let Some(gguf_name) = gguf_tensor_name(&name) else {
+                log::debug!("Skipping tensor {}", name);
+                continue;
+            };
+            let tensor = tensor.to_dtype(DType::F32)?;
+            let tensor = if name.ends_with("self_attn.q_proj.weight") {
+                permute_rotary(&tensor, hparams.num_attention_heads)?
+            } else if name.ends_with("self_attn.k_proj.weight") {
+                permute_rotary(&tensor, hparams.num_key_value_heads())?
+            } else {
+                tensor
+            };
+            if name == EMBED_TOKENS_WEIGHT {
+                embed_tokens = Some(tensor.clone());
+            }
+            tensors.insert(gguf_name, quantize(&tensor, quant)?);
+        }
+    }
+
+    // models with tied word embeddings serve the LM head from the token embeddings
+    if !tensors.contains_key("output.weight") {
+        let embed_tokens = embed_tokens.ok_or(CallmError::LoaderFail(format!(
+            "Missing {} tensor",
+            EMBED_TOKENS_WEIGHT
+        )))?;
+        tensors.insert("output.weight".to_string(), quantize(&embed_tokens, quant)?);
+    }
+
+    let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    let mut file = io::BufWriter::new(fs::File::create(out_path.as_ref())?);
+    gguf_file::write(&mut file, &metadata, &tensors)?;
+    log::info!(
+        "Wrote {} tensors to {}",
+        tensors.len(),
+        out_path.as_ref().display()
+    );
+
+    Ok(())
+}
+
+// build general and architecture metadata for a Llama model
+fn llama_metadata(hparams: &LlamaHyperparams) -> BTreeMap<String, Value> {
+    BTreeMap::from([
+        (
+            "general.architecture".to_string(),
+            Value::String("llama".to_string()),
+        ),
+        (
+            "general.quantization_version".to_string(),
+            Value::U32(GGML_QUANTIZATION_VERSION),
+        ),
+        (
+            "llama.context_length".to_string(),
+            Value::U32(hparams.max_position_embeddings as u32),
+        ),
+        (
+            "llama.embedding_length".to_string(),
+            Value::U32(hparams.hidden_size as u32),
+        ),
+        (
+            "llama.block_count".to_string(),
+            Value::U32(hparams.num_hidden_layers as u32),
+        ),
+        (
+            "llama.feed_forward_length".to_string(),
+            Value::U32(hparams.intermediate_size as u32),
+        ),
+        (
+            "llama.rope.dimension_count".to_string(),
+            Value::U32(hparams.head_dim() as u32),
+        ),
+        (
+            "llama.attention.head_count".to_string(),
+            Value::U32(hparams.num_attention_heads as u32),
+        ),
+        (
+            "llama.attention.head_count_kv".to_string(),
+            Value::U32(hparams.num_key_value_heads() as u32),
+        ),
+        (
+            "llama.attention.layer_norm_rms_epsilon".to_string(),
+            Value::F32(hparams.rms_norm_eps as f32),
+        ),
+        (
+            "llama.rope.freq_base".to_string(),
+            Value::F32(hparams.rope_theta as f32),
+        ),
+    ])
+}
+
+// add byte-level BPE tokenizer metadata from tokenizer JSON, returning the tokens by ID
+fn tokenizer_metadata(
+    tokenizer_json: &serde_json::Value,
+    metadata: &mut BTreeMap<String, Value>,
+) -> Result<Vec<String>, CallmError> {
+    let model = &tokenizer_json["model"];
+    if model["type"].as_str() != Some("BPE")
+        || tokenizer_json["decoder"]["type"].as_str() != Some("ByteLevel")
+    {
+        return Err(CallmError::LoaderFail(
+            "Only byte-level BPE tokenizers can be converted to GGUF".to_string(),
+        ));
+    }
+    let vocab = model["vocab"].as_object().ok_or(CallmError::LoaderFail(
+        "Missing vocabulary in tokenizer".to_string(),
+    ))?;
+
+    // collect regular and added tokens by ID
+    let mut token_map: BTreeMap<usize, (String, i32)> = BTreeMap::new();
+    for (token, id) in vocab {
+        if let Some(id) = id.as_u64() {
+            token_map.insert(id as usize, (token.clone(), TOKEN_TYPE_NORMAL));
+        }
+    }
+    for added_token in tokenizer_json["added_tokens"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        let (Some(id), Some(content)) =
+            (added_token["id"].as_u64(), added_token["content"].as_str())
+        else {
+            continue;
+        };
+        let token_type = match added_token["special"].as_bool() {
+            Some(true) => TOKEN_TYPE_CONTROL,
+            _ => TOKEN_TYPE_USER_DEFINED,
+        };
+        token_map.insert(id as usize, (content.to_string(), token_type));
+    }
+
+    // fill gaps in the ID range with padding tokens, like llama.cpp does
+    let vocab_size = token_map.keys().next_back().map_or(0, |id| id + 1);
+    let (tokens, token_types): (Vec<String>, Vec<i32>) = (0..vocab_size)
+        .map(|id| {
+            token_map
+                .remove(&id)
+                .unwrap_or_else(|| (format!("[PAD{}]", id), TOKEN_TYPE_USER_DEFINED))
+        })
+        .unzip();
+
+    // merges are stored either as "a b" strings or as ["a", "b"] pairs
+    let merges: Vec<Value> = model["merges"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|merge| match merge {
+            serde_json::Value::String(merge) => Some(merge.clone()),
+            serde_json::Value::Array(pair) => match (pair.first(), pair.get(1)) {
+                (Some(serde_json::Value::String(a)), Some(serde_json::Value::String(b))) => {
+                    Some(format!("{} {}", a, b))
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+        .map(Value::String)
+        .collect();
+
+    metadata.insert(
+        "tokenizer.ggml.model".to_string(),
+        Value::String("gpt2".to_string()),
+    );
+    metadata.insert(
+        "tokenizer.ggml.pre".to_string(),
+        Value::String("llama-bpe".to_string()),
+    );
+    metadata.insert(
+        "tokenizer.ggml.tokens".to_string(),
+        Value::Array(tokens.iter().cloned().map(Value::String).collect()),
+    );
+    metadata.insert(
+        "tokenizer.ggml.token_type".to_string(),
+        Value::Array(token_types.into_iter().map(Value::I32).collect()),
+    );
+    metadata.insert("tokenizer.ggml.merges".to_string(), Value::Array(merges));
+
+    Ok(tokens)
+}
+
+// map a Hugging Face Llama tensor name to its GGUF name, skipping unused tensors
+fn gguf_tensor_name(name: &str) -> Option<String> {
+    match name {
+        EMBED_TOKENS_WEIGHT => return Some("token_embd.weight".to_string()),
+        "model.norm.weight" => return Some("output_norm.weight".to_string()),
+        LM_HEAD_WEIGHT => return Some("output.weight".to_string()),
+        _ => {}
+    }
+
+    let rest = name.strip_prefix("model.layers.")?;
+    let (layer, rest) = rest.split_once('.')?;
+    let layer: usize = layer.parse().ok()?;
+    let (module, suffix) = rest.rsplit_once('.')?;
+    let gguf_module = match module {
+        "self_attn.q_proj" => "attn_q",
+        "self_attn.k_proj" => "attn_k",
+        "self_attn.v_proj" => "attn_v",
+        "self_attn.o_proj" => "attn_output",
+        "mlp.gate_proj" => "ffn_gate",
+        "mlp.down_proj" => "ffn_down",
+        "mlp.up_proj" => "ffn_up",
+        "input_layernorm" => "attn_norm",
+        "post_attention_layernorm" => "ffn_norm",
+        _ => return None,
+    };
+
+    Some(format!("blk.{}.{}.{}", layer, gguf_module, suffix))
+}
+
+// reorder Q/K projection rows from the Hugging Face rotary layout to the interleaved GGUF one
+fn permute_rotary(weight: &Tensor, n_head: usize) -> Result<Tensor, CallmError> {
+    let (out_dim, in_dim) = weight.dims2()?;
+    Ok(weight
+        .reshape((n_head, 2, out_dim / n_head / 2, in_dim))?
+        .transpose(1, 2)?
+        .contiguous()?
+        .reshape((out_dim, in_dim))?)
+}
+
+// quantize matrices, keeping vectors and matrices with unaligned rows in F32
+fn quantize(tensor: &Tensor, quant: GgmlDType) -> Result<QTensor, CallmError> {
+    let quant = match tensor.dims() {
+        [.., last] if tensor.rank() > 1 && last % quant.block_size() == 0 => quant,
+        _ => GgmlDType::F32,
+    };
+    Ok(QTensor::quantize(tensor, quant)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gguf_tensor_name() {
+        assert_eq!(
+            gguf_tensor_name("model.embed_tokens.weight").as_deref(),
+            Some("token_embd.weight")
+        );
+        assert_eq!(
+            gguf_tensor_name("model.layers.12.self_attn.o_proj.weight").as_deref(),
+            Some("blk.12.attn_output.weight")
+        );
+        assert_eq!(
+            gguf_tensor_name("model.layers.0.post_attention_layernorm.weight").as_deref(),
+            Some("blk.0.ffn_norm.weight")
+        );
+        assert_eq!(
+            gguf_tensor_name("model.layers.0.self_attn.rotary_emb.inv_freq"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_permute_rotary() {
+        // 2 heads of 4 rows each, the halves of every head get interleaved
+        let weight = Tensor::arange(0f32, 8f32, &Device::Cpu)
+            .unwrap()
+            .reshape((8, 1))
+            .unwrap();
+        let permuted = permute_rotary(&weight, 2).unwrap();
+        assert_eq!(
+            permuted.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            vec![0., 2., 1., 3., 4., 6., 5., 7.]
+        );
+    }
+
+    #[test]
+    fn test_tokenizer_metadata() {
+        let tokenizer_json: serde_json::Value = serde_json::from_str(
+            r#"{
+                "added_tokens": [{"id": 4, "content": "<|eot|>", "special": true}],
+                "decoder": {"type": "ByteLevel"},
+                "model": {
+                    "type": "BPE",
+                    "vocab": {"a": 0, "b": 1, "ab": 2},
+                    "merges": ["a b", ["ab", "b"]]
+                }
+            }"#,
+        )
+        .unwrap();
+        let mut metadata = BTreeMap::new();
+        let tokens = tokenizer_metadata(&tokenizer_json, &mut metadata).unwrap();
+
+        assert_eq!(tokens, vec!["a", "b", "ab", "[PAD3]", "<|eot|>"]);
+        match &metadata["tokenizer.ggml.token_type"] {
+            Value::Array(types) => assert!(matches!(types[4], Value::I32(TOKEN_TYPE_CONTROL))),
+            _ => panic!("Token types not stored as an array"),
+        }
+        match &metadata["tokenizer.ggml.merges"] {
+            Value::Array(merges) => assert!(matches!(&merges[1], Value::String(m) if m == "ab b")),
+            _ => panic!("Merges not stored as an array"),
+        }
+
+        let unigram: serde_json::Value =
+            serde_json::from_str(r#"{"model": {"type": "Unigram"}}"#).unwrap();
+        assert!(tokenizer_metadata(&unigram, &mut BTreeMap::new()).is_err());
+    }
+}
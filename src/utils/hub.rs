@@ -0,0 +1,255 @@
+//! Model downloads from the Hugging Face Hub
+
+use crate::error::CallmError;
+use crate::loaders::safetensors::{
+    read_model_index_json, DEFAULT_MODEL_INDEX_JSON, DEFAULT_MODEL_SAFETENSORS_FILE,
+};
+use hf_hub::api::sync::{ApiBuilder, ApiRepo};
+use hf_hub::api::Progress;
+use hf_hub::{Cache, CacheRepo, Repo, RepoType};
+use std::path::PathBuf;
+
+// files required next to safetensors weights
+const REQUIRED_FILES: &[&str] = &["config.json", "tokenizer.json"];
+// files downloaded when the repo provides them
+const OPTIONAL_FILES: &[&str] = &["tokenizer_config.json", "generation_config.json"];
+
+/// Callback receiving download progress as `(filename, bytes_downloaded, bytes_total)`.
+pub type DownloadProgress = dyn FnMut(&str, usize, usize) + Send;
+
+/// A model repository on the Hugging Face Hub.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HubRepo {
+    repo_id: String,
+    revision: Option<String>,
+    gguf_file: Option<String>,
+}
+
+impl HubRepo {
+    /// Creates a new `HubRepo` for the given repo ID, e.g. `meta-llama/Meta-Llama-3-8B-Instruct`.
+    pub fn new(repo_id: &str) -> Self {
+        Self {
+            repo_id: repo_id.to_string(),
+            revision: None,
+            gguf_file: None,
+        }
+    }
+
+    /// Sets the revision (branch, tag or commit hash) to download, `main` by default.
+    pub fn with_revision(mut self, revision: &str) -> Self {
+        self.revision = Some(revision.to_string());
+        self
+    }
+
+    /// Downloads a single GGUF file of the repo instead of safetensors weights.
+    pub fn with_gguf_file(mut self, filename: &str) -> Self {
+        self.gguf_file = Some(filename.to_string());
+        self
+    }
+
+    /// Gets the repo ID.
+    pub fn repo_id(&self) -> &str {
+        &self.repo_id
+    }
+
+    /// Gets the revision, if set.
+    pub fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
+
+    /// Gets the GGUF filename, if set.
+    pub fn gguf_file(&self) -> Option<&str> {
+        self.gguf_file.as_deref()
+    }
+
+    fn repo(&self) -> Repo {
+        match &self.revision {
+            Some(revision) => {
+                Repo::with_revision(self.repo_id.clone(), RepoType::Model, revision.clone())
+            }
+            None => Repo::model(self.repo_id.clone()),
+        }
+    }
+}
+
+impl From<&str> for HubRepo {
+    fn from(repo_id: &str) -> Self {
+        Self::new(repo_id)
+    }
+}
+
+/// Downloads a model from the Hugging Face Hub into the local cache.
+///
+/// Files already in the cache are not downloaded again, and the Hub is only queried for the
+/// repo file list when a file is missing, so fully cached models resolve offline. The cache
+/// location follows `hf-hub` defaults, honoring the `HF_HOME` environment variable.
+///
+/// Only the safetensors shards listed in `model.safetensors.index.json` are downloaded, or
+/// `model.safetensors` for repos without an index. Other weight files, e.g. duplicate
+/// checkpoints in other formats, are left out.
+///
+/// # Returns
+///
+/// The path to pass to `autodetect_loader`: the downloaded GGUF file if the repo has a GGUF
+/// filename set, otherwise the cache directory holding the config, tokenizer and safetensors
+/// shards.
+pub fn download_model(
+    repo: &HubRepo,
+    mut progress: Option<&mut DownloadProgress>,
+) -> Result<PathBuf, CallmError> {
+    let api = ApiBuilder::new().build().map_err(hub_error)?;
+    let api_repo = api.repo(repo.repo());
+    let cache_repo = Cache::default().repo(repo.repo());
+    let mut fetch =
+        |filename: &str| fetch_file(&api_repo, &cache_repo, filename, progress.as_deref_mut());
+
+    if let Some(gguf_file) = &repo.gguf_file {
+        return fetch(gguf_file.as_str());
+    }
+
+    let mut model_dir = None;
+    for &filename in REQUIRED_FILES {
+        let path = fetch(filename)?;
+        model_dir = path.parent().map(PathBuf::from);
+    }
+
+    // Query the repo file list once, and only for files missing from the cache
+    let mut repo_files: Option<Vec<String>> = None;
+    let mut in_repo = |filename: &str| -> Result<bool, CallmError> {
+        if repo_files.is_none() {
+            let info = api_repo.info().map_err(hub_error)?;
+            repo_files = Some(info.siblings.into_iter().map(|s| s.rfilename).collect());
+        }
+        Ok(repo_files
+            .as_ref()
+            .is_some_and(|files| files.iter().any(|f| f == filename)))
+    };
+
+    for &filename in OPTIONAL_FILES {
+        if cache_repo.get(filename).is_some() {
+            continue;
+        }
+        match in_repo(filename) {
+            Ok(true) => {
+                fetch(filename)?;
+            }
+            Ok(false) => {}
+            Err(e) => log::warn!("Skipping optional {}: {}", filename, e),
+        }
+    }
+
+    // Sharded weights come with an index naming the shards
+    let index = match cache_repo.get(DEFAULT_MODEL_INDEX_JSON) {
+        Some(path) => Some(path),
+        None if cache_repo.get(DEFAULT_MODEL_SAFETENSORS_FILE).is_some() => None,
+        None if in_repo(DEFAULT_MODEL_INDEX_JSON)? => Some(fetch(DEFAULT_MODEL_INDEX_JSON)?),
+        None => None,
+    };
+    let weight_files = match index {
+        Some(path) => read_model_index_json(path)?,
+        None => vec![DEFAULT_MODEL_SAFETENSORS_FILE.to_string()],
+    };
+    for filename in weight_files {
+        fetch(&filename)?;
+    }
+
+    model_dir.ok_or(CallmError::LoaderFail(format!(
+        "Unable to locate downloaded files of {}",
+        repo.repo_id
+    )))
+}
+
+// get a file from the cache, downloading it when missing
+fn fetch_file(
+    api_repo: &ApiRepo,
+    cache_repo: &CacheRepo,
+    filename: &str,
+    progress: Option<&mut DownloadProgress>,
+) -> Result<PathBuf, CallmError> {
+    if let Some(path) = cache_repo.get(filename) {
+        return Ok(path);
+    }
+
+    log::info!("Downloading {}", filename);
+    match progress {
+        Some(callback) => api_repo.download_with_progress(
+            filename,
+            CallbackProgress {
+                callback,
+                filename: String::new(),
+                downloaded: 0,
+                total: 0,
+            },
+        ),
+        None => api_repo.download(filename),
+    }
+    .map_err(hub_error)
+}
+
+fn hub_error(e: impl std::fmt::Display) -> CallmError {
+    CallmError::LoaderFail(format!("Hugging Face Hub download failed: {}", e))
+}
+
+// adapts a progress callback to the hf-hub progress interface
+struct CallbackProgress<'a> {
+    callback: &'a mut DownloadProgress,
+    filename: String,
+    downloaded: usize,
+    total: usize,
+}
+
+impl Progress for CallbackProgress<'_> {
+    fn init(&mut self, size: usize, filename: &str) {
+        self.filename = filename.to_string();
+        self.downloaded = 0;
+        self.total = size;
+        (self.callback)(&self.filename, self.downloaded, self.total);
+    }
+
+    fn update(&mut self, size: usize) {
+        self.downloaded += size;
+        (self.callback)(&self.filename, self.downloaded, self.total);
+    }
+
+    fn finish(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_callback_progress() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut callback = move |filename: &str, downloaded: usize, total: usize| {
+            tx.send((filename.to_string(), downloaded, total)).unwrap();
+        };
+        let mut progress = CallbackProgress {
+            callback: &mut callback,
+            filename: String::new(),
+            downloaded: 0,
+            total: 0,
+        };
+        progress.init(10, "model.safetensors");
+        progress.update(4);
+        progress.update(6);
+        progress.finish();
+
+        let name = "model.safetensors".to_string();
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![(name.clone(), 0, 10), (name.clone(), 4, 10), (name, 10, 10)]
+        );
+    }
+
+    #[test]
+    fn test_hub_repo() {
+        let repo = HubRepo::from("org/model")
+            .with_revision("dev")
+            .with_gguf_file("model.Q4_K_M.gguf");
+        assert_eq!(repo.repo_id(), "org/model");
+        assert_eq!(repo.revision(), Some("dev"));
+        assert_eq!(repo.gguf_file(), Some("model.Q4_K_M.gguf"));
+        assert_eq!(repo.repo().revision(), "dev");
+    }
+}
@@ -0,0 +1,37 @@
+//! Wall clock timer usable on every target
+
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+/// Measures elapsed wall clock time.
+///
+/// `Instant::now` panics on `wasm32-unknown-unknown`, so there the timer measures nothing and
+/// reports zero durations.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Timer {
+    #[cfg(not(target_arch = "wasm32"))]
+    start: Instant,
+}
+
+impl Timer {
+    /// Starts a new timer.
+    pub(crate) fn start() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            start: Instant::now(),
+        }
+    }
+
+    /// Returns the time elapsed since the timer started.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Returns the time elapsed since the timer started, always zero on `wasm32`.
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn elapsed(&self) -> Duration {
+        Duration::ZERO
+    }
+}
@@ -0,0 +1,30 @@
+use callm::templates::{apply, MessageRole};
+
+const JINJA_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}<|{{ message['role'] }}|>{{ message['content'] }}{{ eos_token }}{% endfor %}{% if add_generation_prompt %}<|assistant|>{% endif %}";
+
+#[test]
+fn apply_with_tokens() {
+    let msgs = vec![(MessageRole::User, "User message 1".to_string())];
+
+    assert_eq!(
+        apply(JINJA_TEMPLATE, msgs.as_slice(), Some("<s>"), Some("</s>")).unwrap(),
+        "<s><|user|>User message 1</s><|assistant|>"
+    );
+}
+
+#[test]
+fn apply_without_tokens() {
+    let msgs = vec![(MessageRole::User, "User message 1".to_string())];
+
+    assert_eq!(
+        apply(JINJA_TEMPLATE, msgs.as_slice(), None, None).unwrap(),
+        "<|user|>User message 1<|assistant|>"
+    );
+}
+
+#[test]
+fn apply_invalid_template() {
+    let msgs = vec![(MessageRole::User, "User message 1".to_string())];
+
+    assert!(apply("{% for message in messages %}", msgs.as_slice(), None, None).is_err());
+}
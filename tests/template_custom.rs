@@ -0,0 +1,50 @@
+use callm::error::CallmError;
+use callm::templates::{ChatMessage, MessageRole, TemplateImpl};
+
+// implements only the tuple based `apply`
+struct TemplateUpper;
+
+impl TemplateImpl for TemplateUpper {
+    fn apply(&self, messages: &[(MessageRole, String)]) -> Result<String, CallmError> {
+        Ok(messages
+            .iter()
+            .map(|(role, content)| format!("{}: {}\n", role, content.to_uppercase()))
+            .collect())
+    }
+
+    fn get_bos_token(&self) -> Option<&str> {
+        None
+    }
+
+    fn set_bos_token(&mut self, _bos_token: Option<String>) {}
+
+    fn get_eos_token(&self) -> Option<&str> {
+        None
+    }
+
+    fn set_eos_token(&mut self, _eos_token: Option<String>) {}
+}
+
+#[test]
+fn apply_messages_defaults_to_apply() {
+    let template = TemplateUpper;
+    let messages = [
+        ChatMessage::new(MessageRole::User, "hi").with_name("alice"),
+        ChatMessage::new(MessageRole::Assistant, "hello"),
+    ];
+    let expected = "user: HI\nassistant: HELLO\n";
+    assert_eq!(template.apply_messages(&messages).unwrap(), expected);
+    assert_eq!(
+        template
+            .apply_with_context(&messages, serde_json::Value::Null)
+            .unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn set_add_generation_prompt_defaults_to_noop() {
+    let mut template = TemplateUpper;
+    template.set_add_generation_prompt(true);
+    assert!(!template.adds_generation_prompt());
+}
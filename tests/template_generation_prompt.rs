@@ -0,0 +1,40 @@
+use callm::templates::{MessageRole, TemplateDummy, TemplateImpl, TemplateJinja};
+
+const JINJA_TEMPLATE: &str = "{% for message in messages %}{{ message['content'] }}{% endfor %}{% if add_generation_prompt %}<|assistant|>{% endif %}";
+const JINJA_TEMPLATE_NO_GENERATION_PROMPT: &str =
+    "{% for message in messages %}{{ message['content'] }}{% endfor %}";
+
+#[test]
+fn jinja_adds_generation_prompt() {
+    let mut template = TemplateJinja::new(JINJA_TEMPLATE);
+    assert!(template.adds_generation_prompt());
+
+    template.set_add_generation_prompt(false);
+    assert!(!template.adds_generation_prompt());
+}
+
+#[test]
+fn jinja_renders_without_generation_prompt() {
+    let mut template = TemplateJinja::new(JINJA_TEMPLATE);
+    let messages = [(MessageRole::User, "Hi".to_string())];
+    assert_eq!(template.apply(&messages).unwrap(), "Hi<|assistant|>");
+
+    template.set_add_generation_prompt(false);
+    assert_eq!(template.apply(&messages).unwrap(), "Hi");
+}
+
+#[test]
+fn jinja_without_generation_prompt() {
+    let template = TemplateJinja::new(JINJA_TEMPLATE_NO_GENERATION_PROMPT);
+    assert!(!template.adds_generation_prompt());
+}
+
+#[test]
+fn dummy_without_generation_prompt() {
+    let mut template = TemplateDummy::new();
+    assert!(!template.adds_generation_prompt());
+
+    // accepted as a no-op
+    template.set_add_generation_prompt(true);
+    assert!(!template.adds_generation_prompt());
+}
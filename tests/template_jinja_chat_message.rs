@@ -0,0 +1,58 @@
+use callm::templates::{ChatMessage, MessageRole, TemplateImpl, TemplateJinja as Template};
+
+// Renders the optional participant name and tool call ID when present
+const JINJA_TEMPLATE: &str = "{% for message in messages %}<{{ message['role'] }}{% if message['name'] is defined %} name={{ message['name'] }}{% endif %}{% if message['tool_call_id'] is defined %} id={{ message['tool_call_id'] }}{% endif %}>{{ message['content'] }}{% endfor %}";
+
+#[test]
+fn message_with_name() {
+    let msgs = vec![ChatMessage::new(MessageRole::User, "Hi").with_name("alice")];
+    let template = Template::new(JINJA_TEMPLATE);
+
+    assert_eq!(
+        template.apply_messages(msgs.as_slice()).unwrap(),
+        "<user name=alice>Hi"
+    );
+}
+
+#[test]
+fn message_with_tool_call_id() {
+    let msgs = vec![
+        ChatMessage::new(MessageRole::User, "Hi"),
+        ChatMessage::new(MessageRole::Assistant, "42").with_tool_call_id("call_0"),
+    ];
+    let template = Template::new(JINJA_TEMPLATE);
+
+    assert_eq!(
+        template.apply_messages(msgs.as_slice()).unwrap(),
+        "<user>Hi<assistant id=call_0>42"
+    );
+}
+
+#[test]
+fn tool_and_function_roles() {
+    let msgs = vec![
+        ChatMessage::new(MessageRole::User, "Weather?"),
+        ChatMessage::new(MessageRole::Tool, "sunny").with_tool_call_id("call_0"),
+        ChatMessage::new(MessageRole::Function, "22C").with_name("temperature"),
+    ];
+    let template = Template::new(JINJA_TEMPLATE);
+
+    assert_eq!(
+        template.apply_messages(msgs.as_slice()).unwrap(),
+        "<user>Weather?<tool id=call_0>sunny<function name=temperature>22C"
+    );
+    assert_eq!(MessageRole::Tool.to_string(), "tool");
+    assert_eq!(MessageRole::Function.to_string(), "function");
+}
+
+#[test]
+fn tuple_messages_match_structured() {
+    let tuples = vec![(MessageRole::User, "Hi".to_string())];
+    let msgs: Vec<ChatMessage> = tuples.iter().map(ChatMessage::from).collect();
+    let template = Template::new(JINJA_TEMPLATE);
+
+    assert_eq!(
+        template.apply(tuples.as_slice()).unwrap(),
+        template.apply_messages(msgs.as_slice()).unwrap()
+    );
+}
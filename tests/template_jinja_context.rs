@@ -0,0 +1,65 @@
+use callm::error::CallmError;
+use callm::templates::{ChatMessage, MessageRole, TemplateDummy, TemplateImpl, TemplateJinja};
+use serde_json::json;
+
+// Lists available tools before the messages, like function calling templates do
+const JINJA_TEMPLATE_TOOLS: &str = "{% if tools %}[TOOLS]{% for tool in tools %}{{ tool['function']['name'] }};{% endfor %}[/TOOLS]{% endif %}{% for message in messages %}{{ message['content'] }}{% endfor %}{% if date_string is defined %} ({{ date_string }}){% endif %}";
+
+#[test]
+fn apply_with_tools() {
+    let msgs = vec![ChatMessage::new(MessageRole::User, "Weather in Paris?")];
+    let extra = json!({
+        "tools": [
+            {"type": "function", "function": {"name": "get_weather", "parameters": {}}},
+            {"type": "function", "function": {"name": "get_time", "parameters": {}}},
+        ],
+        "date_string": "26 Jul 2024",
+    });
+    let template = TemplateJinja::new(JINJA_TEMPLATE_TOOLS);
+
+    assert_eq!(
+        template.apply_with_context(&msgs, extra).unwrap(),
+        "[TOOLS]get_weather;get_time;[/TOOLS]Weather in Paris? (26 Jul 2024)"
+    );
+    assert_eq!(
+        template.apply_with_context(&msgs, json!(null)).unwrap(),
+        template.apply_messages(&msgs).unwrap()
+    );
+}
+
+#[test]
+fn builtin_variables_take_precedence() {
+    let msgs = vec![ChatMessage::new(MessageRole::User, "Hi")];
+    let template = TemplateJinja::new("{{ messages | length }}");
+
+    assert_eq!(
+        template
+            .apply_with_context(&msgs, json!({"messages": [1, 2, 3]}))
+            .unwrap(),
+        "1"
+    );
+}
+
+#[test]
+fn extra_context_not_an_object() {
+    let msgs = vec![ChatMessage::new(MessageRole::User, "Hi")];
+    let template = TemplateJinja::new(JINJA_TEMPLATE_TOOLS);
+
+    assert!(matches!(
+        template.apply_with_context(&msgs, json!([1, 2])),
+        Err(CallmError::TemplateError(_))
+    ));
+}
+
+#[test]
+fn dummy_ignores_extra_context() {
+    let msgs = vec![ChatMessage::new(MessageRole::User, "Hi")];
+    let template = TemplateDummy::new();
+
+    assert_eq!(
+        template
+            .apply_with_context(&msgs, json!({"tools": []}))
+            .unwrap(),
+        "Hi"
+    );
+}
@@ -0,0 +1,39 @@
+use callm::error::CallmError;
+use callm::templates::{MessageRole, TemplateImpl, TemplateJinja as Template};
+
+const JINJA_TEMPLATE_RAISE: &str = "{% for message in messages %}{% if message['role'] == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}{{ message['content'] }}{% endfor %}";
+const JINJA_TEMPLATE_DATE: &str = "{{ strftime_now('%Y') }}";
+const JINJA_TEMPLATE_BAD_DATE: &str = "{{ strftime_now('%Q') }}";
+
+#[test]
+fn raise_exception() {
+    let template = Template::new(JINJA_TEMPLATE_RAISE);
+    assert_eq!(
+        template
+            .apply(&[(MessageRole::User, "User message".to_string())])
+            .unwrap(),
+        "User message"
+    );
+
+    match template.apply(&[(MessageRole::System, "System message".to_string())]) {
+        Err(CallmError::TemplateError(msg)) => assert!(msg.contains("System role not supported")),
+        other => panic!("expected a template error, got {:?}", other),
+    }
+}
+
+#[test]
+fn strftime_now() {
+    let template = Template::new(JINJA_TEMPLATE_DATE);
+    let year = template.apply(&[]).unwrap();
+    assert_eq!(year.len(), 4);
+    assert!(year.chars().all(|c| c.is_ascii_digit()));
+}
+
+#[test]
+fn strftime_now_invalid_format() {
+    let template = Template::new(JINJA_TEMPLATE_BAD_DATE);
+    assert!(matches!(
+        template.apply(&[]),
+        Err(CallmError::TemplateError(_))
+    ));
+}
@@ -0,0 +1,57 @@
+use callm::templates::{MessageRole, TemplateImpl, TemplateJinja as Template};
+
+// Generation prompt added inline on the last message
+const JINJA_TEMPLATE_LOOP_LAST: &str = "{% for message in messages %}<|im_start|>{{ message['role'] }}\n{{ message['content'] }}<|im_end|>\n{% if loop.last and add_generation_prompt %}<|im_start|>assistant\n{% endif %}{% endfor %}";
+
+// Exercises remaining loop attributes
+const JINJA_TEMPLATE_LOOP_ATTRS: &str = "{% for message in messages %}{% if loop.first %}{{ bos_token }}{% endif %}[{{ loop.index0 }}/{{ loop.index }}]{{ message['content'] }}{% endfor %}";
+
+#[test]
+fn loop_last_single_message() {
+    let msgs = vec![(MessageRole::User, "User message 1".to_string())];
+    let template = Template::new(JINJA_TEMPLATE_LOOP_LAST);
+
+    assert_eq!(
+        template.apply(msgs.as_slice()).unwrap(),
+        "<|im_start|>user\nUser message 1<|im_end|>\n<|im_start|>assistant\n"
+    );
+}
+
+#[test]
+fn loop_last_three_messages() {
+    let msgs = vec![
+        (MessageRole::User, "User message 1".to_string()),
+        (MessageRole::Assistant, "Assistant message 1".to_string()),
+        (MessageRole::User, "User message 2".to_string()),
+    ];
+    let template = Template::new(JINJA_TEMPLATE_LOOP_LAST);
+
+    assert_eq!(
+        template.apply(msgs.as_slice()).unwrap(),
+        "<|im_start|>user\nUser message 1<|im_end|>\n<|im_start|>assistant\nAssistant message 1<|im_end|>\n<|im_start|>user\nUser message 2<|im_end|>\n<|im_start|>assistant\n"
+    );
+}
+
+#[test]
+fn loop_last_without_generation_prompt() {
+    let msgs = vec![(MessageRole::User, "User message 1".to_string())];
+    let mut template = Template::new(JINJA_TEMPLATE_LOOP_LAST);
+    template.set_add_generation_prompt(false);
+
+    assert_eq!(
+        template.apply(msgs.as_slice()).unwrap(),
+        "<|im_start|>user\nUser message 1<|im_end|>\n"
+    );
+}
+
+#[test]
+fn loop_first_and_indices() {
+    let msgs = vec![
+        (MessageRole::User, "a".to_string()),
+        (MessageRole::Assistant, "b".to_string()),
+    ];
+    let mut template = Template::new(JINJA_TEMPLATE_LOOP_ATTRS);
+    template.set_bos_token(Some("<s>".to_string()));
+
+    assert_eq!(template.apply(msgs.as_slice()).unwrap(), "<s>[0/1]a[1/2]b");
+}
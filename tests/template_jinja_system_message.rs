@@ -0,0 +1,54 @@
+use callm::templates::{MessageRole, SystemMessageMode, TemplateImpl, TemplateJinja as Template};
+
+// System prompt passed as a dedicated variable (Command-R style)
+const JINJA_TEMPLATE_VARIABLE: &str = "{% if system_message is defined %}<SYS>{{ system_message }}</SYS>{% endif %}{% for message in messages %}<{{ message['role'] }}>{{ message['content'] }}{% endfor %}";
+
+// System prompt read from the message list
+const JINJA_TEMPLATE_MESSAGES: &str =
+    "{% for message in messages %}<{{ message['role'] }}>{{ message['content'] }}{% endfor %}";
+
+fn messages() -> Vec<(MessageRole, String)> {
+    vec![
+        (MessageRole::System, "Be brief.".to_string()),
+        (MessageRole::User, "Hi".to_string()),
+    ]
+}
+
+#[test]
+fn auto_detects_system_message_variable() {
+    let template = Template::new(JINJA_TEMPLATE_VARIABLE);
+
+    assert_eq!(
+        template.apply(messages().as_slice()).unwrap(),
+        "<SYS>Be brief.</SYS><user>Hi"
+    );
+}
+
+#[test]
+fn auto_keeps_system_message_in_messages() {
+    let template = Template::new(JINJA_TEMPLATE_MESSAGES);
+
+    assert_eq!(
+        template.apply(messages().as_slice()).unwrap(),
+        "<system>Be brief.<user>Hi"
+    );
+}
+
+#[test]
+fn forced_messages_mode() {
+    let mut template = Template::new(JINJA_TEMPLATE_VARIABLE);
+    template.set_system_message_mode(SystemMessageMode::Messages);
+
+    assert_eq!(
+        template.apply(messages().as_slice()).unwrap(),
+        "<system>Be brief.<user>Hi"
+    );
+}
+
+#[test]
+fn no_system_message() {
+    let msgs = vec![(MessageRole::User, "Hi".to_string())];
+    let template = Template::new(JINJA_TEMPLATE_VARIABLE);
+
+    assert_eq!(template.apply(msgs.as_slice()).unwrap(), "<user>Hi");
+}
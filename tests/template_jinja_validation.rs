@@ -0,0 +1,19 @@
+use callm::error::CallmError;
+use callm::templates::{MessageRole, TemplateImpl, TemplateJinja as Template};
+
+#[test]
+fn valid_template() {
+    let msgs = vec![(MessageRole::User, "User message 1".to_string())];
+    let template =
+        Template::try_new("{% for message in messages %}{{ message['content'] }}{% endfor %}")
+            .unwrap();
+
+    assert_eq!(template.apply(msgs.as_slice()).unwrap(), "User message 1");
+}
+
+#[test]
+fn invalid_template() {
+    let result = Template::try_new("{% for message in messages %}{{ message['content'] }}");
+
+    assert!(matches!(result, Err(CallmError::TemplateError(_))));
+}